@@ -0,0 +1,31 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[path = "../src/encode.rs"]
+mod encode;
+
+const CONCURRENT_SENDS: usize = 10;
+
+fn bench_encode(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("encode");
+    for size in [1024, 64 * 1024] {
+        let msg = "x".repeat(size);
+        for (mode, threshold) in [("sync", usize::MAX), ("async", 0)] {
+            group.bench_with_input(BenchmarkId::new(mode, size), &msg, |b, msg| {
+                b.to_async(&rt).iter(|| async {
+                    let sends = (0..CONCURRENT_SENDS).map(|_| {
+                        let msg = msg.clone();
+                        tokio::spawn(async move { encode::to_vec(msg, size, threshold).await })
+                    });
+                    for send in sends.collect::<Vec<_>>() {
+                        send.await.unwrap().unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode);
+criterion_main!(benches);
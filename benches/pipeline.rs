@@ -0,0 +1,152 @@
+//! Receive path of a 1 KB chat message: decode, then hand the raw payload on to a consumer. And
+//! the encoding of a replay of 10k messages, into fresh or reused buffers, and its rendering,
+//! along with the allocations per rendered message.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use chrono::Utc;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use libp2p::PeerId;
+
+#[allow(dead_code)]
+#[path = "../src/api.rs"]
+mod api;
+#[allow(dead_code)]
+#[path = "../src/display.rs"]
+mod display;
+#[allow(dead_code)]
+#[path = "../src/encode.rs"]
+mod encode;
+#[allow(dead_code)]
+#[path = "../src/shutdown.rs"]
+mod shutdown;
+
+/// Counts allocations, including reallocations.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn bench_pipeline(c: &mut Criterion) {
+    let data = encode::to_cbor(&api::ChatApi::message("x".repeat(1024))).unwrap();
+
+    let mut group = c.benchmark_group("pipeline-1k");
+    group.bench_function("vec", |b| {
+        b.iter_batched(
+            || data.clone(),
+            |data| {
+                // Event carries a copy, the consumer another one.
+                let raw = data.to_vec();
+                let message: api::ChatApi = encode::from_cbor(&raw).unwrap();
+                black_box((raw.clone(), message))
+            },
+            criterion::BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("bytes", |b| {
+        b.iter_batched(
+            || data.clone(),
+            |data| {
+                let raw = Bytes::from(data);
+                let message: api::ChatApi = encode::from_cbor(&raw).unwrap();
+                black_box((raw.clone(), message))
+            },
+            criterion::BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+const REPLAY: usize = 10_000;
+
+fn bench_replay(c: &mut Criterion) {
+    let messages = (0..REPLAY)
+        .map(|i| api::ChatApi::message(format!("message {} of the replay", i)))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("replay-10k");
+    group.throughput(criterion::Throughput::Elements(REPLAY as u64));
+    group.bench_function("fresh", |b| {
+        b.iter(|| {
+            for msg in &messages {
+                let bytes = encode::to_cbor(msg).unwrap();
+                black_box(&bytes[..]);
+            }
+        })
+    });
+    group.bench_function("reused", |b| {
+        b.iter(|| {
+            for msg in &messages {
+                encode::with_cbor(msg, |bytes| black_box(bytes.len())).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_render(c: &mut Criterion) {
+    let peer = PeerId::random();
+    let messages = (0..REPLAY)
+        .map(|i| format!("message {} of the replay", i))
+        .collect::<Vec<_>>();
+    let mut renderer =
+        display::Renderer::new(Default::default(), None).with_writer(std::io::sink());
+    let render = |renderer: &mut display::Renderer| {
+        let now = Utc::now();
+        for message in &messages {
+            let rendered = renderer
+                .message(
+                    peer,
+                    now,
+                    now,
+                    "bob",
+                    message,
+                    Some(80),
+                    Instant::now(),
+                    None,
+                )
+                .unwrap();
+            black_box(rendered);
+        }
+    };
+
+    // Warmed up, the renderer's buffer is grown already.
+    render(&mut renderer);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    render(&mut renderer);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "render-10k: {:.2} allocations per rendered message",
+        allocations as f64 / REPLAY as f64
+    );
+
+    let mut group = c.benchmark_group("render-10k");
+    group.throughput(criterion::Throughput::Elements(REPLAY as u64));
+    group.bench_function("reused", |b| b.iter(|| render(&mut renderer)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_pipeline, bench_replay, bench_render);
+criterion_main!(benches);
@@ -0,0 +1,100 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use libp2p::PeerId;
+
+#[allow(dead_code)]
+#[path = "../src/api.rs"]
+mod api;
+#[allow(dead_code)]
+#[path = "../src/peers.rs"]
+mod peers;
+#[allow(dead_code)]
+#[path = "../src/state.rs"]
+mod state;
+
+const PEERS: usize = 10_000;
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The previous layout, garbage collected by a full sweep.
+#[derive(Default)]
+struct BTreeState {
+    connected_peers: BTreeSet<PeerId>,
+    known_nicknames: BTreeMap<PeerId, String>,
+}
+
+fn populate(peers: &[PeerId]) -> (state::State, BTreeState) {
+    let mut hashed = state::State::default();
+    let mut btree = BTreeState::default();
+    let now = Instant::now();
+    for (i, peer) in peers.iter().enumerate() {
+        hashed.seen(*peer, now);
+        hashed.connected_peers.insert(*peer);
+        hashed.known_nicknames.insert(*peer, format!("peer-{}", i));
+        btree.connected_peers.insert(*peer);
+        btree.known_nicknames.insert(*peer, format!("peer-{}", i));
+    }
+    // A tenth of the peers left.
+    for peer in peers.iter().step_by(10) {
+        hashed.disconnected(*peer, now);
+        btree.connected_peers.remove(peer);
+    }
+    (hashed, btree)
+}
+
+fn bench_state(c: &mut Criterion) {
+    let peers = (0..PEERS).map(|_| PeerId::random()).collect::<Vec<_>>();
+    let (hashed, btree) = populate(&peers);
+
+    let mut group = c.benchmark_group("state");
+    group.bench_with_input(BenchmarkId::new("lookup", "hashed"), &hashed, |b, s| {
+        b.iter(|| {
+            peers
+                .iter()
+                .filter(|p| s.known_nicknames.contains_key(p))
+                .count()
+        })
+    });
+    group.bench_with_input(BenchmarkId::new("lookup", "btree"), &btree, |b, s| {
+        b.iter(|| {
+            peers
+                .iter()
+                .filter(|p| s.known_nicknames.contains_key(p))
+                .count()
+        })
+    });
+
+    // A GC tick with nothing expired yet, the common case.
+    group.bench_function(BenchmarkId::new("gc", "incremental"), |b| {
+        b.iter_batched_ref(
+            || populate(&peers).0,
+            |s| s.gc(Instant::now(), MAX_AGE),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function(BenchmarkId::new("gc", "full-sweep"), |b| {
+        b.iter_batched_ref(
+            || populate(&peers).1,
+            |s| {
+                let connected = &s.connected_peers;
+                s.known_nicknames.retain(|p, _| connected.contains(p))
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    // Everything expired.
+    group.bench_function(BenchmarkId::new("gc", "incremental-expired"), |b| {
+        b.iter_batched_ref(
+            || populate(&peers).0,
+            |s| s.gc(Instant::now() + MAX_AGE, MAX_AGE),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_state);
+criterion_main!(benches);
@@ -0,0 +1,85 @@
+//! Encoding and decoding of each `ChatApi` variant at several payload sizes.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[allow(dead_code)]
+#[path = "../src/api.rs"]
+mod api;
+#[allow(dead_code)]
+#[path = "../src/encode.rs"]
+mod encode;
+
+use api::ChatApi;
+
+fn variants(size: usize) -> Vec<(&'static str, ChatApi)> {
+    let text = "x".repeat(size);
+    vec![
+        ("message", ChatApi::message(text.clone())),
+        (
+            "change-nickname",
+            ChatApi::ChangeNickname { nick: text.clone() },
+        ),
+        (
+            "capabilities",
+            ChatApi::Capabilities {
+                supported: vec![text.clone()],
+            },
+        ),
+        (
+            "direct-message",
+            ChatApi::DirectMessage {
+                to: libp2p::PeerId::random(),
+                message: text.clone(),
+                origin_timestamp: chrono::Utc::now(),
+            },
+        ),
+        (
+            "sealed-direct-message",
+            ChatApi::SealedDirectMessage {
+                to: libp2p::PeerId::random(),
+                sealed: text.clone().into_bytes(),
+            },
+        ),
+        (
+            "history-response",
+            ChatApi::HistoryResponse {
+                to: libp2p::PeerId::random(),
+                entries: vec![api::HistoryEntry {
+                    from: libp2p::PeerId::random(),
+                    nick: "bench".into(),
+                    message: text.clone(),
+                    origin_timestamp: chrono::Utc::now(),
+                }],
+            },
+        ),
+        (
+            "meta-event",
+            ChatApi::MetaEvent {
+                event_type: "bench".into(),
+                payload: serde_json::json!({ "text": text }),
+            },
+        ),
+    ]
+}
+
+fn bench_wire(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wire");
+    for size in [16, 1024, 32 * 1024] {
+        for (variant, msg) in variants(size) {
+            group.bench_with_input(
+                BenchmarkId::new(format!("encode/{}", variant), size),
+                &msg,
+                |b, msg| b.iter(|| encode::to_cbor(black_box(msg)).unwrap()),
+            );
+            let data = encode::to_cbor(&msg).unwrap();
+            group.bench_with_input(
+                BenchmarkId::new(format!("decode/{}", variant), size),
+                &data,
+                |b, data| b.iter(|| encode::from_cbor::<ChatApi>(black_box(data)).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_wire);
+criterion_main!(benches);
@@ -0,0 +1,10 @@
+-- /roll [sides]: rolls a die and publishes the result into the current channel.
+agora.command("roll")
+
+function on_command(name, args, channel)
+    local sides = tonumber(args) or 6
+    if sides < 1 then
+        error("a die needs at least one side, got " .. args)
+    end
+    agora.send(channel, string.format("rolled %d (1-%d)", math.random(sides), sides))
+end
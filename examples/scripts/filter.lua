@@ -0,0 +1,19 @@
+-- Hides messages with words you'd rather not read, and expands a few shortcodes.
+local hidden = { "spoiler" }
+local shortcodes = { [":shrug:"] = "¯\\_(ツ)_/¯", [":tableflip:"] = "(╯°□°)╯︵ ┻━┻" }
+
+function on_message(peer, nick, text)
+    local lower = text:lower()
+    for _, word in ipairs(hidden) do
+        if lower:find(word, 1, true) then
+            return false
+        end
+    end
+    local expanded = text
+    for code, replacement in pairs(shortcodes) do
+        expanded = expanded:gsub(code:gsub("%p", "%%%0"), (replacement:gsub("%%", "%%%%")))
+    end
+    if expanded ~= text then
+        return expanded
+    end
+end
@@ -0,0 +1,20 @@
+//! Decoding untrusted payloads and checking their limits must never panic, and whatever decodes
+//! must re-encode stably.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/api.rs"]
+mod api;
+#[allow(dead_code)]
+#[path = "../../src/encode.rs"]
+mod encode;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = encode::from_cbor::<api::ChatApi>(data) {
+        let _ = api::Limits::default().check(&msg);
+        let bytes = encode::to_cbor(&msg).expect("Serialization works");
+        let again = encode::from_cbor::<api::ChatApi>(&bytes).expect("Re-encoding decodes");
+        assert_eq!(encode::to_cbor(&again).unwrap(), bytes);
+    }
+});
@@ -0,0 +1,52 @@
+//! Lets the swarm dial peers by id alone, at the addresses they were reached at before (see
+//! [`State::add_address`](crate::state::State::add_address)).
+use std::task::{Context, Poll};
+
+use libp2p::{
+    core::connection::ConnectionId,
+    swarm::{
+        handler::DummyConnectionHandler, KeepAlive, NetworkBehaviour, NetworkBehaviourAction,
+        PollParameters,
+    },
+    Multiaddr, PeerId,
+};
+
+use crate::state::SharedAddresses;
+
+#[derive(Debug, Default)]
+pub(crate) struct AddressBook {
+    pub(crate) addresses: SharedAddresses,
+}
+
+impl NetworkBehaviour for AddressBook {
+    type ConnectionHandler = DummyConnectionHandler;
+    type OutEvent = void::Void;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        // Leaves keeping connections alive to the other behaviours.
+        DummyConnectionHandler {
+            keep_alive: KeepAlive::No,
+        }
+    }
+
+    fn addresses_of_peer(&mut self, peer: &PeerId) -> Vec<Multiaddr> {
+        self.addresses
+            .read()
+            .expect("Not poisoned")
+            .get(peer)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn inject_event(&mut self, _: PeerId, _: ConnectionId, event: void::Void) {
+        void::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        Poll::Pending
+    }
+}
@@ -0,0 +1,583 @@
+//! Alerts for messages, routed by the config's `[[alerts]]` rules: the first rule whose
+//! conditions all match decides, e.g.
+//!
+//! ```toml
+//! [[alerts]]
+//! peer = "12D3KooW..."
+//! action = "none"
+//!
+//! [[alerts]]
+//! kind = "mention"
+//! action = "exec"
+//! command = ["paplay", "ding.ogg"]
+//!
+//! [[alerts]]
+//! kind = "direct"
+//! action = "notify"
+//! ```
+//!
+//! Conditions are the `kind` of message (`message` in a channel, `mention` of the own nickname
+//! in a channel, or `direct`), the sending `peer`, the `channel` and a `keyword` in the text.
+//! Actions are ringing the terminal's `bell`, a desktop notification through the terminal
+//! (`notify`, OSC 9), running a `command` (`exec`, with the message in `AGORA_EVENT`,
+//! `AGORA_PEER`, `AGORA_NICK`, `AGORA_CHANNEL` and `AGORA_MESSAGE`), or `none`. Messages no
+//! rule matches raise no alert, and none do while not to be disturbed. A rule runs its command at
+//! most once per [`EXEC_COOLDOWN`], and no more than [`MAX_RUNNING`] commands run at once, so a
+//! flood of messages can't fork a flood of processes.
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use serde::Deserialize;
+use tracing::*;
+
+use crate::{dm_requests::Held, dnd};
+
+/// Minimum time between two runs of a rule's command.
+const EXEC_COOLDOWN: Duration = Duration::from_secs(10);
+/// Commands running at most, alerts being skipped beyond.
+const MAX_RUNNING: usize = 4;
+
+/// A rule as written in the config, see [`Rules::new`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RawRule {
+    /// Named in errors, rather than the rule's position.
+    name: Option<String>,
+    kind: Option<String>,
+    peer: Option<String>,
+    channel: Option<String>,
+    keyword: Option<String>,
+    action: String,
+    command: Option<Vec<String>>,
+}
+
+/// Why a rule is invalid.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum RuleError {
+    #[error("unknown kind {0:?}, expected message, mention or direct")]
+    UnknownKind(String),
+    #[error("invalid peer id {0:?}")]
+    InvalidPeer(String),
+    #[error("empty keyword")]
+    EmptyKeyword,
+    #[error("unknown action {0:?}, expected bell, notify, exec or none")]
+    UnknownAction(String),
+    #[error("exec needs a command")]
+    MissingCommand,
+    #[error("only exec takes a command")]
+    UnexpectedCommand,
+}
+
+/// An invalid rule, named or numbered from 1.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Alert rule {rule}: {error}")]
+pub(crate) struct InvalidRule {
+    rule: String,
+    error: RuleError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// A message in a channel, mentioning the own nickname or not.
+    Message,
+    /// A message in a channel mentioning the own nickname.
+    Mention,
+    Direct,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Message => "message",
+            Kind::Mention => "mention",
+            Kind::Direct => "direct",
+        }
+    }
+
+    /// Whether a rule for `self` applies to a message of `kind`.
+    fn covers(&self, kind: Kind) -> bool {
+        *self == kind || (*self == Kind::Message && kind == Kind::Mention)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Action {
+    Bell,
+    Notify,
+    /// The program and its arguments.
+    Exec(Vec<String>),
+    None,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    kind: Option<Kind>,
+    peer: Option<PeerId>,
+    channel: Option<String>,
+    /// Lowercase.
+    keyword: Option<String>,
+    action: Action,
+}
+
+impl Rule {
+    fn parse(raw: RawRule) -> Result<Self, RuleError> {
+        let kind = raw
+            .kind
+            .map(|kind| match kind.as_str() {
+                "message" => Ok(Kind::Message),
+                "mention" => Ok(Kind::Mention),
+                "direct" => Ok(Kind::Direct),
+                _ => Err(RuleError::UnknownKind(kind)),
+            })
+            .transpose()?;
+        let peer = raw
+            .peer
+            .map(|peer| peer.parse().map_err(|_| RuleError::InvalidPeer(peer)))
+            .transpose()?;
+        let keyword = match raw.keyword {
+            Some(keyword) if keyword.trim().is_empty() => return Err(RuleError::EmptyKeyword),
+            keyword => keyword.map(|keyword| keyword.to_lowercase()),
+        };
+        let action = match (raw.action.as_str(), raw.command) {
+            ("exec", Some(command)) if !command.is_empty() => Action::Exec(command),
+            ("exec", _) => return Err(RuleError::MissingCommand),
+            (_, Some(_)) => return Err(RuleError::UnexpectedCommand),
+            ("bell", None) => Action::Bell,
+            ("notify", None) => Action::Notify,
+            ("none", None) => Action::None,
+            (_, None) => return Err(RuleError::UnknownAction(raw.action)),
+        };
+        Ok(Self {
+            kind,
+            peer,
+            channel: raw.channel,
+            keyword,
+            action,
+        })
+    }
+
+    /// Whether all conditions match, `lowercase` being the message's text in lowercase.
+    fn matches(&self, message: &Message, lowercase: &str) -> bool {
+        self.kind.map_or(true, |kind| kind.covers(message.kind))
+            && self.peer.map_or(true, |peer| peer == *message.peer)
+            && self
+                .channel
+                .as_ref()
+                .map_or(true, |channel| channel == message.channel)
+            && self
+                .keyword
+                .as_ref()
+                .map_or(true, |keyword| lowercase.contains(keyword.as_str()))
+    }
+}
+
+/// A message shown, to alert of.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Message<'a> {
+    pub(crate) kind: Kind,
+    pub(crate) peer: &'a PeerId,
+    pub(crate) nick: &'a str,
+    pub(crate) channel: &'a str,
+    pub(crate) text: &'a str,
+    /// A direct message sealed to us, rather than readable by the whole channel.
+    pub(crate) sealed: bool,
+}
+
+/// The rules, in order.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rules(Vec<Rule>);
+
+impl Rules {
+    /// Validates the rules, failing on the first invalid one.
+    pub(crate) fn new(raw: Vec<RawRule>) -> Result<Self, InvalidRule> {
+        raw.into_iter()
+            .enumerate()
+            .map(|(i, raw)| {
+                let rule = raw.name.clone().unwrap_or_else(|| format!("#{}", i + 1));
+                Rule::parse(raw).map_err(|error| InvalidRule { rule, error })
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// The action of the first rule matching `message`, if any.
+    pub(crate) fn route(&self, message: &Message) -> Option<&Action> {
+        self.first_match(message).map(|(_, action)| action)
+    }
+
+    /// The position and action of the first rule matching `message`.
+    fn first_match(&self, message: &Message) -> Option<(usize, &Action)> {
+        let mut lowercase = None;
+        self.0
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| {
+                let lowercase = match &rule.keyword {
+                    Some(_) => lowercase
+                        .get_or_insert_with(|| message.text.to_lowercase())
+                        .as_str(),
+                    None => "",
+                };
+                rule.matches(message, lowercase)
+            })
+            .map(|(i, rule)| (i, &rule.action))
+    }
+}
+
+/// Limits the commands run by `exec` rules.
+#[derive(Debug, Default)]
+struct Throttle {
+    /// When each rule last ran its command, by position.
+    last_run: HashMap<usize, Instant>,
+    /// Commands started and not exited yet.
+    running: Arc<AtomicUsize>,
+}
+
+impl Throttle {
+    /// Whether the command of `rule` may run `now`, recording that it does.
+    fn permit(&mut self, rule: usize, now: Instant) -> bool {
+        if matches!(self.last_run.get(&rule), Some(at) if now < *at + EXEC_COOLDOWN) {
+            debug!(rule, "Alert command ran recently, skipping");
+            return false;
+        }
+        if self.running.load(Ordering::Relaxed) >= MAX_RUNNING {
+            warn!(rule, "Too many alert commands running, skipping");
+            return false;
+        }
+        self.last_run.insert(rule, now);
+        true
+    }
+}
+
+/// Raises alerts of messages as routed by the rules.
+#[derive(Debug, Default)]
+pub(crate) struct Alerts {
+    rules: Rules,
+    /// Own nickname, to tell mentions.
+    nick: String,
+    throttle: Throttle,
+}
+
+impl Alerts {
+    pub(crate) fn new(rules: Rules, nick: String) -> Self {
+        Self {
+            rules,
+            nick,
+            throttle: Throttle::default(),
+        }
+    }
+
+    pub(crate) fn set_nick(&mut self, nick: String) {
+        self.nick = nick;
+    }
+
+    /// Alerts of the message `text` of `peer` in `channel`, shown as from `nick`.
+    pub(crate) fn message(&mut self, peer: &PeerId, nick: &str, channel: &str, text: &str) {
+        let kind = match dnd::mentions(text, &self.nick) {
+            true => Kind::Mention,
+            false => Kind::Message,
+        };
+        self.alert(&Message {
+            kind,
+            peer,
+            nick,
+            channel,
+            text,
+            sealed: false,
+        });
+    }
+
+    /// Alerts of the direct message `held` of `peer`, received through `channel`.
+    pub(crate) fn direct(&mut self, peer: &PeerId, nick: &str, channel: &str, held: &Held) {
+        self.alert(&Message {
+            kind: Kind::Direct,
+            peer,
+            nick,
+            channel,
+            text: &held.message,
+            sealed: held.sealed,
+        });
+    }
+
+    fn alert(&mut self, message: &Message) {
+        let (rule, action) = match self.rules.first_match(message) {
+            Some(matched) => matched,
+            None => return,
+        };
+        debug!(kind = message.kind.as_str(), peer = %message.peer, ?action, "Alerting");
+        let alerted = match action {
+            Action::Bell => terminal("\x07"),
+            Action::Notify => terminal(&format!("\x1b]9;{}\x07", Notification(message))),
+            Action::Exec(command) => match self.throttle.permit(rule, Instant::now()) {
+                true => exec(command, message, &self.throttle.running),
+                false => Ok(()),
+            },
+            Action::None => Ok(()),
+        };
+        if let Err(error) = alerted {
+            warn!(%error, ?action, "Alerting failed");
+        }
+    }
+}
+
+/// The title and text of a notification, without control characters ending it early.
+struct Notification<'a, 'b>(&'a Message<'b>);
+
+impl fmt::Display for Notification<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Message {
+            kind,
+            nick,
+            text,
+            sealed,
+            ..
+        } = self.0;
+        let title = match kind {
+            Kind::Direct if *sealed => format!("{} (private)", nick),
+            Kind::Direct => format!("{} (unencrypted)", nick),
+            _ => nick.to_string(),
+        };
+        for c in title.chars().chain(": ".chars()).chain(text.chars()) {
+            if !c.is_control() {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn terminal(sequence: &str) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(sequence.as_bytes())?;
+    stdout.flush()
+}
+
+/// Starts `command` without waiting for it, counted as `running` until it exited.
+fn exec(command: &[String], message: &Message, running: &Arc<AtomicUsize>) -> io::Result<()> {
+    let (program, args) = command.split_first().expect("Validated to not be empty");
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .env("AGORA_EVENT", message.kind.as_str())
+        .env("AGORA_PEER", message.peer.to_base58())
+        .env("AGORA_NICK", message.nick)
+        .env("AGORA_CHANNEL", message.channel)
+        .env("AGORA_MESSAGE", message.text)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    running.fetch_add(1, Ordering::Relaxed);
+    let running = running.clone();
+    tokio::spawn(async move {
+        if let Err(error) = child.wait().await {
+            warn!(%error, "Waiting for alert command failed");
+        }
+        running.fetch_sub(1, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(toml: &str) -> Result<Rules, InvalidRule> {
+        #[derive(Deserialize)]
+        struct Config {
+            alerts: Vec<RawRule>,
+        }
+        Rules::new(toml::from_str::<Config>(toml).unwrap().alerts)
+    }
+
+    fn message<'a>(kind: Kind, peer: &'a PeerId, channel: &'a str, text: &'a str) -> Message<'a> {
+        Message {
+            kind,
+            peer,
+            nick: "alice",
+            channel,
+            text,
+            sealed: true,
+        }
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let quiet = PeerId::random();
+        let other = PeerId::random();
+        let rules = rules(&format!(
+            r#"
+            [[alerts]]
+            peer = "{}"
+            action = "none"
+
+            [[alerts]]
+            kind = "mention"
+            action = "exec"
+            command = ["paplay", "ding.ogg"]
+
+            [[alerts]]
+            channel = "ops"
+            keyword = "Deploy"
+            action = "notify"
+
+            [[alerts]]
+            kind = "message"
+            channel = "ops"
+            action = "bell"
+
+            [[alerts]]
+            kind = "direct"
+            action = "notify"
+            "#,
+            quiet
+        ))
+        .unwrap();
+        let exec = Action::Exec(vec!["paplay".into(), "ding.ogg".into()]);
+        for (message, action) in [
+            // The peer's rule comes first, even for mentions and direct messages.
+            (
+                message(Kind::Mention, &quiet, "ops", "hi"),
+                Some(&Action::None),
+            ),
+            (
+                message(Kind::Direct, &quiet, "agora", "hi"),
+                Some(&Action::None),
+            ),
+            (message(Kind::Mention, &other, "ops", "deploy"), Some(&exec)),
+            (
+                message(Kind::Message, &other, "ops", "DEPLOYING now"),
+                Some(&Action::Notify),
+            ),
+            (
+                message(Kind::Direct, &other, "ops", "deploy"),
+                Some(&Action::Notify),
+            ),
+            (
+                message(Kind::Message, &other, "ops", "hi"),
+                Some(&Action::Bell),
+            ),
+            (message(Kind::Message, &other, "agora", "deploy"), None),
+        ] {
+            assert_eq!(rules.route(&message), action, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn messages_cover_mentions() {
+        let rules = rules(
+            r#"
+            [[alerts]]
+            kind = "message"
+            action = "bell"
+            "#,
+        )
+        .unwrap();
+        let peer = PeerId::random();
+        for (kind, action) in [
+            (Kind::Message, Some(&Action::Bell)),
+            (Kind::Mention, Some(&Action::Bell)),
+            (Kind::Direct, None),
+        ] {
+            assert_eq!(rules.route(&message(kind, &peer, "agora", "hi")), action);
+        }
+        assert_eq!(
+            Rules::default().route(&message(Kind::Direct, &peer, "agora", "hi")),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_rules_are_named() {
+        for (rule, error) in [
+            (
+                r#"kind = "mentions"
+                action = "bell""#,
+                RuleError::UnknownKind("mentions".into()),
+            ),
+            (
+                r#"peer = "alice"
+                action = "bell""#,
+                RuleError::InvalidPeer("alice".into()),
+            ),
+            (
+                r#"keyword = " "
+                action = "bell""#,
+                RuleError::EmptyKeyword,
+            ),
+            (
+                r#"action = "beep""#,
+                RuleError::UnknownAction("beep".into()),
+            ),
+            (r#"action = "exec""#, RuleError::MissingCommand),
+            (
+                r#"action = "exec"
+                command = []"#,
+                RuleError::MissingCommand,
+            ),
+            (
+                r#"action = "bell"
+                command = ["true"]"#,
+                RuleError::UnexpectedCommand,
+            ),
+        ] {
+            let toml = format!(
+                "[[alerts]]\naction = \"none\"\n[[alerts]]\n{}\n[[alerts]]\naction = \"beep\"",
+                rule
+            );
+            assert_eq!(
+                rules(&toml).unwrap_err(),
+                InvalidRule {
+                    rule: "#2".into(),
+                    error
+                }
+            );
+        }
+        let named = rules("[[alerts]]\nname = \"dings\"\naction = \"exec\"").unwrap_err();
+        assert_eq!(named.to_string(), "Alert rule dings: exec needs a command");
+    }
+
+    #[test]
+    fn throttles_commands() {
+        let start = Instant::now();
+        let mut throttle = Throttle::default();
+        assert!(throttle.permit(0, start));
+        assert!(!throttle.permit(0, start + EXEC_COOLDOWN / 2));
+        // Per rule.
+        assert!(throttle.permit(1, start));
+        assert!(throttle.permit(0, start + EXEC_COOLDOWN));
+
+        throttle.running.store(MAX_RUNNING, Ordering::Relaxed);
+        assert!(!throttle.permit(2, start));
+        throttle.running.fetch_sub(1, Ordering::Relaxed);
+        assert!(throttle.permit(2, start));
+    }
+
+    #[test]
+    fn notifications_are_one_line() {
+        let peer = PeerId::random();
+        let text = "look\x07\x1b]9;spoofed\nhere";
+        let direct = message(Kind::Direct, &peer, "agora", text);
+        assert_eq!(
+            Notification(&direct).to_string(),
+            "alice (private): look]9;spoofedhere"
+        );
+        let plain = Message {
+            sealed: false,
+            ..direct
+        };
+        assert_eq!(
+            Notification(&plain).to_string(),
+            "alice (unencrypted): look]9;spoofedhere"
+        );
+    }
+}
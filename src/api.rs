@@ -1,36 +1,657 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum ChatApi {
     Message {
         message: String,
         #[serde(with = "chrono::serde::ts_milliseconds")]
         origin_timestamp: chrono::DateTime<chrono::Utc>,
+        /// Set if posted through an incoming webhook, displayed namespaced as "<nick> [hook]".
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hook_nick: Option<String>,
+        /// Set if relayed from another system (e.g. "mqtt"), so it's not bridged back.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bridged_from: Option<String>,
+        /// Wall-clock time to display, set if `origin_timestamp` is monotonic (see `clock.rs`).
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "chrono::serde::ts_milliseconds_option"
+        )]
+        wall_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+        /// Set on canned responses to keywords (see `template.rs`), never responded to in turn.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        auto_response: bool,
     },
     ChangeNickname {
         nick: String,
     },
+    /// Features the sender supports, see [`capability`]. Announced along with the nickname.
+    Capabilities {
+        supported: Vec<String>,
+    },
+    /// Published into the channel, but only displayed by the addressed peer.
+    DirectMessage {
+        #[serde(with = "peerid_serializer")]
+        to: libp2p::PeerId,
+        message: String,
+        #[serde(with = "chrono::serde::ts_milliseconds")]
+        origin_timestamp: chrono::DateTime<chrono::Utc>,
+    },
+    /// A direct message sealed to the recipient and signed by the sender, see `dm.rs`.
+    SealedDirectMessage {
+        #[serde(with = "peerid_serializer")]
+        to: libp2p::PeerId,
+        #[serde(with = "serde_bytes")]
+        sealed: Vec<u8>,
+    },
+    /// Asks the sender of a direct message to `to` to prove its identity, by signing `nonce`.
+    DmChallenge {
+        #[serde(with = "peerid_serializer")]
+        to: libp2p::PeerId,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+    },
+    /// Answers a [`ChatApi::DmChallenge`] of `to`.
+    DmChallengeResponse {
+        #[serde(with = "peerid_serializer")]
+        to: libp2p::PeerId,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+    },
+    /// Asks peers serving history (`--serve-history`) for recent messages.
+    HistoryRequest {
+        #[serde(with = "chrono::serde::ts_milliseconds")]
+        since: chrono::DateTime<chrono::Utc>,
+        max: usize,
+    },
+    /// Recent messages, in response to a [`ChatApi::HistoryRequest`] of `to`.
+    HistoryResponse {
+        #[serde(with = "peerid_serializer")]
+        to: libp2p::PeerId,
+        entries: Vec<HistoryEntry>,
+    },
+    /// Published by a moderated channel's owner, peers ignore it from anybody else.
+    Moderate {
+        action: ModerationAction,
+        #[serde(with = "peerid_serializer")]
+        target: libp2p::PeerId,
+    },
+    /// A participant's X25519 key for a round of group key exchange (`--e2e-encrypt`), started
+    /// when peers join a channel. `participants` are peer ids.
+    GroupKeyExchange {
+        #[serde(with = "serde_bytes")]
+        initiator_pubkey: Vec<u8>,
+        round: u32,
+        participants: Vec<String>,
+    },
+    /// Machine-readable event from bridges and bots, e.g. `irc_join`. Peers not knowing this
+    /// variant fail to decode and drop it.
+    MetaEvent {
+        event_type: String,
+        payload: serde_json::Value,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum ModerationAction {
+    /// Hides the target's messages for the rest of the session.
+    Kick,
+    /// Hides the target's messages for a while.
+    Mute { duration_secs: u64 },
+}
+
+/// Names of the features announced with [`ChatApi::Capabilities`].
+pub(crate) mod capability {
+    /// Opens [`super::ChatApi::SealedDirectMessage`]s.
+    pub(crate) const SEALED_DIRECT_MESSAGES: &str = "sealed_direct_messages";
+    /// Responds to [`super::ChatApi::HistoryRequest`]s.
+    pub(crate) const SERVE_HISTORY: &str = "serve_history";
+    /// Channel keys tagged with key ids, see `/rekey`.
+    pub(crate) const KEY_ROTATION: &str = "key_rotation";
+    /// Answers [`super::ChatApi::DmChallenge`]s.
+    pub(crate) const DM_CHALLENGES: &str = "dm_challenges";
+    /// Takes part in [`super::ChatApi::GroupKeyExchange`]s.
+    pub(crate) const GROUP_KEY_EXCHANGE: &str = "group_key_exchange";
+}
+
+/// Bytes of a [`ChatApi::DmChallenge`]'s nonce.
+pub(crate) const DM_NONCE_LEN: usize = 32;
+/// Bytes of a [`ChatApi::DmChallengeResponse`]'s (Ed25519) signature.
+pub(crate) const DM_SIGNATURE_LEN: usize = 64;
+/// Bytes of a [`ChatApi::GroupKeyExchange`]'s (X25519) public key.
+pub(crate) const GROUP_KEY_LEN: usize = 32;
+
+/// A message as kept in the history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct HistoryEntry {
+    #[serde(with = "peerid_serializer")]
+    pub(crate) from: libp2p::PeerId,
+    pub(crate) nick: String,
+    pub(crate) message: String,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub(crate) origin_timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// A message posted from outside (incoming webhook, MQTT), to be published by the node.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct HookPost {
+    pub(crate) channel: String,
+    pub(crate) nick: String,
+    pub(crate) text: String,
+}
+
+impl ChatApi {
+    /// Name of the variant, e.g. to keep track of per kind of message.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::Message { .. } => "message",
+            Self::ChangeNickname { .. } => "change_nickname",
+            Self::Capabilities { .. } => "capabilities",
+            Self::DirectMessage { .. } => "direct_message",
+            Self::SealedDirectMessage { .. } => "sealed_direct_message",
+            Self::DmChallenge { .. } => "dm_challenge",
+            Self::DmChallengeResponse { .. } => "dm_challenge_response",
+            Self::HistoryRequest { .. } => "history_request",
+            Self::HistoryResponse { .. } => "history_response",
+            Self::Moderate { .. } => "moderate",
+            Self::GroupKeyExchange { .. } => "group_key_exchange",
+            Self::MetaEvent { .. } => "meta_event",
+        }
+    }
+
+    /// Rough estimate of the serialized size.
+    pub(crate) fn len_hint(&self) -> usize {
+        match self {
+            Self::Message { message, .. } | Self::DirectMessage { message, .. } => message.len(),
+            Self::ChangeNickname { nick } => nick.len(),
+            Self::Capabilities { supported } => supported.iter().map(String::len).sum(),
+            Self::SealedDirectMessage { sealed, .. } => sealed.len(),
+            Self::DmChallenge { nonce, .. } => nonce.len(),
+            Self::DmChallengeResponse {
+                nonce, signature, ..
+            } => nonce.len() + signature.len(),
+            Self::HistoryRequest { .. } | Self::Moderate { .. } => 0,
+            Self::HistoryResponse { entries, .. } => {
+                entries.iter().map(|e| e.nick.len() + e.message.len()).sum()
+            }
+            Self::GroupKeyExchange {
+                initiator_pubkey,
+                participants,
+                ..
+            } => initiator_pubkey.len() + participants.iter().map(String::len).sum::<usize>(),
+            Self::MetaEvent { event_type, .. } => event_type.len(),
+        }
+    }
+
+    pub(crate) fn message(message: String) -> Self {
+        Self::Message {
+            message,
+            origin_timestamp: chrono::Utc::now(),
+            hook_nick: None,
+            bridged_from: None,
+            wall_timestamp: None,
+            auto_response: false,
+        }
+    }
+}
+
+/// Bounds on decoded messages, beyond the size of the payload. Exceeding any rejects the whole
+/// message rather than truncating it. Configured in the `[limits]` section of `--config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub(crate) struct Limits {
+    /// Bytes of a message's text, including direct messages and history entries.
+    pub(crate) max_message_len: usize,
+    /// Bytes of a nickname, including hook nicknames and those of history entries.
+    pub(crate) max_nick_len: usize,
+    /// Bytes of an identifier: a capability, an event type or a bridge's name.
+    pub(crate) max_name_len: usize,
+    pub(crate) max_capabilities: usize,
+    pub(crate) max_history_entries: usize,
+    /// Participants of a group key exchange.
+    pub(crate) max_participants: usize,
+    /// Bytes of a sealed direct message.
+    pub(crate) max_sealed_len: usize,
+    /// Bytes of a meta event's payload, as JSON.
+    pub(crate) max_meta_payload_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_len: 32 * 1024,
+            max_nick_len: 64,
+            max_name_len: 64,
+            max_capabilities: 32,
+            // Above the batches sent in response to history requests.
+            max_history_entries: 64,
+            max_participants: 256,
+            max_sealed_len: 48 * 1024,
+            max_meta_payload_len: 8 * 1024,
+        }
+    }
+}
+
+/// A decoded message exceeding one of the [`Limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LimitExceeded {
+    pub(crate) field: &'static str,
+    pub(crate) len: usize,
+    pub(crate) max: usize,
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} exceeds the limit of {}",
+            self.field, self.len, self.max
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+fn bound(field: &'static str, len: usize, max: usize) -> Result<(), LimitExceeded> {
+    match len <= max {
+        true => Ok(()),
+        false => Err(LimitExceeded { field, len, max }),
+    }
+}
+
+impl Limits {
+    pub(crate) fn check(&self, msg: &ChatApi) -> Result<(), LimitExceeded> {
+        match msg {
+            ChatApi::Message {
+                message,
+                hook_nick,
+                bridged_from,
+                ..
+            } => {
+                bound("message", message.len(), self.max_message_len)?;
+                if let Some(nick) = hook_nick {
+                    bound("hook_nick", nick.len(), self.max_nick_len)?;
+                }
+                if let Some(bridge) = bridged_from {
+                    bound("bridged_from", bridge.len(), self.max_name_len)?;
+                }
+                Ok(())
+            }
+            ChatApi::ChangeNickname { nick } => bound("nick", nick.len(), self.max_nick_len),
+            ChatApi::Capabilities { supported } => {
+                bound("capabilities", supported.len(), self.max_capabilities)?;
+                supported
+                    .iter()
+                    .try_for_each(|c| bound("capability", c.len(), self.max_name_len))
+            }
+            ChatApi::DirectMessage { message, .. } => {
+                bound("message", message.len(), self.max_message_len)
+            }
+            ChatApi::SealedDirectMessage { sealed, .. } => {
+                bound("sealed", sealed.len(), self.max_sealed_len)
+            }
+            ChatApi::DmChallenge { nonce, .. } => bound("nonce", nonce.len(), DM_NONCE_LEN),
+            ChatApi::DmChallengeResponse {
+                nonce, signature, ..
+            } => {
+                bound("nonce", nonce.len(), DM_NONCE_LEN)?;
+                bound("signature", signature.len(), DM_SIGNATURE_LEN)
+            }
+            ChatApi::HistoryRequest { .. } | ChatApi::Moderate { .. } => Ok(()),
+            ChatApi::HistoryResponse { entries, .. } => {
+                bound("entries", entries.len(), self.max_history_entries)?;
+                entries.iter().try_for_each(|entry| {
+                    bound("nick", entry.nick.len(), self.max_nick_len)?;
+                    bound("message", entry.message.len(), self.max_message_len)
+                })
+            }
+            ChatApi::GroupKeyExchange {
+                initiator_pubkey,
+                participants,
+                ..
+            } => {
+                bound("initiator_pubkey", initiator_pubkey.len(), GROUP_KEY_LEN)?;
+                bound("participants", participants.len(), self.max_participants)?;
+                participants
+                    .iter()
+                    .try_for_each(|p| bound("participant", p.len(), self.max_name_len))
+            }
+            ChatApi::MetaEvent {
+                event_type,
+                payload,
+            } => {
+                bound("event_type", event_type.len(), self.max_name_len)?;
+                let len = serde_json::to_vec(payload).map_or(usize::MAX, |json| json.len());
+                bound("payload", len, self.max_meta_payload_len)
+            }
+        }
+    }
+}
+
+pub(crate) mod peerid_serializer {
+    use libp2p::PeerId;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S>(value: &PeerId, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        value.to_base58().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<PeerId, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let str = String::deserialize(deserializer)?;
+        PeerId::from_str(&str).map_err(|e| {
+            serde::de::Error::custom(format!("peer id deserialization failed for {:?}", e))
+        })
+    }
 }
 
-//mod peerid_serializer {
-//    use libp2p::PeerId;
-//    use serde::{Deserialize, Deserializer, Serialize, Serializer};
-//    use std::str::FromStr;
-//
-//    pub fn serialize<S>(value: &PeerId, serializer: S) -> Result<S::Ok, S::Error>
-//    where
-//        S: Serializer,
-//    {
-//        value.to_base58().serialize(serializer)
-//    }
-//
-//    pub fn deserialize<'de, D>(deserializer: D) -> Result<PeerId, D::Error>
-//    where
-//        D: Deserializer<'de>,
-//    {
-//        let str = String::deserialize(deserializer)?;
-//        PeerId::from_str(&str).map_err(|e| {
-//            serde::de::Error::custom(format!("peer id deserialization failed for {:?}", e))
-//        })
-//    }
-//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Limits of 4, so every bound is hit with 5 of something.
+    const SMALL: Limits = Limits {
+        max_message_len: 4,
+        max_nick_len: 4,
+        max_name_len: 4,
+        max_capabilities: 4,
+        max_history_entries: 4,
+        max_participants: 4,
+        max_sealed_len: 4,
+        max_meta_payload_len: 4,
+    };
+
+    fn entry(nick: &str, message: &str) -> HistoryEntry {
+        HistoryEntry {
+            from: libp2p::PeerId::random(),
+            nick: nick.into(),
+            message: message.into(),
+            origin_timestamp: chrono::Utc::now(),
+        }
+    }
+
+    fn message(message: &str, hook_nick: Option<&str>, bridged_from: Option<&str>) -> ChatApi {
+        ChatApi::Message {
+            message: message.into(),
+            origin_timestamp: chrono::Utc::now(),
+            hook_nick: hook_nick.map(Into::into),
+            bridged_from: bridged_from.map(Into::into),
+            wall_timestamp: None,
+            auto_response: false,
+        }
+    }
+
+    /// Pairs of a message at the limits and one exceeding the named field.
+    fn cases() -> Vec<(ChatApi, ChatApi, &'static str)> {
+        let to = libp2p::PeerId::random();
+        let names = |n: usize, len: usize| vec!["x".repeat(len); n];
+        vec![
+            (
+                message("1234", Some("nick"), Some("mqtt")),
+                message("12345", None, None),
+                "message",
+            ),
+            (
+                message("", Some("nick"), None),
+                message("", Some("nick5"), None),
+                "hook_nick",
+            ),
+            (
+                message("", None, Some("mqtt")),
+                message("", None, Some("mqtt5")),
+                "bridged_from",
+            ),
+            (
+                ChatApi::ChangeNickname {
+                    nick: "nick".into(),
+                },
+                ChatApi::ChangeNickname {
+                    nick: "nick5".into(),
+                },
+                "nick",
+            ),
+            (
+                ChatApi::Capabilities {
+                    supported: names(4, 4),
+                },
+                ChatApi::Capabilities {
+                    supported: names(5, 1),
+                },
+                "capabilities",
+            ),
+            (
+                ChatApi::Capabilities {
+                    supported: names(1, 4),
+                },
+                ChatApi::Capabilities {
+                    supported: names(1, 5),
+                },
+                "capability",
+            ),
+            (
+                ChatApi::DirectMessage {
+                    to,
+                    message: "1234".into(),
+                    origin_timestamp: chrono::Utc::now(),
+                },
+                ChatApi::DirectMessage {
+                    to,
+                    message: "12345".into(),
+                    origin_timestamp: chrono::Utc::now(),
+                },
+                "message",
+            ),
+            (
+                ChatApi::SealedDirectMessage {
+                    to,
+                    sealed: vec![0; 4],
+                },
+                ChatApi::SealedDirectMessage {
+                    to,
+                    sealed: vec![0; 5],
+                },
+                "sealed",
+            ),
+            (
+                ChatApi::HistoryResponse {
+                    to,
+                    entries: vec![entry("nick", "1234"); 4],
+                },
+                ChatApi::HistoryResponse {
+                    to,
+                    entries: vec![entry("", ""); 5],
+                },
+                "entries",
+            ),
+            (
+                ChatApi::HistoryResponse {
+                    to,
+                    entries: vec![entry("nick", "")],
+                },
+                ChatApi::HistoryResponse {
+                    to,
+                    entries: vec![entry("nick5", "")],
+                },
+                "nick",
+            ),
+            (
+                ChatApi::HistoryResponse {
+                    to,
+                    entries: vec![entry("", "1234")],
+                },
+                ChatApi::HistoryResponse {
+                    to,
+                    entries: vec![entry("", "12345")],
+                },
+                "message",
+            ),
+            (
+                ChatApi::MetaEvent {
+                    event_type: "join".into(),
+                    payload: serde_json::json!(12),
+                },
+                ChatApi::MetaEvent {
+                    event_type: "join5".into(),
+                    payload: serde_json::Value::Null,
+                },
+                "event_type",
+            ),
+            (
+                ChatApi::MetaEvent {
+                    event_type: "".into(),
+                    payload: serde_json::json!("ab"),
+                },
+                ChatApi::MetaEvent {
+                    event_type: "".into(),
+                    payload: serde_json::json!("abc"),
+                },
+                "payload",
+            ),
+            (
+                key_exchange(vec!["p".into(); 4]),
+                key_exchange(vec!["p".into(); 5]),
+                "participants",
+            ),
+            (
+                key_exchange(vec!["1234".into()]),
+                key_exchange(vec!["12345".into()]),
+                "participant",
+            ),
+        ]
+    }
+
+    fn key_exchange(participants: Vec<String>) -> ChatApi {
+        ChatApi::GroupKeyExchange {
+            initiator_pubkey: vec![0; GROUP_KEY_LEN],
+            round: 0,
+            participants,
+        }
+    }
+
+    #[test]
+    fn enforces_every_limit() {
+        for (within, exceeding, field) in cases() {
+            assert_eq!(SMALL.check(&within), Ok(()), "{:?}", within);
+            let error = SMALL.check(&exceeding).unwrap_err();
+            assert_eq!(error.field, field, "{:?}", exceeding);
+            assert_eq!(error.max, 4);
+            assert_eq!(error.len, 5);
+        }
+    }
+
+    #[test]
+    fn unbounded_kinds_always_pass() {
+        let kinds = [
+            ChatApi::HistoryRequest {
+                since: chrono::Utc::now(),
+                max: usize::MAX,
+            },
+            ChatApi::Moderate {
+                action: ModerationAction::Kick,
+                target: libp2p::PeerId::random(),
+            },
+        ];
+        for msg in kinds {
+            assert_eq!(SMALL.check(&msg), Ok(()));
+        }
+    }
+
+    #[test]
+    fn bounds_dm_challenges() {
+        let to = libp2p::PeerId::random();
+        let response = |nonce: usize, signature: usize| ChatApi::DmChallengeResponse {
+            to,
+            nonce: vec![0; nonce],
+            signature: vec![0; signature],
+        };
+        let challenge = ChatApi::DmChallenge {
+            to,
+            nonce: vec![0; DM_NONCE_LEN],
+        };
+        assert_eq!(SMALL.check(&challenge), Ok(()));
+        assert_eq!(
+            SMALL.check(&response(DM_NONCE_LEN, DM_SIGNATURE_LEN)),
+            Ok(())
+        );
+        let exceeding = [
+            (
+                ChatApi::DmChallenge {
+                    to,
+                    nonce: vec![0; DM_NONCE_LEN + 1],
+                },
+                "nonce",
+            ),
+            (response(DM_NONCE_LEN + 1, 0), "nonce"),
+            (response(0, DM_SIGNATURE_LEN + 1), "signature"),
+        ];
+        for (msg, field) in exceeding {
+            assert_eq!(SMALL.check(&msg).unwrap_err().field, field, "{:?}", msg);
+        }
+    }
+
+    #[test]
+    fn bounds_group_keys() {
+        let mut msg = key_exchange(Vec::new());
+        assert_eq!(SMALL.check(&msg), Ok(()));
+        if let ChatApi::GroupKeyExchange {
+            initiator_pubkey, ..
+        } = &mut msg
+        {
+            initiator_pubkey.push(0);
+        }
+        assert_eq!(SMALL.check(&msg).unwrap_err().field, "initiator_pubkey");
+    }
+
+    #[test]
+    fn defaults_admit_what_we_send() {
+        let limits = Limits::default();
+        let nick = "x".repeat(limits.max_nick_len);
+        assert_eq!(limits.check(&ChatApi::ChangeNickname { nick }), Ok(()));
+        let capabilities = ChatApi::Capabilities {
+            supported: [
+                capability::SEALED_DIRECT_MESSAGES,
+                capability::SERVE_HISTORY,
+                capability::KEY_ROTATION,
+                capability::DM_CHALLENGES,
+                capability::GROUP_KEY_EXCHANGE,
+            ]
+            .map(String::from)
+            .to_vec(),
+        };
+        assert_eq!(limits.check(&capabilities), Ok(()));
+        let participants = (0..limits.max_participants)
+            .map(|_| libp2p::PeerId::random().to_string())
+            .collect();
+        assert_eq!(limits.check(&key_exchange(participants)), Ok(()));
+        assert_eq!(
+            limits.check(&ChatApi::message("x".repeat(16 * 1024))),
+            Ok(())
+        );
+    }
+
+    /// The vectors the wasm build is tested against, see `wasm/tests/golden.rs`.
+    #[test]
+    fn decodes_golden_vectors() {
+        let vectors: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("../wasm/tests/golden.json")).unwrap();
+        for vector in vectors {
+            let cbor = hex::decode(vector["cbor"].as_str().unwrap()).unwrap();
+            let msg: ChatApi = ciborium::de::from_reader(&cbor[..]).unwrap();
+            assert_eq!(
+                serde_json::to_value(&msg).unwrap(),
+                vector["json"],
+                "{}",
+                vector["name"]
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Audit log of suspicious activity (`--audit-log`): rejected signatures, replays, messages
+//! exceeding the limits, rate-limited peers, moderation and the like, appended as JSON lines.
+//! It's kept apart from the diagnostic logs and written regardless of what's displayed.
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Context;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::rejections::{Notice, Rejections};
+
+/// Entries shown by `/audit tail` without a count.
+pub(crate) const DEFAULT_TAIL: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Kind {
+    /// A message claiming a source without being signed, or a nickname pinned to another key.
+    InvalidSignature,
+    /// A message from a peer not in `--members`.
+    NonMember,
+    /// A message which must be signed, but isn't.
+    Unsigned,
+    /// A message received before.
+    Replayed,
+    /// A signed message without a sequence, as sent by builds before replay protection, under
+    /// `--require-sequence`.
+    Unsequenced,
+    DecodeFailed,
+    OverLimits,
+    /// Nickname changes deferred for being too frequent.
+    RateLimited,
+    /// A kick or mute, applied or rejected.
+    Moderation,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::InvalidSignature => "invalid signature",
+            Self::NonMember => "non-member",
+            Self::Unsigned => "unsigned",
+            Self::Replayed => "replayed",
+            Self::Unsequenced => "unsequenced",
+            Self::DecodeFailed => "decode failed",
+            Self::OverLimits => "over limits",
+            Self::RateLimited => "rate limited",
+            Self::Moderation => "moderation",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Record {
+    pub(crate) timestamp: chrono::DateTime<chrono::Utc>,
+    pub(crate) kind: Kind,
+    #[serde(with = "crate::api::peerid_serializer")]
+    pub(crate) peer: PeerId,
+    pub(crate) details: String,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}: {}",
+            self.timestamp, self.kind, self.peer, self.details
+        )
+    }
+}
+
+/// Handle to the audit log, shared by the behaviour and the gossipsub transform. Records are
+/// dropped until [`AuditLog::open`] is called, but invalid messages are always counted for
+/// [`AuditLog::rejection_notices`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditLog {
+    file: Arc<Mutex<Option<(PathBuf, File)>>>,
+    rejections: Arc<Mutex<Rejections>>,
+}
+
+impl AuditLog {
+    /// Appends further records to `path`, creating it if needed.
+    pub(crate) fn open(&self, path: &Path) -> anyhow::Result<()> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options
+            .open(path)
+            .with_context(|| format!("Opening audit log {}", path.display()))?;
+        *self.file.lock().expect("Not poisoned") = Some((path.to_path_buf(), file));
+        Ok(())
+    }
+
+    pub(crate) fn record(&self, kind: Kind, peer: &PeerId, details: impl fmt::Display) {
+        self.rejections
+            .lock()
+            .expect("Not poisoned")
+            .record(kind, peer);
+        let mut file = self.file.lock().expect("Not poisoned");
+        let (path, file) = match file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+        let record = Record {
+            timestamp: chrono::Utc::now(),
+            kind,
+            peer: *peer,
+            details: details.to_string(),
+        };
+        let mut line = serde_json::to_vec(&record).expect("Serializable");
+        line.push(b'\n');
+        // One write per record, so concurrent appends don't interleave.
+        if let Err(error) = file.write_all(&line) {
+            warn!(%error, path = %path.display(), ?record, "Writing the audit log failed");
+        }
+    }
+
+    /// The notices of invalid messages dropped due at `now`, rate limited per peer.
+    pub(crate) fn rejection_notices(&self, now: Instant) -> Vec<Notice> {
+        self.rejections.lock().expect("Not poisoned").due(now)
+    }
+
+    /// The last `n` records, oldest first. Lines which can't be parsed are skipped.
+    pub(crate) fn tail(&self, n: usize) -> anyhow::Result<Vec<Record>> {
+        let path = match self.file.lock().expect("Not poisoned").as_ref() {
+            Some((path, _)) => path.clone(),
+            None => anyhow::bail!("No audit log, set --audit-log"),
+        };
+        let file = File::open(&path).with_context(|| format!("Reading {}", path.display()))?;
+        let mut records = VecDeque::with_capacity(n.min(1024));
+        for line in BufReader::new(file).lines() {
+            let record = match serde_json::from_str(&line?) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            if records.len() == n {
+                records.pop_front();
+            }
+            if n > 0 {
+                records.push_back(record);
+            }
+        }
+        Ok(records.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tails_records() {
+        let path = std::env::temp_dir().join(format!(
+            "agora-audit-{}-{}",
+            std::process::id(),
+            PeerId::random()
+        ));
+        let log = AuditLog::default();
+        let peer = PeerId::random();
+        // Dropped while not open.
+        log.record(Kind::Replayed, &peer, "before");
+        assert!(log.tail(1).is_err());
+
+        log.open(&path).unwrap();
+        for i in 0..5 {
+            log.record(Kind::OverLimits, &peer, i);
+        }
+        let tail = log.tail(3).unwrap();
+        let details = tail.iter().map(|r| r.details.as_str()).collect::<Vec<_>>();
+        assert_eq!(details, ["2", "3", "4"]);
+        assert!(tail
+            .iter()
+            .all(|r| r.kind == Kind::OverLimits && r.peer == peer));
+        assert!(log.tail(0).unwrap().is_empty());
+
+        // Appended to after reopening.
+        let reopened = AuditLog::default();
+        reopened.open(&path).unwrap();
+        reopened.record(Kind::Moderation, &peer, "kick");
+        assert_eq!(reopened.tail(10).unwrap().len(), 6);
+        std::fs::remove_file(path).unwrap();
+    }
+}
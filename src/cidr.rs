@@ -0,0 +1,121 @@
+//! Address prefixes, to ignore discovered addresses in them (`--ignore-addr-prefix`).
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+
+/// Parsing a prefix like `127.0.0.0/8` or `fe80::/10` failed.
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid address prefix {0:?}, expected e.g. 127.0.0.0/8")]
+pub(crate) struct CidrError(String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    /// `127.0.0.0/8` and `::1/128`.
+    pub(crate) fn loopback() -> [Self; 2] {
+        [
+            Self {
+                addr: Ipv4Addr::new(127, 0, 0, 0).into(),
+                len: 8,
+            },
+            Self {
+                addr: Ipv6Addr::LOCALHOST.into(),
+                len: 128,
+            },
+        ]
+    }
+
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(prefix), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(prefix) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(prefix), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(prefix) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `addr` starts with an IP address in the prefix.
+    pub(crate) fn contains_addr(&self, addr: &Multiaddr) -> bool {
+        match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => self.contains(&ip.into()),
+            Some(Protocol::Ip6(ip)) => self.contains(&ip.into()),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    /// An address without a length is a prefix of its full length.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || CidrError(s.to_string());
+        let (addr, len) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr.parse().map_err(|_| err())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            "" => max,
+            len => len.parse().ok().filter(|len| *len <= max).ok_or_else(err)?,
+        };
+        Ok(Self { addr, len })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_prefixes() {
+        for invalid in ["", "127.0.0.1/33", "::1/129", "localhost/8", "10.0.0.0/x"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            "10.1.0.0/16".parse::<Cidr>().unwrap(),
+            Cidr {
+                addr: ip("10.1.0.0"),
+                len: 16
+            }
+        );
+        assert_eq!("::1".parse::<Cidr>().unwrap(), Cidr::loopback()[1]);
+    }
+
+    #[test]
+    fn matches_addresses() {
+        let [v4, v6] = Cidr::loopback();
+        assert!(v4.contains(&ip("127.0.0.1")));
+        assert!(v4.contains(&ip("127.255.0.3")));
+        assert!(!v4.contains(&ip("128.0.0.1")));
+        assert!(!v4.contains(&ip("::1")));
+        assert!(v6.contains(&ip("::1")));
+        assert!(!v6.contains(&ip("::2")));
+
+        let all = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(all.contains(&ip("192.168.1.1")));
+        let link_local = "fe80::/10".parse::<Cidr>().unwrap();
+        assert!(link_local.contains(&ip("fe80::1")));
+        assert!(link_local.contains(&ip("febf::1")));
+        assert!(!link_local.contains(&ip("fec0::1")));
+
+        assert!(v4.contains_addr(&"/ip4/127.0.0.1/tcp/4001".parse().unwrap()));
+        assert!(v6.contains_addr(&"/ip6/::1/tcp/4001".parse().unwrap()));
+        assert!(!v4.contains_addr(&"/ip4/10.0.0.1/tcp/4001".parse().unwrap()));
+        assert!(!v4.contains_addr(&"/dns4/localhost/tcp/4001".parse().unwrap()));
+    }
+}
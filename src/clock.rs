@@ -0,0 +1,61 @@
+//! Timestamps of sent messages.
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+/// Wall-clock time at startup, advanced by the monotonic clock. A sender's timestamps keep
+/// increasing even if the wall-clock jumps backwards, e.g. on NTP corrections.
+pub(crate) struct MonotonicClock {
+    anchor: DateTime<Utc>,
+    started: Instant,
+    last: Option<DateTime<Utc>>,
+}
+
+impl MonotonicClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            anchor: Utc::now(),
+            started: Instant::now(),
+            last: None,
+        }
+    }
+
+    /// Strictly increasing at the wire format's millisecond precision.
+    pub(crate) fn now(&mut self) -> DateTime<Utc> {
+        self.at(Instant::now())
+    }
+
+    fn at(&mut self, now: Instant) -> DateTime<Utc> {
+        let elapsed = Duration::from_std(now.duration_since(self.started))
+            .expect("Running for less than 292 million years");
+        let mut timestamp = Utc.timestamp_millis((self.anchor + elapsed).timestamp_millis());
+        if let Some(last) = self.last.filter(|last| timestamp <= *last) {
+            timestamp = last + Duration::milliseconds(1);
+        }
+        self.last = Some(timestamp);
+        timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strictly_increasing() {
+        let mut clock = MonotonicClock::new();
+        let started = clock.started;
+        let first = clock.at(started);
+        assert_eq!(first.timestamp_millis(), clock.anchor.timestamp_millis());
+        // Same millisecond.
+        assert_eq!(clock.at(started), first + Duration::milliseconds(1));
+        assert_eq!(
+            clock.at(started + std::time::Duration::from_micros(1500)),
+            first + Duration::milliseconds(2)
+        );
+        assert_eq!(
+            clock.at(started + std::time::Duration::from_secs(60)),
+            first + Duration::seconds(60)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::path::PathBuf;
+
+use crate::audit;
+
+/// Commands entered on stdin, starting with `/`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    Quit,
+    /// Publish the own nickname to all joined channels right away.
+    AnnounceSelf,
+    /// Change the own nickname.
+    Nick(String),
+    /// Subscribe to another channel.
+    Join(String),
+    /// Unsubscribe from a channel.
+    Leave(String),
+    /// List the joined channels, with the topic of those of unknown name.
+    Channels,
+    /// Direct message to a nickname, or a peer id prefix if starting with `@`.
+    Msg {
+        to: String,
+        text: String,
+    },
+    /// Show the direct messages held of a peer, and further ones right away.
+    AcceptDm(String),
+    /// Drop the direct messages of a peer.
+    DeclineDm(String),
+    /// List the peers whose direct messages wait for `/accept-dm`.
+    DmRequests,
+    /// Redial known peers which aren't connected, or only the given one.
+    Reconnect(Option<String>),
+    /// Close all connections, restart discovery, rejoin the channels and redial.
+    ResetConnections,
+    /// Show the own fingerprint, or a peer's.
+    Fingerprint(Option<String>),
+    /// Show a peer's id, nickname and connections.
+    Whois(String),
+    /// Show a peer's fingerprint to be compared out of band, then mark the peer as verified once
+    /// confirmed.
+    Verify {
+        nick: String,
+        confirm: bool,
+    },
+    /// Hide a peer's messages in the current channel for everybody, if we own it.
+    Kick(String),
+    /// Like [`Command::Kick`], for a number of minutes.
+    Mute {
+        nick: String,
+        minutes: u64,
+    },
+    /// Neither show nor relay a peer's messages, in all channels.
+    Block(String),
+    Unblock(String),
+    /// Read the `--members` file again.
+    ReloadMembers,
+    /// Show the gossipsub mesh of the joined channels.
+    Topology,
+    /// Show the gossip health of the channels, see `mesh.rs`.
+    Mesh,
+    /// Make a peer an explicit gossipsub peer, sent all messages regardless of the mesh.
+    MeshGraft(String),
+    /// Show counters of dropped messages.
+    Stats,
+    /// Show the last records of the `--audit-log`.
+    AuditTail(usize),
+    /// Write a transcript of a channel's kept history, by the file's extension.
+    Export {
+        channel: String,
+        path: PathBuf,
+    },
+    /// Seal with a key derived from a new passphrase, still opening with the old ones for a
+    /// while.
+    Rekey(String),
+    /// Stop opening messages sealed with replaced keys.
+    RetireKeys,
+    /// Publish a canned response, `{nick}` being the given nickname or the own one.
+    Template {
+        name: String,
+        nick: Option<String>,
+    },
+    /// Show whether not to be disturbed, or turn it on or off until the schedule changes.
+    Dnd(Option<bool>),
+    /// List the messages waiting for peers and those which failed to send.
+    Outbox,
+    /// Forget the messages which failed to send.
+    ClearOutbox,
+    /// Load the scripts again, see `scripting.rs`.
+    ReloadScripts,
+    /// Save the session's identity, peers, channels and transport settings as a named profile.
+    SaveProfile(String),
+    /// Join a profile's channels and dial its peers, leaving the other channels and releasing the
+    /// previous profile's peers, see `profile.rs`.
+    LoadProfile(String),
+    /// Not built in, possibly registered by a script.
+    Unknown {
+        name: String,
+        args: String,
+    },
+    Invalid(String),
+}
+
+impl Command {
+    /// Returns `None` for ordinary chat messages.
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        let line = line.strip_prefix('/')?;
+        let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let cmd = match cmd {
+            "quit" => Self::Quit,
+            "announce-self" => Self::AnnounceSelf,
+            "nick" => match rest.trim() {
+                "" => Self::Invalid("Usage: /nick <nickname>".into()),
+                nick => Self::Nick(nick.into()),
+            },
+            "join" | "leave" => match rest.trim() {
+                "" => Self::Invalid(format!("Usage: /{} <channel>", cmd)),
+                channel if cmd == "join" => Self::Join(channel.into()),
+                channel => Self::Leave(channel.into()),
+            },
+            "channels" => Self::Channels,
+            "msg" => match rest.trim_start().split_once(' ') {
+                Some((to, text)) if !text.trim().is_empty() => Self::Msg {
+                    to: to.into(),
+                    text: text.trim().into(),
+                },
+                _ => Self::Invalid("Usage: /msg <nick|@peer-id-prefix> <message>".into()),
+            },
+            "accept-dm" | "decline-dm" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [from] if cmd == "accept-dm" => Self::AcceptDm(from.into()),
+                [from] => Self::DeclineDm(from.into()),
+                _ => Self::Invalid(format!("Usage: /{} <nick|@peer-id-prefix>", cmd)),
+            },
+            "dm-requests" => Self::DmRequests,
+            "reconnect" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => Self::Reconnect(None),
+                ["--reset"] => Self::ResetConnections,
+                [to] => Self::Reconnect(Some(to.into())),
+                _ => Self::Invalid("Usage: /reconnect [nick|@peer-id-prefix|--reset]".into()),
+            },
+            "fingerprint" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => Self::Fingerprint(None),
+                [to] => Self::Fingerprint(Some(to.into())),
+                _ => Self::Invalid("Usage: /fingerprint [nick|@peer-id-prefix]".into()),
+            },
+            "whois" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [who] => Self::Whois(who.into()),
+                _ => Self::Invalid("Usage: /whois <nick|@peer-id-prefix>".into()),
+            },
+            "verify" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [nick] => Self::Verify {
+                    nick: nick.into(),
+                    confirm: false,
+                },
+                [nick, "yes"] => Self::Verify {
+                    nick: nick.into(),
+                    confirm: true,
+                },
+                _ => Self::Invalid("Usage: /verify <nick|@peer-id-prefix> [yes]".into()),
+            },
+            "kick" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [nick] => Self::Kick(nick.into()),
+                _ => Self::Invalid("Usage: /kick <nick|@peer-id-prefix>".into()),
+            },
+            "mute" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [nick, minutes] => match minutes.parse() {
+                    Ok(minutes) => Self::Mute {
+                        nick: nick.into(),
+                        minutes,
+                    },
+                    Err(_) => Self::Invalid(format!("Invalid number of minutes {}", minutes)),
+                },
+                _ => Self::Invalid("Usage: /mute <nick|@peer-id-prefix> <minutes>".into()),
+            },
+            "block" | "unblock" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [who] if cmd == "block" => Self::Block(who.into()),
+                [who] => Self::Unblock(who.into()),
+                _ => Self::Invalid(format!("Usage: /{} <nick|@peer-id-prefix>", cmd)),
+            },
+            "members" => match rest.trim() {
+                "reload" => Self::ReloadMembers,
+                _ => Self::Invalid("Usage: /members reload".into()),
+            },
+            "topology" => Self::Topology,
+            "stats" => Self::Stats,
+            "audit" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                ["tail"] => Self::AuditTail(audit::DEFAULT_TAIL),
+                ["tail", n] => match n.parse() {
+                    Ok(n) => Self::AuditTail(n),
+                    Err(_) => Self::Invalid(format!("Invalid number of records {}", n)),
+                },
+                _ => Self::Invalid("Usage: /audit tail [count]".into()),
+            },
+            "export" => match rest.split_once(' ') {
+                Some((channel, path)) if !path.trim().is_empty() => Self::Export {
+                    channel: channel.into(),
+                    path: path.trim().into(),
+                },
+                _ => Self::Invalid("Usage: /export <channel> <file.md|file.html>".into()),
+            },
+            "rekey" => match rest.trim() {
+                "" => Self::Invalid("Usage: /rekey <new passphrase> | /rekey --retire".into()),
+                "--retire" => Self::RetireKeys,
+                passphrase => Self::Rekey(passphrase.into()),
+            },
+            "template" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [name] => Self::Template {
+                    name: name.into(),
+                    nick: None,
+                },
+                [name, nick] => Self::Template {
+                    name: name.into(),
+                    nick: Some(nick.into()),
+                },
+                _ => Self::Invalid("Usage: /template <name> [nick]".into()),
+            },
+            "dnd" => match rest.trim() {
+                "" => Self::Dnd(None),
+                "on" => Self::Dnd(Some(true)),
+                "off" => Self::Dnd(Some(false)),
+                _ => Self::Invalid("Usage: /dnd [on|off]".into()),
+            },
+            "outbox" => match rest.trim() {
+                "" => Self::Outbox,
+                "clear" => Self::ClearOutbox,
+                _ => Self::Invalid("Usage: /outbox [clear]".into()),
+            },
+            "mesh" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => Self::Mesh,
+                ["graft", peer] => Self::MeshGraft(peer.into()),
+                _ => Self::Invalid("Usage: /mesh [graft <nick|@peer-id-prefix>]".into()),
+            },
+            "script" => match rest.trim() {
+                "reload" => Self::ReloadScripts,
+                _ => Self::Invalid("Usage: /script reload".into()),
+            },
+            "profile" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                ["save", name] => Self::SaveProfile(name.into()),
+                ["load", name] => Self::LoadProfile(name.into()),
+                _ => Self::Invalid("Usage: /profile save|load <name>".into()),
+            },
+            other => Self::Unknown {
+                name: other.into(),
+                args: rest.trim().into(),
+            },
+        };
+        Some(cmd)
+    }
+}
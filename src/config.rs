@@ -0,0 +1,132 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use libp2p::PeerId;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    alert::{RawRule, Rules},
+    api::Limits,
+    discovery::PeerAddr,
+    dnd::Range,
+    template::Responses,
+};
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Config {
+    /// Incoming webhooks, keyed by their secret token.
+    #[serde(default)]
+    pub(crate) hooks: BTreeMap<String, Hook>,
+    /// MQTT bridge of the joined channel
+    pub(crate) mqtt: Option<Mqtt>,
+    /// Canned responses
+    #[serde(default)]
+    pub(crate) responses: Responses,
+    /// Moderated channels, keyed by their name.
+    #[serde(default)]
+    pub(crate) moderation: BTreeMap<String, Moderation>,
+    /// Bounds of received messages
+    #[serde(default)]
+    pub(crate) limits: Limits,
+    /// Peers to dial like `--peer`s, as addresses ending in `/p2p/<peer id>`
+    #[serde(default, deserialize_with = "peers")]
+    pub(crate) peers: Vec<PeerAddr>,
+    /// Daily ranges of local time to not be disturbed in, e.g. `["22:00-07:00"]`
+    #[serde(default, deserialize_with = "ranges")]
+    pub(crate) dnd: Vec<Range>,
+    /// Alerts of messages, as `[[alerts]]` rules, see [`alert`](crate::alert)
+    #[serde(default, deserialize_with = "alerts")]
+    pub(crate) alerts: Rules,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Hook {
+    /// Channel to post into
+    pub(crate) channel: String,
+    /// Nickname used if the caller doesn't provide (or may not override) one
+    #[serde(default = "default_hook_nick")]
+    pub(crate) nick: String,
+    /// Whether callers may set the nickname via the `nick` field
+    #[serde(default)]
+    pub(crate) allow_nick: bool,
+    /// Maximum number of posts per minute
+    #[serde(default = "default_hook_rate")]
+    pub(crate) per_minute: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Mqtt {
+    /// e.g. `mqtt://localhost:1883`
+    pub(crate) broker: String,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    #[serde(default = "default_mqtt_prefix")]
+    pub(crate) prefix: String,
+    /// Nickname of messages posted from MQTT
+    #[serde(default = "default_mqtt_nick")]
+    pub(crate) nick: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Moderation {
+    /// Peer id of the channel's owner, the only one who may kick and mute (`/kick`, `/mute`)
+    #[serde(deserialize_with = "peer_id")]
+    pub(crate) owner: PeerId,
+    /// Whether to leave the channel when kicked
+    #[serde(default)]
+    pub(crate) auto_part: bool,
+}
+
+fn peer_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn peers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PeerAddr>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|raw| raw.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn ranges<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Range>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|raw| raw.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn alerts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rules, D::Error> {
+    Rules::new(Vec::<RawRule>::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn default_mqtt_prefix() -> String {
+    "agora".into()
+}
+
+fn default_mqtt_nick() -> String {
+    "mqtt".into()
+}
+
+fn default_hook_nick() -> String {
+    "webhook".into()
+}
+
+fn default_hook_rate() -> u32 {
+    30
+}
+
+impl Config {
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading config {}", path.display()))?;
+        let config: Self =
+            toml::from_str(&raw).with_context(|| format!("Parsing config {}", path.display()))?;
+        config
+            .responses
+            .validate()
+            .with_context(|| format!("Invalid responses in {}", path.display()))?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,129 @@
+//! Connection attempts as they happen (`--verbose-connections`), showing where they fail, e.g. in
+//! the TCP connect or the handshake.
+use std::{
+    error::Error,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use libp2p::swarm::SwarmEvent;
+
+use crate::display::DisplayTime;
+
+/// Lines printed at most per window, the ones beyond are counted and summarized.
+const MAX_LINES: u32 = 20;
+const WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionLog {
+    /// Start of the current window, and the lines printed within.
+    window: Option<(Instant, u32)>,
+    suppressed: u32,
+}
+
+impl ConnectionLog {
+    /// Prints `event` if it's about connecting and the rate allows.
+    pub(crate) fn print<T, E>(&mut self, event: &SwarmEvent<T, E>) {
+        for line in self.lines(event, Instant::now()) {
+            println!("{} {}", DisplayTime::now(), line);
+        }
+    }
+
+    /// The lines to print for `event` at `now`, preceded by a summary of the ones suppressed in
+    /// the previous window.
+    fn lines<T, E>(&mut self, event: &SwarmEvent<T, E>, now: Instant) -> Vec<String> {
+        let line = match describe(event) {
+            Some(line) => line,
+            None => return Vec::new(),
+        };
+        let mut lines = Vec::new();
+        let (start, printed) = self.window.get_or_insert((now, 0));
+        if now.duration_since(*start) >= WINDOW {
+            if self.suppressed > 0 {
+                lines.push(format!(
+                    "({} connection events not shown)",
+                    std::mem::take(&mut self.suppressed)
+                ));
+            }
+            *start = now;
+            *printed = 0;
+        }
+        if *printed < MAX_LINES {
+            *printed += 1;
+            lines.push(line);
+        } else {
+            self.suppressed += 1;
+        }
+        lines
+    }
+}
+
+fn describe<T, E>(event: &SwarmEvent<T, E>) -> Option<String> {
+    Some(match event {
+        SwarmEvent::Dialing(peer) => format!("Dialing {}", peer),
+        SwarmEvent::IncomingConnection {
+            local_addr,
+            send_back_addr,
+        } => format!(
+            "Incoming connection from {} on {}",
+            send_back_addr, local_addr
+        ),
+        SwarmEvent::IncomingConnectionError {
+            local_addr,
+            send_back_addr,
+            error,
+        } => format!(
+            "Incoming connection from {} on {} failed: {}",
+            send_back_addr,
+            local_addr,
+            chain(error)
+        ),
+        SwarmEvent::OutgoingConnectionError { peer_id, error } => match peer_id {
+            Some(peer) => format!("Dialing {} failed: {}", peer, chain(error)),
+            None => format!("Dialing failed: {}", chain(error)),
+        },
+        _ => return None,
+    })
+}
+
+/// `error` and its sources, e.g. `Handshake failed: Noise error: ..`.
+fn chain(error: &dyn Error) -> String {
+    let mut out = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        let _ = write!(out, ": {}", error);
+        source = error.source();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::*;
+
+    #[test]
+    fn summarizes_beyond_the_rate() {
+        let start = Instant::now();
+        let mut log = ConnectionLog::default();
+        let peer = PeerId::random();
+        let dialing = SwarmEvent::<(), void::Void>::Dialing(peer);
+        let printed = (0..MAX_LINES + 5)
+            .map(|_| log.lines(&dialing, start).len())
+            .sum::<usize>();
+        assert_eq!(printed, MAX_LINES as usize);
+        assert!(log
+            .lines(&SwarmEvent::<(), void::Void>::Behaviour(()), start + WINDOW)
+            .is_empty());
+
+        assert_eq!(
+            log.lines(&dialing, start + WINDOW),
+            [
+                "(5 connection events not shown)".to_string(),
+                format!("Dialing {}", peer)
+            ]
+        );
+        assert_eq!(log.lines(&dialing, start + WINDOW).len(), 1);
+    }
+}
@@ -0,0 +1,154 @@
+//! Control API: remote tools drive agora with the lines otherwise typed on stdin, i.e. chat
+//! messages and `/` commands, one per line.
+//!
+//! Clients authenticate with `AUTH <token>` as their first line, answered by `OK`. Any other
+//! first line is answered by `ERR unauthorized` and the connection is closed.
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::bail;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tracing::*;
+
+use crate::shutdown::Tasks;
+
+/// Clients not authenticating within this are disconnected.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Compares in constant time. Both sides are hashed first, so not even the token's length leaks.
+fn token_matches(expected: &str, given: &str) -> bool {
+    Sha256::digest(expected)
+        .ct_eq(&Sha256::digest(given))
+        .into()
+}
+
+pub(crate) struct Control {
+    lines: mpsc::UnboundedReceiver<String>,
+}
+
+impl Control {
+    pub(crate) async fn bind_tcp(
+        addr: SocketAddr,
+        token: String,
+        tasks: &mut Tasks,
+    ) -> anyhow::Result<Self> {
+        if token.is_empty() {
+            bail!("The control token must not be empty");
+        }
+        let listener = TcpListener::bind(addr).await?;
+        info!("Control API listening on {}", listener.local_addr()?);
+        Ok(Self::serve(listener, token, tasks))
+    }
+
+    fn serve(listener: TcpListener, token: String, tasks: &mut Tasks) -> Self {
+        let (tx, lines) = mpsc::unbounded_channel();
+        tasks.spawn(|shutdown| async move {
+            loop {
+                let accepted = tokio::select! {
+                    _ = shutdown.wait() => break,
+                    accepted = listener.accept() => accepted,
+                };
+                match accepted {
+                    Ok((stream, remote)) => {
+                        debug!(%remote, "Control client connected");
+                        let (tx, token) = (tx.clone(), token.clone());
+                        tokio::spawn(async move {
+                            if let Err(error) = serve(stream, &token, tx).await {
+                                debug!(%remote, %error, "Control client failed");
+                            }
+                        });
+                    }
+                    Err(error) => warn!(%error, "Control API accept failed"),
+                }
+            }
+        });
+        Self { lines }
+    }
+
+    pub(crate) async fn next_line(control: &mut Option<Self>) -> Option<String> {
+        match control {
+            Some(control) => control.lines.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    token: &str,
+    lines: mpsc::UnboundedSender<String>,
+) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read).lines();
+    let auth = tokio::time::timeout(AUTH_TIMEOUT, read.next_line()).await;
+    let authenticated = match &auth {
+        Ok(Ok(Some(line))) => line
+            .strip_prefix("AUTH ")
+            .map_or(false, |given| token_matches(token, given.trim_end())),
+        _ => false,
+    };
+    if !authenticated {
+        write.write_all(b"ERR unauthorized\n").await?;
+        bail!("Unauthenticated");
+    }
+    write.write_all(b"OK\n").await?;
+    while let Some(line) = read.next_line().await? {
+        trace!(%line, "Control client");
+        if lines.send(line).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(token_matches("s3cret", "s3cret"));
+        assert!(!token_matches("s3cret", "s3cre"));
+        assert!(!token_matches("s3cret", "s3cret!"));
+        assert!(!token_matches("s3cret", ""));
+    }
+
+    async fn connect(control_addr: SocketAddr, first_line: &str) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(control_addr).await.unwrap();
+        stream.write_all(first_line.as_bytes()).await.unwrap();
+        let mut reply = [0; 32];
+        let n = stream.read(&mut reply).await.unwrap();
+        (stream, String::from_utf8_lossy(&reply[..n]).into_owned())
+    }
+
+    #[tokio::test]
+    async fn authenticates_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut tasks = Tasks::default();
+        let mut control = Some(Control::serve(listener, "s3cret".into(), &mut tasks));
+
+        for attempt in ["AUTH wrong\n", "/quit\n", "AUTH \n"] {
+            let (mut stream, reply) = connect(addr, attempt).await;
+            assert_eq!(reply, "ERR unauthorized\n");
+            // Closed by the server.
+            assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+        }
+
+        let (mut stream, reply) = connect(addr, "AUTH s3cret\n").await;
+        assert_eq!(reply, "OK\n");
+        stream.write_all(b"hello\n/join rust\n").await.unwrap();
+        assert_eq!(Control::next_line(&mut control).await.unwrap(), "hello");
+        assert_eq!(
+            Control::next_line(&mut control).await.unwrap(),
+            "/join rust"
+        );
+    }
+}
@@ -0,0 +1,717 @@
+//! Channel encryption (`--channel-key`): payloads are sealed with XChaCha20-Poly1305 under a key
+//! derived from a passphrase with Argon2id.
+//!
+//! Every node picks a random salt at startup and sends it along in the clear:
+//!
+//! ```text
+//! "agx2" | key id (4) | m_cost (u32 LE) | t_cost (u32 LE) | p_cost (u32 LE) | salt (16) | nonce (24) | ciphertext
+//! ```
+//!
+//! The header up to the salt and the topic are authenticated as associated data, so messages
+//! can't be replayed into another channel. Nonces are random, which is safe with XChaCha's 192
+//! bits.
+//!
+//! The key id identifies the passphrase, so after `/rekey` receivers pick the matching one of
+//! the keys they hold. It's derived with Argon2id as well, under a fixed salt, so it's no cheaper
+//! to brute force than the keys.
+//!
+//! Keys for peers' salts are slow to derive by design, so they're handed out as [`Derivation`]s
+//! to run off the swarm's thread, each peer causing at most one per [`DERIVE_INTERVAL`].
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use argon2::{Algorithm, Argon2, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use libp2p::{gossipsub::TopicHash, PeerId};
+
+const MAGIC: &[u8; 4] = b"agx2";
+const KEY_ID_LEN: usize = 4;
+const KEY_ID_SALT: &[u8] = b"agora-channel-key-id";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + 3 * 4 + SALT_LEN;
+
+/// Argon2id parameters used for the own key.
+const PARAMS: Params = Params {
+    m_cost: 19 * 1024,
+    t_cost: 2,
+    p_cost: 1,
+};
+/// Upper bounds of parameters accepted from peers, so they can't make us burn memory and time.
+const MAX_PARAMS: Params = Params {
+    m_cost: 64 * 1024,
+    t_cost: 8,
+    p_cost: 4,
+};
+/// Keys of a peer's salts are derived at most this often.
+const DERIVE_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_PEER_KEYS: usize = 64;
+/// Peers whose last derivation is remembered, those beyond are rate limited together.
+const MAX_DERIVING_PEERS: usize = 1024;
+/// Replaced keys still open messages this long after `/rekey`, unless retired before.
+pub(crate) const REKEY_GRACE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Params {
+    /// KiB
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+/// Why a payload couldn't be opened.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum OpenError {
+    #[error("not encrypted")]
+    NotEncrypted,
+    #[error("unsupported key derivation parameters")]
+    UnsupportedParams,
+    #[error("too many new salts, try again later")]
+    RateLimited,
+    #[error("authentication failed")]
+    Authentication,
+    #[error("sealed with a key not held")]
+    UnknownKey,
+    /// Retry once a [`Derivation`] handed out by [`Keyring::take_derivations`] is done.
+    #[error("key still being derived")]
+    Deriving,
+}
+
+type KeyId = [u8; KEY_ID_LEN];
+type Header = [u8; HEADER_LEN];
+
+/// Deriving the key of a peer's salt, to run on the blocking thread pool, see
+/// [`Keyring::take_derivations`].
+pub(crate) struct Derivation {
+    header: Header,
+    passphrase: String,
+}
+
+impl Derivation {
+    pub(crate) fn run(self) -> Derived {
+        let cipher = derive(&self.passphrase, params(&self.header), &self.header[20..]);
+        if let Err(error) = &cipher {
+            tracing::debug!(%error, "Deriving a peer's key failed");
+        }
+        Derived {
+            header: self.header,
+            cipher: cipher.ok(),
+        }
+    }
+}
+
+/// The result of a [`Derivation`], for [`Keyring::derived`].
+pub(crate) struct Derived {
+    header: Header,
+    cipher: Option<XChaCha20Poly1305>,
+}
+
+fn argon2id(passphrase: &str, params: Params, salt: &[u8], out: &mut [u8]) -> anyhow::Result<()> {
+    let argon2_params =
+        argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, Some(out.len()))
+            .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))?;
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+        .hash_password_into(passphrase.as_bytes(), salt, out)
+        .map_err(|e| anyhow!("Deriving the channel key failed: {}", e))
+}
+
+fn derive(passphrase: &str, params: Params, salt: &[u8]) -> anyhow::Result<XChaCha20Poly1305> {
+    let mut key = [0; 32];
+    argon2id(passphrase, params, salt, &mut key)?;
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn key_id(passphrase: &str, params: Params) -> anyhow::Result<KeyId> {
+    let mut id = [0; KEY_ID_LEN];
+    argon2id(passphrase, params, KEY_ID_SALT, &mut id)?;
+    Ok(id)
+}
+
+fn header(key_id: &KeyId, params: Params, salt: &[u8; SALT_LEN]) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..8].copy_from_slice(key_id);
+    header[8..12].copy_from_slice(&params.m_cost.to_le_bytes());
+    header[12..16].copy_from_slice(&params.t_cost.to_le_bytes());
+    header[16..20].copy_from_slice(&params.p_cost.to_le_bytes());
+    header[20..].copy_from_slice(salt);
+    header
+}
+
+fn params(header: &[u8; HEADER_LEN]) -> Params {
+    let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().expect("4 bytes"));
+    Params {
+        m_cost: u32_at(8),
+        t_cost: u32_at(12),
+        p_cost: u32_at(16),
+    }
+}
+
+/// Splits a sealed payload into its header and the rest.
+fn split(data: &[u8]) -> Result<([u8; HEADER_LEN], &[u8]), OpenError> {
+    if data.len() < HEADER_LEN + NONCE_LEN || !data.starts_with(MAGIC) {
+        return Err(OpenError::NotEncrypted);
+    }
+    let (header, rest) = data.split_at(HEADER_LEN);
+    Ok((header.try_into().expect("Checked length"), rest))
+}
+
+fn header_key_id(header: &[u8; HEADER_LEN]) -> KeyId {
+    header[4..8].try_into().expect("4 bytes")
+}
+
+fn aad(header: &[u8], topic: &TopicHash) -> Vec<u8> {
+    [header, topic.as_str().as_bytes()].concat()
+}
+
+fn seal_with(
+    cipher: &XChaCha20Poly1305,
+    header: &[u8; HEADER_LEN],
+    nonce: &XNonce,
+    topic: &TopicHash,
+    plaintext: &[u8],
+) -> Vec<u8> {
+    let aad = aad(header, topic);
+    let ciphertext = cipher
+        .encrypt(
+            nonce,
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )
+        .expect("Encryption works");
+    [&header[..], nonce.as_slice(), &ciphertext].concat()
+}
+
+pub(crate) struct ChannelKey {
+    passphrase: String,
+    key_id: KeyId,
+    header: [u8; HEADER_LEN],
+    cipher: XChaCha20Poly1305,
+    /// Keys derived for peers' headers, and the headers by when they were added.
+    peers: HashMap<Header, XChaCha20Poly1305>,
+    added: VecDeque<Header>,
+    /// Headers handed out as derivations, not done yet.
+    deriving: HashSet<Header>,
+    derivations: Vec<Derivation>,
+    /// When a peer's message last caused a derivation.
+    last_derived: HashMap<PeerId, Instant>,
+}
+
+impl ChannelKey {
+    pub(crate) fn new(passphrase: String) -> anyhow::Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::with_salt(passphrase, PARAMS, salt)
+    }
+
+    fn with_salt(passphrase: String, params: Params, salt: [u8; SALT_LEN]) -> anyhow::Result<Self> {
+        let key_id = key_id(&passphrase, params)?;
+        Ok(Self {
+            cipher: derive(&passphrase, params, &salt)?,
+            header: header(&key_id, params, &salt),
+            key_id,
+            passphrase,
+            peers: Default::default(),
+            added: Default::default(),
+            deriving: Default::default(),
+            derivations: Default::default(),
+            last_derived: Default::default(),
+        })
+    }
+
+    pub(crate) fn seal(&self, topic: &TopicHash, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        seal_with(&self.cipher, &self.header, &nonce, topic, plaintext)
+    }
+
+    fn open(
+        &mut self,
+        peer: &PeerId,
+        topic: &TopicHash,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<Vec<u8>, OpenError> {
+        let (header, rest) = split(data)?;
+        if header_key_id(&header) != self.key_id {
+            return Err(OpenError::UnknownKey);
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let cipher = self.cipher_for(peer, &header, now)?;
+        cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad(&header, topic),
+                },
+            )
+            .map_err(|_| OpenError::Authentication)
+    }
+
+    /// The key of `header`, or [`OpenError::Deriving`] after queueing its derivation, which
+    /// `peer` causes at most once per [`DERIVE_INTERVAL`].
+    fn cipher_for(
+        &mut self,
+        peer: &PeerId,
+        header: &Header,
+        now: Instant,
+    ) -> Result<&XChaCha20Poly1305, OpenError> {
+        if *header == self.header {
+            return Ok(&self.cipher);
+        }
+        if self.peers.contains_key(header) {
+            return Ok(&self.peers[header]);
+        }
+        if self.deriving.contains(header) {
+            return Err(OpenError::Deriving);
+        }
+        let params = params(header);
+        if params.m_cost > MAX_PARAMS.m_cost
+            || params.t_cost > MAX_PARAMS.t_cost
+            || params.p_cost > MAX_PARAMS.p_cost
+        {
+            return Err(OpenError::UnsupportedParams);
+        }
+        let recent = |at: &Instant| now.saturating_duration_since(*at) < DERIVE_INTERVAL;
+        if self.last_derived.get(peer).map_or(false, recent) {
+            return Err(OpenError::RateLimited);
+        }
+        if self.last_derived.len() >= MAX_DERIVING_PEERS {
+            self.last_derived.retain(|_, at| recent(at));
+            if self.last_derived.len() >= MAX_DERIVING_PEERS {
+                return Err(OpenError::RateLimited);
+            }
+        }
+        self.last_derived.insert(*peer, now);
+        self.deriving.insert(*header);
+        self.derivations.push(Derivation {
+            header: *header,
+            passphrase: self.passphrase.clone(),
+        });
+        Err(OpenError::Deriving)
+    }
+
+    /// Keeps the key of a done derivation, replacing the oldest one if there are too many.
+    fn derived(&mut self, derived: Derived) {
+        self.deriving.remove(&derived.header);
+        let cipher = match derived.cipher {
+            Some(cipher) => cipher,
+            None => return,
+        };
+        if self.peers.len() >= MAX_PEER_KEYS {
+            if let Some(oldest) = self.added.pop_front() {
+                self.peers.remove(&oldest);
+            }
+        }
+        self.peers.insert(derived.header, cipher);
+        self.added.push_back(derived.header);
+    }
+}
+
+/// The key sealing outgoing messages, and the ones it replaced, still opening messages for
+/// [`REKEY_GRACE`] so peers can switch over one by one.
+pub(crate) struct Keyring {
+    current: ChannelKey,
+    /// With when they were replaced.
+    old: Vec<(ChannelKey, Instant)>,
+}
+
+impl Keyring {
+    pub(crate) fn new(key: ChannelKey) -> Self {
+        Self {
+            current: key,
+            old: Vec::new(),
+        }
+    }
+
+    pub(crate) fn seal(&self, topic: &TopicHash, plaintext: &[u8]) -> Vec<u8> {
+        self.current.seal(topic, plaintext)
+    }
+
+    /// Seals with `key` from now on.
+    pub(crate) fn rekey(&mut self, key: ChannelKey, now: Instant) {
+        let old = std::mem::replace(&mut self.current, key);
+        self.old.retain(|(k, _)| k.key_id != self.current.key_id);
+        if old.key_id != self.current.key_id {
+            self.old.push((old, now));
+        }
+    }
+
+    /// Drops the replaced keys before their grace period ends, returning how many.
+    pub(crate) fn retire(&mut self) -> usize {
+        std::mem::take(&mut self.old).len()
+    }
+
+    /// Opens `data` received from `peer`.
+    pub(crate) fn open(
+        &mut self,
+        peer: &PeerId,
+        topic: &TopicHash,
+        data: &[u8],
+    ) -> Result<Vec<u8>, OpenError> {
+        self.open_at(peer, topic, data, Instant::now())
+    }
+
+    fn open_at(
+        &mut self,
+        peer: &PeerId,
+        topic: &TopicHash,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<Vec<u8>, OpenError> {
+        self.old
+            .retain(|(_, replaced)| now.duration_since(*replaced) < REKEY_GRACE);
+        let (header, _) = split(data)?;
+        match self.key(&header_key_id(&header)) {
+            Some(key) => key.open(peer, topic, data, now),
+            None => Err(OpenError::UnknownKey),
+        }
+    }
+
+    fn key(&mut self, key_id: &KeyId) -> Option<&mut ChannelKey> {
+        if *key_id == self.current.key_id {
+            return Some(&mut self.current);
+        }
+        self.old
+            .iter_mut()
+            .map(|(key, _)| key)
+            .find(|key| key.key_id == *key_id)
+    }
+
+    /// The derivations queued by opening messages since the last call.
+    pub(crate) fn take_derivations(&mut self) -> Vec<Derivation> {
+        std::iter::once(&mut self.current)
+            .chain(self.old.iter_mut().map(|(key, _)| key))
+            .flat_map(|key| std::mem::take(&mut key.derivations))
+            .collect()
+    }
+
+    /// Keeps the key of a done derivation, so messages failing with [`OpenError::Deriving`] can
+    /// be opened.
+    pub(crate) fn derived(&mut self, derived: Derived) {
+        if let Some(key) = self.key(&header_key_id(&derived.header)) {
+            key.derived(derived);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap enough for tests.
+    const TEST_PARAMS: Params = Params {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn topic(name: &str) -> TopicHash {
+        TopicHash::from_raw(name)
+    }
+
+    fn salt() -> [u8; SALT_LEN] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    fn key(passphrase: &str, salt: [u8; SALT_LEN]) -> ChannelKey {
+        ChannelKey::with_salt(passphrase.into(), TEST_PARAMS, salt).unwrap()
+    }
+
+    /// Opens `data`, running the derivations right away.
+    fn open(key: &mut ChannelKey, topic: &TopicHash, data: &[u8]) -> Result<Vec<u8>, OpenError> {
+        let (peer, now) = (PeerId::random(), Instant::now());
+        match key.open(&peer, topic, data, now) {
+            Err(OpenError::Deriving) => {
+                for derivation in std::mem::take(&mut key.derivations) {
+                    key.derived(derivation.run());
+                }
+                key.open(&peer, topic, data, now)
+            }
+            opened => opened,
+        }
+    }
+
+    fn open_at(
+        keyring: &mut Keyring,
+        topic: &TopicHash,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<Vec<u8>, OpenError> {
+        let peer = PeerId::random();
+        match keyring.open_at(&peer, topic, data, now) {
+            Err(OpenError::Deriving) => {
+                for derivation in keyring.take_derivations() {
+                    keyring.derived(derivation.run());
+                }
+                keyring.open_at(&peer, topic, data, now)
+            }
+            opened => opened,
+        }
+    }
+
+    #[test]
+    fn xchacha20poly1305_vector() {
+        // draft-irtf-cfrg-xchacha-03, A.3.1
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(
+            &hex::decode("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f")
+                .unwrap(),
+        ));
+        let nonce = hex::decode("404142434445464748494a4b4c4d4e4f5051525354555657").unwrap();
+        let ciphertext = cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.",
+                    aad: &hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap(),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            hex::encode(ciphertext),
+            "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb\
+             731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452\
+             2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9\
+             21f9664c97637da9768812f615c68b13b52e\
+             c0875924c1c7987947deafd8780acf49"
+        );
+    }
+
+    #[test]
+    fn envelope_vector() {
+        let key = key(PASSPHRASE, salt());
+        let nonce: [u8; NONCE_LEN] = std::array::from_fn(|i| i as u8);
+        let sealed = seal_with(
+            &key.cipher,
+            &key.header,
+            XNonce::from_slice(&nonce),
+            &topic("agora"),
+            b"hello",
+        );
+        assert_eq!(
+            hex::encode(&sealed),
+            "616778327ecc0a3f4000000001000000\
+             01000000000102030405060708090a0b0c0d0e0f\
+             000102030405060708090a0b0c0d0e0f1011121314151617\
+             a1e1c60d759dd10e6f615934d0b21c7f55ba1dce5e"
+        );
+    }
+
+    #[test]
+    fn roundtrip_across_salts() {
+        let sender = key(PASSPHRASE, [1; SALT_LEN]);
+        let mut receiver = key(PASSPHRASE, [2; SALT_LEN]);
+        let sealed = sender.seal(&topic("agora"), b"hello");
+        assert_eq!(
+            open(&mut receiver, &topic("agora"), &sealed).unwrap(),
+            b"hello"
+        );
+        // The own messages, with a fresh nonce each.
+        let again = receiver.seal(&topic("agora"), b"hello");
+        assert_ne!(again, receiver.seal(&topic("agora"), b"hello"));
+        assert_eq!(
+            open(&mut receiver, &topic("agora"), &again).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let sender = key(PASSPHRASE, salt());
+        let mut receiver = key(PASSPHRASE, salt());
+        let sealed = sender.seal(&topic("agora"), b"hello");
+
+        // Bound to the topic.
+        assert!(matches!(
+            open(&mut receiver, &topic("other"), &sealed),
+            Err(OpenError::Authentication)
+        ));
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open(&mut receiver, &topic("agora"), &flipped),
+            Err(OpenError::Authentication)
+        ));
+        assert!(matches!(
+            open(&mut receiver, &topic("agora"), b"plain cbor"),
+            Err(OpenError::NotEncrypted)
+        ));
+        let mut wrong = key("wrong", salt());
+        assert!(matches!(
+            open(&mut wrong, &topic("agora"), &sealed),
+            Err(OpenError::UnknownKey)
+        ));
+        // A forged key id.
+        let mut forged = sealed.clone();
+        forged[4..8].copy_from_slice(&wrong.key_id);
+        assert!(matches!(
+            open(&mut wrong, &topic("agora"), &forged),
+            Err(OpenError::Authentication)
+        ));
+    }
+
+    #[test]
+    fn limits_derivations_per_peer() {
+        let mut receiver = key(PASSPHRASE, salt());
+        let now = Instant::now();
+        let key_id = receiver.key_id;
+        let header = |salt: &[u8; SALT_LEN]| header(&key_id, TEST_PARAMS, salt);
+        let (alice, mallory) = (PeerId::random(), PeerId::random());
+        let mut greedy = header(&[1; SALT_LEN]);
+        greedy[8..12].copy_from_slice(&(MAX_PARAMS.m_cost + 1).to_le_bytes());
+        assert!(matches!(
+            receiver.cipher_for(&mallory, &greedy, now),
+            Err(OpenError::UnsupportedParams)
+        ));
+
+        assert!(matches!(
+            receiver.cipher_for(&mallory, &header(&[1; SALT_LEN]), now),
+            Err(OpenError::Deriving)
+        ));
+        // Once per header, while it's being derived.
+        assert!(matches!(
+            receiver.cipher_for(&alice, &header(&[1; SALT_LEN]), now),
+            Err(OpenError::Deriving)
+        ));
+        assert_eq!(receiver.derivations.len(), 1);
+        assert!(matches!(
+            receiver.cipher_for(&mallory, &header(&[2; SALT_LEN]), now),
+            Err(OpenError::RateLimited)
+        ));
+        // Other peers aren't starved by one rotating salts.
+        assert!(matches!(
+            receiver.cipher_for(&alice, &header(&[3; SALT_LEN]), now),
+            Err(OpenError::Deriving)
+        ));
+        for derivation in std::mem::take(&mut receiver.derivations) {
+            receiver.derived(derivation.run());
+        }
+        // Known salts don't count.
+        assert!(receiver
+            .cipher_for(&mallory, &header(&[1; SALT_LEN]), now)
+            .is_ok());
+        assert!(receiver
+            .cipher_for(&alice, &header(&[3; SALT_LEN]), now)
+            .is_ok());
+        assert!(matches!(
+            receiver.cipher_for(&mallory, &header(&[2; SALT_LEN]), now + DERIVE_INTERVAL),
+            Err(OpenError::Deriving)
+        ));
+    }
+
+    #[test]
+    fn replaces_the_oldest_peer_keys() {
+        let mut receiver = key(PASSPHRASE, salt());
+        let now = Instant::now();
+        let key_id = receiver.key_id;
+        let headers = (0..=MAX_PEER_KEYS)
+            .map(|i| header(&key_id, TEST_PARAMS, &[i as u8 + 1; SALT_LEN]))
+            .collect::<Vec<_>>();
+        for header in &headers {
+            let _ = receiver.cipher_for(&PeerId::random(), header, now);
+        }
+        for derivation in std::mem::take(&mut receiver.derivations) {
+            receiver.derived(derivation.run());
+        }
+        assert_eq!(receiver.peers.len(), MAX_PEER_KEYS);
+        let peer = PeerId::random();
+        assert!(matches!(
+            receiver.cipher_for(&peer, &headers[0], now),
+            Err(OpenError::Deriving)
+        ));
+        for header in &headers[1..] {
+            assert!(receiver.cipher_for(&peer, header, now).is_ok());
+        }
+    }
+
+    #[test]
+    fn key_ids_identify_passphrases() {
+        assert_eq!(
+            hex::encode(key_id(PASSPHRASE, TEST_PARAMS).unwrap()),
+            "7ecc0a3f"
+        );
+        assert_eq!(
+            hex::encode(key_id("wrong", TEST_PARAMS).unwrap()),
+            "59e574ce"
+        );
+        // The same whatever the salt.
+        let (a, b) = (
+            key(PASSPHRASE, [1; SALT_LEN]),
+            key(PASSPHRASE, [2; SALT_LEN]),
+        );
+        assert_eq!(a.key_id, b.key_id);
+        assert_eq!(header_key_id(&a.header), a.key_id);
+    }
+
+    #[test]
+    fn keyring_opens_with_replaced_keys() {
+        let agora = topic("agora");
+        let old_sender = key(PASSPHRASE, [1; SALT_LEN]);
+        let new_sender = key("new passphrase", [2; SALT_LEN]);
+        let stranger = key("another one", [3; SALT_LEN]);
+        let old_sealed = old_sender.seal(&agora, b"old");
+        let new_sealed = new_sender.seal(&agora, b"new");
+
+        let mut keyring = Keyring::new(key(PASSPHRASE, [4; SALT_LEN]));
+        let now = Instant::now();
+        assert!(matches!(
+            open_at(&mut keyring, &agora, &new_sealed, now),
+            Err(OpenError::UnknownKey)
+        ));
+        keyring.rekey(key("new passphrase", [4; SALT_LEN]), now);
+        assert_eq!(
+            open_at(&mut keyring, &agora, &new_sealed, now).unwrap(),
+            b"new"
+        );
+        assert_eq!(
+            open_at(&mut keyring, &agora, &old_sealed, now).unwrap(),
+            b"old"
+        );
+        assert!(matches!(
+            open_at(&mut keyring, &agora, &stranger.seal(&agora, b"?"), now),
+            Err(OpenError::UnknownKey)
+        ));
+        // Sealing with the new key.
+        let mut receiver = key("new passphrase", [5; SALT_LEN]);
+        assert_eq!(
+            open(&mut receiver, &agora, &keyring.seal(&agora, b"hi")).unwrap(),
+            b"hi"
+        );
+
+        // Until the grace period ends.
+        let later = now + REKEY_GRACE;
+        assert!(matches!(
+            open_at(&mut keyring, &agora, &old_sealed, later),
+            Err(OpenError::UnknownKey)
+        ));
+        assert_eq!(
+            open_at(&mut keyring, &agora, &new_sealed, later).unwrap(),
+            b"new"
+        );
+
+        // Or they are retired.
+        keyring.rekey(key(PASSPHRASE, [4; SALT_LEN]), later);
+        assert_eq!(
+            open_at(&mut keyring, &agora, &new_sealed, later).unwrap(),
+            b"new"
+        );
+        assert_eq!(keyring.retire(), 1);
+        assert!(matches!(
+            open_at(&mut keyring, &agora, &new_sealed, later),
+            Err(OpenError::UnknownKey)
+        ));
+        assert_eq!(
+            open_at(&mut keyring, &agora, &old_sealed, later).unwrap(),
+            b"old"
+        );
+    }
+}
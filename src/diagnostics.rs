@@ -0,0 +1,74 @@
+//! Diagnostic snapshots of the state and the gossipsub mesh, dumped as JSON on `SIGUSR1` (to
+//! stderr, or `--sigusr1-dump-file`) and served at `GET /debug/state`.
+use std::{io::Write, path::Path, time::Instant};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{
+    p2p::Gossipsub,
+    state::{Snapshot, State},
+    topology::{self, ChannelTopology},
+};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Diagnostics {
+    pub(crate) state: Snapshot,
+    pub(crate) topology: Vec<ChannelTopology>,
+}
+
+impl Diagnostics {
+    pub(crate) fn collect(gossipsub: &Gossipsub, state: &State) -> Self {
+        Self {
+            state: state.snapshot(Instant::now()),
+            topology: topology::collect(gossipsub, state),
+        }
+    }
+
+    /// Writes the snapshot as pretty-printed JSON to `file`, replacing it, or to stderr.
+    pub(crate) fn dump(&self, file: Option<&Path>) -> anyhow::Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        match file {
+            // Renamed into place, so readers never see a partial dump.
+            Some(path) => {
+                let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+                std::fs::write(&tmp, json)
+                    .and_then(|()| std::fs::rename(&tmp, path))
+                    .with_context(|| format!("Writing {}", path.display()))
+            }
+            None => Ok(std::io::stderr().lock().write_all(json.as_bytes())?),
+        }
+    }
+}
+
+/// Receives `SIGUSR1`, asking for a dump. Never on platforms without it.
+pub(crate) struct DumpSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl DumpSignal {
+    pub(crate) fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::user_defined1())
+                .map_err(|error| tracing::warn!(%error, "Can't listen for SIGUSR1"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    pub(crate) async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
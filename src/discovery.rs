@@ -0,0 +1,407 @@
+//! Ways of finding peers to dial, e.g. mDNS on the local network or a static list from the
+//! config. [`Behaviour`](crate::p2p::Behaviour) polls any number of them through [`Discoveries`]
+//! and dials what they find.
+use std::{
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use libp2p::{
+    core::{connection::ConnectionId, transport::ListenerId},
+    futures::{
+        future::{self, BoxFuture},
+        Future,
+    },
+    multiaddr::Protocol,
+    swarm::{
+        handler::DummyConnectionHandler, AddressRecord, KeepAlive, NetworkBehaviour,
+        NetworkBehaviourAction, PollParameters,
+    },
+    Multiaddr, PeerId,
+};
+use tokio::time::{Instant, Sleep};
+
+use crate::state::RECONNECT_INTERVAL;
+
+/// A source of peers, polled by [`Discoveries`].
+pub(crate) trait Discovery: Send {
+    /// For logs and errors.
+    fn name(&self) -> &'static str;
+
+    /// Peers found since the last poll, with an address each.
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut Params<'_>,
+    ) -> Poll<Vec<(PeerId, Multiaddr)>>;
+
+    /// The swarm started listening on `addr`.
+    fn listening(&mut self, _id: ListenerId, _addr: &Multiaddr) {}
+
+    /// Forgets the peers found, to find and report them again.
+    fn restart(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// The swarm's [`PollParameters`], passed through to trait objects behind a reference.
+pub(crate) struct Params<'a>(&'a dyn ErasedParams<'a>);
+
+impl<'a> Params<'a> {
+    fn new(params: &'a impl PollParameters) -> Self {
+        Self(params)
+    }
+}
+
+/// [`PollParameters`] with boxed iterators, so it can be a trait object.
+trait ErasedParams<'a> {
+    fn supported_protocols(&self) -> Box<dyn ExactSizeIterator<Item = Vec<u8>> + 'a>;
+    fn listened_addresses(&self) -> Box<dyn ExactSizeIterator<Item = Multiaddr> + 'a>;
+    fn external_addresses(&self) -> Box<dyn ExactSizeIterator<Item = AddressRecord> + 'a>;
+    fn local_peer_id(&self) -> &PeerId;
+}
+
+impl<'a, P: PollParameters + 'a> ErasedParams<'a> for P {
+    fn supported_protocols(&self) -> Box<dyn ExactSizeIterator<Item = Vec<u8>> + 'a> {
+        Box::new(PollParameters::supported_protocols(self))
+    }
+
+    fn listened_addresses(&self) -> Box<dyn ExactSizeIterator<Item = Multiaddr> + 'a> {
+        Box::new(PollParameters::listened_addresses(self))
+    }
+
+    fn external_addresses(&self) -> Box<dyn ExactSizeIterator<Item = AddressRecord> + 'a> {
+        Box::new(PollParameters::external_addresses(self))
+    }
+
+    fn local_peer_id(&self) -> &PeerId {
+        PollParameters::local_peer_id(self)
+    }
+}
+
+impl<'a> PollParameters for Params<'a> {
+    type SupportedProtocolsIter = Box<dyn ExactSizeIterator<Item = Vec<u8>> + 'a>;
+    type ListenedAddressesIter = Box<dyn ExactSizeIterator<Item = Multiaddr> + 'a>;
+    type ExternalAddressesIter = Box<dyn ExactSizeIterator<Item = AddressRecord> + 'a>;
+
+    fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+        self.0.supported_protocols()
+    }
+
+    fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+        self.0.listened_addresses()
+    }
+
+    fn external_addresses(&self) -> Self::ExternalAddressesIter {
+        self.0.external_addresses()
+    }
+
+    fn local_peer_id(&self) -> &PeerId {
+        self.0.local_peer_id()
+    }
+}
+
+/// Peers found by a [`Discovery`].
+#[derive(Debug)]
+pub(crate) struct Discovered {
+    pub(crate) backend: &'static str,
+    pub(crate) peers: Vec<(PeerId, Multiaddr)>,
+}
+
+/// The enabled [`Discovery`] backends, none doing nothing.
+#[derive(Default)]
+pub(crate) struct Discoveries {
+    /// The peers of the loaded profile, or given by `--peer`.
+    profile: Option<StaticPeers>,
+    backends: Vec<Box<dyn Discovery>>,
+}
+
+impl Discoveries {
+    pub(crate) fn new(backends: Vec<Box<dyn Discovery>>) -> Self {
+        Self {
+            profile: None,
+            backends,
+        }
+    }
+
+    pub(crate) fn add(&mut self, backend: Box<dyn Discovery>) {
+        self.backends.push(backend);
+    }
+
+    /// Replaces the peers of the loaded profile, to be dialed right away.
+    pub(crate) fn set_profile(&mut self, peers: Vec<PeerAddr>) {
+        self.profile = Some(StaticPeers::new(peers));
+    }
+
+    /// Restarts all backends, failing with the name of the first one failing.
+    pub(crate) async fn restart(&mut self) -> Result<(), (&'static str, io::Error)> {
+        if let Some(peers) = &mut self.profile {
+            let name = peers.name();
+            peers.restart().await.map_err(|error| (name, error))?;
+        }
+        for backend in &mut self.backends {
+            let name = backend.name();
+            backend.restart().await.map_err(|error| (name, error))?;
+        }
+        Ok(())
+    }
+}
+
+impl NetworkBehaviour for Discoveries {
+    type ConnectionHandler = DummyConnectionHandler;
+    type OutEvent = Discovered;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        // Leaves keeping connections alive to the other behaviours.
+        DummyConnectionHandler {
+            keep_alive: KeepAlive::No,
+        }
+    }
+
+    fn inject_event(&mut self, _: PeerId, _: ConnectionId, event: void::Void) {
+        void::unreachable(event)
+    }
+
+    fn inject_new_listen_addr(&mut self, id: ListenerId, addr: &Multiaddr) {
+        for backend in &mut self.backends {
+            backend.listening(id, addr);
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        if self.profile.is_none() && self.backends.is_empty() {
+            return Poll::Pending;
+        }
+        let mut params = Params::new(&*params);
+        if let Some(backend) = &mut self.profile {
+            if let Poll::Ready(peers) = backend.poll(cx, &mut params) {
+                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(Discovered {
+                    backend: backend.name(),
+                    peers,
+                }));
+            }
+        }
+        for backend in &mut self.backends {
+            if let Poll::Ready(peers) = backend.poll(cx, &mut params) {
+                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(Discovered {
+                    backend: backend.name(),
+                    peers,
+                }));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Peers on the local network.
+#[cfg(feature = "mdns")]
+pub(crate) struct Mdns(libp2p::mdns::Mdns);
+
+#[cfg(feature = "mdns")]
+impl Mdns {
+    pub(crate) async fn new() -> io::Result<Self> {
+        libp2p::mdns::Mdns::new(Default::default()).await.map(Self)
+    }
+}
+
+#[cfg(feature = "mdns")]
+impl Discovery for Mdns {
+    fn name(&self) -> &'static str {
+        "mDNS"
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut Params<'_>,
+    ) -> Poll<Vec<(PeerId, Multiaddr)>> {
+        use libp2p::mdns::MdnsEvent;
+
+        loop {
+            match NetworkBehaviour::poll(&mut self.0, cx, params) {
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(MdnsEvent::Discovered(
+                    addrs,
+                ))) => return Poll::Ready(addrs.collect()),
+                // Expired peers are left to the connections' keep-alive.
+                Poll::Ready(_) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn listening(&mut self, id: ListenerId, addr: &Multiaddr) {
+        self.0.inject_new_listen_addr(id, addr);
+    }
+
+    fn restart(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            *self = Self::new().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Parsing an address ending in `/p2p/<peer id>` failed.
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid peer address {0:?}, expected e.g. /ip4/10.0.0.1/tcp/4001/p2p/<peer id>")]
+pub(crate) struct PeerAddrError(String);
+
+/// A peer and the address to dial it at, e.g. of `--peer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PeerAddr {
+    pub(crate) peer: PeerId,
+    pub(crate) addr: Multiaddr,
+}
+
+impl FromStr for PeerAddr {
+    type Err = PeerAddrError;
+
+    /// The peer id is the address' last component, `/p2p/<peer id>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PeerAddrError(s.to_string());
+        let mut addr = s.parse::<Multiaddr>().map_err(|_| err())?;
+        match addr.pop() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+                .map(|peer| Self { peer, addr })
+                .map_err(|_| err()),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/p2p/{}", self.addr, self.peer)
+    }
+}
+
+/// Peers at fixed addresses, from `--peer` and the config's `peers`, reported right away, again
+/// every [`RECONNECT_INTERVAL`] like `/reconnect` is rate limited, and on restart. Dialing them is
+/// skipped while connected.
+pub(crate) struct StaticPeers {
+    peers: Vec<(PeerId, Multiaddr)>,
+    next: Pin<Box<Sleep>>,
+}
+
+impl StaticPeers {
+    pub(crate) fn new(peers: impl IntoIterator<Item = PeerAddr>) -> Self {
+        Self {
+            peers: peers.into_iter().map(|p| (p.peer, p.addr)).collect(),
+            next: Box::pin(tokio::time::sleep_until(Instant::now())),
+        }
+    }
+}
+
+impl Discovery for StaticPeers {
+    fn name(&self) -> &'static str {
+        "static peers"
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>, _: &mut Params<'_>) -> Poll<Vec<(PeerId, Multiaddr)>> {
+        if self.peers.is_empty() {
+            return Poll::Pending;
+        }
+        if self.next.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.next
+            .as_mut()
+            .reset(Instant::now() + RECONNECT_INTERVAL);
+        Poll::Ready(self.peers.clone())
+    }
+
+    fn restart(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.next.as_mut().reset(Instant::now());
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::futures::task::noop_waker_ref;
+
+    use super::*;
+
+    /// Of a swarm not listening.
+    struct NoParams(PeerId);
+
+    impl PollParameters for NoParams {
+        type SupportedProtocolsIter = std::vec::IntoIter<Vec<u8>>;
+        type ListenedAddressesIter = std::vec::IntoIter<Multiaddr>;
+        type ExternalAddressesIter = std::vec::IntoIter<AddressRecord>;
+
+        fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+            vec![].into_iter()
+        }
+
+        fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+            vec![].into_iter()
+        }
+
+        fn external_addresses(&self) -> Self::ExternalAddressesIter {
+            vec![].into_iter()
+        }
+
+        fn local_peer_id(&self) -> &PeerId {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn parses_peer_addresses() {
+        let peer = PeerId::random();
+        let raw = format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", peer);
+        let parsed = raw.parse::<PeerAddr>().unwrap();
+        assert_eq!(
+            parsed,
+            PeerAddr {
+                peer,
+                addr: "/ip4/10.0.0.1/tcp/4001".parse().unwrap()
+            }
+        );
+        assert_eq!(parsed.to_string(), raw);
+        for invalid in [
+            "/ip4/10.0.0.1/tcp/4001",
+            "/ip4/10.0.0.1/tcp/4001/p2p/xyz",
+            "10.0.0.1",
+        ] {
+            assert!(invalid.parse::<PeerAddr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn static_peers_are_reported_periodically() {
+        let peer = PeerAddr {
+            peer: PeerId::random(),
+            addr: "/ip4/10.0.0.1/tcp/4001".parse().unwrap(),
+        };
+        let mut discoveries = Discoveries::default();
+        discoveries.add(Box::new(StaticPeers::new(vec![])));
+        discoveries.add(Box::new(StaticPeers::new(vec![peer.clone()])));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let no_params = NoParams(PeerId::random());
+        let mut poll = |discoveries: &mut Discoveries| {
+            let mut params = Params::new(&no_params);
+            discoveries.backends.iter_mut().find_map(|backend| {
+                match backend.poll(&mut cx, &mut params) {
+                    Poll::Ready(peers) => Some((backend.name(), peers)),
+                    Poll::Pending => None,
+                }
+            })
+        };
+        let reported = Some(("static peers", vec![(peer.peer, peer.addr)]));
+
+        assert_eq!(poll(&mut discoveries), reported);
+        assert_eq!(poll(&mut discoveries), None);
+        discoveries.restart().await.unwrap();
+        assert_eq!(poll(&mut discoveries), reported);
+        tokio::time::advance(RECONNECT_INTERVAL / 2).await;
+        assert_eq!(poll(&mut discoveries), None);
+        tokio::time::advance(RECONNECT_INTERVAL / 2).await;
+        assert_eq!(poll(&mut discoveries), reported);
+    }
+}
@@ -0,0 +1,917 @@
+//! Rendering of received messages in the terminal.
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    io::{self, Write as _},
+    sync::{
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local, TimeZone, Utc};
+use libp2p::PeerId;
+
+use crate::shutdown::Tasks;
+
+/// Narrower terminals, after the indent, aren't wrapped.
+const MIN_COLUMNS: usize = 16;
+/// ANSI escapes starting and ending dimmed text.
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+/// How long received messages are held to be rendered in order, unless `--no-reorder`.
+pub(crate) const REORDER_WINDOW: Duration = Duration::from_millis(250);
+/// Messages held at most, the oldest being released early beyond.
+const MAX_HELD: usize = 256;
+/// Of [`DisplayTime`]s, with the offset so times remain comparable whatever the zone.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S %:z";
+
+/// Whether [`DisplayTime`]s are shown in UTC rather than local time (`--utc-times`), set once on
+/// startup.
+static UTC_TIMES: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_utc_times(utc: bool) {
+    UTC_TIMES.store(utc, Ordering::Relaxed);
+}
+
+/// How [`DisplayTime`]s are shown (`--time-style`), set once on startup.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimeStyle {
+    Absolute,
+    /// How long ago, e.g. "3m ago", as of rendering.
+    Relative,
+    Both,
+}
+
+static TIME_STYLE: AtomicU8 = AtomicU8::new(TimeStyle::Absolute as u8);
+
+pub(crate) fn set_time_style(style: TimeStyle) {
+    TIME_STYLE.store(style as u8, Ordering::Relaxed);
+}
+
+fn time_style() -> TimeStyle {
+    match TIME_STYLE.load(Ordering::Relaxed) {
+        1 => TimeStyle::Relative,
+        2 => TimeStyle::Both,
+        _ => TimeStyle::Absolute,
+    }
+}
+
+/// How long before `now` `time` was, coarsely. Times ahead by more than a second, i.e. skewed
+/// clocks, are "in the future".
+pub(crate) fn relative(time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let age = now.signed_duration_since(time);
+    match age.num_seconds() {
+        secs if secs < -1 => "in the future".into(),
+        secs if secs < 10 => "just now".into(),
+        secs if secs < 60 => format!("{}s ago", secs),
+        secs if secs < 60 * 60 => format!("{}m ago", age.num_minutes()),
+        secs if secs < 24 * 60 * 60 => format!("{}h ago", age.num_hours()),
+        _ => format!("{}d ago", age.num_days()),
+    }
+}
+
+/// A time as rendered, of remote messages and local events alike: kept in UTC, shown in local
+/// time, or UTC with `--utc-times`, in a single format, and/or relative to when it's rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct DisplayTime(DateTime<Utc>);
+
+impl DisplayTime {
+    pub(crate) fn now() -> Self {
+        Self(Utc::now())
+    }
+
+    fn format_in<Tz: TimeZone>(&self, zone: &Tz) -> String
+    where
+        Tz::Offset: fmt::Display,
+    {
+        self.0.with_timezone(zone).format(TIME_FORMAT).to_string()
+    }
+}
+
+impl From<DateTime<Utc>> for DisplayTime {
+    fn from(time: DateTime<Utc>) -> Self {
+        Self(time)
+    }
+}
+
+impl fmt::Display for DisplayTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let absolute = || match UTC_TIMES.load(Ordering::Relaxed) {
+            true => self.format_in(&Utc),
+            false => self.format_in(&Local),
+        };
+        match time_style() {
+            TimeStyle::Absolute => f.write_str(&absolute()),
+            TimeStyle::Relative => f.write_str(&relative(self.0, Utc::now())),
+            TimeStyle::Both => write!(f, "{} ({})", absolute(), relative(self.0, Utc::now())),
+        }
+    }
+}
+
+/// How current a received message is, by the time it was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Freshness {
+    Live,
+    /// Older than [`Staleness::window`], rendered dimmed and marked "(delayed)".
+    Delayed,
+    /// Older than [`Staleness::cap`], not rendered live at all.
+    Stale,
+}
+
+/// Ages beyond which messages are rendered as delayed (`--delayed-after-mins`), or not at all
+/// (`--stale-after-hours`). Messages replayed or flushed after hours offline would otherwise
+/// look like live chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Staleness {
+    pub(crate) window: chrono::Duration,
+    pub(crate) cap: chrono::Duration,
+}
+
+impl Default for Staleness {
+    fn default() -> Self {
+        Self {
+            window: chrono::Duration::minutes(10),
+            cap: chrono::Duration::hours(24),
+        }
+    }
+}
+
+impl Staleness {
+    /// Classifies a message sent at `sent`. Ones from the future (clock skew) are live.
+    pub(crate) fn classify(&self, sent: DateTime<Utc>, now: DateTime<Utc>) -> Freshness {
+        let age = now.signed_duration_since(sent);
+        if age > self.cap {
+            Freshness::Stale
+        } else if age > self.window {
+            Freshness::Delayed
+        } else {
+            Freshness::Live
+        }
+    }
+}
+
+/// Holds messages for up to `window` after their receipt, releasing them ordered by key, so
+/// ones overtaking each other on different paths are rendered in order.
+///
+/// The window counts from the receipt, not the key's timestamp, so no message is delayed longer
+/// than it, whatever the senders' clocks. Messages ordered before one already released are late
+/// and not held at all.
+#[derive(Debug)]
+pub(crate) struct Reorder<K, T> {
+    window: Duration,
+    /// With the time they're due.
+    held: BTreeMap<K, (Instant, T)>,
+    released: Option<K>,
+}
+
+impl<K: Ord + Clone, T> Reorder<K, T> {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            held: BTreeMap::new(),
+            released: None,
+        }
+    }
+
+    /// Holds `item`, or returns it if it's late.
+    pub(crate) fn push(&mut self, key: K, item: T, now: Instant) -> Option<T> {
+        if self
+            .released
+            .as_ref()
+            .map_or(false, |released| key < *released)
+        {
+            return Some(item);
+        }
+        self.held.insert(key, (now + self.window, item));
+        None
+    }
+
+    /// The items due by `now`, in order: those held for the window, any ordered before them, and
+    /// the oldest beyond [`MAX_HELD`].
+    pub(crate) fn release(&mut self, now: Instant) -> Vec<T> {
+        let mut released = match self
+            .held
+            .iter()
+            .filter(|(_, (due, _))| *due <= now)
+            .map(|(key, _)| key)
+            .max()
+            .cloned()
+        {
+            Some(last) => {
+                let later = self.held.split_off(&last);
+                let mut due = std::mem::replace(&mut self.held, later);
+                let item = self.held.remove(&last).expect("split at an existing key");
+                due.insert(last, item);
+                due.into_iter().collect::<Vec<_>>()
+            }
+            None => vec![],
+        };
+        while self.held.len() > MAX_HELD {
+            let first = self.held.keys().next().cloned().expect("not empty");
+            let item = self.held.remove(&first).expect("existing key");
+            released.push((first, item));
+        }
+        if let Some((last, _)) = released.last() {
+            self.released = Some(last.clone());
+        }
+        released.into_iter().map(|(_, (_, item))| item).collect()
+    }
+
+    /// When the next item is due.
+    pub(crate) fn next_release(&self) -> Option<Instant> {
+        self.held.values().map(|(due, _)| *due).min()
+    }
+
+    /// All held items, in order.
+    pub(crate) fn flush(&mut self) -> Vec<T> {
+        if let Some(last) = self.held.keys().next_back() {
+            self.released = Some(last.clone());
+        }
+        std::mem::take(&mut self.held)
+            .into_values()
+            .map(|(_, item)| item)
+            .collect()
+    }
+}
+
+/// A channel message to render, sorted by origin timestamp and author, the sequence number
+/// telling apart messages otherwise equal.
+type Key = (DateTime<Utc>, PeerId, u64);
+
+#[derive(Debug)]
+struct Line {
+    sent: DisplayTime,
+    from: String,
+    message: String,
+    freshness: Freshness,
+    width: Option<usize>,
+    alert: Option<Shown>,
+}
+
+/// A message to alert of once it's written, see [`Renderer::take_shown`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Shown {
+    pub(crate) peer: PeerId,
+    pub(crate) nick: String,
+    pub(crate) channel: String,
+    pub(crate) text: String,
+}
+
+impl Line {
+    /// Marked and dimmed if delayed, marked if late. Appends to `out`, with a newline.
+    fn render(&self, late: bool, out: &mut String) {
+        let dimmed = self.freshness == Freshness::Delayed && self.width.is_some();
+        if dimmed {
+            out.push_str(DIM);
+        }
+        let start = out.len();
+        if self.freshness == Freshness::Delayed {
+            out.push_str("(delayed) ");
+        }
+        if late {
+            out.push_str("(late) ");
+        }
+        let _ = write!(out, "{} ", self.sent);
+        let indent = out[start..].chars().count();
+        let _ = write!(out, "{}: ", self.from);
+        let column = out[start..].chars().count();
+        wrap_into(out, column, &self.message, indent, self.width);
+        if dimmed {
+            out.push_str(RESET);
+        }
+        out.push('\n');
+    }
+}
+
+/// Where a [`Renderer`] writes lines to.
+enum Output {
+    Stdout,
+    /// Writing failed, e.g. stdout is a closed pipe, so lines are dropped.
+    Off,
+    Writer(Box<dyn io::Write + Send>),
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::Stdout
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Output::Stdout => "Stdout",
+            Output::Off => "Off",
+            Output::Writer(_) => "Writer",
+        })
+    }
+}
+
+/// Keeps what's written, to be inspected by tests.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Captured {
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+#[cfg(test)]
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fails writing every time, like stdout when it's a closed pipe.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub(crate) struct BrokenPipe(Arc<AtomicUsize>);
+
+#[cfg(test)]
+impl BrokenPipe {
+    /// The writes attempted.
+    pub(crate) fn attempts(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl io::Write for BrokenPipe {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    /// Writes `line`, rendered into the reused `out`. Switches off on the first failure, which is
+    /// returned, rather than failing on every line.
+    fn print(&mut self, out: &mut String, line: &Line, late: bool) -> io::Result<()> {
+        out.clear();
+        line.render(late, out);
+        self.write(out)
+    }
+
+    /// Writes `out` as is, switching off on the first failure like [`Output::print`].
+    fn write(&mut self, out: &str) -> io::Result<()> {
+        let written = match self {
+            Output::Stdout => io::stdout().lock().write_all(out.as_bytes()),
+            Output::Off => return Ok(()),
+            Output::Writer(writer) => writer.write_all(out.as_bytes()),
+        };
+        if written.is_err() {
+            *self = Output::Off;
+        }
+        written
+    }
+}
+
+/// Renders channel messages: in order if reordering (see [`Reorder`]), dimmed if delayed, and
+/// not at all if stale.
+#[derive(Debug, Default)]
+pub(crate) struct Renderer {
+    staleness: Staleness,
+    reorder: Option<Reorder<Key, Line>>,
+    seq: u64,
+    /// Lines are rendered into, to not allocate for each.
+    out: String,
+    output: Output,
+    /// Written since [`Renderer::take_shown`], to alert of.
+    shown: Vec<Shown>,
+}
+
+impl Renderer {
+    /// Reordering within `reorder`, if any.
+    pub(crate) fn new(staleness: Staleness, reorder: Option<Duration>) -> Self {
+        Self {
+            staleness,
+            reorder: reorder.map(Reorder::new),
+            seq: 0,
+            out: String::new(),
+            output: Output::Stdout,
+            shown: vec![],
+        }
+    }
+
+    /// Writes to `writer` rather than stdout, in tests and benchmarks.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_writer(self, writer: impl io::Write + Send + 'static) -> Self {
+        Self {
+            output: Output::Writer(Box::new(writer)),
+            ..self
+        }
+    }
+
+    /// Renders, or holds, the message `from` sent at `sent`, ordered by `origin` and `peer`.
+    /// An `origin` beyond the reordering window from now counts as its end, so a sender's clock
+    /// ahead can't have all later messages treated as late. Returns `false`, rendering nothing,
+    /// if it's stale. Fails if writing failed, after which nothing is written anymore. `alert` is
+    /// returned by [`Renderer::take_shown`] once the message is written, rather than held.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn message(
+        &mut self,
+        peer: PeerId,
+        origin: DateTime<Utc>,
+        sent: DateTime<Utc>,
+        from: &str,
+        message: &str,
+        width: Option<usize>,
+        now: Instant,
+        alert: Option<Shown>,
+    ) -> io::Result<bool> {
+        let received = Utc::now();
+        let freshness = self.staleness.classify(sent, received);
+        if freshness == Freshness::Stale {
+            return Ok(false);
+        }
+        let line = Line {
+            sent: sent.into(),
+            from: from.into(),
+            message: message.into(),
+            freshness,
+            width,
+            alert,
+        };
+        let reorder = match &mut self.reorder {
+            Some(reorder) => reorder,
+            None => {
+                self.show(line, false)?;
+                return Ok(true);
+            }
+        };
+        self.seq += 1;
+        let latest =
+            chrono::Duration::from_std(reorder.window).map_or(origin, |window| received + window);
+        if let Some(line) = reorder.push((origin.min(latest), peer, self.seq), line, now) {
+            self.show(line, true)?;
+        }
+        // Beyond the bound, the oldest are released right away.
+        self.release(now)?;
+        Ok(true)
+    }
+
+    /// Writes a line other than a message, e.g. a notice, right away. Fails like
+    /// [`Renderer::message`].
+    pub(crate) fn line(&mut self, line: fmt::Arguments<'_>) -> io::Result<()> {
+        self.out.clear();
+        let _ = self.out.write_fmt(line);
+        self.out.push('\n');
+        self.output.write(&self.out)
+    }
+
+    /// Renders the messages held long enough.
+    pub(crate) fn release(&mut self, now: Instant) -> io::Result<()> {
+        let released = self
+            .reorder
+            .as_mut()
+            .map(|reorder| reorder.release(now))
+            .unwrap_or_default();
+        for line in released {
+            self.show(line, false)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn next_release(&self) -> Option<Instant> {
+        self.reorder.as_ref().and_then(Reorder::next_release)
+    }
+
+    /// Renders all held messages, e.g. on exit.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        let held = self
+            .reorder
+            .as_mut()
+            .map(Reorder::flush)
+            .unwrap_or_default();
+        for line in held {
+            self.show(line, false)?;
+        }
+        Ok(())
+    }
+
+    /// The messages written since the last call, of those passed with an `alert`.
+    pub(crate) fn take_shown(&mut self) -> Vec<Shown> {
+        std::mem::take(&mut self.shown)
+    }
+
+    fn show(&mut self, line: Line, late: bool) -> io::Result<()> {
+        self.output.print(&mut self.out, &line, late)?;
+        if !matches!(self.output, Output::Off) {
+            self.shown.extend(line.alert);
+        }
+        Ok(())
+    }
+}
+
+/// Dims `text` on terminals.
+pub(crate) fn dim(text: &str, terminal: bool) -> String {
+    match terminal {
+        true => format!("{}{}{}", DIM, text, RESET),
+        false => text.into(),
+    }
+}
+
+/// Width of the terminal on stdout, kept up to date on resizes.
+pub(crate) struct TerminalWidth(Arc<AtomicUsize>);
+
+fn detect() -> usize {
+    terminal_size::terminal_size().map_or(0, |(terminal_size::Width(w), _)| w.into())
+}
+
+impl TerminalWidth {
+    pub(crate) fn detect(tasks: &mut Tasks) -> Self {
+        let width = Arc::new(AtomicUsize::new(detect()));
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::window_change()) {
+                Ok(mut sigwinch) => {
+                    let width = width.clone();
+                    tasks.spawn(|shutdown| async move {
+                        loop {
+                            tokio::select! {
+                                _ = shutdown.wait() => break,
+                                received = sigwinch.recv() => match received {
+                                    Some(()) => width.store(detect(), Ordering::Relaxed),
+                                    None => break,
+                                },
+                            }
+                        }
+                    });
+                }
+                Err(error) => tracing::debug!(%error, "Can't listen for SIGWINCH"),
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = tasks;
+        Self(width)
+    }
+
+    /// `None` if stdout isn't a terminal.
+    pub(crate) fn get(&self) -> Option<usize> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            width => Some(width),
+        }
+    }
+}
+
+/// Soft-wraps `prefix` followed by `text` to `width` columns, indenting continuation lines by
+/// `indent`. Words longer than a line are broken. Without a (usable) width, `text` is returned
+/// as is.
+pub(crate) fn wrap(prefix: &str, text: &str, indent: usize, width: Option<usize>) -> String {
+    let mut out = String::with_capacity(prefix.len() + text.len());
+    out.push_str(prefix);
+    wrap_into(&mut out, prefix.chars().count(), text, indent, width);
+    out
+}
+
+/// Like [`wrap`], appending `text` to `out`, whose last line is `column` wide so far.
+fn wrap_into(out: &mut String, mut column: usize, text: &str, indent: usize, width: Option<usize>) {
+    let width = match width {
+        Some(width) if width >= indent + MIN_COLUMNS => width,
+        _ => {
+            out.push_str(text);
+            return;
+        }
+    };
+    let newline = |out: &mut String| {
+        out.push('\n');
+        out.extend(std::iter::repeat(' ').take(indent));
+    };
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            newline(out);
+            column = indent;
+        }
+        let mut needs_space = false;
+        for word in line.split_whitespace() {
+            let len = word.chars().count();
+            if column + usize::from(needs_space) + len > width && column > indent {
+                newline(out);
+                column = indent;
+            } else if needs_space {
+                out.push(' ');
+                column += 1;
+            }
+            let mut rest = word;
+            while column + rest.chars().count() > width {
+                let split = rest
+                    .char_indices()
+                    .nth(width - column)
+                    .map_or(rest.len(), |(i, _)| i);
+                out.push_str(&rest[..split]);
+                newline(out);
+                column = indent;
+                rest = &rest[split..];
+            }
+            out.push_str(rest);
+            column += rest.chars().count();
+            needs_space = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "the quick brown fox jumps over the lazy dog";
+
+    #[test]
+    fn no_width_no_wrap() {
+        assert_eq!(
+            wrap("12:00 bob: ", TEXT, 6, None),
+            format!("12:00 bob: {}", TEXT)
+        );
+        // Too narrow to be worth it.
+        assert_eq!(
+            wrap("12:00 bob: ", TEXT, 6, Some(20)),
+            format!("12:00 bob: {}", TEXT)
+        );
+    }
+
+    #[test]
+    fn hanging_indent() {
+        assert_eq!(
+            wrap("12:00 bob: ", TEXT, 6, Some(24)),
+            "12:00 bob: the quick\n      brown fox jumps\n      over the lazy dog"
+        );
+    }
+
+    #[test]
+    fn keeps_line_breaks_and_breaks_long_words() {
+        assert_eq!(
+            wrap("> ", "abcdefghijklmnopqrstuvwxyz\nok ñññ", 2, Some(20)),
+            "> abcdefghijklmnopqr\n  stuvwxyz\n  ok ñññ"
+        );
+    }
+
+    #[test]
+    fn lines_fit() {
+        for width in 22..60 {
+            let wrapped = wrap("12:00 bob: ", &TEXT.repeat(3), 6, Some(width));
+            assert!(wrapped.lines().all(|l| l.chars().count() <= width));
+            assert_eq!(
+                wrapped.split_whitespace().collect::<Vec<_>>()[2..],
+                TEXT.repeat(3).split_whitespace().collect::<Vec<_>>()[..]
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_freshness_at_the_boundaries() {
+        let staleness = Staleness::default();
+        let now = Utc.timestamp_millis_opt(1_700_000_000_000).unwrap();
+        let ms = chrono::Duration::milliseconds;
+        let cases = [
+            (now + ms(1), Freshness::Live),
+            (now, Freshness::Live),
+            (now - staleness.window, Freshness::Live),
+            (now - staleness.window - ms(1), Freshness::Delayed),
+            (now - staleness.cap, Freshness::Delayed),
+            (now - staleness.cap - ms(1), Freshness::Stale),
+            (Utc.timestamp_millis_opt(0).unwrap(), Freshness::Stale),
+        ];
+        for (sent, freshness) in cases {
+            assert_eq!(staleness.classify(sent, now), freshness, "{}", sent);
+        }
+
+        // Without a window, anything older than now is delayed.
+        let strict = Staleness {
+            window: chrono::Duration::zero(),
+            ..staleness
+        };
+        assert_eq!(strict.classify(now, now), Freshness::Live);
+        assert_eq!(strict.classify(now - ms(1), now), Freshness::Delayed);
+    }
+
+    #[test]
+    fn dims_on_terminals_only() {
+        assert_eq!(dim("late", false), "late");
+        assert_eq!(dim("late", true), "\x1b[2mlate\x1b[0m");
+    }
+
+    #[test]
+    fn releases_in_order_within_the_window() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut reorder = Reorder::new(REORDER_WINDOW);
+        assert_eq!(reorder.push(3, "c", start), None);
+        assert_eq!(reorder.push(1, "a", start + ms(100)), None);
+        assert_eq!(reorder.push(5, "e", start + ms(200)), None);
+        assert_eq!(reorder.next_release(), Some(start + REORDER_WINDOW));
+        assert!(reorder.release(start + ms(249)).is_empty());
+        // "c" is due, and "a", received later, is ordered before it.
+        assert_eq!(reorder.release(start + ms(250)), ["a", "c"]);
+        assert_eq!(reorder.next_release(), Some(start + ms(450)));
+
+        // Ordered before what's been released: rendered right away.
+        assert_eq!(reorder.push(2, "b", start + ms(300)), Some("b"));
+        assert_eq!(reorder.push(4, "d", start + ms(300)), None);
+        assert_eq!(reorder.release(start + ms(450)), ["d", "e"]);
+        assert_eq!(reorder.next_release(), None);
+    }
+
+    #[test]
+    fn delays_no_longer_than_the_window_whatever_the_keys() {
+        let start = Instant::now();
+        let mut reorder = Reorder::new(REORDER_WINDOW);
+        // A sender's clock far ahead doesn't hold back its message.
+        reorder.push(i64::MAX, "future", start);
+        reorder.push(0, "now", start);
+        assert_eq!(reorder.release(start + REORDER_WINDOW), ["now", "future"]);
+    }
+
+    #[test]
+    fn holds_a_bounded_number() {
+        let now = Instant::now();
+        let mut reorder = Reorder::new(REORDER_WINDOW);
+        for key in (0..MAX_HELD + 2).rev() {
+            reorder.push(key, key, now);
+        }
+        assert_eq!(reorder.release(now), [0, 1]);
+        assert_eq!(reorder.held.len(), MAX_HELD);
+        assert_eq!(reorder.push(0, 0, now), Some(0));
+        assert_eq!(reorder.flush(), (2..MAX_HELD + 2).collect::<Vec<_>>());
+        assert_eq!(reorder.push(MAX_HELD, 0, now), Some(0));
+    }
+
+    #[test]
+    fn times_in_one_format() {
+        let time = DisplayTime::from(Utc.timestamp(1_600_000_000, 0));
+        assert_eq!(time.format_in(&Utc), "2020-09-13 12:26:40 +00:00");
+        let offset = chrono::FixedOffset::east(2 * 3600);
+        assert_eq!(time.format_in(&offset), "2020-09-13 14:26:40 +02:00");
+        let offset = chrono::FixedOffset::west(5 * 3600 + 1800);
+        assert_eq!(time.format_in(&offset), "2020-09-13 06:56:40 -05:30");
+        // Local event times and remote timestamps alike.
+        let now = DisplayTime::now().format_in(&offset);
+        assert_eq!(now.len(), "2020-09-13 06:56:40 -05:30".len());
+        assert!(now.ends_with(" -05:30"));
+    }
+
+    #[test]
+    fn relative_times() {
+        let now = Utc.timestamp(1_600_000_000, 0);
+        let ago = |secs| relative(now - chrono::Duration::seconds(secs), now);
+        assert_eq!(ago(0), "just now");
+        assert_eq!(ago(9), "just now");
+        assert_eq!(ago(10), "10s ago");
+        assert_eq!(ago(59), "59s ago");
+        assert_eq!(ago(60), "1m ago");
+        assert_eq!(ago(3 * 60 + 59), "3m ago");
+        assert_eq!(ago(2 * 60 * 60), "2h ago");
+        assert_eq!(ago(3 * 24 * 60 * 60), "3d ago");
+        // Skewed clocks.
+        assert_eq!(ago(-1), "just now");
+        assert_eq!(ago(-2), "in the future");
+        assert_eq!(ago(-3600), "in the future");
+    }
+
+    #[test]
+    fn renders_lines_as_wrapped() {
+        let sent = DisplayTime::from(Utc.timestamp(1_600_000_000, 0));
+        let mut line = Line {
+            sent,
+            from: "bob".into(),
+            message: TEXT.into(),
+            freshness: Freshness::Live,
+            width: Some(40),
+            alert: None,
+        };
+        let mut out = String::from("reused");
+        out.clear();
+        line.render(false, &mut out);
+        let prefix = format!("{} ", sent);
+        let wrapped = wrap(
+            &format!("{}bob: ", prefix),
+            TEXT,
+            prefix.chars().count(),
+            Some(40),
+        );
+        assert_eq!(out, format!("{}\n", wrapped));
+
+        line.freshness = Freshness::Delayed;
+        out.clear();
+        line.render(true, &mut out);
+        let prefix = format!("(delayed) (late) {} ", sent);
+        let wrapped = wrap(
+            &format!("{}bob: ", prefix),
+            TEXT,
+            prefix.chars().count(),
+            Some(40),
+        );
+        assert_eq!(out, format!("{}\n", dim(&wrapped, true)));
+    }
+    #[test]
+    fn stops_writing_once_writing_failed() {
+        let pipe = BrokenPipe::default();
+        let mut renderer = Renderer::new(Default::default(), None).with_writer(pipe.clone());
+        let now = Instant::now();
+        let mut message = || {
+            renderer.message(
+                PeerId::random(),
+                Utc::now(),
+                Utc::now(),
+                "bob",
+                TEXT,
+                None,
+                now,
+                None,
+            )
+        };
+        assert_eq!(message().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert!(message().unwrap());
+        assert_eq!(pipe.attempts(), 1);
+    }
+
+    #[test]
+    fn senders_ahead_dont_make_later_messages_late() {
+        let captured = Captured::default();
+        let mut renderer =
+            Renderer::new(Default::default(), Some(REORDER_WINDOW)).with_writer(captured.clone());
+        let (peer, start) = (PeerId::random(), Instant::now());
+        let mut message = |ahead: i64, text: &str, now: Instant| {
+            let origin = Utc::now() + chrono::Duration::seconds(ahead);
+            renderer
+                .message(peer, origin, Utc::now(), "bob", text, None, now, None)
+                .unwrap();
+        };
+        message(3600, "ahead", start);
+        message(2, "second", start + REORDER_WINDOW);
+        message(1, "first", start + REORDER_WINDOW);
+        renderer.release(start + REORDER_WINDOW * 2).unwrap();
+
+        let lines = captured.text();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("bob: ahead"), "{:?}", lines);
+        assert!(lines[1].ends_with("bob: first"), "{:?}", lines);
+        assert!(lines[2].ends_with("bob: second"), "{:?}", lines);
+        assert!(lines.iter().all(|line| !line.contains("(late)")));
+    }
+
+    #[test]
+    fn writes_lines_through_the_same_output() {
+        let pipe = BrokenPipe::default();
+        let mut renderer = Renderer::new(Default::default(), None).with_writer(pipe.clone());
+        let error = renderer.line(format_args!("{} connected.", "bob"));
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        renderer.line(format_args!("{} left.", "bob")).unwrap();
+        assert_eq!(pipe.attempts(), 1);
+
+        let captured = Captured::default();
+        let mut renderer = Renderer::new(Default::default(), None).with_writer(captured.clone());
+        renderer.line(format_args!("{} connected.", "bob")).unwrap();
+        assert_eq!(captured.text(), "bob connected.\n");
+    }
+
+    #[test]
+    fn alerts_once_written() {
+        let mut renderer = Renderer::new(Default::default(), Some(REORDER_WINDOW))
+            .with_writer(Captured::default());
+        let (peer, now) = (PeerId::random(), Instant::now());
+        let shown = Shown {
+            peer,
+            nick: "bob".into(),
+            channel: "agora".into(),
+            text: TEXT.into(),
+        };
+        assert!(renderer
+            .message(
+                peer,
+                Utc::now(),
+                Utc::now(),
+                "bob",
+                TEXT,
+                None,
+                now,
+                Some(shown.clone())
+            )
+            .unwrap());
+        renderer
+            .message(peer, Utc::now(), Utc::now(), "bob", TEXT, None, now, None)
+            .unwrap();
+        // Held to be reordered.
+        assert!(renderer.take_shown().is_empty());
+        renderer.release(now + REORDER_WINDOW).unwrap();
+        assert_eq!(renderer.take_shown(), [shown]);
+        assert!(renderer.take_shown().is_empty());
+    }
+}
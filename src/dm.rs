@@ -0,0 +1,384 @@
+//! End-to-end encryption of direct messages: sealed to the recipient's identity and signed by the
+//! sender's, so peers relaying them can neither read nor alter them.
+//!
+//! Ed25519 identities are converted to X25519 keys. The sender does an ECDH between a fresh
+//! ephemeral key and the recipient's key ("sealed box"):
+//!
+//! ```text
+//! sealed     = ephemeral public key (32) | ChaCha20-Poly1305(key, nonce 0, aad = recipient peer id, plaintext)
+//! key        = SHA-256("agora-dm-v1" | shared secret | ephemeral public key | recipient public key)
+//! plaintext  = signature (64) | origin timestamp (ms, i64 BE) | message
+//! signed     = "agora-dm-v1" | recipient peer id | origin timestamp (ms, i64 BE) | message
+//! ```
+//!
+//! Every message has its own key, so the fixed nonce is never reused.
+//!
+//! The first direct message of an unknown peer is answered with a challenge (see
+//! `dm_requests.rs`), which the sender signs with its identity key:
+//!
+//! ```text
+//! signed     = "agora-dm-challenge-v1" | challenger peer id | nonce
+//! ```
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use curve25519_dalek::{
+    constants::X25519_BASEPOINT, edwards::CompressedEdwardsY, montgomery::MontgomeryPoint,
+    scalar::Scalar,
+};
+use libp2p::{
+    identity::{ed25519, Keypair, PublicKey},
+    PeerId,
+};
+use sha2::{Digest, Sha256, Sha512};
+
+const DOMAIN: &[u8] = b"agora-dm-v1";
+const CHALLENGE_DOMAIN: &[u8] = b"agora-dm-challenge-v1";
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+/// Multihash prefix of peer ids inlining their public key (identity hash).
+const IDENTITY_HASH: u8 = 0x00;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DmError {
+    #[error("cannot encrypt to this peer, its identity isn't an Ed25519 key")]
+    UnsupportedPeer,
+    #[error("direct messages need an Ed25519 identity")]
+    UnsupportedIdentity,
+    #[error("malformed message")]
+    Malformed,
+    #[error("decryption failed")]
+    Decryption,
+    #[error("the sender's signature is invalid")]
+    BadSignature,
+}
+
+/// The Ed25519 key inlined in `peer`.
+fn ed25519_key(peer: &PeerId) -> Result<ed25519::PublicKey, DmError> {
+    let bytes = peer.to_bytes();
+    let key = match bytes.as_slice() {
+        [IDENTITY_HASH, len, key @ ..] if usize::from(*len) == key.len() => {
+            PublicKey::from_protobuf_encoding(key).map_err(|_| DmError::UnsupportedPeer)?
+        }
+        _ => return Err(DmError::UnsupportedPeer),
+    };
+    #[allow(unreachable_patterns)] // Other key types are behind libp2p features.
+    match key {
+        PublicKey::Ed25519(key) => Ok(key),
+        _ => Err(DmError::UnsupportedPeer),
+    }
+}
+
+fn clamp(mut bytes: [u8; KEY_LEN]) -> Scalar {
+    bytes[0] &= 248;
+    bytes[31] &= 127;
+    bytes[31] |= 64;
+    Scalar::from_bits(bytes)
+}
+
+/// The X25519 public key corresponding to an Ed25519 one.
+fn x25519_public(key: &ed25519::PublicKey) -> Result<MontgomeryPoint, DmError> {
+    let point = CompressedEdwardsY(key.encode())
+        .decompress()
+        .filter(|p| !p.is_small_order())
+        .ok_or(DmError::UnsupportedPeer)?;
+    Ok(point.to_montgomery())
+}
+
+fn ed25519_keypair(keypair: &Keypair) -> Result<&ed25519::Keypair, DmError> {
+    #[allow(unreachable_patterns)]
+    match keypair {
+        Keypair::Ed25519(keypair) => Ok(keypair),
+        _ => Err(DmError::UnsupportedIdentity),
+    }
+}
+
+/// The X25519 secret corresponding to an Ed25519 one, as in RFC 8032 key generation.
+fn x25519_secret(keypair: &Keypair) -> Result<Scalar, DmError> {
+    let keypair = ed25519_keypair(keypair)?;
+    let hash = Sha512::digest(keypair.secret().as_ref());
+    Ok(clamp(hash[..KEY_LEN].try_into().expect("32 bytes")))
+}
+
+fn cipher(
+    shared: MontgomeryPoint,
+    ephemeral: &[u8],
+    recipient: &MontgomeryPoint,
+) -> Result<ChaCha20Poly1305, DmError> {
+    // A low order point yields an all zero secret.
+    if shared.as_bytes() == &[0; KEY_LEN] {
+        return Err(DmError::Malformed);
+    }
+    let key = Sha256::new()
+        .chain_update(DOMAIN)
+        .chain_update(shared.as_bytes())
+        .chain_update(ephemeral)
+        .chain_update(recipient.as_bytes())
+        .finalize();
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn signed_bytes(to: &PeerId, origin_timestamp: DateTime<Utc>, message: &str) -> Vec<u8> {
+    [
+        DOMAIN,
+        &to.to_bytes(),
+        &origin_timestamp.timestamp_millis().to_be_bytes(),
+        message.as_bytes(),
+    ]
+    .concat()
+}
+
+/// Seals `message` to `to`, signed with `keypair`.
+pub(crate) fn seal(
+    keypair: &Keypair,
+    to: &PeerId,
+    origin_timestamp: DateTime<Utc>,
+    message: &str,
+) -> Result<Vec<u8>, DmError> {
+    let mut ephemeral = [0; KEY_LEN];
+    OsRng.fill_bytes(&mut ephemeral);
+    seal_with(keypair, to, clamp(ephemeral), origin_timestamp, message)
+}
+
+fn seal_with(
+    keypair: &Keypair,
+    to: &PeerId,
+    ephemeral: Scalar,
+    origin_timestamp: DateTime<Utc>,
+    message: &str,
+) -> Result<Vec<u8>, DmError> {
+    let recipient = x25519_public(&ed25519_key(to)?)?;
+    let ephemeral_public = X25519_BASEPOINT * ephemeral;
+    let cipher = cipher(
+        recipient * ephemeral,
+        ephemeral_public.as_bytes(),
+        &recipient,
+    )?;
+    let signature = ed25519_keypair(keypair)?.sign(&signed_bytes(to, origin_timestamp, message));
+    let plaintext = [
+        &signature[..],
+        &origin_timestamp.timestamp_millis().to_be_bytes(),
+        message.as_bytes(),
+    ]
+    .concat();
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&[0; 12]),
+            Payload {
+                msg: &plaintext,
+                aad: &to.to_bytes(),
+            },
+        )
+        .expect("Encryption works");
+    Ok([ephemeral_public.as_bytes(), &ciphertext[..]].concat())
+}
+
+/// Opens a message sealed by `from` to the owner of `keypair`, verifying `from`'s signature.
+pub(crate) fn open(
+    keypair: &Keypair,
+    from: &PeerId,
+    sealed: &[u8],
+) -> Result<(DateTime<Utc>, String), DmError> {
+    if sealed.len() < KEY_LEN {
+        return Err(DmError::Malformed);
+    }
+    let (ephemeral_public, ciphertext) = sealed.split_at(KEY_LEN);
+    let ephemeral_public = MontgomeryPoint(ephemeral_public.try_into().expect("32 bytes"));
+    let secret = x25519_secret(keypair)?;
+    let own_peer_id = PeerId::from(keypair.public());
+    let own_public = X25519_BASEPOINT * secret;
+    let cipher = cipher(
+        ephemeral_public * secret,
+        ephemeral_public.as_bytes(),
+        &own_public,
+    )?;
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(&[0; 12]),
+            Payload {
+                msg: ciphertext,
+                aad: &own_peer_id.to_bytes(),
+            },
+        )
+        .map_err(|_| DmError::Decryption)?;
+
+    if plaintext.len() < SIGNATURE_LEN + 8 {
+        return Err(DmError::Malformed);
+    }
+    let (signature, rest) = plaintext.split_at(SIGNATURE_LEN);
+    let (millis, message) = rest.split_at(8);
+    let millis = i64::from_be_bytes(millis.try_into().expect("8 bytes"));
+    let origin_timestamp = Utc
+        .timestamp_millis_opt(millis)
+        .single()
+        .ok_or(DmError::Malformed)?;
+    let message = String::from_utf8(message.to_vec()).map_err(|_| DmError::Malformed)?;
+    let sender = ed25519_key(from).map_err(|_| DmError::BadSignature)?;
+    if !sender.verify(
+        &signed_bytes(&own_peer_id, origin_timestamp, &message),
+        signature,
+    ) {
+        return Err(DmError::BadSignature);
+    }
+    Ok((origin_timestamp, message))
+}
+
+/// Signs the `nonce` of `challenger`'s challenge.
+pub(crate) fn sign_challenge(
+    keypair: &Keypair,
+    challenger: &PeerId,
+    nonce: &[u8],
+) -> Result<Vec<u8>, DmError> {
+    let signed = [CHALLENGE_DOMAIN, &challenger.to_bytes(), nonce].concat();
+    Ok(ed25519_keypair(keypair)?.sign(&signed))
+}
+
+/// Verifies that `from` signed the `nonce` of our (`challenger`'s) challenge.
+pub(crate) fn verify_challenge(
+    from: &PeerId,
+    challenger: &PeerId,
+    nonce: &[u8],
+    signature: &[u8],
+) -> Result<(), DmError> {
+    let signed = [CHALLENGE_DOMAIN, &challenger.to_bytes(), nonce].concat();
+    let sender = ed25519_key(from).map_err(|_| DmError::BadSignature)?;
+    match sender.verify(&signed, signature) {
+        true => Ok(()),
+        false => Err(DmError::BadSignature),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 8032, 7.1, tests 1 and 2
+    const RECIPIENT_SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const SENDER_SEED: &str = "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb";
+
+    fn keypair(seed: &str) -> Keypair {
+        let mut seed = hex::decode(seed).unwrap();
+        let secret = ed25519::SecretKey::from_bytes(&mut seed).unwrap();
+        Keypair::Ed25519(secret.into())
+    }
+
+    fn peer(keypair: &Keypair) -> PeerId {
+        PeerId::from(keypair.public())
+    }
+
+    fn timestamp() -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(1_600_000_000_000).unwrap()
+    }
+
+    #[test]
+    fn converts_keys() {
+        let recipient = keypair(RECIPIENT_SEED);
+        let public = x25519_public(&ed25519_key(&peer(&recipient)).unwrap()).unwrap();
+        assert_eq!(
+            hex::encode(public.as_bytes()),
+            "d85e07ec22b0ad881537c2f44d662d1a143cf830c57aca4305d85c7a90f6b62e"
+        );
+        // The converted secret matches the converted public key.
+        let secret = x25519_secret(&recipient).unwrap();
+        assert_eq!(X25519_BASEPOINT * secret, public);
+    }
+
+    #[test]
+    fn sealed_box_vector() {
+        let (sender, recipient) = (keypair(SENDER_SEED), keypair(RECIPIENT_SEED));
+        let sealed = seal_with(
+            &sender,
+            &peer(&recipient),
+            clamp([0x42; KEY_LEN]),
+            timestamp(),
+            "hi",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(&sealed),
+            "132c442be010fbd57e72603328aa76e71fccc1503aae219327d14d9c9993f472\
+             8cbad6d6de1b74e9afa24465ecbd1329ef0d9be71610ad975ed1cbaf6ee9d3a0\
+             9fb32fc749e36071058e7b49bb6f8293b351c609869a3dca0debc7dae2737525\
+             4d5be1bc06be698456d110aebe6bc6d17dbca896a4d0f6261eb1"
+        );
+        assert_eq!(
+            open(&recipient, &peer(&sender), &sealed).unwrap(),
+            (timestamp(), "hi".into())
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (sender, recipient) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let sealed = seal(&sender, &peer(&recipient), timestamp(), "psst").unwrap();
+        assert_eq!(
+            open(&recipient, &peer(&sender), &sealed).unwrap(),
+            (timestamp(), "psst".into())
+        );
+    }
+
+    #[test]
+    fn rejects_other_recipients_and_senders() {
+        let (sender, recipient) = (keypair(SENDER_SEED), keypair(RECIPIENT_SEED));
+        let sealed = seal(&sender, &peer(&recipient), timestamp(), "psst").unwrap();
+        let other = Keypair::generate_ed25519();
+        assert!(matches!(
+            open(&other, &peer(&sender), &sealed),
+            Err(DmError::Decryption)
+        ));
+        assert!(matches!(
+            open(&recipient, &peer(&other), &sealed),
+            Err(DmError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let (sender, recipient) = (keypair(SENDER_SEED), keypair(RECIPIENT_SEED));
+        let sealed = seal(&sender, &peer(&recipient), timestamp(), "psst").unwrap();
+        for i in [0, KEY_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(open(&recipient, &peer(&sender), &tampered).is_err());
+        }
+        assert!(matches!(
+            open(&recipient, &peer(&sender), &sealed[..KEY_LEN - 1]),
+            Err(DmError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn verifies_challenges() {
+        let (sender, challenger) = (keypair(SENDER_SEED), keypair(RECIPIENT_SEED));
+        let nonce = [7; 32];
+        let signature = sign_challenge(&sender, &peer(&challenger), &nonce).unwrap();
+        assert!(verify_challenge(&peer(&sender), &peer(&challenger), &nonce, &signature).is_ok());
+
+        // Bound to the nonce, the challenger and the sender.
+        let other = peer(&Keypair::generate_ed25519());
+        for (from, challenger, nonce) in [
+            (peer(&sender), peer(&challenger), [8; 32]),
+            (peer(&sender), other, nonce),
+            (other, peer(&challenger), nonce),
+        ] {
+            assert!(matches!(
+                verify_challenge(&from, &challenger, &nonce, &signature),
+                Err(DmError::BadSignature)
+            ));
+        }
+    }
+
+    #[test]
+    fn cannot_encrypt_to_hashed_identities() {
+        // Peer ids of keys too large to be inlined (e.g. RSA) are SHA-256 hashes.
+        let peer = PeerId::from_bytes(&[[0x12, 0x20].as_slice(), &[7; 32]].concat()).unwrap();
+        let err = seal(&keypair(SENDER_SEED), &peer, timestamp(), "psst").unwrap_err();
+        assert!(matches!(err, DmError::UnsupportedPeer));
+        assert_eq!(
+            err.to_string(),
+            "cannot encrypt to this peer, its identity isn't an Ed25519 key"
+        );
+    }
+}
@@ -0,0 +1,449 @@
+//! Gate for direct messages of peers we never chatted with. The first one is held and answered
+//! with a [`ChatApi::DmChallenge`](crate::api::ChatApi::DmChallenge), which the sender's client
+//! signs with its identity key (see [`dm::sign_challenge`]). With `--approve-dms`, the messages
+//! are then held until `/accept-dm`, as are those of senders which can't answer challenges.
+//! Verified peers and peers we sent a direct message to pass right away.
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use chrono::{DateTime, Utc};
+use libp2p::PeerId;
+
+use crate::{api::DM_NONCE_LEN, dm};
+
+/// Time for a challenged peer to answer.
+pub(crate) const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Messages held per peer, further ones are dropped.
+const MAX_HELD: usize = 16;
+/// Peers with pending requests, further ones are dropped.
+const MAX_PENDING: usize = 64;
+
+/// A held direct message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Held {
+    pub(crate) origin_timestamp: DateTime<Utc>,
+    pub(crate) message: String,
+    /// Sealed to us, rather than published in plain text for the whole channel to read.
+    pub(crate) sealed: bool,
+}
+
+#[derive(Debug)]
+enum Stage {
+    Challenged {
+        nonce: Vec<u8>,
+        since: Instant,
+    },
+    /// Waiting for `/accept-dm`.
+    Unapproved,
+}
+
+#[derive(Debug)]
+struct Request {
+    stage: Stage,
+    held: Vec<Held>,
+}
+
+/// What to do with a received direct message.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Received {
+    Show,
+    /// Held, the sender is to be challenged with the nonce.
+    Challenge(Vec<u8>),
+    /// Held, the sender can't answer challenges so the request waits for `/accept-dm` right
+    /// away.
+    Unapproved,
+    /// Held until the sender's request is settled.
+    Held,
+    /// From a declined peer, or beyond the bounds of held messages.
+    Dropped,
+}
+
+/// Outcome of a valid answer to a challenge.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Answered {
+    /// The held messages, to be shown.
+    Accepted(Vec<Held>),
+    /// Waiting for `/accept-dm`.
+    Unapproved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum ChallengeError {
+    #[error("no challenge pending")]
+    Unexpected,
+    #[error("answered another challenge")]
+    WrongNonce,
+    #[error("answered too late")]
+    Expired,
+    #[error("the signature is invalid")]
+    BadSignature,
+}
+
+#[derive(Debug)]
+pub(crate) struct DmRequests {
+    local: PeerId,
+    /// Whether requests wait for `/accept-dm` once the challenge is answered.
+    approve: bool,
+    /// Peers whose direct messages are shown right away.
+    known: HashSet<PeerId>,
+    /// Peers whose direct messages are dropped.
+    declined: HashSet<PeerId>,
+    pending: HashMap<PeerId, Request>,
+}
+
+impl DmRequests {
+    pub(crate) fn new(local: PeerId, approve: bool) -> Self {
+        Self {
+            local,
+            approve,
+            known: Default::default(),
+            declined: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    /// Whether `peer` may message us without a challenge, and is answered when challenging us.
+    pub(crate) fn is_known(&self, peer: &PeerId) -> bool {
+        self.known.contains(peer)
+    }
+
+    /// Lets `peer`'s direct messages pass from now on, e.g. after sending it one. Returns the
+    /// held messages if there was a request.
+    pub(crate) fn accept(&mut self, peer: PeerId) -> Option<Vec<Held>> {
+        self.declined.remove(&peer);
+        self.known.insert(peer);
+        self.pending.remove(&peer).map(|request| request.held)
+    }
+
+    /// Drops `peer`'s direct messages from now on. Returns whether there was a request.
+    pub(crate) fn decline(&mut self, peer: PeerId) -> bool {
+        self.known.remove(&peer);
+        self.declined.insert(peer);
+        self.pending.remove(&peer).is_some()
+    }
+
+    /// Gates a direct message of `peer`. `trusted` peers (e.g. verified ones) pass,
+    /// `answers_challenges` if it announced [`crate::api::capability::DM_CHALLENGES`].
+    pub(crate) fn receive(
+        &mut self,
+        peer: PeerId,
+        trusted: bool,
+        answers_challenges: bool,
+        message: Held,
+        now: Instant,
+    ) -> Received {
+        if trusted || self.known.contains(&peer) {
+            return Received::Show;
+        }
+        if self.declined.contains(&peer) {
+            return Received::Dropped;
+        }
+        if let Some(request) = self.pending.get_mut(&peer) {
+            if request.held.len() >= MAX_HELD {
+                return Received::Dropped;
+            }
+            request.held.push(message);
+            return Received::Held;
+        }
+        if self.pending.len() >= MAX_PENDING {
+            return Received::Dropped;
+        }
+        let (stage, received) = match answers_challenges {
+            true => {
+                let mut nonce = vec![0; DM_NONCE_LEN];
+                OsRng.fill_bytes(&mut nonce);
+                let stage = Stage::Challenged {
+                    nonce: nonce.clone(),
+                    since: now,
+                };
+                (stage, Received::Challenge(nonce))
+            }
+            false => (Stage::Unapproved, Received::Unapproved),
+        };
+        let held = vec![message];
+        self.pending.insert(peer, Request { stage, held });
+        received
+    }
+
+    /// Checks `peer`'s answer to our challenge. A request answered with an invalid signature, or
+    /// too late, is dropped along with its messages.
+    pub(crate) fn answer(
+        &mut self,
+        peer: PeerId,
+        nonce: &[u8],
+        signature: &[u8],
+        now: Instant,
+    ) -> Result<Answered, ChallengeError> {
+        let request = self.pending.get(&peer).ok_or(ChallengeError::Unexpected)?;
+        let result = match &request.stage {
+            Stage::Unapproved => return Err(ChallengeError::Unexpected),
+            Stage::Challenged {
+                nonce: expected, ..
+            } if expected != nonce => return Err(ChallengeError::WrongNonce),
+            Stage::Challenged { since, .. } if now.duration_since(*since) > CHALLENGE_TIMEOUT => {
+                Err(ChallengeError::Expired)
+            }
+            Stage::Challenged { .. } => dm::verify_challenge(&peer, &self.local, nonce, signature)
+                .map_err(|_| ChallengeError::BadSignature),
+        };
+        if let Err(error) = result {
+            self.pending.remove(&peer);
+            return Err(error);
+        }
+        if self.approve {
+            self.pending.get_mut(&peer).expect("Pending").stage = Stage::Unapproved;
+            return Ok(Answered::Unapproved);
+        }
+        let held = self.accept(peer).expect("Pending");
+        Ok(Answered::Accepted(held))
+    }
+
+    /// Drops the requests of peers which didn't answer their challenge in time, returning them.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut expired = Vec::new();
+        self.pending.retain(|peer, request| match request.stage {
+            Stage::Challenged { since, .. } if now.duration_since(since) > CHALLENGE_TIMEOUT => {
+                expired.push(*peer);
+                false
+            }
+            _ => true,
+        });
+        expired.sort_unstable();
+        expired
+    }
+
+    /// Requests waiting for `/accept-dm`, with the number of messages held.
+    pub(crate) fn unapproved(&self) -> Vec<(PeerId, usize)> {
+        let mut requests = self
+            .pending
+            .iter()
+            .filter(|(_, request)| matches!(request.stage, Stage::Unapproved))
+            .map(|(peer, request)| (*peer, request.held.len()))
+            .collect::<Vec<_>>();
+        requests.sort_unstable();
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use libp2p::identity::Keypair;
+
+    use super::*;
+
+    fn held(text: &str) -> Held {
+        Held {
+            origin_timestamp: Utc.timestamp_millis_opt(0).unwrap(),
+            message: text.into(),
+            sealed: true,
+        }
+    }
+
+    struct Peers {
+        local: Keypair,
+        sender: Keypair,
+    }
+
+    impl Peers {
+        fn new() -> Self {
+            Self {
+                local: Keypair::generate_ed25519(),
+                sender: Keypair::generate_ed25519(),
+            }
+        }
+
+        fn local(&self) -> PeerId {
+            self.local.public().to_peer_id()
+        }
+
+        fn sender(&self) -> PeerId {
+            self.sender.public().to_peer_id()
+        }
+
+        /// Receives "first" and "second" from the sender, returning the challenge's nonce.
+        fn challenged(&self, requests: &mut DmRequests, now: Instant) -> Vec<u8> {
+            let nonce = match requests.receive(self.sender(), false, true, held("first"), now) {
+                Received::Challenge(nonce) => nonce,
+                other => panic!("{:?}", other),
+            };
+            assert_eq!(
+                requests.receive(self.sender(), false, true, held("second"), now),
+                Received::Held
+            );
+            nonce
+        }
+
+        fn sign(&self, nonce: &[u8]) -> Vec<u8> {
+            dm::sign_challenge(&self.sender, &self.local(), nonce).unwrap()
+        }
+    }
+
+    #[test]
+    fn passes_answered_challenges() {
+        let peers = Peers::new();
+        let mut requests = DmRequests::new(peers.local(), false);
+        let now = Instant::now();
+        let nonce = peers.challenged(&mut requests, now);
+        assert_eq!(nonce.len(), DM_NONCE_LEN);
+
+        let answered = requests.answer(peers.sender(), &nonce, &peers.sign(&nonce), now);
+        assert_eq!(
+            answered,
+            Ok(Answered::Accepted(vec![held("first"), held("second")]))
+        );
+        assert!(requests.is_known(&peers.sender()));
+        assert_eq!(
+            requests.receive(peers.sender(), false, true, held("third"), now),
+            Received::Show
+        );
+    }
+
+    #[test]
+    fn trusted_and_known_peers_pass() {
+        let peers = Peers::new();
+        let mut requests = DmRequests::new(peers.local(), true);
+        let now = Instant::now();
+        assert_eq!(
+            requests.receive(peers.sender(), true, true, held("hi"), now),
+            Received::Show
+        );
+        let other = PeerId::random();
+        assert_eq!(requests.accept(other), None);
+        assert_eq!(
+            requests.receive(other, false, true, held("hi"), now),
+            Received::Show
+        );
+    }
+
+    #[test]
+    fn unproven_senders_wait_for_approval() {
+        let mut requests = DmRequests::new(PeerId::random(), false);
+        let legacy = PeerId::random();
+        let now = Instant::now();
+        assert_eq!(
+            requests.receive(legacy, false, false, held("hi"), now),
+            Received::Unapproved
+        );
+        assert_eq!(
+            requests.receive(legacy, false, false, held("again"), now),
+            Received::Held
+        );
+        assert_eq!(requests.unapproved(), [(legacy, 2)]);
+        assert_eq!(
+            requests.accept(legacy),
+            Some(vec![held("hi"), held("again")])
+        );
+    }
+
+    #[test]
+    fn drops_wrong_signatures() {
+        let peers = Peers::new();
+        let mut requests = DmRequests::new(peers.local(), false);
+        let now = Instant::now();
+        let nonce = peers.challenged(&mut requests, now);
+
+        // A stale answer leaves the request pending.
+        let stale = vec![0; DM_NONCE_LEN];
+        assert_eq!(
+            requests.answer(peers.sender(), &stale, &peers.sign(&stale), now),
+            Err(ChallengeError::WrongNonce)
+        );
+        // Signed by somebody else.
+        let forged = dm::sign_challenge(&Keypair::generate_ed25519(), &peers.local(), &nonce);
+        assert_eq!(
+            requests.answer(peers.sender(), &nonce, &forged.unwrap(), now),
+            Err(ChallengeError::BadSignature)
+        );
+        // The request and its messages are gone.
+        assert_eq!(
+            requests.answer(peers.sender(), &nonce, &peers.sign(&nonce), now),
+            Err(ChallengeError::Unexpected)
+        );
+        assert!(!requests.is_known(&peers.sender()));
+    }
+
+    #[test]
+    fn challenges_time_out() {
+        let peers = Peers::new();
+        let mut requests = DmRequests::new(peers.local(), false);
+        let now = Instant::now();
+        let nonce = peers.challenged(&mut requests, now);
+        let late = now + CHALLENGE_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(
+            requests.answer(peers.sender(), &nonce, &peers.sign(&nonce), late),
+            Err(ChallengeError::Expired)
+        );
+
+        let nonce = peers.challenged(&mut requests, now);
+        assert!(requests.expire(now + CHALLENGE_TIMEOUT).is_empty());
+        assert_eq!(requests.expire(late), [peers.sender()]);
+        assert_eq!(
+            requests.answer(peers.sender(), &nonce, &peers.sign(&nonce), late),
+            Err(ChallengeError::Unexpected)
+        );
+        // Messages after that start over.
+        assert!(matches!(
+            requests.receive(peers.sender(), false, true, held("again"), late),
+            Received::Challenge(_)
+        ));
+    }
+
+    #[test]
+    fn waits_for_approval() {
+        let peers = Peers::new();
+        let mut requests = DmRequests::new(peers.local(), true);
+        let now = Instant::now();
+        let nonce = peers.challenged(&mut requests, now);
+        assert_eq!(
+            requests.answer(peers.sender(), &nonce, &peers.sign(&nonce), now),
+            Ok(Answered::Unapproved)
+        );
+        assert_eq!(requests.unapproved(), [(peers.sender(), 2)]);
+        // Not expired once answered.
+        assert!(requests.expire(now + CHALLENGE_TIMEOUT * 2).is_empty());
+
+        // Peers not answering challenges wait for approval right away.
+        let legacy = PeerId::random();
+        assert_eq!(
+            requests.receive(legacy, false, false, held("hi"), now),
+            Received::Unapproved
+        );
+        assert_eq!(requests.unapproved().len(), 2);
+
+        assert_eq!(
+            requests.accept(peers.sender()),
+            Some(vec![held("first"), held("second")])
+        );
+        assert_eq!(requests.unapproved(), [(legacy, 1)]);
+    }
+
+    #[test]
+    fn declined_peers_are_dropped() {
+        let peers = Peers::new();
+        let mut requests = DmRequests::new(peers.local(), true);
+        let now = Instant::now();
+        let nonce = peers.challenged(&mut requests, now);
+        assert!(requests.decline(peers.sender()));
+        assert!(requests.unapproved().is_empty());
+        assert_eq!(
+            requests.answer(peers.sender(), &nonce, &peers.sign(&nonce), now),
+            Err(ChallengeError::Unexpected)
+        );
+        assert_eq!(
+            requests.receive(peers.sender(), false, true, held("again"), now),
+            Received::Dropped
+        );
+        assert!(!requests.decline(peers.sender()));
+
+        // Until accepted after all.
+        assert_eq!(requests.accept(peers.sender()), None);
+        assert_eq!(
+            requests.receive(peers.sender(), false, true, held("again"), now),
+            Received::Show
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Do not disturb, by `/dnd on` or during the config's `dnd` ranges of local time, e.g.
+//! `["22:00-07:00"]`. Messages still render and are recorded, while mentions of the own nickname
+//! are counted, to be summarized once it ends.
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use chrono::{DateTime, NaiveTime, TimeZone};
+
+/// Parsing a range of `HH:MM-HH:MM` failed.
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid time range {0:?}, expected e.g. 22:00-07:00")]
+pub(crate) struct RangeError(String);
+
+/// A daily range of wall-clock time, wrapping around midnight if it ends before it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Range {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Range {
+    /// Whether `time` is within, the start included and the end not.
+    fn contains(&self, time: NaiveTime) -> bool {
+        match self.start <= self.end {
+            true => self.start <= time && time < self.end,
+            false => self.start <= time || time < self.end,
+        }
+    }
+}
+
+impl FromStr for Range {
+    type Err = RangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || RangeError(s.to_string());
+        let (start, end) = s.split_once('-').ok_or_else(err)?;
+        let time = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| err());
+        let (start, end) = (time(start)?, time(end)?);
+        if start == end {
+            return Err(err());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Whether any of `ranges` contains the wall-clock time of `now`, in its zone. Across DST
+/// transitions the ranges follow the wall clock, e.g. `22:00-07:00` ends at 07:00 either way.
+pub(crate) fn scheduled<Tz: TimeZone>(ranges: &[Range], now: &DateTime<Tz>) -> bool {
+    let time = now.time();
+    ranges.iter().any(|range| range.contains(time))
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Dnd {
+    schedule: Vec<Range>,
+    /// Set by `/dnd`, overriding the schedule until it next starts or ends.
+    manual: Option<bool>,
+    /// Whether the schedule was active at the last update.
+    scheduled: bool,
+    active: bool,
+    /// Mentions while active, by the nickname of the peer mentioning.
+    mentions: BTreeMap<String, u32>,
+}
+
+impl Dnd {
+    pub(crate) fn new(schedule: Vec<Range>) -> Self {
+        Self {
+            schedule,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Turns it on or off until the schedule next starts or ends, returning the summary of the
+    /// mentions if it ended.
+    pub(crate) fn set<Tz: TimeZone>(&mut self, on: bool, now: &DateTime<Tz>) -> Option<String> {
+        self.manual = Some(on);
+        self.update(now)
+    }
+
+    /// Follows the schedule, returning the summary of the mentions if it ended.
+    pub(crate) fn update<Tz: TimeZone>(&mut self, now: &DateTime<Tz>) -> Option<String> {
+        let scheduled = scheduled(&self.schedule, now);
+        if scheduled != self.scheduled {
+            self.scheduled = scheduled;
+            self.manual = None;
+        }
+        let was_active = std::mem::replace(&mut self.active, self.manual.unwrap_or(scheduled));
+        match was_active && !self.active {
+            true => summary(std::mem::take(&mut self.mentions)),
+            false => None,
+        }
+    }
+
+    /// Counts a mention by `nick` if active, returning whether it did.
+    pub(crate) fn mention(&mut self, nick: &str) -> bool {
+        if self.active {
+            *self.mentions.entry(nick.to_string()).or_default() += 1;
+        }
+        self.active
+    }
+
+    pub(crate) fn schedule(&self) -> &[Range] {
+        &self.schedule
+    }
+}
+
+/// E.g. "While you were away: 2 mentions from alice, 1 from bob.", the most mentioning first.
+fn summary(mentions: BTreeMap<String, u32>) -> Option<String> {
+    let mut mentions = mentions.into_iter().collect::<Vec<_>>();
+    mentions.sort_by(|(_, a), (_, b)| b.cmp(a));
+    let parts = mentions
+        .iter()
+        .enumerate()
+        .map(|(i, (nick, n))| match (i, n) {
+            (0, 1) => format!("1 mention from {}", nick),
+            (0, n) => format!("{} mentions from {}", n, nick),
+            (_, n) => format!("{} from {}", n, nick),
+        })
+        .collect::<Vec<_>>();
+    match parts.is_empty() {
+        true => None,
+        false => Some(format!("While you were away: {}.", parts.join(", "))),
+    }
+}
+
+/// Whether `message` mentions `nick` as a word, ignoring case.
+pub(crate) fn mentions(message: &str, nick: &str) -> bool {
+    let nick = nick.to_lowercase();
+    !nick.is_empty()
+        && message
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric() && c != '-' && c != '_')
+            .any(|word| word == nick)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, NaiveDate};
+
+    use super::*;
+
+    fn at(offset_hours: i32, date: (i32, u32, u32), time: (u32, u32)) -> DateTime<FixedOffset> {
+        FixedOffset::east(offset_hours * 3600)
+            .from_local_datetime(
+                &NaiveDate::from_ymd(date.0, date.1, date.2).and_hms(time.0, time.1, 0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let range = "22:00-07:00".parse::<Range>().unwrap();
+        assert_eq!(range.to_string(), "22:00-07:00");
+        for invalid in ["22:00", "25:00-07:00", "07:00-07:00", "late-early"] {
+            assert!(invalid.parse::<Range>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ranges_wrap_around_midnight() {
+        let night = ["22:00-07:00".parse().unwrap()];
+        let day = (2022, 6, 1);
+        for (time, active) in [
+            ((21, 59), false),
+            ((22, 0), true),
+            ((23, 59), true),
+            ((0, 0), true),
+            ((6, 59), true),
+            ((7, 0), false),
+            ((12, 0), false),
+        ] {
+            assert_eq!(scheduled(&night, &at(2, day, time)), active, "{:?}", time);
+        }
+        let lunch = ["12:00-13:00".parse().unwrap()];
+        assert!(scheduled(&lunch, &at(2, day, (12, 30))));
+        assert!(!scheduled(&lunch, &at(2, day, (13, 0))));
+    }
+
+    #[test]
+    fn follows_the_wall_clock_across_dst() {
+        let night = ["22:00-07:00".parse().unwrap()];
+        // Europe/Berlin springs forward from 02:00 CET to 03:00 CEST on 2022-03-27, and falls
+        // back from 03:00 CEST to 02:00 CET on 2022-10-30.
+        let spring_forward = (2022, 3, 27);
+        let fall_back = (2022, 10, 30);
+        // The same instant, 05:30 UTC, is 06:30 before and 07:30 after springing forward.
+        assert!(scheduled(&night, &at(1, spring_forward, (6, 30))));
+        assert!(!scheduled(&night, &at(2, spring_forward, (7, 30))));
+        assert!(scheduled(&night, &at(2, fall_back, (2, 30))));
+        assert!(scheduled(&night, &at(1, fall_back, (2, 30))));
+        assert!(!scheduled(&night, &at(1, fall_back, (7, 0))));
+
+        // Spanning the hour skipped, e.g. 02:30 never occurs that night.
+        let small_hours = ["02:00-03:00".parse().unwrap()];
+        assert!(scheduled(&small_hours, &at(1, spring_forward, (2, 0))));
+        assert!(!scheduled(&small_hours, &at(2, spring_forward, (3, 0))));
+    }
+
+    #[test]
+    fn summarizes_mentions_once_over() {
+        let mut dnd = Dnd::new(vec!["22:00-07:00".parse().unwrap()]);
+        let day = (2022, 6, 1);
+        assert_eq!(dnd.update(&at(2, day, (21, 0))), None);
+        assert!(!dnd.mention("alice"));
+
+        assert_eq!(dnd.update(&at(2, day, (23, 0))), None);
+        assert!(dnd.is_active());
+        for nick in ["alice", "bob", "alice"] {
+            assert!(dnd.mention(nick));
+        }
+        assert_eq!(
+            dnd.update(&at(2, (2022, 6, 2), (7, 0))).as_deref(),
+            Some("While you were away: 2 mentions from alice, 1 from bob.")
+        );
+        assert!(!dnd.is_active());
+        assert_eq!(dnd.update(&at(2, (2022, 6, 2), (7, 10))), None);
+    }
+
+    #[test]
+    fn manual_until_the_schedule_changes() {
+        let mut dnd = Dnd::new(vec!["22:00-07:00".parse().unwrap()]);
+        let day = (2022, 6, 1);
+        dnd.update(&at(2, day, (12, 0)));
+        assert_eq!(dnd.set(true, &at(2, day, (12, 0))), None);
+        assert!(dnd.is_active());
+        dnd.mention("alice");
+        assert_eq!(
+            dnd.set(false, &at(2, day, (13, 0))).as_deref(),
+            Some("While you were away: 1 mention from alice.")
+        );
+
+        // Off during the schedule, until it ends and starts again.
+        dnd.update(&at(2, day, (23, 0)));
+        dnd.set(false, &at(2, day, (23, 0)));
+        assert!(!dnd.is_active());
+        dnd.update(&at(2, (2022, 6, 2), (8, 0)));
+        dnd.update(&at(2, (2022, 6, 2), (22, 0)));
+        assert!(dnd.is_active());
+    }
+
+    #[test]
+    fn mentions_are_words() {
+        assert!(mentions("hey Alice, look", "alice"));
+        assert!(mentions("@alice", "alice"));
+        assert!(!mentions("malice", "alice"));
+        assert!(!mentions("anything", ""));
+    }
+}
@@ -0,0 +1,129 @@
+//! CBOR encoding of wire messages.
+//!
+//! `serde_cbor` is unmaintained and has been replaced with `ciborium`, see `deny.toml`.
+use std::{cell::RefCell, io};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Larger payloads are rejected without being looked at, matching gossipsub's default maximum
+/// transmit size.
+pub(crate) const MAX_DECODE_LEN: usize = 64 * 1024;
+
+/// Room for field names and headers around a message's payload.
+const OVERHEAD: usize = 128;
+
+thread_local! {
+    /// Reused by [`with_cbor`], so encoding doesn't allocate and grow a buffer per message.
+    static BUF: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(OVERHEAD));
+}
+
+pub(crate) fn to_cbor<T: Serialize>(msg: &T) -> Result<Vec<u8>, ciborium::ser::Error<io::Error>> {
+    to_cbor_sized(msg, 0)
+}
+
+/// Like [`to_cbor`], but allocates once for messages of about `len_hint` bytes instead of growing
+/// the buffer repeatedly.
+fn to_cbor_sized<T: Serialize>(
+    msg: &T,
+    len_hint: usize,
+) -> Result<Vec<u8>, ciborium::ser::Error<io::Error>> {
+    let mut buf = Vec::with_capacity(len_hint + OVERHEAD);
+    ciborium::ser::into_writer(msg, &mut buf)?;
+    Ok(buf)
+}
+
+/// Serializes `msg` into a buffer reused per thread, which is passed to `f`. Buffers grown by
+/// messages beyond [`MAX_DECODE_LEN`] aren't kept. Publishing still copies the encoding once, into
+/// the sequenced payload handed to gossipsub.
+pub(crate) fn with_cbor<T: Serialize, R>(
+    msg: &T,
+    f: impl FnOnce(&[u8]) -> R,
+) -> Result<R, ciborium::ser::Error<io::Error>> {
+    BUF.with(|buf| match buf.try_borrow_mut() {
+        Ok(mut buf) => {
+            buf.clear();
+            let encoded = ciborium::ser::into_writer(msg, &mut *buf).map(|()| f(&buf));
+            if buf.capacity() > MAX_DECODE_LEN {
+                *buf = Vec::with_capacity(OVERHEAD);
+            }
+            encoded
+        }
+        // Called from within `f`.
+        Err(_) => Ok(f(&to_cbor(msg)?)),
+    })
+}
+
+/// Decodes untrusted `bytes`, which must not exceed [`MAX_DECODE_LEN`].
+pub(crate) fn from_cbor<T: DeserializeOwned>(
+    bytes: &[u8],
+) -> Result<T, ciborium::de::Error<io::Error>> {
+    if bytes.len() > MAX_DECODE_LEN {
+        return Err(ciborium::de::Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} bytes exceed the decode limit", bytes.len()),
+        )));
+    }
+    ciborium::de::from_reader(bytes)
+}
+
+/// Serializes `msg` to CBOR. Messages with a `len_hint` of at least `threshold` bytes are
+/// serialized on the blocking thread pool to not stall the executor.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn to_vec<T>(msg: T, len_hint: usize, threshold: usize) -> anyhow::Result<Vec<u8>>
+where
+    T: Serialize + Send + 'static,
+{
+    if len_hint < threshold {
+        Ok(to_cbor_sized(&msg, len_hint)?)
+    } else {
+        Ok(tokio::task::spawn_blocking(move || to_cbor_sized(&msg, len_hint)).await??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let bytes = to_cbor(&("agora", 42u32)).unwrap();
+        assert_eq!(
+            from_cbor::<(String, u32)>(&bytes).unwrap(),
+            ("agora".into(), 42)
+        );
+        assert!(from_cbor::<String>(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized() {
+        let bytes = to_cbor(&"x".repeat(MAX_DECODE_LEN)).unwrap();
+        assert!(from_cbor::<String>(&bytes).is_err());
+    }
+
+    #[test]
+    fn reused_buffer_agrees() {
+        for msg in ["a much longer message than the next", "short", ""] {
+            let reused = with_cbor(&msg, <[u8]>::to_vec).unwrap();
+            assert_eq!(reused, to_cbor(&msg).unwrap());
+        }
+        // Nested, e.g. publishing while publishing.
+        let nested = with_cbor(&"outer", |outer| {
+            let inner = with_cbor(&"inner", <[u8]>::to_vec).unwrap();
+            (outer.to_vec(), inner)
+        })
+        .unwrap();
+        assert_eq!(
+            nested,
+            (to_cbor(&"outer").unwrap(), to_cbor(&"inner").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn blocking_and_inline_agree() {
+        let msg = "x".repeat(MAX_DECODE_LEN - 16);
+        let inline = to_vec(msg.clone(), msg.len(), usize::MAX).await.unwrap();
+        let blocking = to_vec(msg.clone(), msg.len(), 0).await.unwrap();
+        assert_eq!(inline, blocking);
+        assert_eq!(from_cbor::<String>(&blocking).unwrap(), msg);
+    }
+}
@@ -0,0 +1,96 @@
+//! Fingerprints of peer ids, short enough to be compared out of band (e.g. read out on a call).
+//!
+//! A fingerprint is the SHA-256 hash of the peer id's multihash, truncated to 64 bits and
+//! rendered as eight words of 256. Finding another key with the same fingerprint takes about 2^64
+//! attempts.
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+const DOMAIN: &[u8] = b"agora-fingerprint-v1";
+const WORDS_PER_FINGERPRINT: usize = 8;
+
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adobe", "agent", "alarm", "album", "alien", "alpha", "amber",
+    "anchor", "angle", "ankle", "apple", "apron", "arena", "armor", "arrow", "aspen", "atlas",
+    "attic", "audio", "autumn", "bacon", "badge", "bagel", "baker", "bamboo", "banjo", "barley",
+    "barrel", "basil", "basin", "beach", "beacon", "beetle", "bell", "bench", "berry", "bison",
+    "blade", "blanket", "boat", "bonsai", "boot", "border", "bottle", "branch", "bread", "brick",
+    "bridge", "bronze", "brook", "broom", "bucket", "bugle", "butter", "cabin", "cactus", "camel",
+    "candle", "canoe", "canyon", "carbon", "cargo", "carpet", "castle", "cedar", "cello", "cement",
+    "chalk", "cherry", "chess", "cider", "cinema", "circus", "citrus", "clay", "cliff", "clock",
+    "cloud", "clover", "cobalt", "cocoa", "comet", "copper", "coral", "cotton", "cradle", "crane",
+    "crayon", "cricket", "crystal", "cup", "curtain", "daisy", "dancer", "delta", "denim",
+    "desert", "diamond", "dingo", "dolphin", "domino", "donkey", "dragon", "drum", "dune", "eagle",
+    "easel", "echo", "elbow", "elm", "ember", "engine", "falcon", "fern", "ferry", "fiddle", "fig",
+    "flame", "flute", "forest", "fossil", "fox", "frost", "galaxy", "garden", "garlic", "geyser",
+    "ginger", "glacier", "globe", "goblet", "granite", "grape", "gravel", "guitar", "hammer",
+    "harbor", "harp", "hazel", "helmet", "heron", "honey", "hornet", "igloo", "iris", "island",
+    "ivory", "jacket", "jade", "jaguar", "jelly", "jigsaw", "jungle", "kayak", "kernel", "kettle",
+    "kiwi", "koala", "ladder", "lagoon", "lantern", "laser", "lava", "lemon", "lentil", "lily",
+    "linen", "lizard", "llama", "locket", "lotus", "magnet", "mango", "maple", "marble", "meadow",
+    "melon", "meteor", "mint", "mirror", "mosaic", "moss", "muffin", "nectar", "needle", "nickel",
+    "noodle", "nutmeg", "oak", "oasis", "ocean", "olive", "onion", "opal", "orbit", "orchid",
+    "otter", "owl", "oyster", "paddle", "panda", "paper", "parrot", "peach", "pebble", "pepper",
+    "piano", "pigeon", "pillow", "pine", "planet", "plum", "pocket", "pollen", "poppy", "prism",
+    "quartz", "quill", "rabbit", "radar", "radish", "raven", "reef", "ribbon", "river", "robin",
+    "rocket", "saddle", "salmon", "satin", "scarf", "shell", "silver", "sketch", "spruce", "squid",
+    "stone", "sugar", "summit", "sunset", "swan", "tablet", "tiger", "timber", "tulip", "tundra",
+    "turtle", "velvet", "violin", "walnut", "willow", "yarrow", "zebra",
+];
+
+/// The fingerprint of `peer`, e.g. `bell helmet camel butter nectar jade cedar kernel`.
+pub(crate) fn fingerprint(peer: &PeerId) -> String {
+    let hash = Sha256::new()
+        .chain_update(DOMAIN)
+        .chain_update(peer.to_bytes())
+        .finalize();
+    hash[..WORDS_PER_FINGERPRINT]
+        .iter()
+        .map(|b| WORDS[usize::from(*b)])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use libp2p::identity::{ed25519, Keypair};
+
+    use super::*;
+
+    #[test]
+    fn words_are_distinct() {
+        assert_eq!(WORDS.iter().collect::<HashSet<_>>().len(), WORDS.len());
+        assert!(WORDS
+            .iter()
+            .all(|w| w.chars().all(|c| c.is_ascii_lowercase())));
+    }
+
+    #[test]
+    fn known_fingerprint() {
+        // RFC 8032, 7.1, test 1
+        let mut seed =
+            hex::decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+                .unwrap();
+        let secret = ed25519::SecretKey::from_bytes(&mut seed).unwrap();
+        let peer = PeerId::from(Keypair::Ed25519(secret.into()).public());
+        assert_eq!(
+            fingerprint(&peer),
+            "bell helmet camel butter nectar jade cedar kernel"
+        );
+    }
+
+    #[test]
+    fn distinct_peers_distinct_fingerprints() {
+        let peer = PeerId::random();
+        assert_eq!(fingerprint(&peer), fingerprint(&peer));
+        let fingerprints = (0..1000)
+            .map(|_| fingerprint(&PeerId::random()))
+            .collect::<HashSet<_>>();
+        assert_eq!(fingerprints.len(), 1000);
+        assert!(fingerprints
+            .iter()
+            .all(|f| f.split(' ').count() == WORDS_PER_FINGERPRINT));
+    }
+}
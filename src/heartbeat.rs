@@ -0,0 +1,74 @@
+//! The ticker of the periodic work (GC, expiring DM challenges, fallback announcements, ..),
+//! jittered so peers started together don't all publish on the same boundary.
+use std::time::Duration;
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use tokio::time::Instant;
+
+pub(crate) const INTERVAL: Duration = Duration::from_secs(10);
+/// Ticks are up to this fraction of the interval early or late, re-randomized each time.
+const JITTER: f64 = 0.2;
+
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    interval: Duration,
+    next: Instant,
+}
+
+impl Heartbeat {
+    /// Ticks right away, then about every `interval`.
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Instant::now(),
+        }
+    }
+
+    /// Cancel safe: a tick not waited for until the end is still due.
+    pub(crate) async fn tick(&mut self) {
+        tokio::time::sleep_until(self.next).await;
+        self.next = Instant::now() + jittered(self.interval, unit());
+    }
+}
+
+/// A random number in `[0, 1)`.
+fn unit() -> f64 {
+    (OsRng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// `interval` shifted by `unit` in `[0, 1)` within ±[`JITTER`].
+fn jittered(interval: Duration, unit: f64) -> Duration {
+    interval.mul_f64(1.0 + JITTER * (2.0 * unit - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitters_within_bounds() {
+        assert_eq!(jittered(INTERVAL, 0.0), Duration::from_secs(8));
+        assert_eq!(jittered(INTERVAL, 0.5), INTERVAL);
+        assert!(jittered(INTERVAL, 1.0 - f64::EPSILON) < Duration::from_secs(12));
+        for _ in 0..1000 {
+            let unit = unit();
+            assert!((0.0..1.0).contains(&unit));
+            let interval = jittered(INTERVAL, unit);
+            assert!(interval >= Duration::from_secs(8) && interval < Duration::from_secs(12));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ticks_right_away_then_jittered() {
+        let mut heartbeat = Heartbeat::new(INTERVAL);
+        let start = Instant::now();
+        heartbeat.tick().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for _ in 0..10 {
+            let last = Instant::now();
+            heartbeat.tick().await;
+            let elapsed = last.elapsed();
+            assert!(elapsed >= Duration::from_secs(8) && elapsed < Duration::from_secs(12));
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Recent messages per channel, replayed to peers asking for them when joining.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use libp2p::{gossipsub::TopicHash, PeerId};
+
+use crate::api::HistoryEntry;
+
+/// Most entries sent in one response.
+pub(crate) const MAX_BATCH: usize = 50;
+/// Upper bound of the nicknames' and messages' bytes in one response, well below the decode
+/// limit.
+const MAX_BATCH_BYTES: usize = 32 * 1024;
+/// Entries kept per channel.
+const CAPACITY: usize = 1000;
+/// Messages remembered to drop backfilled duplicates.
+const SEEN_CAPACITY: usize = 4096;
+/// Each peer is responded to at most once within this.
+const RESPONSE_INTERVAL: Duration = Duration::from_secs(60);
+/// Responses to all peers within [`RESPONSE_INTERVAL`].
+const MAX_RESPONSES: usize = 10;
+/// Responses to a request of ours are accepted for this long after sending it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Responses accepted per request, further ones are dropped.
+const MAX_RESPONDERS: usize = 5;
+
+type Key = (PeerId, i64, u64);
+
+fn key(entry: &HistoryEntry) -> Key {
+    let mut hasher = DefaultHasher::new();
+    entry.message.hash(&mut hasher);
+    (
+        entry.from,
+        entry.origin_timestamp.timestamp_millis(),
+        hasher.finish(),
+    )
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    /// Whether to keep entries and respond to requests.
+    serve: bool,
+    entries: HashMap<TopicHash, VecDeque<HistoryEntry>>,
+    seen: HashSet<Key>,
+    seen_order: VecDeque<Key>,
+    responded: HashMap<PeerId, Instant>,
+    responses: VecDeque<Instant>,
+    /// Our open requests: when they were sent and the peers which responded so far.
+    requests: HashMap<TopicHash, (Instant, HashSet<PeerId>)>,
+}
+
+impl History {
+    pub(crate) fn new(serve: bool) -> Self {
+        Self {
+            serve,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether `key` is new.
+    fn see(&mut self, key: Key) -> bool {
+        if !self.seen.insert(key) {
+            return false;
+        }
+        self.seen_order.push_back(key);
+        if self.seen_order.len() > SEEN_CAPACITY {
+            let oldest = self.seen_order.pop_front().expect("Not empty");
+            self.seen.remove(&oldest);
+        }
+        true
+    }
+
+    /// Records a message received live.
+    pub(crate) fn record(&mut self, topic: &TopicHash, entry: HistoryEntry) {
+        self.see(key(&entry));
+        if !self.serve {
+            return;
+        }
+        let entries = self.entries.entry(topic.clone()).or_default();
+        if entries.len() >= CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The entries kept of `topic`, oldest first.
+    pub(crate) fn entries(&self, topic: &TopicHash) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.get(topic).into_iter().flatten()
+    }
+
+    /// The most recent entries of `topic` since `since` to send to `peer`, at most `max`. `None`
+    /// if not serving history or `peer` (or everybody) asked too often.
+    pub(crate) fn respond(
+        &mut self,
+        topic: &TopicHash,
+        peer: PeerId,
+        since: DateTime<Utc>,
+        max: usize,
+        now: Instant,
+    ) -> Option<Vec<HistoryEntry>> {
+        if !self.serve {
+            return None;
+        }
+        let recent = |at: &Instant| now.duration_since(*at) < RESPONSE_INTERVAL;
+        while self.responses.front().map_or(false, |at| !recent(at)) {
+            self.responses.pop_front();
+        }
+        self.responded.retain(|_, at| recent(at));
+        if self.responses.len() >= MAX_RESPONSES || self.responded.contains_key(&peer) {
+            return None;
+        }
+        self.responses.push_back(now);
+        self.responded.insert(peer, now);
+
+        let mut batch = Vec::new();
+        let mut bytes = 0;
+        for entry in self.entries.get(topic).into_iter().flatten().rev() {
+            bytes += entry.nick.len() + entry.message.len();
+            if entry.origin_timestamp < since
+                || batch.len() >= max.min(MAX_BATCH)
+                || bytes > MAX_BATCH_BYTES
+            {
+                break;
+            }
+            batch.push(entry.clone());
+        }
+        batch.reverse();
+        Some(batch)
+    }
+
+    /// Records a request for the history of `topic` sent, opening it for responses.
+    pub(crate) fn request(&mut self, topic: &TopicHash, now: Instant) {
+        self.requests.insert(topic.clone(), (now, HashSet::new()));
+    }
+
+    /// Drops entries already seen, live or in an earlier response, and sorts the rest by time.
+    /// `None` if the response of `peer` doesn't answer an open request of ours.
+    pub(crate) fn backfill(
+        &mut self,
+        topic: &TopicHash,
+        peer: PeerId,
+        entries: Vec<HistoryEntry>,
+        now: Instant,
+    ) -> Option<Vec<HistoryEntry>> {
+        self.requests
+            .retain(|_, (sent, _)| now.duration_since(*sent) < REQUEST_TIMEOUT);
+        let (_, responders) = self.requests.get_mut(topic)?;
+        if responders.len() >= MAX_RESPONDERS || !responders.insert(peer) {
+            return None;
+        }
+        let mut entries = entries
+            .into_iter()
+            .filter(|e| self.see(key(e)))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.origin_timestamp);
+        Some(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn entry(from: PeerId, second: i64, message: &str) -> HistoryEntry {
+        HistoryEntry {
+            from,
+            nick: "nick".into(),
+            message: message.into(),
+            origin_timestamp: Utc.timestamp(1_600_000_000 + second, 0),
+        }
+    }
+
+    fn messages(entries: &[HistoryEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.message.as_str()).collect()
+    }
+
+    #[test]
+    fn responds_with_recent_entries() {
+        let topic = TopicHash::from_raw("agora");
+        let (alice, requester) = (PeerId::random(), PeerId::random());
+        let mut history = History::new(true);
+        for (second, message) in [(0, "a"), (10, "b"), (20, "c"), (30, "d")] {
+            history.record(&topic, entry(alice, second, message));
+        }
+        let since = Utc.timestamp(1_600_000_010, 0);
+        let now = Instant::now();
+
+        let batch = history.respond(&topic, requester, since, 2, now).unwrap();
+        assert_eq!(messages(&batch), ["c", "d"]);
+        let batch = history
+            .respond(&topic, PeerId::random(), since, 100, now)
+            .unwrap();
+        assert_eq!(messages(&batch), ["b", "c", "d"]);
+        let other = TopicHash::from_raw("other");
+        let batch = history
+            .respond(&other, PeerId::random(), since, 100, now)
+            .unwrap();
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn limits_responses() {
+        let topic = TopicHash::from_raw("agora");
+        let requester = PeerId::random();
+        let since = Utc.timestamp(0, 0);
+        let now = Instant::now();
+
+        assert!(History::new(false)
+            .respond(&topic, requester, since, 10, now)
+            .is_none());
+
+        let mut history = History::new(true);
+        assert!(history.respond(&topic, requester, since, 10, now).is_some());
+        assert!(history.respond(&topic, requester, since, 10, now).is_none());
+        for _ in 1..MAX_RESPONSES {
+            assert!(history
+                .respond(&topic, PeerId::random(), since, 10, now)
+                .is_some());
+        }
+        assert!(history
+            .respond(&topic, PeerId::random(), since, 10, now)
+            .is_none());
+
+        let later = now + RESPONSE_INTERVAL;
+        assert!(history
+            .respond(&topic, requester, since, 10, later)
+            .is_some());
+    }
+
+    #[test]
+    fn bounds_batches() {
+        let topic = TopicHash::from_raw("agora");
+        let mut history = History::new(true);
+        let alice = PeerId::random();
+        for second in 0..CAPACITY as i64 + 10 {
+            history.record(&topic, entry(alice, second, &"x".repeat(1024)));
+        }
+        assert_eq!(history.entries[&topic].len(), CAPACITY);
+        let batch = history
+            .respond(
+                &topic,
+                alice,
+                Utc.timestamp(0, 0),
+                usize::MAX,
+                Instant::now(),
+            )
+            .unwrap();
+        assert!(batch.len() < MAX_BATCH);
+        assert!(batch.iter().map(|e| e.message.len()).sum::<usize>() <= MAX_BATCH_BYTES);
+    }
+
+    #[test]
+    fn backfill_skips_seen_messages() {
+        let topic = TopicHash::from_raw("agora");
+        let alice = PeerId::random();
+        let now = Instant::now();
+        let mut history = History::new(false);
+        history.record(&topic, entry(alice, 10, "live"));
+        history.request(&topic, now);
+
+        let response = vec![
+            entry(alice, 20, "later"),
+            entry(alice, 10, "live"),
+            entry(alice, 0, "earlier"),
+        ];
+        assert_eq!(
+            messages(
+                &history
+                    .backfill(&topic, alice, response.clone(), now)
+                    .unwrap()
+            ),
+            ["earlier", "later"]
+        );
+        // A second responder sending the same.
+        assert!(history
+            .backfill(&topic, PeerId::random(), response, now)
+            .unwrap()
+            .is_empty());
+        // Not serving, nothing kept.
+        assert!(history.entries.is_empty());
+    }
+
+    #[test]
+    fn backfills_only_open_requests() {
+        let topic = TopicHash::from_raw("agora");
+        let alice = PeerId::random();
+        let response = || vec![entry(alice, 0, "hi")];
+        let now = Instant::now();
+        let mut history = History::new(false);
+
+        // Unsolicited.
+        assert_eq!(history.backfill(&topic, alice, response(), now), None);
+
+        history.request(&topic, now);
+        let other = TopicHash::from_raw("other");
+        assert_eq!(history.backfill(&other, alice, response(), now), None);
+        assert!(history.backfill(&topic, alice, response(), now).is_some());
+        // Once per responder.
+        assert_eq!(history.backfill(&topic, alice, response(), now), None);
+        for _ in 1..MAX_RESPONDERS {
+            assert!(history
+                .backfill(&topic, PeerId::random(), response(), now)
+                .is_some());
+        }
+        assert_eq!(
+            history.backfill(&topic, PeerId::random(), response(), now),
+            None
+        );
+
+        history.request(&topic, now);
+        let late = now + REQUEST_TIMEOUT;
+        assert_eq!(
+            history.backfill(&topic, PeerId::random(), response(), late),
+            None
+        );
+    }
+}
@@ -0,0 +1,434 @@
+//! HTTP API, serving incoming webhooks at `POST /hooks/<token>`, the gossipsub mesh of the
+//! joined channels at `GET /topology` and a diagnostic snapshot at `GET /debug/state`.
+//!
+//! Posts are answered once published: 202 if sent or queued until peers join, otherwise an error
+//! status with the reason, e.g. 413 if the message is too large.
+//!
+//! Health checks for supervisors are answered at `GET /health`, with 200 while the node is
+//! healthy and 503 otherwise, along with the figures as JSON. Healthy means listening on at least
+//! one address and being connected to at least `--health-min-peers` peers.
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    body::HttpBody,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::*;
+
+use crate::{
+    api::HookPost, config::Hook, diagnostics::Diagnostics, publish::Published, shutdown::Tasks,
+};
+
+const MAX_BODY: usize = 16 * 1024;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Where to tell how publishing a [`HookPost`] went, to answer its request.
+pub(crate) type Reply = oneshot::Sender<Published>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct Status {
+    pub(crate) healthy: bool,
+    pub(crate) listen_addrs: usize,
+    pub(crate) peers: usize,
+    pub(crate) min_peers: usize,
+}
+
+impl Status {
+    pub(crate) fn new(listen_addrs: usize, peers: usize, min_peers: usize) -> Self {
+        Self {
+            healthy: listen_addrs > 0 && peers >= min_peers,
+            listen_addrs,
+            peers,
+            min_peers,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HookBody {
+    text: String,
+    nick: Option<String>,
+}
+
+struct Shared {
+    hooks: BTreeMap<String, Hook>,
+    /// Start and count of the current rate limit window per token
+    windows: Mutex<BTreeMap<String, (Instant, u32)>>,
+    posts: mpsc::UnboundedSender<(HookPost, Reply)>,
+    /// As of the last [`HttpApi::set_diagnostics`].
+    diagnostics: Arc<Mutex<Diagnostics>>,
+    /// As of the last [`HttpApi::set_health`].
+    health: Arc<Mutex<Status>>,
+}
+
+impl Shared {
+    fn check_rate(&self, token: &str, per_minute: u32) -> Result<(), StatusCode> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let (start, count) = windows.entry(token.into()).or_insert((now, 0));
+        if now.duration_since(*start) >= RATE_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= per_minute {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+pub(crate) struct HttpApi {
+    posts: mpsc::UnboundedReceiver<(HookPost, Reply)>,
+    diagnostics: Arc<Mutex<Diagnostics>>,
+    health: Arc<Mutex<Status>>,
+}
+
+impl HttpApi {
+    pub(crate) fn bind(
+        addr: SocketAddr,
+        hooks: BTreeMap<String, Hook>,
+        min_peers: usize,
+        tasks: &mut Tasks,
+    ) -> anyhow::Result<Self> {
+        let (tx, posts) = mpsc::unbounded_channel();
+        let diagnostics = Arc::new(Mutex::new(Diagnostics::default()));
+        let health = Arc::new(Mutex::new(Status::new(0, 0, min_peers)));
+        let shared = Arc::new(Shared {
+            hooks,
+            windows: Default::default(),
+            posts: tx,
+            diagnostics: diagnostics.clone(),
+            health: health.clone(),
+        });
+        let make_svc = make_service_fn(move |_| {
+            let shared = shared.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(shared.clone(), req))) }
+        });
+        let server = Server::try_bind(&addr)?.serve(make_svc);
+        info!("HTTP API listening on {}", server.local_addr());
+        tasks.spawn(|shutdown| async move {
+            let server = server.with_graceful_shutdown(async move { shutdown.wait().await });
+            if let Err(error) = server.await {
+                warn!(%error, "HTTP API failed");
+            }
+        });
+        Ok(Self {
+            posts,
+            diagnostics,
+            health,
+        })
+    }
+
+    pub(crate) fn set_diagnostics(&self, diagnostics: Diagnostics) {
+        *self.diagnostics.lock().unwrap() = diagnostics;
+    }
+
+    pub(crate) fn set_health(&self, listen_addrs: usize, peers: usize) {
+        let mut health = self.health.lock().unwrap();
+        *health = Status::new(listen_addrs, peers, health.min_peers);
+    }
+
+    pub(crate) async fn next_post(api: &mut Option<Self>) -> Option<(HookPost, Reply)> {
+        match api {
+            Some(api) => api.posts.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+async fn handle(shared: Arc<Shared>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match route(&shared, req).await {
+        Ok(response) => response,
+        Err(status) => Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .expect("Valid response"),
+    };
+    Ok(response)
+}
+
+async fn route(shared: &Shared, req: Request<Body>) -> Result<Response<Body>, StatusCode> {
+    if (req.method(), req.uri().path()) == (&Method::GET, "/health") {
+        let health = *shared.health.lock().unwrap();
+        let body = serde_json::to_vec(&health).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(Response::builder()
+            .status(match health.healthy {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            })
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("Valid response"));
+    }
+    let body = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/topology") => Some(serde_json::to_vec(
+            &shared.diagnostics.lock().unwrap().topology,
+        )),
+        (&Method::GET, "/debug/state") => {
+            Some(serde_json::to_vec(&*shared.diagnostics.lock().unwrap()))
+        }
+        _ => None,
+    };
+    if let Some(body) = body {
+        let body = body.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("Valid response"));
+    }
+    let token = match (req.method(), req.uri().path().strip_prefix("/hooks/")) {
+        (&Method::POST, Some(token)) => token.to_string(),
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+    let hook = shared.hooks.get(&token).ok_or(StatusCode::NOT_FOUND)?;
+    shared.check_rate(&token, hook.per_minute)?;
+    let body = read_limited(req.into_body(), MAX_BODY).await?;
+    let body: HookBody = serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let nick = body
+        .nick
+        .filter(|_| hook.allow_nick)
+        .unwrap_or_else(|| hook.nick.clone());
+    let post = HookPost {
+        channel: hook.channel.clone(),
+        nick,
+        text: body.text,
+    };
+    let (reply, published) = oneshot::channel();
+    shared
+        .posts
+        .send((post, reply))
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    let published = published
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    let status = match published {
+        Published::Sent { .. } | Published::NoPeers => StatusCode::ACCEPTED,
+        Published::Duplicate => StatusCode::CONFLICT,
+        Published::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Published::SigningFailed | Published::TransformFailed => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let body = match published {
+        Published::NoPeers => "Queued until peers join".into(),
+        published => published.notice().unwrap_or_default(),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .expect("Valid response"))
+}
+
+async fn read_limited(mut body: Body, limit: usize) -> Result<Vec<u8>, StatusCode> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buf.len() + chunk.len() > limit {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared() -> (Shared, mpsc::UnboundedReceiver<(HookPost, Reply)>) {
+        let (posts, rx) = mpsc::unbounded_channel();
+        let hook = |allow_nick| Hook {
+            channel: "ops".into(),
+            nick: "ci".into(),
+            allow_nick,
+            per_minute: 2,
+        };
+        let shared = Shared {
+            hooks: [("secret".into(), hook(false)), ("open".into(), hook(true))].into(),
+            windows: Default::default(),
+            posts,
+            diagnostics: Default::default(),
+            health: Arc::new(Mutex::new(Status::new(0, 0, 2))),
+        };
+        (shared, rx)
+    }
+
+    fn request(method: Method, path: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn posts_to_hooks() {
+        let (shared, mut posts) = shared();
+        let post = |token: &str| {
+            request(
+                Method::POST,
+                &format!("/hooks/{}", token),
+                r#"{"text": "deployed", "nick": "mallory"}"#,
+            )
+        };
+        let sent = Published::Sent { reach: Some(1) };
+        let (response, posted) =
+            tokio::join!(route(&shared, post("secret")), publish(&mut posts, sent));
+        assert_eq!(response.unwrap().status(), StatusCode::ACCEPTED);
+        // The nick may only be set if allowed.
+        let expected = |nick: &str| HookPost {
+            channel: "ops".into(),
+            nick: nick.into(),
+            text: "deployed".into(),
+        };
+        assert_eq!(posted, expected("ci"));
+        let (_, posted) = tokio::join!(route(&shared, post("open")), publish(&mut posts, sent));
+        assert_eq!(posted, expected("mallory"));
+    }
+
+    /// Answers the next post as if publishing it went as `published`.
+    async fn publish(
+        posts: &mut mpsc::UnboundedReceiver<(HookPost, Reply)>,
+        published: Published,
+    ) -> HookPost {
+        let (post, reply) = posts.recv().await.unwrap();
+        reply.send(published).unwrap();
+        post
+    }
+
+    #[tokio::test]
+    async fn answers_with_how_publishing_went() {
+        let (shared, mut posts) = shared();
+        for (published, status, body) in [
+            (
+                Published::NoPeers,
+                StatusCode::ACCEPTED,
+                "Queued until peers join",
+            ),
+            (
+                Published::TooLarge {
+                    size: 70_000,
+                    max: Some(65_536),
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Not sent: the message is 70000 bytes, more than the limit of 65536",
+            ),
+        ] {
+            let req = request(Method::POST, "/hooks/secret", r#"{"text": "deployed"}"#);
+            let (response, _) = tokio::join!(route(&shared, req), publish(&mut posts, published));
+            let response = response.unwrap();
+            assert_eq!(response.status(), status);
+            let answer = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(answer, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_tokens_and_bad_requests() {
+        let (shared, mut posts) = shared();
+        let shared = &shared;
+        let status = |req| async move { route(shared, req).await.unwrap_err() };
+        let text = r#"{"text": "hi"}"#;
+        assert_eq!(
+            status(request(Method::POST, "/hooks/guessed", text)).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(request(Method::POST, "/hooks/", text)).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(request(Method::GET, "/hooks/secret", text)).await,
+            StatusCode::NOT_FOUND
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                status(request(Method::POST, "/hooks/secret", "not json")).await,
+                StatusCode::BAD_REQUEST
+            );
+        }
+        let large = format!(r#"{{"text": "{}"}}"#, "x".repeat(MAX_BODY));
+        assert_eq!(
+            status(request(Method::POST, "/hooks/open", large)).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        // Two posts a minute, including the failed ones.
+        assert_eq!(
+            status(request(Method::POST, "/hooks/secret", text)).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!(posts.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_diagnostics() {
+        let (shared, _posts) = shared();
+        for path in ["/topology", "/debug/state"] {
+            let response = route(&shared, request(Method::GET, path, Body::empty()))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[hyper::header::CONTENT_TYPE],
+                "application/json"
+            );
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        }
+        assert_eq!(
+            route(&shared, request(Method::POST, "/topology", Body::empty()))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn healthy_when_listening_with_enough_peers() {
+        assert!(Status::new(1, 1, 1).healthy);
+        assert!(Status::new(2, 5, 3).healthy);
+        assert!(!Status::new(0, 5, 1).healthy);
+        assert!(!Status::new(1, 0, 1).healthy);
+        assert!(!Status::new(1, 2, 3).healthy);
+        // Without a peer threshold, listening is enough.
+        assert!(Status::new(1, 0, 0).healthy);
+    }
+
+    #[tokio::test]
+    async fn serves_the_live_health() {
+        let (shared, _posts) = shared();
+        let shared = &shared;
+        let get = || async move {
+            let response = route(shared, request(Method::GET, "/health", Body::empty()))
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        assert_eq!(
+            get().await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                r#"{"healthy":false,"listen_addrs":0,"peers":0,"min_peers":2}"#.into()
+            )
+        );
+        *shared.health.lock().unwrap() = Status::new(1, 2, 2);
+        assert_eq!(
+            get().await,
+            (
+                StatusCode::OK,
+                r#"{"healthy":true,"listen_addrs":1,"peers":2,"min_peers":2}"#.into()
+            )
+        );
+    }
+}
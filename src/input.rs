@@ -0,0 +1,110 @@
+//! Lines read from stdin, whether typed, piped or redirected from a file or `/dev/null`.
+//!
+//! Lines are paced by [`PACING`]: a script piped in would otherwise be published in one burst,
+//! identical lines being dropped as duplicates by gossipsub and the rest flood-limited by peers.
+//! Nobody types that fast.
+use std::time::Duration;
+
+use tokio::{
+    io::{self, AsyncBufRead, AsyncBufReadExt},
+    sync::mpsc,
+    time::Instant,
+};
+
+/// Minimum time between lines.
+pub(crate) const PACING: Duration = Duration::from_millis(50);
+
+#[cfg_attr(not(windows), allow(dead_code))]
+enum Lines<R> {
+    Async(io::Lines<R>),
+    /// Read on the blocking thread pool, for consoles without async reads.
+    Blocking(mpsc::Receiver<std::io::Result<String>>),
+}
+
+pub(crate) struct Input<R> {
+    /// `None` at the end of the input.
+    lines: Option<Lines<R>>,
+    next_at: Instant,
+}
+
+impl Input<io::BufReader<io::Stdin>> {
+    pub(crate) fn stdin() -> Self {
+        #[cfg(windows)]
+        return Self::new(Lines::Blocking(blocking_stdin()));
+        #[cfg(not(windows))]
+        Self::new(Lines::Async(io::BufReader::new(io::stdin()).lines()))
+    }
+}
+
+#[cfg(windows)]
+fn blocking_stdin() -> mpsc::Receiver<std::io::Result<String>> {
+    use std::io::BufRead;
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        for line in std::io::stdin().lock().lines() {
+            if tx.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+impl<R: AsyncBufRead + Unpin> Input<R> {
+    fn new(lines: Lines<R>) -> Self {
+        Self {
+            lines: Some(lines),
+            next_at: Instant::now(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_reader(reader: R) -> Self {
+        Self::new(Lines::Async(reader.lines()))
+    }
+
+    /// The next line, `None` at the end of the input, after which it's pending forever. Cancel
+    /// safe.
+    pub(crate) async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        let lines = match &mut self.lines {
+            Some(lines) => lines,
+            None => return std::future::pending().await,
+        };
+        tokio::time::sleep_until(self.next_at).await;
+        let line = match lines {
+            Lines::Async(lines) => lines.next_line().await?,
+            Lines::Blocking(lines) => lines.recv().await.transpose()?,
+        };
+        match line {
+            Some(_) => self.next_at = Instant::now() + PACING,
+            None => self.lines = None,
+        }
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_lines_then_ends() {
+        let mut input = Input::from_reader(&b"hello\nhello\n/nick bob"[..]);
+        let start = Instant::now();
+        assert_eq!(input.next_line().await.unwrap().as_deref(), Some("hello"));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(input.next_line().await.unwrap().as_deref(), Some("hello"));
+        assert_eq!(start.elapsed(), PACING);
+        assert_eq!(
+            input.next_line().await.unwrap().as_deref(),
+            Some("/nick bob")
+        );
+        assert_eq!(start.elapsed(), 2 * PACING);
+        assert_eq!(input.next_line().await.unwrap(), None);
+
+        // Ended input doesn't spin.
+        let pending = tokio::time::timeout(Duration::from_secs(60), input.next_line()).await;
+        assert!(pending.is_err());
+    }
+}
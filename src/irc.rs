@@ -0,0 +1,371 @@
+//! Minimal IRC server bridging a handful of local IRC clients into agora.
+use std::{fmt, net::SocketAddr};
+
+use libp2p::PeerId;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+use tracing::*;
+
+use crate::shutdown::Tasks;
+
+const SERVER: &str = "agora";
+
+/// A single IRC protocol line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Message {
+    pub(crate) prefix: Option<String>,
+    pub(crate) command: String,
+    pub(crate) params: Vec<String>,
+}
+
+impl Message {
+    pub(crate) fn new(prefix: Option<String>, command: &str, params: Vec<String>) -> Self {
+        Self {
+            prefix,
+            command: command.into(),
+            params,
+        }
+    }
+
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+        let (prefix, rest) = match line.strip_prefix(':') {
+            Some(line) => {
+                let (prefix, rest) = line.split_once(' ')?;
+                (Some(prefix.to_string()), rest)
+            }
+            None => (None, line),
+        };
+        let (head, trailing) = match rest.strip_prefix(':') {
+            Some(trailing) => ("", Some(trailing)),
+            None => match rest.split_once(" :") {
+                Some((head, trailing)) => (head, Some(trailing)),
+                None => (rest, None),
+            },
+        };
+        let mut words = head.split_whitespace();
+        let command = words.next()?.to_ascii_uppercase();
+        let mut params = words.map(ToString::to_string).collect::<Vec<_>>();
+        params.extend(trailing.map(ToString::to_string));
+        Some(Self {
+            prefix,
+            command,
+            params,
+        })
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{} ", prefix)?;
+        }
+        write!(f, "{}", self.command)?;
+        if let Some((last, init)) = self.params.split_last() {
+            for p in init {
+                write!(f, " {}", p)?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{}", last)?;
+            } else {
+                write!(f, " {}", last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Requests from connected IRC clients, to be carried out by the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Request {
+    Nick(String),
+    Join(String),
+    Part(String),
+    Privmsg { channel: String, text: String },
+}
+
+/// Maps an IRC channel (`#agora`) to an agora channel (`agora`).
+pub(crate) fn channel_name(irc_channel: &str) -> String {
+    irc_channel.trim_start_matches('#').to_string()
+}
+
+/// IRC nicknames can't contain whitespace.
+pub(crate) fn nick(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+fn user_prefix(nick: &str, peer: &PeerId) -> Option<String> {
+    Some(format!("{}!{}@{}", self::nick(nick), peer, SERVER))
+}
+
+pub(crate) struct Gateway {
+    peer_id: PeerId,
+    channel: String,
+    requests: mpsc::UnboundedReceiver<Request>,
+    lines: broadcast::Sender<String>,
+}
+
+impl Gateway {
+    pub(crate) async fn bind(
+        addr: SocketAddr,
+        peer_id: PeerId,
+        channel: String,
+        tasks: &mut Tasks,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("IRC gateway listening on {}", listener.local_addr()?);
+        let (tx, requests) = mpsc::unbounded_channel();
+        let (lines, _) = broadcast::channel(1024);
+        let clients = lines.clone();
+        tasks.spawn(|shutdown| async move {
+            loop {
+                let accepted = tokio::select! {
+                    _ = shutdown.wait() => break,
+                    accepted = listener.accept() => accepted,
+                };
+                match accepted {
+                    Ok((stream, remote)) => {
+                        debug!(%remote, "IRC client connected");
+                        let (tx, rx) = (tx.clone(), clients.subscribe());
+                        tokio::spawn(async move {
+                            if let Err(error) = serve(stream, tx, rx).await {
+                                debug!(%remote, %error, "IRC client failed");
+                            }
+                        });
+                    }
+                    Err(error) => warn!(%error, "IRC gateway accept failed"),
+                }
+            }
+        });
+        Ok(Self {
+            peer_id,
+            channel,
+            requests,
+            lines,
+        })
+    }
+
+    fn send(&self, msg: Message) {
+        // No connected clients is not an error.
+        let _ = self.lines.send(msg.to_string());
+    }
+
+    pub(crate) fn privmsg(&self, nick: &str, peer: &PeerId, channel: &str, text: &str) {
+        for line in text.lines() {
+            self.send(Message::new(
+                user_prefix(nick, peer),
+                "PRIVMSG",
+                vec![format!("#{}", channel), line.into()],
+            ));
+        }
+    }
+
+    pub(crate) fn nick_changed(&self, old: &str, new: &str, peer: &PeerId) {
+        self.send(Message::new(
+            user_prefix(old, peer),
+            "NICK",
+            vec![nick(new)],
+        ));
+    }
+
+    pub(crate) fn joined(&self, nick: &str, peer: &PeerId) {
+        self.send(Message::new(
+            user_prefix(nick, peer),
+            "JOIN",
+            vec![format!("#{}", self.channel)],
+        ));
+    }
+
+    pub(crate) fn quit(&self, nick: &str, peer: &PeerId) {
+        self.send(Message::new(
+            user_prefix(nick, peer),
+            "QUIT",
+            vec!["disconnected".into()],
+        ));
+    }
+
+    /// Confirms a JOIN issued by a local client, followed by the channel's NAMES.
+    pub(crate) fn names<'a>(
+        &self,
+        own_nick: &str,
+        channel: &str,
+        members: impl IntoIterator<Item = &'a str>,
+    ) {
+        let own_nick = nick(own_nick);
+        let channel = format!("#{}", channel);
+        self.send(Message::new(
+            user_prefix(&own_nick, &self.peer_id),
+            "JOIN",
+            vec![channel.clone()],
+        ));
+        let mut names = vec![own_nick.clone()];
+        names.extend(members.into_iter().map(nick));
+        self.send(Message::new(
+            Some(SERVER.into()),
+            "353",
+            vec![
+                own_nick.clone(),
+                "=".into(),
+                channel.clone(),
+                names.join(" "),
+            ],
+        ));
+        self.send(Message::new(
+            Some(SERVER.into()),
+            "366",
+            vec![own_nick, channel, "End of /NAMES list".into()],
+        ));
+    }
+
+    /// Tells the local clients something about `channel`, e.g. that their message was queued.
+    pub(crate) fn notice(&self, channel: &str, text: &str) {
+        self.send(Message::new(
+            Some(SERVER.into()),
+            "NOTICE",
+            vec![format!("#{}", channel), text.into()],
+        ));
+    }
+
+    /// Answers a request about `channel` which failed with an error numeric, e.g. `403`
+    /// (ERR_NOSUCHCHANNEL).
+    pub(crate) fn error(&self, own_nick: &str, numeric: &str, channel: &str, text: &str) {
+        self.send(Message::new(
+            Some(SERVER.into()),
+            numeric,
+            vec![nick(own_nick), format!("#{}", channel), text.into()],
+        ));
+    }
+
+    pub(crate) async fn next_request(gateway: &mut Option<Self>) -> Option<Request> {
+        match gateway {
+            Some(gw) => gw.requests.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    requests: mpsc::UnboundedSender<Request>,
+    mut lines: broadcast::Receiver<String>,
+) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read).lines();
+    let mut nick = None;
+    let mut user = false;
+    let mut registered = false;
+    loop {
+        tokio::select! {
+            line = read.next_line() => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => break,
+                };
+                let msg = match Message::parse(&line) {
+                    Some(msg) => msg,
+                    None => continue,
+                };
+                trace!(?msg, "IRC client");
+                match (msg.command.as_str(), &msg.params[..]) {
+                    ("NICK", [n, ..]) => {
+                        nick = Some(n.clone());
+                        let _ = requests.send(Request::Nick(n.clone()));
+                    }
+                    ("USER", _) => user = true,
+                    ("PING", params) => {
+                        let pong = Message::new(Some(SERVER.into()), "PONG", params.to_vec());
+                        write.write_all(format!("{}\r\n", pong).as_bytes()).await?;
+                    }
+                    ("JOIN", [channels, ..]) => {
+                        for c in channels.split(',') {
+                            let _ = requests.send(Request::Join(channel_name(c)));
+                        }
+                    }
+                    ("PART", [channels, ..]) => {
+                        for c in channels.split(',') {
+                            let _ = requests.send(Request::Part(channel_name(c)));
+                        }
+                    }
+                    ("PRIVMSG", [target, text]) if target.starts_with('#') => {
+                        let _ = requests.send(Request::Privmsg {
+                            channel: channel_name(target),
+                            text: text.clone(),
+                        });
+                    }
+                    ("QUIT", _) => break,
+                    _ => debug!(?msg, "Unhandled IRC command"),
+                }
+                if let (false, true, Some(nick)) = (registered, user, &nick) {
+                    let welcome = Message::new(
+                        Some(SERVER.into()),
+                        "001",
+                        vec![nick.clone(), "Welcome to agora".into()],
+                    );
+                    write.write_all(format!("{}\r\n", welcome).as_bytes()).await?;
+                    registered = true;
+                }
+            }
+            line = lines.recv() => match line {
+                Ok(line) => write.write_all(format!("{}\r\n", line).as_bytes()).await?,
+                Err(broadcast::error::RecvError::Lagged(n)) => warn!(n, "IRC client lagging behind"),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_with_trailing() {
+        let msg = Message::parse("PRIVMSG #agora :hello there\r\n").unwrap();
+        assert_eq!(msg.prefix, None);
+        assert_eq!(msg.command, "PRIVMSG");
+        assert_eq!(msg.params, vec!["#agora", "hello there"]);
+    }
+
+    #[test]
+    fn parse_prefix_and_lowercase_command() {
+        let msg = Message::parse(":nick!user@host join #a,#b").unwrap();
+        assert_eq!(msg.prefix.as_deref(), Some("nick!user@host"));
+        assert_eq!(msg.command, "JOIN");
+        assert_eq!(msg.params, vec!["#a,#b"]);
+    }
+
+    #[test]
+    fn parse_only_trailing() {
+        let msg = Message::parse("QUIT :gone fishing").unwrap();
+        assert_eq!(msg.params, vec!["gone fishing"]);
+        let msg = Message::parse("PING :").unwrap();
+        assert_eq!(msg.params, vec![""]);
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        assert_eq!(Message::parse(""), None);
+        assert_eq!(Message::parse(":prefix-only"), None);
+    }
+
+    #[test]
+    fn roundtrip() {
+        for line in [
+            ":agora 353 me = #agora :me you",
+            ":a!b@agora PRIVMSG #agora hi",
+            "PONG agora",
+        ] {
+            assert_eq!(Message::parse(line).unwrap().to_string(), line);
+        }
+    }
+
+    #[test]
+    fn names_and_channels() {
+        assert_eq!(channel_name("#agora"), "agora");
+        assert_eq!(nick("some body"), "some_body");
+    }
+}
@@ -0,0 +1,394 @@
+//! Keeps connections to important peers (bootstrap nodes, relays, trusted peers) and to peers
+//! sharing a subscribed topic with us open, while connections to others close once no
+//! sub-behaviour has anything left to do on them.
+//!
+//! Connection handlers are built as a connection is established, so a peer's importance is
+//! decided then: marking a peer important keeps its later connections alive, not the ones it
+//! has already. The peers of the loaded profile are the exception, replaced along with it, see
+//! [`KeepAlivePeers::set_profile`]. Sharing a topic is passed on to the handlers whenever it
+//! changes. Keeping a connection alive doesn't redial a peer which disconnected.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use libp2p::{
+    core::{connection::ConnectionId, upgrade::DeniedUpgrade, ConnectedPoint},
+    swarm::{
+        ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, IntoConnectionHandler,
+        KeepAlive, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters,
+        SubstreamProtocol,
+    },
+    Multiaddr, PeerId,
+};
+use void::Void;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Targets {
+    pub(crate) peers: HashSet<PeerId>,
+    /// Addresses of bootstrap nodes whose peer id isn't known upfront.
+    pub(crate) addresses: HashSet<Multiaddr>,
+}
+
+impl Targets {
+    fn contains(&self, peer: &PeerId, endpoint: &ConnectedPoint) -> bool {
+        self.peers.contains(peer)
+            || matches!(endpoint, ConnectedPoint::Dialer { address, .. } if self.addresses.contains(address))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Important {
+    always: Targets,
+    /// Of the loaded profile.
+    profile: Targets,
+}
+
+impl Important {
+    fn contains(&self, peer: &PeerId, endpoint: &ConnectedPoint) -> bool {
+        self.always.contains(peer, endpoint) || self.profile.contains(peer, endpoint)
+    }
+}
+
+/// Told to the handlers when it changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Update {
+    /// Whether the remote shares a topic.
+    Sharing(bool),
+    Important(bool),
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct KeepAlivePeers {
+    important: Arc<Important>,
+    /// Connected peers subscribed to a topic we're subscribed to as well.
+    sharing: HashSet<PeerId>,
+    /// Established connections, with whether their remote was important as of the last update.
+    connections: HashMap<ConnectionId, (PeerId, ConnectedPoint, bool)>,
+    events: VecDeque<NetworkBehaviourAction<Void, IntoKeepAliveHandler>>,
+    /// Of the last [`NetworkBehaviour::poll`], as sharing may change outside of the swarm's
+    /// polling.
+    waker: Option<Waker>,
+}
+
+impl KeepAlivePeers {
+    pub(crate) fn insert_peer(&mut self, peer: PeerId) {
+        Arc::make_mut(&mut self.important).always.peers.insert(peer);
+    }
+
+    /// Connections dialed to `address`. Use [`KeepAlivePeers::insert_peer`] instead if it ends
+    /// in `/p2p/<peer id>`, to cover connections opened by the peer as well.
+    pub(crate) fn insert_address(&mut self, address: Multiaddr) {
+        Arc::make_mut(&mut self.important)
+            .always
+            .addresses
+            .insert(address);
+    }
+
+    pub(crate) fn is_important(&self, peer: &PeerId) -> bool {
+        self.important.always.peers.contains(peer) || self.important.profile.peers.contains(peer)
+    }
+
+    /// Replaces the peers of the loaded profile. Unlike the others, this applies to the
+    /// established connections as well, releasing those to the previous profile's peers.
+    pub(crate) fn set_profile(&mut self, targets: Targets) {
+        if self.important.profile == targets {
+            return;
+        }
+        Arc::make_mut(&mut self.important).profile = targets;
+        for (connection, (peer, endpoint, important)) in &mut self.connections {
+            let now_important = self.important.contains(peer, endpoint);
+            if *important != now_important {
+                *important = now_important;
+                self.events
+                    .push_back(NetworkBehaviourAction::NotifyHandler {
+                        peer_id: *peer,
+                        handler: NotifyHandler::One(*connection),
+                        event: Update::Important(now_important),
+                    });
+            }
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Keeps the connections to `peer` alive while it `shares` a topic with us.
+    pub(crate) fn set_sharing(&mut self, peer: PeerId, shares: bool) {
+        let changed = match shares {
+            true => self.sharing.insert(peer),
+            false => self.sharing.remove(&peer),
+        };
+        if changed {
+            self.events
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: peer,
+                    handler: NotifyHandler::All,
+                    event: Update::Sharing(shares),
+                });
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Builds a handler keeping the connection alive if the remote is important.
+#[derive(Debug)]
+pub(crate) struct IntoKeepAliveHandler {
+    important: Arc<Important>,
+}
+
+impl IntoConnectionHandler for IntoKeepAliveHandler {
+    type Handler = KeepAliveHandler;
+
+    fn into_handler(self, remote: &PeerId, endpoint: &ConnectedPoint) -> Self::Handler {
+        KeepAliveHandler {
+            important: self.important.contains(remote, endpoint),
+            sharing: false,
+        }
+    }
+
+    fn inbound_protocol(&self) -> DeniedUpgrade {
+        DeniedUpgrade
+    }
+}
+
+/// Keeps its connection alive while the remote is important or shares a topic, told by
+/// [`KeepAlivePeers::set_sharing`] and [`KeepAlivePeers::set_profile`]. Opens no substreams.
+#[derive(Debug)]
+pub(crate) struct KeepAliveHandler {
+    important: bool,
+    sharing: bool,
+}
+
+impl ConnectionHandler for KeepAliveHandler {
+    type InEvent = Update;
+    type OutEvent = Void;
+    type Error = Void;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = Void;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<DeniedUpgrade, ()> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn inject_fully_negotiated_inbound(&mut self, protocol: Void, _: ()) {
+        void::unreachable(protocol)
+    }
+
+    fn inject_fully_negotiated_outbound(&mut self, protocol: Void, _: Void) {
+        void::unreachable(protocol)
+    }
+
+    fn inject_event(&mut self, update: Update) {
+        match update {
+            Update::Sharing(sharing) => self.sharing = sharing,
+            Update::Important(important) => self.important = important,
+        }
+    }
+
+    fn inject_dial_upgrade_error(&mut self, info: Void, _: ConnectionHandlerUpgrErr<Void>) {
+        void::unreachable(info)
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        match self.important || self.sharing {
+            true => KeepAlive::Yes,
+            false => KeepAlive::No,
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<DeniedUpgrade, Void, Void, Void>> {
+        Poll::Pending
+    }
+}
+
+impl NetworkBehaviour for KeepAlivePeers {
+    type ConnectionHandler = IntoKeepAliveHandler;
+    type OutEvent = Void;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        IntoKeepAliveHandler {
+            important: self.important.clone(),
+        }
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        _: Option<&Vec<Multiaddr>>,
+        _: usize,
+    ) {
+        // The handler may have been built before the profile changed.
+        let important = self.important.contains(peer, endpoint);
+        self.connections
+            .insert(*connection, (*peer, endpoint.clone(), important));
+        self.events
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id: *peer,
+                handler: NotifyHandler::One(*connection),
+                event: Update::Important(important),
+            });
+        // A further connection to a peer already sharing a topic.
+        if self.sharing.contains(peer) {
+            self.events
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: *peer,
+                    handler: NotifyHandler::One(*connection),
+                    event: Update::Sharing(true),
+                });
+        }
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer: &PeerId,
+        connection: &ConnectionId,
+        _: &ConnectedPoint,
+        _: KeepAliveHandler,
+        remaining_established: usize,
+    ) {
+        self.connections.remove(connection);
+        if remaining_established == 0 {
+            self.sharing.remove(peer);
+        }
+    }
+
+    fn inject_event(&mut self, _: PeerId, _: ConnectionId, event: Void) {
+        void::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::core::Endpoint;
+
+    use super::*;
+
+    fn keep_alive(peers: &mut KeepAlivePeers, peer: &PeerId, endpoint: &ConnectedPoint) -> bool {
+        let handler = peers.new_handler().into_handler(peer, endpoint);
+        handler.connection_keep_alive() == KeepAlive::Yes
+    }
+
+    #[test]
+    fn keeps_important_peers_alive() {
+        let [trusted, ordinary, bootstrap] = [(); 3].map(|_| PeerId::random());
+        let bootstrap_addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let dialed = |address: &Multiaddr| ConnectedPoint::Dialer {
+            address: address.clone(),
+            role_override: Endpoint::Dialer,
+        };
+        let listened = ConnectedPoint::Listener {
+            local_addr: "/ip4/0.0.0.0/tcp/4001".parse().unwrap(),
+            send_back_addr: "/ip4/10.0.0.2/tcp/50000".parse().unwrap(),
+        };
+        let other_addr: Multiaddr = "/ip4/10.0.0.3/tcp/4001".parse().unwrap();
+
+        let mut peers = KeepAlivePeers::default();
+        // Handed out before, so it's unaffected.
+        let earlier = peers.new_handler();
+        peers.insert_peer(trusted);
+        peers.insert_address(bootstrap_addr.clone());
+
+        assert!(keep_alive(&mut peers, &trusted, &listened));
+        assert!(keep_alive(&mut peers, &trusted, &dialed(&other_addr)));
+        assert!(keep_alive(&mut peers, &bootstrap, &dialed(&bootstrap_addr)));
+        assert!(!keep_alive(&mut peers, &ordinary, &listened));
+        assert!(!keep_alive(&mut peers, &ordinary, &dialed(&other_addr)));
+        assert_eq!(
+            earlier
+                .into_handler(&trusted, &listened)
+                .connection_keep_alive(),
+            KeepAlive::No
+        );
+    }
+
+    #[test]
+    fn keeps_peers_sharing_a_topic_alive() {
+        let peer = PeerId::random();
+        let mut peers = KeepAlivePeers::default();
+        let listened = ConnectedPoint::Listener {
+            local_addr: "/ip4/0.0.0.0/tcp/4001".parse().unwrap(),
+            send_back_addr: "/ip4/10.0.0.2/tcp/50000".parse().unwrap(),
+        };
+        let mut handler = peers.new_handler().into_handler(&peer, &listened);
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::No);
+
+        peers.set_sharing(peer, true);
+        // Unchanged, not notified again.
+        peers.set_sharing(peer, true);
+        assert_eq!(peers.events.len(), 1);
+        match peers.events.pop_front() {
+            Some(NetworkBehaviourAction::NotifyHandler { peer_id, event, .. }) => {
+                assert_eq!(peer_id, peer);
+                handler.inject_event(event);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::Yes);
+
+        peers.set_sharing(peer, false);
+        match peers.events.pop_front() {
+            Some(NetworkBehaviourAction::NotifyHandler { event, .. }) => {
+                handler.inject_event(event)
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::No);
+    }
+
+    #[test]
+    fn releases_the_previous_profiles_peers() {
+        let [peer, other] = [(); 2].map(|_| PeerId::random());
+        let listened = ConnectedPoint::Listener {
+            local_addr: "/ip4/0.0.0.0/tcp/4001".parse().unwrap(),
+            send_back_addr: "/ip4/10.0.0.2/tcp/50000".parse().unwrap(),
+        };
+        let profile = |peer| Targets {
+            peers: HashSet::from([peer]),
+            ..Default::default()
+        };
+        let mut peers = KeepAlivePeers::default();
+        let mut handler = peers.new_handler().into_handler(&peer, &listened);
+        peers.set_profile(profile(peer));
+        peers.inject_connection_established(&peer, &ConnectionId::new(1), &listened, None, 0);
+        let mut update = |peers: &mut KeepAlivePeers| match peers.events.pop_front() {
+            Some(NetworkBehaviourAction::NotifyHandler { event, .. }) => {
+                handler.inject_event(event);
+                handler.connection_keep_alive()
+            }
+            other => panic!("{:?}", other),
+        };
+        // Built before the profile was set.
+        assert_eq!(update(&mut peers), KeepAlive::Yes);
+
+        peers.set_profile(profile(other));
+        assert_eq!(update(&mut peers), KeepAlive::No);
+        assert!(peers.events.is_empty());
+        peers.set_profile(profile(peer));
+        assert_eq!(update(&mut peers), KeepAlive::Yes);
+    }
+}
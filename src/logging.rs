@@ -0,0 +1,111 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use anyhow::Context;
+use tracing::{level_filters::LevelFilter, Level, Subscriber};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter, Layer, Registry};
+
+/// Diagnostics go to stderr or a file, never to stdout where the chat is rendered.
+#[derive(clap::Args, Debug, Default)]
+pub(crate) struct LogArgs {
+    /// Log level [error, warn, info, debug, trace], defaults to `RUST_LOG` or info
+    #[clap(long, global = true)]
+    log_level: Option<Level>,
+
+    /// Per-target log levels, e.g. `libp2p_gossipsub=debug`
+    #[clap(long, global = true, multiple_occurrences = true)]
+    log_filter: Vec<String>,
+
+    /// Log as JSON
+    #[clap(long, global = true)]
+    log_json: bool,
+
+    /// Append logs to this file instead of stderr
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    #[cfg(feature = "otlp")]
+    #[clap(flatten)]
+    otlp: crate::otlp::OtlpArgs,
+}
+
+impl LogArgs {
+    fn subscriber(&self) -> anyhow::Result<impl Subscriber + Send + Sync> {
+        let mut filter = match self.log_level {
+            Some(level) => {
+                EnvFilter::default().add_directive(LevelFilter::from_level(level).into())
+            }
+            None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        };
+        for directive in &self.log_filter {
+            filter = filter.add_directive(
+                directive
+                    .parse()
+                    .with_context(|| format!("Invalid log filter {}", directive))?,
+            );
+        }
+
+        let layer: Box<dyn Layer<Registry> + Send + Sync> = match &self.log_file {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Opening log file {}", path.display()))?;
+                let layer = fmt::layer().with_writer(Mutex::new(file)).with_ansi(false);
+                if self.log_json {
+                    Box::new(layer.json())
+                } else {
+                    Box::new(layer)
+                }
+            }
+            None => {
+                let layer = fmt::layer().with_writer(std::io::stderr);
+                if self.log_json {
+                    Box::new(layer.json())
+                } else {
+                    Box::new(layer)
+                }
+            }
+        };
+        // The filter only applies to the log output, exported spans are sampled instead.
+        let subscriber = tracing_subscriber::registry().with(layer.with_filter(filter));
+        #[cfg(feature = "otlp")]
+        let subscriber = subscriber.with(self.otlp.layer()?);
+        Ok(subscriber)
+    }
+
+    pub(crate) fn init(&self) -> anyhow::Result<()> {
+        tracing::subscriber::set_global_default(self.subscriber()?)?;
+        Ok(())
+    }
+}
+
+/// Flushes outstanding diagnostics before exiting.
+pub(crate) fn shutdown() {
+    #[cfg(feature = "otlp")]
+    crate::otlp::shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_logger_receives_events() {
+        let path = std::env::temp_dir().join(format!("agora-log-test-{}", std::process::id()));
+        let args = LogArgs {
+            log_level: Some(Level::DEBUG),
+            log_filter: vec!["agora=trace".into()],
+            log_file: Some(path.clone()),
+            ..Default::default()
+        };
+        tracing::subscriber::with_default(args.subscriber().unwrap(), || {
+            tracing::debug!("hello log file");
+            tracing::trace!(target: "other", "filtered");
+        });
+        let logged = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(logged.contains("hello log file"));
+        assert!(!logged.contains("filtered"));
+    }
+}
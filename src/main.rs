@@ -1,26 +1,202 @@
 use std::{
-    collections::{BTreeMap, BTreeSet},
-    time::Duration,
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
-use ::libp2p::{futures::StreamExt, gossipsub, swarm::SwarmEvent, Multiaddr};
-use anyhow::Context;
-use clap::Parser;
-use libp2p::{
-    gossipsub::{Hasher, Topic},
-    PeerId,
+use ::libp2p::{
+    core::ConnectedPoint,
+    futures::StreamExt,
+    gossipsub,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        DialError, SwarmEvent,
+    },
+    Multiaddr,
 };
+use anyhow::{anyhow, Context as _};
+use clap::{Parser, Subcommand};
+use libp2p::{identity::Keypair, PeerId, Swarm};
 use tokio::io::{self, AsyncBufReadExt};
 use tracing::*;
 
+use command::Command;
+#[cfg(feature = "http-api")]
+use http::HttpApi;
 use p2p::{Behaviour, BehaviourEvent, SwarmError};
+use publish::{publish, publish_all, publish_chat, Published};
+#[cfg(feature = "scripting")]
+use scripting::Scripts;
+use shutdown::ShutdownReason;
+use state::State;
 
+mod address_book;
+mod alert;
 mod api;
+mod audit;
+mod cidr;
+mod clock;
+mod command;
+mod config;
+mod connection_log;
+mod control;
+mod crypt;
+mod diagnostics;
+mod discovery;
+mod display;
+mod dm;
+mod dm_requests;
+mod dnd;
+mod encode;
+mod fingerprint;
+mod heartbeat;
+mod history;
+#[cfg(feature = "http-api")]
+mod http;
+mod input;
+mod irc;
+mod keep_alive;
+mod logging;
+mod mesh;
+mod mqtt;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod outbox;
 mod p2p;
+mod peers;
+mod preview;
+mod profile;
+#[cfg(feature = "profiling")]
+mod profiling;
+mod publish;
+mod rejections;
+mod replay;
+mod retry;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod shutdown;
+mod state;
+mod tcp;
+mod template;
+mod topology;
+mod transcript;
+mod verified;
+mod webhook;
 
 /// Chat with your peers
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Commands>,
+
+    #[clap(flatten)]
+    log: logging::LogArgs,
+
+    #[cfg(feature = "profiling")]
+    #[clap(flatten)]
+    profile: profiling::ProfileArgs,
+
+    /// Arguments of `chat`, the default command
+    #[clap(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Chat interactively (default)
+    Chat(Args),
+    /// Send a single message and exit
+    Send {
+        #[clap(flatten)]
+        args: Args,
+
+        /// Message to send
+        #[clap(short, long)]
+        message: String,
+
+        /// Seconds to wait for a peer in the channel, or `--min-peers` in its mesh
+        #[clap(long, default_value_t = 10)]
+        timeout: u64,
+
+        /// Only send once this many peers are in the channel's mesh, and fail unless the message
+        /// reached as many
+        #[clap(long, default_value_t = 0)]
+        min_peers: usize,
+
+        /// Milliseconds to keep running after sending, for the message to go out and settle
+        #[clap(long, default_value_t = 1000)]
+        settle_ms: u64,
+    },
+    /// Print the channel's messages without reading stdin
+    Listen(Args),
+    /// Run a headless node strengthening the mesh, e.g. as an always-on bootstrap node
+    Node {
+        #[clap(flatten)]
+        args: Args,
+
+        #[clap(flatten)]
+        node: NodeArgs,
+    },
+    /// List the channel's peers and exit
+    Peers {
+        #[clap(flatten)]
+        args: Args,
+
+        /// Seconds to wait for peers to be discovered (and announce their nicknames)
+        #[clap(long, default_value_t = 11)]
+        wait: u64,
+    },
+    /// Generate an identity keypair, to be used with `--identity`
+    GenerateIdentity {
+        /// File to write the keypair to
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+    /// Print version information
+    Version,
+}
+
+impl Commands {
+    fn args(&self) -> Option<&Args> {
+        match self {
+            Self::Chat(args) | Self::Listen(args) => Some(args),
+            Self::Send { args, .. } | Self::Node { args, .. } | Self::Peers { args, .. } => {
+                Some(args)
+            }
+            Self::GenerateIdentity { .. } | Self::Version => None,
+        }
+    }
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ValidationMode {
+    Permissive,
+    Author,
+    Strict,
+}
+
+#[derive(clap::Args, Debug)]
+struct NodeArgs {
+    /// Further channel to subscribe to (repeatable)
+    #[clap(long, multiple_occurrences = true)]
+    join: Vec<String>,
+
+    /// Print the channels' messages
+    #[clap(long)]
+    render: bool,
+
+    /// Close connections to peers beyond this many
+    #[clap(long, default_value_t = 256)]
+    max_peers: usize,
+
+    /// Seconds between logging the number of connected peers
+    #[clap(long, default_value_t = 60)]
+    status_interval: u64,
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Your name
     #[clap(short, long, default_value_t = random_name())]
@@ -30,151 +206,3700 @@ struct Args {
     #[clap(short, long, default_value = "agora")]
     channel: String,
 
+    /// Pattern the names of all channels joined must match
+    #[clap(long, default_value = DEFAULT_CHANNEL_NAME_REGEX)]
+    channel_name_regex: regex::Regex,
+
     /// Channel to join
     #[clap(short, long)]
     bootstrap: Option<Multiaddr>,
+
+    /// Known peer to dial, redialed whenever disconnected, as an address ending in
+    /// `/p2p/<peer id>` (repeatable). Connections to it are kept alive
+    #[clap(long, multiple_occurrences = true)]
+    peer: Vec<discovery::PeerAddr>,
+
+    /// Address to listen on
+    #[clap(long, default_value = "/ip4/0.0.0.0/tcp/0")]
+    listen: Multiaddr,
+
+    /// Run a local IRC server on this address (e.g. 127.0.0.1:6667)
+    #[clap(long)]
+    irc_gateway: Option<SocketAddr>,
+
+    /// POST received messages as JSON to this URL
+    #[clap(long)]
+    webhook_url: Option<hyper::Uri>,
+
+    /// Also POST joins and leaves to the webhook
+    #[clap(long, requires = "webhook-url")]
+    webhook_events: bool,
+
+    /// Shared secret to sign webhook payloads with (HMAC-SHA256)
+    #[clap(long, requires = "webhook-url")]
+    webhook_secret: Option<String>,
+
+    /// Only connect to and accept messages from this peer (repeatable)
+    #[clap(long, multiple_occurrences = true)]
+    allow_peer: Vec<PeerId>,
+
+    /// Only connect to and accept messages from the peers listed in this file
+    #[clap(long)]
+    allowlist: Option<PathBuf>,
+
+    /// Keep connections to this peer open even when idle (repeatable). The bootstrap node and
+    /// allowlisted peers are kept alive as well, connections to other peers close once idle
+    #[clap(long, multiple_occurrences = true)]
+    keep_alive: Vec<PeerId>,
+
+    /// Don't dial discovered loopback addresses (127.0.0.0/8 and ::1), e.g. of other instances
+    /// on this host
+    #[clap(long)]
+    no_local_discovery: bool,
+
+    /// Don't discover peers on the local network with mDNS, only dial --bootstrap, --peer and
+    /// the config's `peers`
+    #[clap(long)]
+    no_mdns: bool,
+
+    /// Don't dial discovered addresses in this prefix, e.g. 10.0.0.0/8 (repeatable)
+    #[clap(long, multiple_occurrences = true)]
+    ignore_addr_prefix: Vec<cidr::Cidr>,
+
+    /// Only accept and forward the channel's messages from the peers listed in this file, one
+    /// peer id per line (reload with `/members reload`)
+    #[clap(long)]
+    members: Option<PathBuf>,
+
+    /// Forget nicknames of peers not seen for this many hours
+    #[clap(long, default_value_t = 24)]
+    nickname_gc_hours: u64,
+
+    /// Encrypt the channels' messages with a key derived from this passphrase
+    #[clap(long)]
+    channel_key: Option<String>,
+
+    /// Exchange group keys with the channel's peers, again whenever peers join (experimental:
+    /// the keys aren't used for encryption yet)
+    #[clap(long)]
+    e2e_encrypt: bool,
+
+    /// Keypair file (see `generate-identity`), a new identity is generated if omitted
+    #[clap(short, long)]
+    identity: Option<PathBuf>,
+
+    /// Profile saved by `/profile save <name>`, in place of --identity, --bootstrap, --peer,
+    /// --channel, --private-topic, --listen and the TCP keep-alive settings
+    #[clap(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = &["identity", "bootstrap", "peer", "channel", "private-topic", "listen"]
+    )]
+    profile: Option<String>,
+
+    /// Further channels to join, of the --profile
+    #[clap(skip)]
+    channels: Vec<String>,
+
+    /// Serve the HTTP API (incoming webhooks, mesh topology, health checks) on this address
+    #[cfg(feature = "http-api")]
+    #[clap(long)]
+    http_listen: Option<SocketAddr>,
+
+    /// Directory of Lua scripts to load [default: ~/.config/agora/scripts]
+    #[cfg(feature = "scripting")]
+    #[clap(long, value_name = "DIR")]
+    scripts: Option<PathBuf>,
+
+    /// Peers needed to be healthy: the HTTP API answers `GET /health` with 200 while listening
+    /// and connected to at least this many peers, 503 otherwise
+    #[clap(long, default_value_t = 1)]
+    health_min_peers: usize,
+
+    /// Accept control clients, sending the lines otherwise typed on stdin, on this address
+    #[clap(long, requires = "control-token")]
+    control_tcp: Option<SocketAddr>,
+
+    /// Token control clients authenticate with (`AUTH <token>`)
+    #[clap(long)]
+    control_token: Option<String>,
+
+    /// Configuration file (TOML)
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Keep recent messages and send them to peers joining later
+    #[clap(long)]
+    serve_history: bool,
+
+    /// On joining, ask peers serving history for the messages of the last this many minutes
+    #[clap(long)]
+    history_since: Option<i64>,
+
+    /// Timestamp sent messages by the wall-clock at startup advanced by a monotonic clock, so
+    /// they keep increasing if the wall-clock jumps back. The wall-clock time is sent as well,
+    /// for display.
+    #[clap(long)]
+    monotonic_timestamps: bool,
+
+    /// Print `/topology` as pipe separated fields: channel, mesh, fanout and all peers, each a
+    /// comma separated list of peer ids
+    #[clap(long)]
+    machine_readable: bool,
+
+    /// File keeping the peers' latest message sequences across restarts, so messages captured
+    /// before can't be replayed then [default: ~/.local/share/agora/replay-state]
+    #[clap(long)]
+    replay_state: Option<PathBuf>,
+
+    /// Drop signed messages without a sequence, as sent by builds before replay protection.
+    /// Off by default for the transition, as those builds' messages would be dropped otherwise
+    #[clap(long)]
+    require_sequence: bool,
+
+    /// Append suspicious activity (invalid signatures, replays, messages over the limits,
+    /// moderation, ...) to this file, see `/audit tail`
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+
+    /// On SIGUSR1, write the diagnostic dump of the state and mesh to this file instead of stderr
+    #[clap(long)]
+    sigusr1_dump_file: Option<PathBuf>,
+
+    /// File remembering peers verified with `/verify`
+    #[clap(long)]
+    verified_peers: Option<PathBuf>,
+
+    /// Also close the connections to peers blocked with `/block`, and redial them once unblocked.
+    /// Off by default, as it may thin out the mesh
+    #[clap(long)]
+    block_disconnects: bool,
+
+    /// Hold direct messages of peers never chatted with until accepted with `/accept-dm`
+    #[clap(long)]
+    approve_dms: bool,
+
+    /// Drop messages lacking a signature, source or sequence number (gossipsub's strict
+    /// validation) instead of showing them as unsigned. Short for `--validation-mode strict`
+    #[clap(long, conflicts_with_all = &["i-know-permissive", "validation-mode"])]
+    strict_validation: bool,
+
+    /// How received messages are validated: `permissive` shows unsigned messages as such,
+    /// `author` additionally pins nicknames to the key of the first peer using them (or to those
+    /// of `--pinned-keys-file`) and drops them from any other, `strict` drops unsigned messages
+    #[clap(long, arg_enum)]
+    validation_mode: Option<ValidationMode>,
+
+    /// JSON map of nicknames to the base64 encoded public keys of the only peers allowed to use
+    /// them (`--validation-mode author`)
+    #[clap(long)]
+    pinned_keys_file: Option<PathBuf>,
+
+    /// Don't warn about permissive validation at startup
+    #[clap(long)]
+    i_know_permissive: bool,
+
+    /// Use hashed topics, hiding the channels' names from outsiders (all peers need to set it)
+    #[clap(long)]
+    private_topic: bool,
+
+    /// Idle seconds before the OS sends TCP keep-alive probes, keeping connections through
+    /// firewalls dropping idle ones (0 to disable). Unlike the libp2p pings of the `ping`
+    /// feature, these are sent by the kernel below libp2p.
+    #[clap(long, default_value_t = 60, value_name = "SECS")]
+    tcp_keepalive_secs: u64,
+
+    /// Seconds between unanswered TCP keep-alive probes (TCP_KEEPINTVL), where supported
+    #[clap(long, value_name = "SECS")]
+    tcp_keepalive_interval_secs: Option<u64>,
+
+    /// Unanswered TCP keep-alive probes before a connection is dropped (TCP_KEEPCNT), where
+    /// supported
+    #[clap(long, value_name = "N")]
+    tcp_keepalive_probes: Option<u32>,
+
+    /// Display structured events of bridges and bots
+    #[clap(long)]
+    show_meta_events: bool,
+
+    /// Show dials and incoming connections, and where they failed, at most 20 lines per 10s
+    #[clap(long)]
+    verbose_connections: bool,
+
+    /// Show payloads which aren't agora messages as plain UTF-8 chat lines, as sent by libp2p's
+    /// gossipsub chat examples
+    #[clap(long)]
+    compat_plaintext: bool,
+
+    /// Render messages sent longer ago than this many minutes dimmed and marked as delayed
+    #[clap(long, default_value_t = 10)]
+    delayed_after_mins: i64,
+
+    /// Don't render messages sent longer ago than this many hours at all; they're still kept in
+    /// the history and passed on to bridges and webhooks
+    #[clap(long, default_value_t = 24)]
+    stale_after_hours: i64,
+
+    /// Render messages as received, instead of holding them briefly to render them in the order
+    /// they were sent
+    #[clap(long)]
+    no_reorder: bool,
+
+    /// Show times in UTC rather than local time
+    #[clap(long)]
+    utc_times: bool,
+
+    /// Show times as they were, how long ago, or both
+    #[clap(long, arg_enum, default_value = "absolute")]
+    time_style: display::TimeStyle,
+
+    /// Exit once no line was read from stdin for this many seconds, counting from the first line
+    /// or the first connection, whichever is later (0 to never exit)
+    #[clap(long, default_value_t = 0)]
+    stdin_timeout: u64,
+
+    /// Exit at the end of stdin, e.g. of a piped script, instead of receiving on
+    #[clap(long)]
+    exit_on_stdin_eof: bool,
+
+    /// Show the titles of pages linked in messages (only http:// links). Never when listening
+    #[clap(long)]
+    link_previews: bool,
+
+    /// Only preview links to this domain or its subdomains (repeatable)
+    #[clap(long, multiple_occurrences = true, requires = "link-previews")]
+    preview_allow_domain: Vec<String>,
+
+    /// Never preview links to this domain or its subdomains (repeatable)
+    #[clap(long, multiple_occurrences = true, requires = "link-previews")]
+    preview_deny_domain: Vec<String>,
+
+    /// Serialize messages of at least this many bytes off the async executor
+    #[clap(long, default_value_t = 8 * 1024)]
+    async_encode_threshold: usize,
 }
 
-fn random_name() -> String {
-    names::Generator::default().next().unwrap()
+const DEFAULT_CHANNEL_NAME_REGEX: &str = "^[a-zA-Z0-9_-]{1,64}$";
+
+/// A channel name not matching `--channel-name-regex`.
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid channel name: must match {0}")]
+struct InvalidChannelName(String);
+
+fn check_channel_name(pattern: &regex::Regex, channel: &str) -> Result<(), InvalidChannelName> {
+    match pattern.is_match(channel) {
+        true => Ok(()),
+        false => Err(InvalidChannelName(pattern.as_str().into())),
+    }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+/// Delay before announcing ourselves to peers newly joined to our channels, so that a burst of
+/// joins results in a single announcement.
+const ANNOUNCE_DEBOUNCE: Duration = Duration::from_secs(1);
 
-    tracing_subscriber::fmt::init();
-    debug!("{:#?}", args);
+/// Announce ourselves every this many ticks (about 10 minutes) regardless, in case an
+/// announcement was missed.
+const ANNOUNCE_EVERY_TICKS: u64 = 60;
 
-    let mut swarm = Behaviour::bootstrap().await?;
+/// Run [`State::gc`] every this many ticks.
+const GC_EVERY_TICKS: u64 = 10;
 
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+/// How long the swarm is still driven when exiting at the end of stdin or on `/quit`, so messages
+/// published by the last lines go out.
+const FLUSH_GRACE: Duration = Duration::from_millis(500);
 
-    let topic = gossipsub::IdentTopic::new(args.channel);
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+/// Stand-in for the HTTP API when built without it, never constructed.
+#[cfg(not(feature = "http-api"))]
+enum HttpApi {}
 
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-    let mut state = Default::default();
-    let mut ticker = tokio::time::interval(Duration::from_secs(10));
-    let msg_nickname = serde_cbor::to_vec(&api::ChatApi::ChangeNickname { nick: args.name })
-        .expect("Serialization works");
+#[cfg(not(feature = "http-api"))]
+impl HttpApi {
+    async fn next_post(
+        _: &mut Option<Self>,
+    ) -> Option<(api::HookPost, tokio::sync::oneshot::Sender<Published>)> {
+        std::future::pending().await
+    }
 
-    loop {
-        tokio::select! {
-            line = stdin.next_line() => {
-                let message = line?.context("stdin closed")?;
-                if !message.is_empty() {
-                    debug!(?message, ?topic, "gossipsub publish");
-                    let msg = api::ChatApi::Message { message, origin_timestamp: chrono::Utc::now() };
-                    publish(
-                        &mut swarm.behaviour_mut().gossipsub, topic.clone(),
-                        &serde_cbor::to_vec(&msg).expect("Serialization works")
-                    )?;
+    fn set_diagnostics(&self, _: diagnostics::Diagnostics) {
+        match *self {}
+    }
 
-                }
-            }
-            event = swarm.select_next_some() => {
-                handle_swarm_event(swarm.behaviour_mut(), &mut state, event)?;
-            }
-            _ = ticker.tick() => {
-                publish(&mut swarm.behaviour_mut().gossipsub, topic.clone(), &*msg_nickname)?;
+    fn set_health(&self, _: usize, _: usize) {
+        match *self {}
+    }
+}
 
-            }
-            _ = tokio::signal::ctrl_c() =>  break
-        }
+/// Stand-in for the scripts when built without scripting, never constructed.
+#[cfg(not(feature = "scripting"))]
+enum Scripts {}
+
+#[cfg(not(feature = "scripting"))]
+impl Scripts {
+    fn reload(&mut self) -> anyhow::Result<usize> {
+        match *self {}
     }
 
-    Ok(())
+    fn on_message(&mut self, _: &PeerId, _: &str, _: &str) -> Option<String> {
+        match *self {}
+    }
+
+    fn has_command(&self, _: &str) -> bool {
+        match *self {}
+    }
+
+    fn command(&mut self, _: &str, _: &str, _: &str) {
+        match *self {}
+    }
+
+    fn take_outgoing(&mut self) -> Vec<(String, String)> {
+        match *self {}
+    }
 }
 
-fn publish<S: Hasher>(
-    gossipsub: &mut gossipsub::Gossipsub,
-    topic: Topic<S>,
-    message: &[u8],
+/// Starts over with a clean slate: leaves the channels and closes all connections, then rejoins,
+/// and dials the bootstrap node and `targets` again while discovery finds peers anew.
+async fn reset_connections(
+    swarm: &mut Swarm<Behaviour>,
+    state: &State,
+    bootstrap: Option<&Multiaddr>,
+    private_topic: bool,
+    targets: Vec<(PeerId, Vec<Multiaddr>)>,
 ) -> anyhow::Result<()> {
-    match gossipsub.publish(topic, message) {
-        Err(gossipsub::error::PublishError::InsufficientPeers) => println!("No peers available"),
-        Err(e) => Err(e)?,
-        _ => {}
+    let topics = swarm
+        .behaviour()
+        .gossipsub
+        .topics()
+        .cloned()
+        .collect::<Vec<_>>();
+    for topic in &topics {
+        leave_channel(
+            &mut swarm.behaviour_mut().gossipsub,
+            state.channel(topic),
+            private_topic,
+        )?;
+    }
+    let peers = swarm.connected_peers().copied().collect::<Vec<_>>();
+    println!("Resetting connections to {} peers", peers.len());
+    for peer in peers {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+    if let Err(error) = swarm.behaviour_mut().restart_discovery().await {
+        warn!("{:#}", anyhow!(error));
+    }
+    for topic in &topics {
+        join_channel(
+            &mut swarm.behaviour_mut().gossipsub,
+            state.channel(topic),
+            private_topic,
+        )?;
+    }
+    if let Some(addr) = bootstrap {
+        if let Err(error) = swarm.dial(addr.clone()) {
+            warn!(%addr, %error, "Dialing bootstrap node failed");
+        }
+    }
+    for (peer, addresses) in targets {
+        // The old connections may still be closing.
+        let opts = DialOpts::peer_id(peer)
+            .condition(PeerCondition::Always)
+            .addresses(addresses)
+            .build();
+        if let Err(error) = swarm.dial(opts) {
+            debug!(%peer, %error, "Redialing failed");
+        }
     }
     Ok(())
 }
 
-#[derive(Debug, Default)]
-struct State {
-    connected_peers: BTreeSet<PeerId>,
-    known_nicknames: BTreeMap<PeerId, String>,
+/// Dumps the state and mesh on SIGUSR1.
+fn dump_diagnostics(behaviour: &Behaviour, state: &State, file: Option<&Path>) {
+    let dump = diagnostics::Diagnostics::collect(&behaviour.gossipsub, state).dump(file);
+    if let Err(error) = dump {
+        warn!("Dumping diagnostics failed: {:#}", error);
+    }
 }
-fn handle_swarm_event(
-    _swarm: &mut Behaviour,
-    state: &mut State,
-    event: SwarmEvent<BehaviourEvent, SwarmError>,
-) -> anyhow::Result<()> {
-    debug!(?event);
-    match event {
-        SwarmEvent::Behaviour(ev) => match ev {
-            BehaviourEvent::Chat { peer, message } => match message {
-                api::ChatApi::Message {
-                    message,
-                    origin_timestamp,
-                } => println!(
-                    "{} {}: {}",
-                    origin_timestamp,
-                    state
-                        .known_nicknames
-                        .get(&peer)
-                        .unwrap_or(&peer.to_string()),
-                    message
-                ),
-                api::ChatApi::ChangeNickname { nick } => {
-                    let old = state
-                        .known_nicknames
-                        .insert(peer, nick.clone())
-                        .unwrap_or_else(|| peer.to_string());
-                    if old != nick {
-                        println!(
-                            "{} {} changed his name to {}.",
-                            chrono::Utc::now(),
-                            old,
-                            nick
-                        );
-                    }
-                }
-            },
+
+/// `--replay-state`, or the default path if there's a data dir.
+fn replay_state(args: &Args) -> Option<PathBuf> {
+    args.replay_state.clone().or_else(replay::default_path)
+}
+
+/// Persists the replay protection's marks to [`replay_state`].
+fn save_replay_state(behaviour: &mut Behaviour, args: &Args) {
+    if let Some(path) = replay_state(args) {
+        if let Err(error) = behaviour.replay.save(&path) {
+            warn!("{:#}", error);
+        }
+    }
+}
+
+/// Capabilities announced to peers, depending on the enabled features.
+fn capabilities(args: &Args) -> Vec<String> {
+    let mut supported = vec![
+        api::capability::SEALED_DIRECT_MESSAGES,
+        api::capability::DM_CHALLENGES,
+    ];
+    if args.serve_history {
+        supported.push(api::capability::SERVE_HISTORY);
+    }
+    if args.channel_key.is_some() {
+        supported.push(api::capability::KEY_ROTATION);
+    }
+    if args.e2e_encrypt {
+        supported.push(api::capability::GROUP_KEY_EXCHANGE);
+    }
+    supported.into_iter().map(String::from).collect()
+}
+
+/// Configures the rendering of messages by the flags.
+fn renderer(args: &Args) -> display::Renderer {
+    let staleness = display::Staleness {
+        window: chrono::Duration::minutes(args.delayed_after_mins),
+        cap: chrono::Duration::hours(args.stale_after_hours),
+    };
+    let reorder = Some(display::REORDER_WINDOW).filter(|_| !args.no_reorder);
+    display::Renderer::new(staleness, reorder)
+}
+
+/// Timestamps `msg` by `clock` if monotonic timestamps are enabled, keeping the wall-clock time
+/// for display.
+fn stamp(clock: &mut Option<clock::MonotonicClock>, mut msg: api::ChatApi) -> api::ChatApi {
+    if let (
+        Some(clock),
+        api::ChatApi::Message {
+            origin_timestamp,
+            wall_timestamp,
+            ..
         },
-        SwarmEvent::NewListenAddr { address, .. } => {
-            info!("Listening on {:?}", address);
+    ) = (clock, &mut msg)
+    {
+        *wall_timestamp = Some(*origin_timestamp);
+        *origin_timestamp = clock.now();
+    }
+    msg
+}
+
+/// Shows `peer`'s id, nickname and connections.
+fn print_whois(state: &State, peer: &PeerId) {
+    println!("{} ({})", state.nick(peer), peer);
+    let connections = state.connections(peer);
+    match connections.len() {
+        0 => println!("  not connected"),
+        n => println!("  {} connection{}:", n, if n == 1 { "" } else { "s" }),
+    }
+    for endpoint in connections {
+        match endpoint {
+            ConnectedPoint::Dialer { address, .. } => println!("    dialed {}", address),
+            ConnectedPoint::Listener { send_back_addr, .. } => {
+                println!("    accepted from {}", send_back_addr)
+            }
         }
-        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-            if state.connected_peers.insert(peer_id) {
-                // TODO: handle channel joins, not only connections.
-                println!(
-                    "{} {} connected.",
-                    chrono::Local::now(),
-                    state
-                        .known_nicknames
-                        .get(&peer_id)
-                        .unwrap_or(&peer_id.to_string())
-                );
+    }
+}
+
+/// Resolves `to` to a single peer, explaining why if that's not possible.
+fn resolve_one(state: &State, to: &str) -> Option<PeerId> {
+    match state.resolve(to).as_slice() {
+        [peer] => Some(*peer),
+        [] => {
+            println!("No peer matching {}", to);
+            None
+        }
+        candidates => {
+            println!("{} is ambiguous:", to);
+            for peer in candidates {
+                println!("  {} ({})", peer, state.nick(peer));
             }
+            None
         }
-        SwarmEvent::ConnectionClosed {
-            peer_id,
-            num_established,
-            ..
-        } if num_established == 0 => {
+    }
+}
+
+/// Until when a moderated peer's messages are hidden, `None` for good.
+fn hidden_until(action: api::ModerationAction, now: Instant) -> Option<Instant> {
+    match action {
+        api::ModerationAction::Kick => None,
+        api::ModerationAction::Mute { duration_secs } => {
+            now.checked_add(Duration::from_secs(duration_secs))
+        }
+    }
+}
+
+/// e.g. "muted for 5 minutes".
+fn moderation_done(action: api::ModerationAction) -> String {
+    match action {
+        api::ModerationAction::Kick => "kicked".into(),
+        api::ModerationAction::Mute { duration_secs } => match duration_secs {
+            1 => "muted for 1 second".into(),
+            secs if secs < 60 => format!("muted for {} seconds", secs),
+            // Rounded up, not to claim less than applies.
+            secs => match (secs + 59) / 60 {
+                1 => "muted for 1 minute".into(),
+                minutes => format!("muted for {} minutes", minutes),
+            },
+        },
+    }
+}
+
+/// Applies `action` to the peer `to` in the channel of `topic` locally, returning the message
+/// telling the others, unless we don't own the channel.
+fn moderate(
+    state: &mut State,
+    local: &PeerId,
+    topic: &gossipsub::TopicHash,
+    to: &str,
+    action: api::ModerationAction,
+) -> Option<api::ChatApi> {
+    let target = resolve_one(state, to)?;
+    let now = Instant::now();
+    match state.moderate(topic, local, target, hidden_until(action, now), now) {
+        Ok(()) => {
             println!(
-                "{} {} disconnected.",
-                chrono::Local::now(),
-                state
-                    .known_nicknames
-                    .get(&peer_id)
-                    .unwrap_or(&peer_id.to_string())
+                "{} was {} in {}.",
+                to,
+                moderation_done(action),
+                state.channel(topic)
             );
-            state.connected_peers.remove(&peer_id);
-            // TODO: eventually gc `state.known_nicknames`
+            Some(api::ChatApi::Moderate { action, target })
         }
-        _ => {}
+        Err(state::ModerationRejected::NotModerated) => {
+            println!(
+                "{} isn't moderated, configure its owner in the config's [moderation] section.",
+                state.channel(topic)
+            );
+            None
+        }
+        Err(state::ModerationRejected::NotOwner) => {
+            println!("Only the owner of {} can do that.", state.channel(topic));
+            None
+        }
+    }
+}
+
+/// When the next held message is due, see [`display::Reorder`].
+fn release_at(renderer: &display::Renderer) -> tokio::time::Instant {
+    renderer
+        .next_release()
+        .map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)
+}
+
+fn print_direct_message(
+    renderer: &mut display::Renderer,
+    state: &State,
+    peer: &PeerId,
+    held: &dm_requests::Held,
+    width: Option<usize>,
+) -> std::io::Result<()> {
+    let prefix = format!("{} ", display::DisplayTime::from(held.origin_timestamp));
+    let indent = prefix.chars().count();
+    // Plain direct messages are readable by everyone in the channel.
+    let label = if held.sealed {
+        "private"
+    } else {
+        "unencrypted"
+    };
+    let head = format!("{}{} ({}): ", prefix, state.display_nick(peer), label);
+    renderer.line(format_args!(
+        "{}",
+        display::wrap(&head, &held.message, indent, width)
+    ))
+}
+
+fn print_dm_request(
+    renderer: &mut display::Renderer,
+    state: &State,
+    peer: &PeerId,
+) -> std::io::Result<()> {
+    let nick = state.nick(peer);
+    renderer.line(format_args!(
+        "{} {} wants to send you direct messages: /accept-dm {} or /decline-dm {}",
+        display::DisplayTime::now(),
+        nick,
+        nick,
+        nick
+    ))
+}
+
+/// Drops the requests of peers which didn't answer our challenge in time.
+fn expire_dm_requests(state: &State, dm_requests: &mut dm_requests::DmRequests) {
+    for peer in dm_requests.expire(Instant::now()) {
+        println!(
+            "{} Dropped direct messages of {}, they didn't prove their identity in time.",
+            display::DisplayTime::now(),
+            state.nick(&peer)
+        );
+    }
+}
+
+/// Shows a direct message of `peer`, and alerts of it, unless held or dropped by `dm_requests`.
+#[allow(clippy::too_many_arguments)]
+fn gate_direct_message(
+    behaviour: &mut Behaviour,
+    state: &State,
+    dm_requests: &mut dm_requests::DmRequests,
+    alerts: Option<&mut alert::Alerts>,
+    renderer: &mut display::Renderer,
+    topic: &gossipsub::TopicHash,
+    peer: PeerId,
+    message: dm_requests::Held,
+    width: Option<usize>,
+) -> anyhow::Result<()> {
+    let trusted = state.verified.contains_key(&peer);
+    let answers_challenges = state.supports(&peer, api::capability::DM_CHALLENGES);
+    let now = Instant::now();
+    match dm_requests.receive(peer, trusted, answers_challenges, message.clone(), now) {
+        dm_requests::Received::Show => {
+            print_direct_message(renderer, state, &peer, &message, width)
+                .context("Rendering direct message")?;
+            if let Some(alerts) = alerts {
+                alerts.direct(&peer, &state.nick(&peer), &state.channel(topic), &message);
+            }
+        }
+        dm_requests::Received::Challenge(nonce) => {
+            debug!(%peer, "Challenging sender of a direct message");
+            let msg = api::ChatApi::DmChallenge { to: peer, nonce };
+            publish(behaviour, topic.clone(), &encode::to_cbor(&msg)?);
+        }
+        dm_requests::Received::Unapproved => {
+            print_dm_request(renderer, state, &peer).context("Rendering request")?
+        }
+        dm_requests::Received::Held => {}
+        dm_requests::Received::Dropped => debug!(%peer, "Dropping direct message"),
     }
     Ok(())
 }
+
+/// What was typed to send a message queued by [`retry::Retries`], sealed direct messages
+/// standing in by their recipient.
+fn queued_text(state: &State, msg: &api::ChatApi) -> Option<String> {
+    match msg {
+        api::ChatApi::Message { message, .. } => Some(message.clone()),
+        api::ChatApi::SealedDirectMessage { to, .. } => Some(format!("/msg {}", state.nick(to))),
+        _ => None,
+    }
+}
+
+/// Queues `msg`, which found no peers in `topic`, to be published once one joins. The oldest
+/// queued message is dropped for it beyond [`retry::MAX_QUEUED`], recorded in the `outbox`.
+fn queue_retry(
+    state: &State,
+    retries: &mut retry::Retries,
+    outbox: &mut outbox::Outbox,
+    topic: gossipsub::TopicHash,
+    msg: api::ChatApi,
+) {
+    if let Some(text) = queued_text(state, &msg) {
+        println!(
+            "{} Queued — no peers yet: {}",
+            display::DisplayTime::now(),
+            text
+        );
+    }
+    if let Some((topic, dropped)) = retries.push(topic, msg) {
+        if let Some(text) = queued_text(state, &dropped) {
+            println!(
+                "{} Dropped queued message, more than {} waiting for peers: {}",
+                display::DisplayTime::now(),
+                retry::MAX_QUEUED,
+                text
+            );
+            outbox.dropped(&state.channel(&topic), &text);
+        }
+    }
+}
+
+/// Records what became of `msg`, posted through a gateway to `topic`, like messages typed in:
+/// queued for retrying if it found no peers, in the `outbox` if it failed for good.
+fn record_posted(
+    state: &State,
+    retries: &mut retry::Retries,
+    outbox: &mut outbox::Outbox,
+    topic: gossipsub::TopicHash,
+    msg: api::ChatApi,
+    published: Published,
+) {
+    match published {
+        Published::NoPeers => queue_retry(state, retries, outbox, topic, msg),
+        published => {
+            if let (Some(text), Some(notice)) = (queued_text(state, &msg), published.notice()) {
+                if outbox.record(&state.channel(&topic), &text, published) {
+                    println!("{} {}: {}", display::DisplayTime::now(), notice, text);
+                }
+            }
+        }
+    }
+}
+
+/// Alerts of the messages the renderer wrote since, unless not to be disturbed.
+fn alert_shown(renderer: &mut display::Renderer, mut alerts: Option<&mut alert::Alerts>) {
+    for shown in renderer.take_shown() {
+        if let Some(alerts) = alerts.as_deref_mut() {
+            alerts.message(&shown.peer, &shown.nick, &shown.channel, &shown.text);
+        }
+    }
+}
+
+/// Feedback on a message queued by [`retry::Retries`], once published or failed for good.
+fn print_retried(state: &State, msg: &api::ChatApi, published: Published) {
+    if let Some(text) = queued_text(state, msg) {
+        match published.notice() {
+            None => println!(
+                "{} Sent queued message: {}",
+                display::DisplayTime::now(),
+                text
+            ),
+            Some(notice) => println!("{} {}: {}", display::DisplayTime::now(), notice, text),
+        }
+    }
+}
+
+/// Lists the messages waiting for peers and those which failed for good, for `/outbox` and on
+/// exit.
+fn print_outbox(state: &State, retries: &retry::Retries, outbox: &outbox::Outbox) {
+    for (topic, msg) in retries.queued() {
+        match msg {
+            api::ChatApi::Message {
+                message,
+                origin_timestamp,
+                wall_timestamp,
+                ..
+            } => println!(
+                "{} {}: {} (waiting for peers)",
+                display::DisplayTime::from(wall_timestamp.unwrap_or(*origin_timestamp)),
+                state.channel(topic),
+                message
+            ),
+            msg => {
+                if let Some(text) = queued_text(state, msg) {
+                    println!("{}: {} (waiting for peers)", state.channel(topic), text);
+                }
+            }
+        }
+    }
+    for failed in outbox.failed() {
+        println!(
+            "{} {}: {} ({})",
+            failed.at, failed.channel, failed.text, failed.reason
+        );
+    }
+}
+
+/// Feedback on how many peers a message sent from stdin reached directly, or why it wasn't sent.
+fn print_published(published: Published) {
+    match published {
+        Published::Sent { reach: Some(n) } => println!("  (→ {} peers)", n),
+        published => {
+            if let Some(notice) = published.notice() {
+                println!("{}", notice);
+            }
+        }
+    }
+}
+
+/// Topic of `channel`. Private channels use the SHA-256 hash of their name, so it isn't visible to
+/// outsiders observing the mesh.
+fn topic_hash(channel: &str, private: bool) -> gossipsub::TopicHash {
+    if private {
+        gossipsub::Sha256Topic::new(channel).hash()
+    } else {
+        gossipsub::IdentTopic::new(channel).hash()
+    }
+}
+
+/// Subscribes to `channel`, unless already subscribed, which would perturb the mesh. Returns
+/// whether it was newly subscribed.
+fn join_channel(
+    gossipsub: &mut p2p::Gossipsub,
+    channel: &str,
+    private: bool,
+) -> anyhow::Result<bool> {
+    let hash = topic_hash(channel, private);
+    if gossipsub.topics().any(|t| *t == hash) {
+        return Ok(false);
+    }
+    Ok(if private {
+        gossipsub.subscribe(&gossipsub::Sha256Topic::new(channel))?
+    } else {
+        gossipsub.subscribe(&gossipsub::IdentTopic::new(channel))?
+    })
+}
+
+/// Returns whether `channel` was subscribed.
+fn leave_channel(
+    gossipsub: &mut p2p::Gossipsub,
+    channel: &str,
+    private: bool,
+) -> anyhow::Result<bool> {
+    Ok(if private {
+        gossipsub.unsubscribe(&gossipsub::Sha256Topic::new(channel))?
+    } else {
+        gossipsub.unsubscribe(&gossipsub::IdentTopic::new(channel))?
+    })
+}
+
+/// Meta events are for machines, only displayed with `--show-meta-events`.
+fn meta_event_line(
+    show: bool,
+    nick: &str,
+    event_type: &str,
+    payload: &serde_json::Value,
+) -> Option<String> {
+    show.then(|| {
+        format!(
+            "{} {} [{}] {}",
+            display::DisplayTime::now(),
+            nick,
+            event_type,
+            payload
+        )
+    })
+}
+
+fn random_name() -> String {
+    names::Generator::default().next().unwrap()
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    if let Err(error) = cli.log.init() {
+        eprintln!("Setting up logging failed: {:#}", error);
+        std::process::exit(ShutdownReason::Fatal(error).exit_code());
+    }
+    debug!("{:#?}", cli);
+
+    let command = cli.command.unwrap_or(Commands::Chat(cli.args));
+    // Times are shown alike everywhere, from messages to status lines.
+    if let Some(args) = command.args() {
+        display::set_utc_times(args.utc_times);
+        display::set_time_style(args.time_style);
+    }
+    let command = async move {
+        match command {
+            Commands::Chat(args) => run(args, Mode::Chat).await,
+            Commands::Listen(args) => run(args, Mode::Listen).await,
+            Commands::Node { args, node: opts } => node(args, opts).await,
+            Commands::Send {
+                args,
+                message,
+                timeout,
+                min_peers,
+                settle_ms,
+            } => {
+                let delivery = Delivery {
+                    timeout: Duration::from_secs(timeout),
+                    min_peers,
+                    settle: Duration::from_millis(settle_ms),
+                };
+                send(args, message, delivery).await
+            }
+            Commands::Peers { args, wait } => peers(args, Duration::from_secs(wait)).await,
+            Commands::GenerateIdentity { output } => {
+                p2p::generate_identity(&output).map(|keypair| {
+                    println!("{}", PeerId::from(keypair.public()));
+                    ShutdownReason::Done
+                })
+            }
+            Commands::Version => {
+                println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+                Ok(ShutdownReason::Done)
+            }
+        }
+    };
+    #[cfg(feature = "profiling")]
+    let reason = cli.profile.run(command).await;
+    #[cfg(not(feature = "profiling"))]
+    let reason = command.await;
+    let reason = reason.unwrap_or_else(ShutdownReason::Fatal);
+    let code = reason.exit_code();
+    info!(%reason, code, "Shutting down");
+    eprintln!("{} Shutting down: {}", display::DisplayTime::now(), reason);
+    logging::shutdown();
+    std::process::exit(code);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Chat,
+    /// Like chat, but without reading stdin
+    Listen,
+}
+
+/// Next line typed on stdin (if `read_stdin`) or sent by a control client.
+async fn next_input(
+    stdin: &mut input::Input<io::BufReader<io::Stdin>>,
+    read_stdin: bool,
+    control: &mut Option<control::Control>,
+) -> std::io::Result<Option<String>> {
+    tokio::select! {
+        line = stdin.next_line(), if read_stdin => line,
+        Some(line) = control::Control::next_line(control) => Ok(Some(line)),
+        else => std::future::pending().await,
+    }
+}
+
+/// When to exit for stdin being idle: `timeout` after the last line, once a line was read and a
+/// peer connected.
+fn stdin_idle_deadline(
+    timeout: Option<Duration>,
+    last_line: Option<tokio::time::Instant>,
+    connected_at: Option<tokio::time::Instant>,
+) -> Option<tokio::time::Instant> {
+    Some(last_line?.max(connected_at?) + timeout?)
+}
+
+/// Starts the node and joins the channel.
+async fn join(args: &Args) -> anyhow::Result<(Swarm<Behaviour>, gossipsub::TopicHash)> {
+    check_channel_name(&args.channel_name_regex, &args.channel)?;
+    let keypair = match &args.identity {
+        Some(path) => p2p::load_identity(path)?,
+        None => Keypair::generate_ed25519(),
+    };
+    let mut allowlist = args.allow_peer.clone();
+    if let Some(path) = &args.allowlist {
+        allowlist.extend(p2p::load_allowlist(path)?);
+    }
+    let keep_alive = args
+        .keep_alive
+        .iter()
+        .chain(&allowlist)
+        .copied()
+        .collect::<Vec<_>>();
+    let allowlist = if allowlist.is_empty() && args.allowlist.is_none() {
+        None
+    } else {
+        Some(allowlist.into_iter().collect())
+    };
+    let mut gossipsub_config =
+        gossipsub::GossipsubConfigBuilder::from(p2p::default_gossipsub_config());
+    let validation_mode = match (args.validation_mode, args.strict_validation) {
+        (Some(mode), _) => mode,
+        (None, true) => ValidationMode::Strict,
+        (None, false) => {
+            if !args.i_know_permissive {
+                warn!(
+                    "Running in permissive validation mode; message authorship is not verified. \
+                     Use --strict-validation to drop unsigned messages, or --i-know-permissive \
+                     to hide this."
+                );
+            }
+            ValidationMode::Permissive
+        }
+    };
+    // Gossipsub has no mode of its own for checking authors' keys, signatures are verified
+    // permissively and the keys then checked by `p2p::RequireSignedSource`.
+    if validation_mode == ValidationMode::Strict {
+        gossipsub_config.validation_mode(gossipsub::ValidationMode::Strict);
+    }
+    anyhow::ensure!(
+        args.pinned_keys_file.is_none() || validation_mode == ValidationMode::Author,
+        "--pinned-keys-file requires --validation-mode author"
+    );
+    if args.members.is_some() {
+        gossipsub_config.validate_messages();
+    }
+    let gossipsub_config = gossipsub_config.build().map_err(|e| anyhow!(e))?;
+    let tcp_keepalive = tcp::TcpKeepalive::from_secs(
+        args.tcp_keepalive_secs,
+        args.tcp_keepalive_interval_secs,
+        args.tcp_keepalive_probes,
+    );
+    #[cfg_attr(not(feature = "mdns"), allow(unused_mut))]
+    let mut backends = Vec::<Box<dyn discovery::Discovery>>::new();
+    #[cfg(feature = "mdns")]
+    if !args.no_mdns {
+        backends.push(Box::new(discovery::Mdns::new().await.map_err(|error| {
+            p2p::BehaviourBootstrapError::Discovery("mDNS", error)
+        })?));
+    }
+    let mut swarm = Behaviour::bootstrap_with_discovery(
+        keypair,
+        allowlist,
+        gossipsub_config,
+        tcp_keepalive,
+        backends,
+    )
+    .await?;
+    for peer in keep_alive {
+        swarm.behaviour_mut().keep_alive(peer);
+    }
+    swarm
+        .behaviour_mut()
+        .ignore_addr_prefixes(args.ignore_addr_prefix.iter().copied());
+    if args.no_local_discovery {
+        swarm
+            .behaviour_mut()
+            .ignore_addr_prefixes(cidr::Cidr::loopback());
+    }
+    swarm
+        .behaviour_mut()
+        .set_profile(args.peer.clone(), args.bootstrap.as_ref());
+    if let Some(path) = &args.audit_log {
+        swarm.behaviour().audit_log().open(path)?;
+    }
+    if let Some(path) = &args.pinned_keys_file {
+        let pinned = p2p::load_pinned_keys(path)?;
+        info!("Pinned the keys of {} nicknames", pinned.len());
+        *swarm
+            .behaviour()
+            .pinned_keys()
+            .write()
+            .expect("Not poisoned") = pinned;
+    }
+    if validation_mode == ValidationMode::Author {
+        swarm.behaviour_mut().pin_nicknames();
+    }
+    if args.require_sequence {
+        swarm.behaviour_mut().require_sequence();
+    }
+    if let Some(passphrase) = &args.channel_key {
+        let key = crypt::ChannelKey::new(passphrase.clone())?;
+        swarm.behaviour_mut().set_channel_key(key);
+    }
+    if let Some(path) = replay_state(args) {
+        swarm.behaviour_mut().replay = replay::ReplayGuard::load(&path)?;
+    }
+    swarm
+        .behaviour_mut()
+        .set_compat_plaintext(args.compat_plaintext);
+    let topic = topic_hash(&args.channel, args.private_topic);
+    if let Some(path) = &args.members {
+        load_members(swarm.behaviour_mut(), path, topic.clone())?;
+        if args.channel_key.is_none() {
+            warn!(
+                "--members filters cooperatively, it's no access control: non-members can still \
+                 read the channel unless it's encrypted with --channel-key"
+            );
+        }
+    }
+
+    swarm.listen_on(args.listen.clone())?;
+    match &args.bootstrap {
+        Some(addr) => swarm.dial(addr.clone())?,
+        None if !cfg!(feature = "mdns") && args.peer.is_empty() => warn!(
+            "Built without mDNS support, peers are only found through --bootstrap, --peer and \
+             the config's peers"
+        ),
+        None => {}
+    }
+
+    join_channel(
+        &mut swarm.behaviour_mut().gossipsub,
+        &args.channel,
+        args.private_topic,
+    )?;
+    for channel in &args.channels {
+        check_channel_name(&args.channel_name_regex, channel)?;
+        join_channel(
+            &mut swarm.behaviour_mut().gossipsub,
+            channel,
+            args.private_topic,
+        )?;
+    }
+    Ok((swarm, topic))
+}
+
+/// Takes the settings of `--profile`, if given, in place of the arguments'.
+fn apply_profile(mut args: Args) -> anyhow::Result<Args> {
+    let name = match &args.profile {
+        Some(name) => name,
+        None => return Ok(args),
+    };
+    let dir = profile::default_dir().context("No data dir for profiles, set $XDG_DATA_HOME")?;
+    let profile = profile::Profile::load(&dir, name)?;
+    let mut channels = profile.channels.into_iter();
+    args.channel = channels.next().expect("Loaded profiles have channels");
+    args.channels = channels.collect();
+    args.identity = profile.identity;
+    args.bootstrap = profile.bootstrap;
+    args.peer = profile.peers;
+    args.private_topic = profile.private_topic;
+    if let Some(listen) = profile.listen {
+        args.listen = listen;
+    }
+    if let Some(secs) = profile.tcp_keepalive_secs {
+        args.tcp_keepalive_secs = secs;
+    }
+    args.tcp_keepalive_interval_secs = profile
+        .tcp_keepalive_interval_secs
+        .or(args.tcp_keepalive_interval_secs);
+    args.tcp_keepalive_probes = profile.tcp_keepalive_probes.or(args.tcp_keepalive_probes);
+    Ok(args)
+}
+
+/// The session's settings as a profile, with the channel typed in first.
+fn session_profile(args: &Args) -> profile::Profile {
+    profile::Profile {
+        identity: args.identity.clone(),
+        bootstrap: args.bootstrap.clone(),
+        peers: args.peer.clone(),
+        channels: std::iter::once(&args.channel)
+            .chain(&args.channels)
+            .cloned()
+            .collect(),
+        private_topic: args.private_topic,
+        listen: Some(args.listen.clone()),
+        tcp_keepalive_secs: Some(args.tcp_keepalive_secs),
+        tcp_keepalive_interval_secs: args.tcp_keepalive_interval_secs,
+        tcp_keepalive_probes: args.tcp_keepalive_probes,
+    }
+}
+
+/// Joins the channels of `profile` and leaves the others, but the one typed in (`typed_in`),
+/// and dials its peers, replacing the previous profile's in `session`. Settings which only take
+/// effect on restart are reported, as are channels failing to be joined or left.
+fn load_profile(
+    swarm: &mut Swarm<Behaviour>,
+    state: &mut State,
+    session: &mut profile::Profile,
+    typed_in: &gossipsub::TopicHash,
+    channel_name_regex: &regex::Regex,
+    (name, profile): (&str, profile::Profile),
+) {
+    let private_topic = session.private_topic;
+    let gossipsub = &mut swarm.behaviour_mut().gossipsub;
+    for channel in &profile.channels {
+        if let Err(error) = check_channel_name(channel_name_regex, channel) {
+            println!("{}", error);
+            continue;
+        }
+        match join_channel(gossipsub, channel, private_topic) {
+            Ok(true) => {
+                let topic = topic_hash(channel, private_topic);
+                state.channel_names.insert(topic, channel.clone());
+                println!("Joined {}.", channel);
+            }
+            Ok(false) => {}
+            Err(error) => println!("Joining {} failed: {:#}", channel, error),
+        }
+    }
+    let keep = profile
+        .channels
+        .iter()
+        .map(|channel| topic_hash(channel, private_topic))
+        .collect::<HashSet<_>>();
+    let leaving = gossipsub
+        .topics()
+        .filter(|topic| !keep.contains(*topic) && *topic != typed_in)
+        .filter_map(|topic| state.channel_names.get(topic).cloned())
+        .collect::<Vec<_>>();
+    for channel in leaving {
+        match leave_channel(gossipsub, &channel, private_topic) {
+            Ok(true) => println!("Left {}.", channel),
+            Ok(false) => {}
+            Err(error) => println!("Leaving {} failed: {:#}", channel, error),
+        }
+    }
+
+    swarm
+        .behaviour_mut()
+        .set_profile(profile.peers.clone(), profile.bootstrap.as_ref());
+    if let Some(addr) = &profile.bootstrap {
+        if let Err(error) = swarm.dial(addr.clone()) {
+            println!("Dialing {} failed: {}", addr, error);
+        }
+    }
+    session.bootstrap = profile.bootstrap.clone();
+    session.peers = profile.peers.clone();
+
+    println!("Loaded profile {}.", name);
+    let restart_needed = profile.restart_needed(session);
+    if !restart_needed.is_empty() {
+        println!(
+            "Restart with --profile {} to also use its {}.",
+            name,
+            restart_needed.join(", ")
+        );
+    }
+}
+
+/// Restricts the channel of `topic` to the peers listed at `path`, returning their number.
+fn load_members(
+    behaviour: &mut Behaviour,
+    path: &Path,
+    topic: gossipsub::TopicHash,
+) -> anyhow::Result<usize> {
+    let members = p2p::load_allowlist(path)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let count = members.len();
+    behaviour.set_members(topic, members)?;
+    Ok(count)
+}
+
+/// What `send` waits for around publishing its message.
+#[derive(Debug, Clone, Copy)]
+struct Delivery {
+    /// For a peer in the channel, or `min_peers` in its mesh.
+    timeout: Duration,
+    /// Mesh peers required before and when sending, 0 to send to any peer in the channel.
+    min_peers: usize,
+    /// Running after sending.
+    settle: Duration,
+}
+
+/// Publishes a single message once a peer joined the channel.
+async fn send(args: Args, message: String, delivery: Delivery) -> anyhow::Result<ShutdownReason> {
+    let args = apply_profile(args)?;
+    let (mut swarm, topic) = join(&args).await?;
+    let msgs = [
+        api::ChatApi::ChangeNickname { nick: args.name },
+        api::ChatApi::message(message),
+    ];
+    deliver(&mut swarm, &topic, &args.channel, msgs, delivery).await?;
+    Ok(ShutdownReason::Done)
+}
+
+/// Publishes `msgs` as set out by `delivery`. Fails if there are too few peers to send to, or if
+/// the mesh shrank below `min_peers` while settling: there's no acknowledgement of delivery, the
+/// mesh peers are those the message was sent to directly.
+async fn deliver(
+    swarm: &mut Swarm<Behaviour>,
+    topic: &gossipsub::TopicHash,
+    channel: &str,
+    msgs: impl IntoIterator<Item = api::ChatApi>,
+    delivery: Delivery,
+) -> anyhow::Result<()> {
+    let mesh_peers =
+        |swarm: &Swarm<Behaviour>| swarm.behaviour().gossipsub.mesh_peers(topic).count();
+    let ready = |swarm: &Swarm<Behaviour>| match delivery.min_peers {
+        0 => swarm
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .any(|(_, topics)| topics.contains(&topic)),
+        min_peers => mesh_peers(swarm) >= min_peers,
+    };
+    tokio::time::timeout(delivery.timeout, async {
+        while !ready(swarm) {
+            swarm.select_next_some().await;
+        }
+    })
+    .await
+    .map_err(|_| match delivery.min_peers {
+        0 => anyhow!("No peer joined {} within {:?}", channel, delivery.timeout),
+        min_peers => anyhow!(
+            "Only {} peers in the mesh of {} within {:?}, {} required",
+            mesh_peers(swarm),
+            channel,
+            delivery.timeout,
+            min_peers
+        ),
+    })?;
+
+    for msg in msgs {
+        let published = publish(
+            swarm.behaviour_mut(),
+            topic.clone(),
+            &encode::to_cbor(&msg).expect("Serialization works"),
+        );
+        if let Some(notice) = published.notice() {
+            anyhow::bail!(notice);
+        }
+        if let Published::Sent { reach } = published {
+            let reach = reach.unwrap_or(0);
+            anyhow::ensure!(
+                reach >= delivery.min_peers,
+                "Sent to {} peers, {} required",
+                reach,
+                delivery.min_peers
+            );
+        }
+    }
+    let _ = tokio::time::timeout(delivery.settle, async {
+        loop {
+            swarm.select_next_some().await;
+        }
+    })
+    .await;
+    let settled = mesh_peers(swarm);
+    anyhow::ensure!(
+        settled >= delivery.min_peers,
+        "Only {} peers left in the mesh of {} after sending, {} required",
+        settled,
+        channel,
+        delivery.min_peers
+    );
+    Ok(())
+}
+
+/// Lists the peers subscribed to the channel after waiting for `wait`.
+async fn peers(args: Args, wait: Duration) -> anyhow::Result<ShutdownReason> {
+    let args = apply_profile(args)?;
+    let (mut swarm, topic) = join(&args).await?;
+    let mut nicknames = BTreeMap::new();
+    let _ = tokio::time::timeout(wait, async {
+        loop {
+            if let SwarmEvent::Behaviour(BehaviourEvent::Chat {
+                peer,
+                message: api::ChatApi::ChangeNickname { nick },
+                ..
+            }) = swarm.select_next_some().await
+            {
+                nicknames.insert(peer, nick);
+            }
+        }
+    })
+    .await;
+
+    for (peer, topics) in swarm.behaviour().gossipsub.all_peers() {
+        if topics.contains(&&topic) {
+            match nicknames.get(peer) {
+                Some(nick) => println!("{} {}", peer, nick),
+                None => println!("{}", peer),
+            }
+        }
+    }
+    Ok(ShutdownReason::Done)
+}
+
+/// Helps peers meet: relays the channels' messages, but never publishes any itself.
+async fn node(args: Args, opts: NodeArgs) -> anyhow::Result<ShutdownReason> {
+    let args = apply_profile(args)?;
+    // Keep a stable peer id across restarts, so bootstrap addresses stay valid.
+    if let Some(path) = args.identity.as_ref().filter(|p| !p.exists()) {
+        let keypair = p2p::generate_identity(path)?;
+        info!(peer = %PeerId::from(keypair.public()), "Generated identity {}", path.display());
+    }
+    for channel in &opts.join {
+        check_channel_name(&args.channel_name_regex, channel)?;
+    }
+    let config = match &args.config {
+        Some(path) => config::Config::load(path)?,
+        None => Default::default(),
+    };
+    let (mut swarm, topic) = join(&args).await?;
+    add_static_peers(swarm.behaviour_mut(), config.peers);
+    let mut state = State {
+        peer_addresses: swarm.behaviour().address_book(),
+        pinned_keys: swarm.behaviour().pinned_keys(),
+        hashed_topics: args.private_topic,
+        ..Default::default()
+    };
+    let mut history = history::History::new(args.serve_history);
+    let mut dm_requests = dm_requests::DmRequests::new(*swarm.local_peer_id(), false);
+    let mut renderer = renderer(&args);
+    state.channel_names.insert(topic, args.channel.clone());
+    for channel in &args.channels {
+        let topic = topic_hash(channel, args.private_topic);
+        state.channel_names.insert(topic, channel.clone());
+    }
+    for channel in &opts.join {
+        join_channel(
+            &mut swarm.behaviour_mut().gossipsub,
+            channel,
+            args.private_topic,
+        )?;
+        let topic = topic_hash(channel, args.private_topic);
+        state.channel_names.insert(topic, channel.clone());
+    }
+    info!(peer = %swarm.local_peer_id(), "Node running");
+
+    let mut tasks = shutdown::Tasks::default();
+    // Never publishing, the node has no webhooks to serve, only the diagnostics. Neither does it
+    // load scripts, which could only send into the void.
+    #[cfg(feature = "http-api")]
+    let http_api = match args.http_listen {
+        Some(addr) => Some(HttpApi::bind(
+            addr,
+            Default::default(),
+            args.health_min_peers,
+            &mut tasks,
+        )?),
+        None => None,
+    };
+    #[cfg(not(feature = "http-api"))]
+    let http_api: Option<HttpApi> = None;
+    let mut status = tokio::time::interval(Duration::from_secs(opts.status_interval.max(1)));
+    let terminate = shutdown::terminate();
+    tokio::pin!(terminate);
+    let mut dump_signal = diagnostics::DumpSignal::new();
+    let mut connection_log = args
+        .verbose_connections
+        .then(connection_log::ConnectionLog::default);
+    let reason = loop {
+        tokio::select! {
+            event = swarm.select_next_some() => {
+                update_health(http_api.as_ref(), &swarm);
+                if let Some(log) = &mut connection_log {
+                    log.print(&event);
+                }
+                if closed_unless_allowed(&mut swarm, &event) {
+                    continue;
+                }
+                if let SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } = &event {
+                    let peers = swarm.network_info().num_peers();
+                    if num_established.get() == 1 && peers > opts.max_peers {
+                        warn!(%peer_id, peers, "Too many peers, closing connection");
+                        let _ = swarm.disconnect_peer_id(*peer_id);
+                        continue;
+                    }
+                }
+                if opts.render {
+                    let handled = handle_swarm_event(swarm.behaviour_mut(), &mut state, Sinks { show_meta: args.show_meta_events, ..Default::default() }, &mut history, &mut dm_requests, &mut renderer, event);
+                    log_failure(&mut state, handled);
+                } else {
+                    trace!(?event);
+                }
+            }
+            _ = tokio::time::sleep_until(release_at(&renderer)), if renderer.next_release().is_some() => {
+                let released = renderer.release(Instant::now()).context("Rendering messages");
+                log_failure(&mut state, released);
+            }
+            _ = status.tick() => {
+                info!(peers = swarm.network_info().num_peers(), "Connected peers");
+                if opts.render {
+                    for (peer, renamed) in state.apply_pending_renames(Instant::now()) {
+                        let shown = renamed_peer(&mut renderer, None, &peer, &renamed).context("Rendering rename");
+                        log_failure(&mut state, shown);
+                    }
+                    expire_dm_requests(&state, &mut dm_requests);
+                }
+                save_replay_state(swarm.behaviour_mut(), &args);
+                if let Some(api) = &http_api {
+                    api.set_diagnostics(diagnostics::Diagnostics::collect(&swarm.behaviour().gossipsub, &state));
+                }
+            }
+            _ = dump_signal.recv() => {
+                dump_diagnostics(swarm.behaviour(), &state, args.sigusr1_dump_file.as_deref());
+            }
+            _ = tokio::signal::ctrl_c() => break ShutdownReason::Interrupted,
+            _ = &mut terminate => break ShutdownReason::Terminated,
+        }
+    };
+    if let Err(error) = renderer.flush() {
+        warn!(%error, "Rendering held messages failed");
+    }
+    tasks.shutdown().await;
+    save_replay_state(swarm.behaviour_mut(), &args);
+    Ok(reason)
+}
+
+/// Dials the config's `peers`, and keeps the connections to them alive.
+fn add_static_peers(behaviour: &mut Behaviour, peers: Vec<discovery::PeerAddr>) {
+    if peers.is_empty() {
+        return;
+    }
+    for peer in &peers {
+        behaviour.keep_alive(peer.peer);
+    }
+    behaviour.add_discovery(Box::new(discovery::StaticPeers::new(peers)));
+}
+
+fn update_health(http_api: Option<&HttpApi>, swarm: &Swarm<Behaviour>) {
+    if let Some(api) = http_api {
+        api.set_health(swarm.listeners().count(), swarm.network_info().num_peers());
+    }
+}
+
+async fn run(args: Args, mode: Mode) -> anyhow::Result<ShutdownReason> {
+    let args = apply_profile(args)?;
+    let config = match &args.config {
+        Some(path) => config::Config::load(path)?,
+        None => Default::default(),
+    };
+
+    let (mut swarm, topic) = join(&args).await?;
+    swarm.behaviour_mut().set_limits(config.limits);
+    add_static_peers(swarm.behaviour_mut(), config.peers);
+    // Before fields of `args` are moved out.
+    let msg_capabilities = encode::to_cbor(&api::ChatApi::Capabilities {
+        supported: capabilities(&args),
+    })
+    .expect("Serialization works");
+    let mut tasks = shutdown::Tasks::default();
+    let terminal = display::TerminalWidth::detect(&mut tasks);
+
+    let webhook = args.webhook_url.map(|url| {
+        webhook::Webhook::spawn(
+            url,
+            args.webhook_secret,
+            args.webhook_events,
+            args.channel.clone(),
+            &mut tasks,
+        )
+    });
+
+    let mut mqtt = config
+        .mqtt
+        .map(|c| mqtt::Bridge::spawn(c, args.channel.clone(), &mut tasks))
+        .transpose()?;
+
+    let responses = config.responses;
+
+    let mut gateway = match args.irc_gateway {
+        Some(addr) => Some(
+            irc::Gateway::bind(
+                addr,
+                *swarm.local_peer_id(),
+                args.channel.clone(),
+                &mut tasks,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+
+    #[cfg(feature = "http-api")]
+    let mut http_api = match args.http_listen {
+        Some(addr) => Some(HttpApi::bind(
+            addr,
+            config.hooks,
+            args.health_min_peers,
+            &mut tasks,
+        )?),
+        None => None,
+    };
+    #[cfg(not(feature = "http-api"))]
+    let mut http_api: Option<HttpApi> = {
+        if !config.hooks.is_empty() {
+            warn!("Built without HTTP API support, ignoring configured hooks");
+        }
+        None
+    };
+
+    // Fetching links would reveal that someone is reading along.
+    if args.link_previews && mode == Mode::Listen {
+        warn!("Link previews are never fetched when listening");
+    }
+    let mut previews = (args.link_previews && mode == Mode::Chat).then(|| {
+        let filter = preview::DomainFilter {
+            allow: args.preview_allow_domain.clone(),
+            deny: args.preview_deny_domain.clone(),
+        };
+        preview::LinkPreviews::spawn(filter, &mut tasks)
+    });
+
+    let mut control = match (args.control_tcp, args.control_token) {
+        (Some(addr), Some(token)) => {
+            Some(control::Control::bind_tcp(addr, token, &mut tasks).await?)
+        }
+        _ => None,
+    };
+
+    let encode_threshold = args.async_encode_threshold;
+    let mut clock = args.monotonic_timestamps.then(clock::MonotonicClock::new);
+    let private_topic = args.private_topic;
+
+    let mut stdin = input::Input::stdin();
+    let mut state = State {
+        peer_addresses: swarm.behaviour().address_book(),
+        pinned_keys: swarm.behaviour().pinned_keys(),
+        e2e_encrypt: args.e2e_encrypt,
+        hashed_topics: args.private_topic,
+        ..Default::default()
+    };
+    state
+        .channel_names
+        .insert(topic.clone(), args.channel.clone());
+    for channel in &args.channels {
+        let topic = topic_hash(channel, private_topic);
+        state.channel_names.insert(topic, channel.clone());
+    }
+    let mut session = session_profile(&args);
+    if let Some(path) = &args.verified_peers {
+        state.verified = verified::load(path)?;
+    }
+    for (channel, moderation) in config.moderation {
+        let topic = topic_hash(&channel, private_topic);
+        state.channel_owners.insert(topic.clone(), moderation.owner);
+        if moderation.auto_part {
+            state.auto_part.insert(topic);
+        }
+    }
+    let mut history = history::History::new(args.serve_history);
+    let mut dm_requests = dm_requests::DmRequests::new(*swarm.local_peer_id(), args.approve_dms);
+    let mut retries = retry::Retries::default();
+    let mut outbox = outbox::Outbox::default();
+    let mut dnd = dnd::Dnd::new(config.dnd);
+    dnd.update(&chrono::Local::now());
+    let mut alerts = alert::Alerts::new(config.alerts, args.name.clone());
+    let mut renderer = renderer(&args);
+    #[cfg(feature = "scripting")]
+    let mut scripts = match args.scripts.clone().or_else(scripting::default_dir) {
+        Some(dir) => Some(Scripts::load(dir)?),
+        None => None,
+    };
+    #[cfg(not(feature = "scripting"))]
+    let mut scripts: Option<Scripts> = None;
+    // Sent once the first peers are connected.
+    let mut history_request = args
+        .history_since
+        .map(|minutes| api::ChatApi::HistoryRequest {
+            since: chrono::Utc::now() - chrono::Duration::minutes(minutes),
+            max: history::MAX_BATCH,
+        });
+    // Peer shown by `/verify <nick>`, to be confirmed by `/verify <nick> yes`.
+    let mut pending_verification = None;
+    let mut ticker = heartbeat::Heartbeat::new(heartbeat::INTERVAL);
+    let mut ticks = 0u64;
+    let mut dump_signal = diagnostics::DumpSignal::new();
+    let mut connection_log = args
+        .verbose_connections
+        .then(connection_log::ConnectionLog::default);
+    let nickname_max_age = Duration::from_secs(args.nickname_gc_hours * 60 * 60);
+    let mut nick = args.name;
+    let mut msg_nickname = encode::to_cbor(&api::ChatApi::ChangeNickname { nick: nick.clone() })
+        .expect("Serialization works");
+    // Peers joining our channels learn about us right away, see `debounce_announcement`.
+    let mut announce_at = None;
+    let stdin_timeout = (args.stdin_timeout > 0).then(|| Duration::from_secs(args.stdin_timeout));
+    let (mut last_line, mut connected_at) = (None, None);
+
+    let reason = loop {
+        let idle_at = stdin_idle_deadline(stdin_timeout, last_line, connected_at);
+        tokio::select! {
+            line = next_input(&mut stdin, mode == Mode::Chat, &mut control) => {
+                let message = match line? {
+                    Some(message) => message,
+                    None if args.exit_on_stdin_eof => break ShutdownReason::StdinClosed,
+                    None => {
+                        info!("Stdin closed, still receiving");
+                        continue;
+                    }
+                };
+                last_line = Some(tokio::time::Instant::now());
+                match Command::parse(&message) {
+                    Some(Command::Quit) => break ShutdownReason::Quit,
+                    Some(Command::AnnounceSelf) => {
+                        publish_all(swarm.behaviour_mut(), &*msg_nickname);
+                        publish_all(swarm.behaviour_mut(), &*msg_capabilities);
+                    }
+                    Some(Command::Nick(new)) if new == nick => println!("You are {} already.", nick),
+                    Some(Command::Nick(new)) => {
+                        nick = new;
+                        alerts.set_nick(nick.clone());
+                        msg_nickname = encode::to_cbor(&api::ChatApi::ChangeNickname { nick: nick.clone() })
+                            .expect("Serialization works");
+                        publish_all(swarm.behaviour_mut(), &*msg_nickname);
+                        println!("You are {} now.", nick);
+                    }
+                    Some(Command::Join(channel)) => {
+                        if let Err(error) = check_channel_name(&args.channel_name_regex, &channel) {
+                            println!("{}", error);
+                        } else if join_channel(&mut swarm.behaviour_mut().gossipsub, &channel, private_topic)? {
+                            state.channel_names.insert(topic_hash(&channel, private_topic), channel.clone());
+                            println!("Joined {}.", channel);
+                        } else {
+                            println!("Already in {}.", channel);
+                        }
+                    }
+                    Some(Command::Channels) => {
+                        for topic in swarm.behaviour().gossipsub.topics() {
+                            match state.channel_names.contains_key(topic) {
+                                true => println!("{}", state.channel(topic)),
+                                false => println!("{} (topic {})", state.channel(topic), topic),
+                            }
+                        }
+                    }
+                    Some(Command::Leave(channel)) => {
+                        if leave_channel(&mut swarm.behaviour_mut().gossipsub, &channel, private_topic)? {
+                            println!("Left {}.", channel);
+                        } else {
+                            println!("Not in {}.", channel);
+                        }
+                    }
+                    Some(Command::Msg { to, text }) => {
+                        if let Some(peer) = resolve_one(&state, &to) {
+                            if !state.supports(&peer, api::capability::SEALED_DIRECT_MESSAGES) {
+                                println!("{} hasn't announced support for sealed direct messages (yet), not sent.", to);
+                                continue;
+                            }
+                            match swarm.behaviour().seal_direct_message(peer, &text) {
+                                Ok(msg) => {
+                                    match publish_chat(swarm.behaviour_mut(), topic.clone(), msg.clone(), encode_threshold).await? {
+                                        Published::NoPeers => queue_retry(&state, &mut retries, &mut outbox, topic.clone(), msg),
+                                        published => {
+                                            outbox.record(&args.channel, &format!("/msg {} {}", to, text), published);
+                                            print_published(published);
+                                        }
+                                    }
+                                    // Messaging a peer answers its request, if any.
+                                    for message in dm_requests.accept(peer).unwrap_or_default() {
+                                        let shown = print_direct_message(&mut renderer, &state, &peer, &message, terminal.get()).context("Rendering direct message");
+                                        log_failure(&mut state, shown);
+                                    }
+                                }
+                                Err(error) => println!("{}: {}", to, error),
+                            }
+                        }
+                    }
+                    Some(Command::AcceptDm(from)) => {
+                        if let Some(peer) = resolve_one(&state, &from) {
+                            match dm_requests.accept(peer) {
+                                Some(held) => {
+                                    println!("Accepted direct messages of {}.", from);
+                                    for message in held {
+                                        let shown = print_direct_message(&mut renderer, &state, &peer, &message, terminal.get()).context("Rendering direct message");
+                                        log_failure(&mut state, shown);
+                                    }
+                                }
+                                None => println!("No request of {}, accepting their direct messages from now on.", from),
+                            }
+                        }
+                    }
+                    Some(Command::DeclineDm(from)) => {
+                        if let Some(peer) = resolve_one(&state, &from) {
+                            match dm_requests.decline(peer) {
+                                true => println!("Declined direct messages of {}.", from),
+                                false => println!("No request of {}, dropping their direct messages from now on.", from),
+                            }
+                        }
+                    }
+                    Some(Command::DmRequests) => {
+                        let requests = dm_requests.unapproved();
+                        if requests.is_empty() {
+                            println!("No direct message requests.");
+                        }
+                        for (peer, held) in requests {
+                            println!("  {} ({}): {} messages", state.nick(&peer), peer, held);
+                        }
+                    }
+                    Some(Command::Reconnect(to)) => {
+                        let only = match to {
+                            None => None,
+                            Some(to) => match resolve_one(&state, &to) {
+                                Some(peer) => Some(peer),
+                                None => continue,
+                            },
+                        };
+                        match state.reconnect_targets(only, Instant::now()) {
+                            Ok(targets) => {
+                                println!("Attempting to reconnect to {} known peers", targets.len());
+                                for (peer, addresses) in targets {
+                                    let opts = DialOpts::peer_id(peer).addresses(addresses).build();
+                                    if let Err(error) = swarm.dial(opts) {
+                                        debug!(%peer, %error, "Redialing failed");
+                                    }
+                                }
+                            }
+                            Err(wait) => println!("Reconnected recently, try again in {}s", wait.as_secs() + 1),
+                        }
+                    }
+                    Some(Command::ResetConnections) => {
+                        match state.reset_targets(Instant::now()) {
+                            Ok(targets) => reset_connections(&mut swarm, &state, args.bootstrap.as_ref(), private_topic, targets).await?,
+                            Err(wait) => println!("Reconnected recently, try again in {}s", wait.as_secs() + 1),
+                        }
+                    }
+                    Some(Command::Kick(to)) => {
+                        let action = api::ModerationAction::Kick;
+                        if let Some(msg) = moderate(&mut state, swarm.local_peer_id(), &topic, &to, action) {
+                            print_published(publish_chat(swarm.behaviour_mut(), topic.clone(), msg, encode_threshold).await?);
+                        }
+                    }
+                    Some(Command::Mute { nick: to, minutes }) => {
+                        let action = api::ModerationAction::Mute { duration_secs: minutes.saturating_mul(60) };
+                        if let Some(msg) = moderate(&mut state, swarm.local_peer_id(), &topic, &to, action) {
+                            print_published(publish_chat(swarm.behaviour_mut(), topic.clone(), msg, encode_threshold).await?);
+                        }
+                    }
+                    Some(Command::Block(who)) => {
+                        if let Some(peer) = resolve_one(&state, &who) {
+                            if state.blocked_peers.insert(peer) {
+                                swarm.behaviour_mut().block(peer);
+                                if args.block_disconnects {
+                                    let _ = swarm.disconnect_peer_id(peer);
+                                }
+                                println!("Blocked {}.", who);
+                            } else {
+                                println!("{} is blocked already.", who);
+                            }
+                        }
+                    }
+                    Some(Command::Unblock(who)) => {
+                        if let Some(peer) = resolve_one(&state, &who) {
+                            if state.blocked_peers.remove(&peer) {
+                                swarm.behaviour_mut().unblock(peer);
+                                if args.block_disconnects {
+                                    let opts = DialOpts::peer_id(peer).condition(PeerCondition::Disconnected).build();
+                                    if let Err(error) = swarm.dial(opts) {
+                                        debug!(%peer, %error, "Redialing unblocked peer failed");
+                                    }
+                                }
+                                println!("Unblocked {}.", who);
+                            } else {
+                                println!("{} isn't blocked.", who);
+                            }
+                        }
+                    }
+                    Some(Command::ReloadMembers) => match &args.members {
+                        Some(path) => match load_members(swarm.behaviour_mut(), path, topic.clone()) {
+                            Ok(count) => println!("Reloaded {} members.", count),
+                            Err(error) => println!("{:#}", error),
+                        },
+                        None => println!("Not restricted to members, see --members."),
+                    },
+                    Some(Command::Topology) => {
+                        for channel in topology::collect(&swarm.behaviour().gossipsub, &state) {
+                            println!("{}", channel.format(args.machine_readable));
+                        }
+                    }
+                    Some(Command::Mesh) => {
+                        for line in mesh::report(swarm.behaviour(), &state, Instant::now()) {
+                            println!("{}", line);
+                        }
+                    }
+                    Some(Command::MeshGraft(to)) => {
+                        if let Some(peer) = resolve_one(&state, &to) {
+                            match swarm.behaviour_mut().graft(peer) {
+                                true => println!("{} is an explicit peer now, sent all messages of the channels it's in regardless of the mesh.", to),
+                                false => println!("{} is an explicit peer already.", to),
+                            }
+                        }
+                    }
+                    Some(Command::Rekey(passphrase)) => {
+                        let rekeyed = crypt::ChannelKey::new(passphrase)
+                            .and_then(|key| swarm.behaviour_mut().rekey(key));
+                        match rekeyed {
+                            Ok(()) => println!(
+                                "Sealing with the new key. Messages sealed with the old one are opened for {} more minutes, or until /rekey --retire.",
+                                crypt::REKEY_GRACE.as_secs() / 60
+                            ),
+                            Err(error) => println!("{:#}", error),
+                        }
+                    }
+                    Some(Command::Stats) => {
+                        let stats = swarm.behaviour().stats();
+                        println!("Dropped messages: {} undecryptable, {} replayed", stats.undecryptable, stats.replayed);
+                        println!("Rejected messages from non-members: {}", stats.non_members);
+                        println!("Rejected messages exceeding limits: {} from {} peers", stats.over_limits, stats.over_limits_peers);
+                        println!("Self-dial attempts: {}", state.self_dial_attempts);
+                        println!("Events failed to handle: {}", state.failed_events);
+                        println!("Stale messages not shown: {} ({} from {} signing peers)", state.stale_dropped_total, state.stale_dropped.values().sum::<u64>(), state.stale_dropped.len());
+                    }
+                    Some(Command::AuditTail(n)) => {
+                        match swarm.behaviour().audit_log().tail(n) {
+                            Ok(records) if records.is_empty() => println!("No audit records."),
+                            Ok(records) => records.iter().for_each(|record| println!("{}", record)),
+                            Err(error) => println!("{:#}", error),
+                        }
+                    }
+                    Some(Command::Export { channel, path }) => {
+                        let topic = topic_hash(&channel, private_topic);
+                        if !args.serve_history {
+                            println!("No history kept, set --serve-history.");
+                        } else if !state.channel_names.contains_key(&topic) {
+                            println!("Not in {}.", channel);
+                        } else {
+                            let entries = history.entries(&topic).cloned().collect::<Vec<_>>();
+                            match transcript::export(&path, &channel, &entries) {
+                                Ok(()) => println!("Exported {} messages of {} to {}.", entries.len(), channel, path.display()),
+                                Err(error) => println!("{:#}", error),
+                            }
+                        }
+                    }
+                    Some(Command::RetireKeys) => {
+                        println!("Retired {} old keys.", swarm.behaviour_mut().retire_keys());
+                    }
+                    Some(Command::Fingerprint(None)) => {
+                        println!("Your fingerprint: {}", fingerprint::fingerprint(swarm.local_peer_id()));
+                    }
+                    Some(Command::Fingerprint(Some(to))) => {
+                        if let Some(peer) = resolve_one(&state, &to) {
+                            println!("Fingerprint of {} ({}): {}", to, peer, fingerprint::fingerprint(&peer));
+                        }
+                    }
+                    Some(Command::Whois(who)) => {
+                        if let Some(peer) = resolve_one(&state, &who) {
+                            print_whois(&state, &peer);
+                        }
+                    }
+                    Some(Command::Verify { nick: to, confirm: false }) => {
+                        if let Some(peer) = resolve_one(&state, &to) {
+                            println!("Fingerprint of {} ({}): {}", to, peer, fingerprint::fingerprint(&peer));
+                            println!("Compare it with {} out of band, e.g. on a call. If it matches, run /verify {} yes", to, to);
+                            pending_verification = Some(peer);
+                        }
+                    }
+                    Some(Command::Verify { nick: to, confirm: true }) => match resolve_one(&state, &to) {
+                        Some(peer) if pending_verification == Some(peer) => {
+                            pending_verification = None;
+                            // The nick, rather than the `@prefix` it may have been resolved from.
+                            let verified_nick = state.nick(&peer);
+                            match &args.verified_peers {
+                                Some(path) => verified::append(path, &peer, &verified_nick)?,
+                                None => println!("Not persisted, use --verified-peers to remember verified peers."),
+                            }
+                            state.verified.insert(peer, verified_nick.clone());
+                            println!("{} is verified ✔", verified_nick);
+                        }
+                        Some(_) => println!("Compare the fingerprint first, see /verify {}", to),
+                        None => {}
+                    },
+                    Some(Command::Template { name, nick: to }) => {
+                        let ctx = template::Context::now(to.as_deref().unwrap_or(&nick), &args.channel);
+                        match responses.render(&name, &ctx) {
+                            Ok(text) => {
+                                let msg = stamp(&mut clock, api::ChatApi::message(text.clone()));
+                                match publish_chat(swarm.behaviour_mut(), topic.clone(), msg.clone(), encode_threshold).await? {
+                                    Published::NoPeers => queue_retry(&state, &mut retries, &mut outbox, topic.clone(), msg),
+                                    published => {
+                                        outbox.record(&args.channel, &text, published);
+                                        print_published(published);
+                                    }
+                                }
+                            }
+                            Err(error) => println!("{}", error),
+                        }
+                    }
+                    Some(Command::Dnd(None)) => {
+                        let status = if dnd.is_active() { "on" } else { "off" };
+                        match dnd.schedule() {
+                            [] => println!("Do not disturb is {}.", status),
+                            ranges => println!(
+                                "Do not disturb is {}, scheduled {}.",
+                                status,
+                                ranges.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+                            ),
+                        }
+                    }
+                    Some(Command::Dnd(Some(on))) => {
+                        let summary = dnd.set(on, &chrono::Local::now());
+                        println!("Do not disturb is {} now.", if on { "on" } else { "off" });
+                        if let Some(summary) = summary {
+                            println!("{}", summary);
+                        }
+                    }
+                    Some(Command::Outbox) if retries.is_empty() && outbox.failed().is_empty() => {
+                        println!("All messages were sent.");
+                    }
+                    Some(Command::Outbox) => print_outbox(&state, &retries, &outbox),
+                    Some(Command::ClearOutbox) => println!("Cleared {} failed messages.", outbox.clear()),
+                    Some(Command::ReloadScripts) => match &mut scripts {
+                        Some(scripts) => match scripts.reload() {
+                            Ok(loaded) => println!("Loaded {} scripts.", loaded),
+                            Err(error) => println!("{:#}", error),
+                        },
+                        None => println!("Scripting isn't available."),
+                    },
+                    Some(Command::SaveProfile(name)) => {
+                        session.channels.truncate(1);
+                        session.channels.extend(
+                            swarm.behaviour().gossipsub.topics()
+                                .filter(|t| **t != topic)
+                                .filter_map(|t| state.channel_names.get(t).cloned()),
+                        );
+                        let saved = profile::default_dir()
+                            .context("No data dir for profiles, set $XDG_DATA_HOME")
+                            .and_then(|dir| session.save(&dir, &name));
+                        match saved {
+                            Ok(path) => println!("Saved profile {} to {}.", name, path.display()),
+                            Err(error) => println!("{:#}", error),
+                        }
+                    }
+                    Some(Command::LoadProfile(name)) => {
+                        let loaded = profile::default_dir()
+                            .context("No data dir for profiles, set $XDG_DATA_HOME")
+                            .and_then(|dir| profile::Profile::load(&dir, &name));
+                        match loaded {
+                            Ok(loaded) => load_profile(&mut swarm, &mut state, &mut session, &topic, &args.channel_name_regex, (&name, loaded)),
+                            Err(error) => println!("{:#}", error),
+                        }
+                    }
+                    Some(Command::Unknown { name, args: rest }) => match &mut scripts {
+                        Some(scripts) if scripts.has_command(&name) => scripts.command(&name, &rest, &args.channel),
+                        _ => println!("Unknown command /{}", name),
+                    },
+                    Some(Command::Invalid(reason)) => println!("{}", reason),
+                    None if message.is_empty() => {}
+                    None => {
+                        debug!(?message, ?topic, "gossipsub publish");
+                        let msg = stamp(&mut clock, api::ChatApi::message(message.clone()));
+                        match publish_chat(swarm.behaviour_mut(), topic.clone(), msg.clone(), encode_threshold).await? {
+                            Published::NoPeers => queue_retry(&state, &mut retries, &mut outbox, topic.clone(), msg),
+                            published => {
+                                outbox.record(&args.channel, &message, published);
+                                print_published(published);
+                            }
+                        }
+                    }
+                }
+            }
+            event = swarm.select_next_some() => {
+                update_health(http_api.as_ref(), &swarm);
+                if let Some(log) = &mut connection_log {
+                    log.print(&event);
+                }
+                if closed_unless_allowed(&mut swarm, &event) {
+                    continue;
+                }
+                if debounce_announcement(&mut announce_at, &swarm.behaviour().gossipsub, &event) {
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Membership { topic, joined: true, .. }) = &event {
+                        for (msg, published) in retries.retry(swarm.behaviour_mut(), topic, encode_threshold).await? {
+                            print_retried(&state, &msg, published);
+                            if let Some(text) = queued_text(&state, &msg) {
+                                outbox.record(&state.channel(topic), &text, published);
+                            }
+                        }
+                    }
+                }
+                if let SwarmEvent::ConnectionEstablished { .. } = &event {
+                    connected_at.get_or_insert_with(tokio::time::Instant::now);
+                }
+                if let SwarmEvent::Behaviour(BehaviourEvent::Chat { peer, signed: true, message: api::ChatApi::Message { message, .. }, .. }) = &event {
+                    if dnd::mentions(message, &nick) && dnd.mention(&state.nick(peer)) {
+                        debug!(%peer, "Mentioned while not to be disturbed");
+                    }
+                }
+                let sinks = Sinks {
+                    gateway: gateway.as_ref(),
+                    webhook: webhook.as_ref(),
+                    mqtt: mqtt.as_ref(),
+                    previews: previews.as_ref(),
+                    responses: Some(&responses),
+                    scripts: scripts.as_mut(),
+                    alerts: (!dnd.is_active()).then(|| &mut alerts),
+                    width: terminal.get(),
+                    show_meta: args.show_meta_events,
+                };
+                let handled = handle_swarm_event(swarm.behaviour_mut(), &mut state, sinks, &mut history, &mut dm_requests, &mut renderer, event);
+                log_failure(&mut state, handled);
+            }
+            Some(request) = irc::Gateway::next_request(&mut gateway) => {
+                let behaviour = swarm.behaviour_mut();
+                match request {
+                    irc::Request::Nick(new) if new == nick => {}
+                    irc::Request::Nick(new) => {
+                        nick = new;
+                        alerts.set_nick(nick.clone());
+                        msg_nickname = encode::to_cbor(&api::ChatApi::ChangeNickname { nick: nick.clone() })
+                            .expect("Serialization works");
+                        publish(behaviour, topic.clone(), &*msg_nickname);
+                    }
+                    irc::Request::Join(channel) => {
+                        if let Err(error) = check_channel_name(&args.channel_name_regex, &channel) {
+                            warn!(%channel, "{}", error);
+                            if let Some(gw) = &gateway {
+                                gw.error(&nick, "479", &channel, &error.to_string());
+                            }
+                            continue;
+                        }
+                        match join_channel(&mut behaviour.gossipsub, &channel, private_topic) {
+                            Ok(true) => {}
+                            Ok(false) => debug!(%channel, "Already subscribed"),
+                            Err(error) => {
+                                warn!(%channel, %error, "IRC JOIN failed");
+                                if let Some(gw) = &gateway {
+                                    gw.error(&nick, "403", &channel, &format!("{:#}", error));
+                                }
+                                continue;
+                            }
+                        }
+                        state.channel_names.insert(topic_hash(&channel, private_topic), channel.clone());
+                        if let Some(gw) = &gateway {
+                            gw.names(&nick, &channel, state.connected_peers.iter().map(|p| {
+                                state.peers.known_nicknames.get(p).map(String::as_str).unwrap_or("")
+                            }).filter(|n| !n.is_empty()));
+                        }
+                    }
+                    irc::Request::Part(channel) => {
+                        let failed = match leave_channel(&mut behaviour.gossipsub, &channel, private_topic) {
+                            Ok(true) => None,
+                            Ok(false) => Some(("442", "You're not on that channel".to_string())),
+                            Err(error) => {
+                                warn!(%channel, %error, "IRC PART failed");
+                                Some(("403", format!("{:#}", error)))
+                            }
+                        };
+                        if let (Some((numeric, text)), Some(gw)) = (failed, &gateway) {
+                            gw.error(&nick, numeric, &channel, &text);
+                        }
+                    }
+                    irc::Request::Privmsg { channel, text } => {
+                        let topic = topic_hash(&channel, private_topic);
+                        let msg = stamp(&mut clock, api::ChatApi::message(text));
+                        let published = publish_chat(behaviour, topic.clone(), msg.clone(), encode_threshold).await?;
+                        if let Some(gw) = &gateway {
+                            match published {
+                                Published::Sent { .. } => {}
+                                Published::NoPeers => gw.notice(&channel, "Queued until peers join"),
+                                // ERR_CANNOTSENDTOCHAN
+                                published => gw.error(&nick, "404", &channel, &published.notice().unwrap_or_default()),
+                            }
+                        }
+                        record_posted(&state, &mut retries, &mut outbox, topic, msg, published);
+                    }
+                }
+            }
+            Some((post, reply)) = HttpApi::next_post(&mut http_api) => {
+                debug!(?post, "webhook post");
+                let msg = api::ChatApi::Message {
+                    message: post.text,
+                    origin_timestamp: chrono::Utc::now(),
+                    hook_nick: Some(post.nick),
+                    bridged_from: None,
+                    wall_timestamp: None,
+                    auto_response: false,
+                };
+                let msg = stamp(&mut clock, msg);
+                let topic = topic_hash(&post.channel, private_topic);
+                let published = publish_chat(swarm.behaviour_mut(), topic.clone(), msg.clone(), encode_threshold).await?;
+                // Unless the request was given up on.
+                let _ = reply.send(published);
+                record_posted(&state, &mut retries, &mut outbox, topic, msg, published);
+            }
+            Some(preview) = preview::LinkPreviews::next_preview(&mut previews) => {
+                println!("{}", preview.line(terminal.get().is_some()));
+            }
+            Some(post) = mqtt::Bridge::next_post(&mut mqtt) => {
+                debug!(?post, "MQTT post");
+                let msg = api::ChatApi::Message {
+                    message: post.text,
+                    origin_timestamp: chrono::Utc::now(),
+                    hook_nick: Some(post.nick),
+                    bridged_from: Some(mqtt::BRIDGE.into()),
+                    wall_timestamp: None,
+                    auto_response: false,
+                };
+                let msg = stamp(&mut clock, msg);
+                let topic = topic_hash(&post.channel, private_topic);
+                let published = publish_chat(swarm.behaviour_mut(), topic.clone(), msg.clone(), encode_threshold).await?;
+                // MQTT has no way to answer the publisher, so failures are only logged.
+                if let (Some(notice), false) = (published.notice(), published == Published::NoPeers) {
+                    warn!(channel = %post.channel, nick = %post.nick, "MQTT post: {}", notice);
+                }
+                record_posted(&state, &mut retries, &mut outbox, topic, msg, published);
+            }
+            _ = tokio::time::sleep_until(announce_at.unwrap_or_else(tokio::time::Instant::now)), if announce_at.is_some() => {
+                announce_at = None;
+                publish_all(swarm.behaviour_mut(), &*msg_nickname);
+                publish_all(swarm.behaviour_mut(), &*msg_capabilities);
+                if args.e2e_encrypt {
+                    start_key_exchange(swarm.behaviour_mut(), &mut state, &topic)?;
+                }
+                // Kept for the next announcement if the peer joined another channel.
+                if let Some(request) = history_request.take() {
+                    history.request(&topic, Instant::now());
+                    let published = publish_chat(swarm.behaviour_mut(), topic.clone(), request.clone(), encode_threshold).await?;
+                    if published == Published::NoPeers {
+                        history_request = Some(request);
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(release_at(&renderer)), if renderer.next_release().is_some() => {
+                let released = renderer.release(Instant::now()).context("Rendering messages");
+                log_failure(&mut state, released);
+                alert_shown(&mut renderer, (!dnd.is_active()).then(|| &mut alerts));
+            }
+            _ = tokio::time::sleep_until(idle_at.unwrap_or_else(tokio::time::Instant::now)), if idle_at.is_some() => {
+                println!("Stdin idle timeout, exiting");
+                break ShutdownReason::StdinIdle;
+            }
+            _ = ticker.tick() => {
+                ticks += 1;
+                for (peer, renamed) in state.apply_pending_renames(Instant::now()) {
+                    let shown = renamed_peer(&mut renderer, gateway.as_ref(), &peer, &renamed).context("Rendering rename");
+                    log_failure(&mut state, shown);
+                }
+                if let Some(summary) = dnd.update(&chrono::Local::now()) {
+                    println!("{} {}", display::DisplayTime::now(), summary);
+                }
+                let notices = swarm.behaviour().audit_log().rejection_notices(Instant::now());
+                for line in rejections::lines(&notices, |peer| state.nick(peer)) {
+                    println!("{} {}", display::DisplayTime::now(), line);
+                }
+                expire_dm_requests(&state, &mut dm_requests);
+                if ticks % GC_EVERY_TICKS == 0 {
+                    let evicted = state.gc(Instant::now(), nickname_max_age);
+                    debug!(evicted, "Nickname GC");
+                }
+                if ticks % ANNOUNCE_EVERY_TICKS == 0 {
+                    publish_all(swarm.behaviour_mut(), &*msg_nickname);
+                    publish_all(swarm.behaviour_mut(), &*msg_capabilities);
+                }
+                save_replay_state(swarm.behaviour_mut(), &args);
+                if let Some(api) = &http_api {
+                    api.set_diagnostics(diagnostics::Diagnostics::collect(&swarm.behaviour().gossipsub, &state));
+                }
+            }
+            _ = dump_signal.recv() => {
+                dump_diagnostics(swarm.behaviour(), &state, args.sigusr1_dump_file.as_deref());
+            }
+            _ = tokio::signal::ctrl_c() => break ShutdownReason::Interrupted,
+        }
+        for (channel, text) in scripts
+            .as_mut()
+            .map(Scripts::take_outgoing)
+            .unwrap_or_default()
+        {
+            if let Err(error) = check_channel_name(&args.channel_name_regex, &channel) {
+                println!("{}", error);
+                continue;
+            }
+            let msg = stamp(&mut clock, api::ChatApi::message(text.clone()));
+            let topic = topic_hash(&channel, private_topic);
+            match publish_chat(
+                swarm.behaviour_mut(),
+                topic.clone(),
+                msg.clone(),
+                encode_threshold,
+            )
+            .await?
+            {
+                Published::NoPeers => queue_retry(&state, &mut retries, &mut outbox, topic, msg),
+                published => {
+                    outbox.record(&channel, &text, published);
+                    print_published(published);
+                }
+            }
+        }
+    };
+    if !retries.is_empty() || !outbox.failed().is_empty() {
+        println!("Not sent:");
+        print_outbox(&state, &retries, &outbox);
+    }
+    if matches!(reason, ShutdownReason::StdinClosed | ShutdownReason::Quit) {
+        flush(&mut swarm, FLUSH_GRACE).await;
+    }
+    if let Err(error) = renderer.flush() {
+        warn!(%error, "Rendering held messages failed");
+    }
+    tasks.shutdown().await;
+    save_replay_state(swarm.behaviour_mut(), &args);
+
+    Ok(reason)
+}
+
+/// Drives `swarm` for `grace`, sending what's been published. Gossipsub doesn't tell when its
+/// queues are drained, so events are only traced.
+async fn flush(swarm: &mut Swarm<Behaviour>, grace: Duration) {
+    let _ = tokio::time::timeout(grace, async {
+        loop {
+            let event = swarm.select_next_some().await;
+            trace!(?event, "Flushing");
+        }
+    })
+    .await;
+}
+
+/// Starts a round of group key exchange with `topic`'s connected peers, if there are any.
+fn start_key_exchange(
+    behaviour: &mut Behaviour,
+    state: &mut State,
+    topic: &gossipsub::TopicHash,
+) -> anyhow::Result<()> {
+    let peers = behaviour
+        .gossipsub
+        .all_peers()
+        .filter(|(peer, topics)| topics.contains(&topic) && state.connected_peers.contains(peer))
+        .map(|(peer, _)| *peer)
+        .collect::<Vec<_>>();
+    if peers.is_empty() {
+        return Ok(());
+    }
+    let local = *behaviour.local_peer_id();
+    let offer = state::KeyExchangeState::start(&mut state.key_exchange, local, peers);
+    debug!(
+        round = offer.round,
+        participants = offer.participants.len(),
+        "Starting group key exchange"
+    );
+    publish(
+        behaviour,
+        topic.clone(),
+        &encode::to_cbor(&key_exchange_message(offer))?,
+    );
+    Ok(())
+}
+
+fn key_exchange_message(offer: state::KeyExchangeOffer) -> api::ChatApi {
+    api::ChatApi::GroupKeyExchange {
+        initiator_pubkey: offer.public_key.to_vec(),
+        round: offer.round,
+        participants: offer.participants.iter().map(PeerId::to_string).collect(),
+    }
+}
+
+/// Schedules announcing ourselves [`ANNOUNCE_DEBOUNCE`] from now if `event` is a peer joining
+/// one of our channels, unless already scheduled. Returns whether it was.
+fn debounce_announcement(
+    announce_at: &mut Option<tokio::time::Instant>,
+    gossipsub: &p2p::Gossipsub,
+    event: &SwarmEvent<BehaviourEvent, SwarmError>,
+) -> bool {
+    let joined = peer_joined(gossipsub, event);
+    if joined && announce_at.is_none() {
+        *announce_at = Some(tokio::time::Instant::now() + ANNOUNCE_DEBOUNCE);
+    }
+    joined
+}
+
+fn peer_joined(gossipsub: &p2p::Gossipsub, event: &SwarmEvent<BehaviourEvent, SwarmError>) -> bool {
+    match event {
+        SwarmEvent::Behaviour(BehaviourEvent::Membership {
+            topic,
+            joined: true,
+            ..
+        }) => gossipsub.topics().any(|t| t == topic),
+        _ => false,
+    }
+}
+
+/// Displays a nickname change, unless it's a quiet one.
+fn renamed_peer(
+    renderer: &mut display::Renderer,
+    gateway: Option<&irc::Gateway>,
+    peer: &PeerId,
+    renamed: &peers::Renamed,
+) -> std::io::Result<()> {
+    // IRC clients track nicknames themselves.
+    if let Some(gw) = gateway {
+        gw.nick_changed(&renamed.old, &renamed.new, peer);
+    }
+    if !renamed.announce {
+        return Ok(());
+    }
+    renderer.line(format_args!(
+        "{} {} changed his name to {}.",
+        display::DisplayTime::now(),
+        renamed.old,
+        renamed.new
+    ))
+}
+
+/// Logs and counts a failure to handle an event, leaving ending the session to the explicit
+/// shutdown paths.
+fn log_failure(state: &mut State, handled: anyhow::Result<()>) {
+    if let Err(error) = handled {
+        state.failed_events += 1;
+        error!(failed = state.failed_events, "{:#}", error);
+    }
+}
+
+/// Closes connections to peers not on the allowlist. Returns whether `event` is about such a
+/// peer, and to be ignored.
+fn closed_unless_allowed(
+    swarm: &mut Swarm<Behaviour>,
+    event: &SwarmEvent<BehaviourEvent, SwarmError>,
+) -> bool {
+    match event {
+        SwarmEvent::ConnectionEstablished { peer_id, .. }
+        | SwarmEvent::ConnectionClosed { peer_id, .. }
+            if !swarm.behaviour().is_allowed(peer_id) =>
+        {
+            if matches!(event, SwarmEvent::ConnectionEstablished { .. }) {
+                debug!(%peer_id, "Closing connection to peer not on the allowlist");
+                let _ = swarm.disconnect_peer_id(*peer_id);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Where [`handle_swarm_event`] passes messages on besides the renderer, none by default.
+#[derive(Default)]
+struct Sinks<'a> {
+    gateway: Option<&'a irc::Gateway>,
+    webhook: Option<&'a webhook::Webhook>,
+    mqtt: Option<&'a mqtt::Bridge>,
+    previews: Option<&'a preview::LinkPreviews>,
+    responses: Option<&'a template::Responses>,
+    scripts: Option<&'a mut Scripts>,
+    /// `None` while not to be disturbed.
+    alerts: Option<&'a mut alert::Alerts>,
+    /// Of the terminal, if known.
+    width: Option<usize>,
+    show_meta: bool,
+}
+
+fn handle_swarm_event(
+    behaviour: &mut Behaviour,
+    state: &mut State,
+    sinks: Sinks<'_>,
+    history: &mut history::History,
+    dm_requests: &mut dm_requests::DmRequests,
+    renderer: &mut display::Renderer,
+    event: SwarmEvent<BehaviourEvent, SwarmError>,
+) -> anyhow::Result<()> {
+    let Sinks {
+        gateway,
+        webhook,
+        mqtt,
+        previews,
+        responses,
+        scripts,
+        mut alerts,
+        width,
+        show_meta,
+    } = sinks;
+    let _span = match &event {
+        SwarmEvent::Behaviour(BehaviourEvent::Chat { span, .. }) => span.clone(),
+        SwarmEvent::ConnectionEstablished { peer_id, .. }
+        | SwarmEvent::ConnectionClosed { peer_id, .. } => debug_span!("peer", peer = %peer_id),
+        _ => Span::none(),
+    }
+    .entered();
+    debug!(?event);
+    match event {
+        SwarmEvent::Behaviour(ev) => match ev {
+            BehaviourEvent::Chat {
+                peer,
+                signed: false,
+                message:
+                    api::ChatApi::Message {
+                        message,
+                        origin_timestamp,
+                        wall_timestamp,
+                        ..
+                    },
+                ..
+            } => {
+                // Relayed without a known author, so not attributed to anybody.
+                let sent = wall_timestamp.unwrap_or(origin_timestamp);
+                let now = Instant::now();
+                if !renderer.message(peer, origin_timestamp, sent, "(unsigned)", &message, width, now, None).context("Rendering message")? {
+                    debug!("Not rendering stale unsigned message");
+                    state.dropped_stale(None);
+                }
+            }
+            // Not passed on by the behaviour, see `p2p::accept`.
+            BehaviourEvent::Chat { signed: false, .. } => {}
+            BehaviourEvent::Chat {
+                peer,
+                topic,
+                message,
+                message_raw,
+                ..
+            } => {
+                let now = Instant::now();
+                state.seen(peer, now);
+                let channel = state.channel(&topic).to_string();
+                let shown = matches!(
+                    message,
+                    api::ChatApi::Message { .. }
+                        | api::ChatApi::DirectMessage { .. }
+                        | api::ChatApi::SealedDirectMessage { .. }
+                        | api::ChatApi::MetaEvent { .. }
+                );
+                if shown && state.is_hidden(&topic, &peer, now) {
+                    debug!(%peer, %channel, "Hiding message of kicked or muted peer");
+                    return Ok(());
+                }
+                match message {
+                    api::ChatApi::Message {
+                        message,
+                        origin_timestamp,
+                        hook_nick,
+                        bridged_from,
+                        wall_timestamp,
+                        auto_response,
+                    } => {
+                        trace!(%peer, bytes = message_raw.len(), "Chat message");
+                        let (nick, shown) = match hook_nick {
+                            Some(hook_nick) => {
+                                let nick = format!("{} [hook]", hook_nick);
+                                (nick.clone(), nick)
+                            }
+                            None => (state.nick(&peer), state.display_nick(&peer)),
+                        };
+                        // Scripts change what's shown and responded to, while the history and
+                        // bridges keep what was sent.
+                        let text = match scripts {
+                            Some(scripts) => scripts.on_message(&peer, &nick, &message),
+                            None => Some(message.clone()),
+                        };
+                        let sent = wall_timestamp.unwrap_or(origin_timestamp);
+                        match &text {
+                            Some(text) => {
+                                let alert = alerts.is_some().then(|| display::Shown {
+                                    peer,
+                                    nick: nick.clone(),
+                                    channel: channel.clone(),
+                                    text: text.clone(),
+                                });
+                                if !renderer.message(peer, origin_timestamp, sent, &shown, text, width, now, alert).context("Rendering message")? {
+                                    debug!(%peer, %channel, %sent, "Not rendering stale message");
+                                    state.dropped_stale(Some(peer));
+                                }
+                                alert_shown(renderer, alerts);
+                            }
+                            None => debug!(%peer, %channel, "Message hidden by a script"),
+                        }
+                        if let (Some(previews), Some(text)) = (previews, &text) {
+                            previews.request(text);
+                        }
+                        let ctx = template::Context::now(&nick, &channel);
+                        let response = text
+                            .as_deref()
+                            .filter(|_| !auto_response)
+                            .zip(responses)
+                            .and_then(|(text, responses)| responses.respond(&peer, &channel, text, &ctx, now));
+                        if let Some(response) = response {
+                            info!(%response, "Responding");
+                            let mut msg = api::ChatApi::message(response);
+                            if let api::ChatApi::Message { auto_response, .. } = &mut msg {
+                                *auto_response = true;
+                            }
+                            publish(behaviour, topic.clone(), &encode::to_cbor(&msg)?);
+                        }
+                        history.record(
+                            &topic,
+                            api::HistoryEntry {
+                                from: peer,
+                                nick: nick.clone(),
+                                message: message.clone(),
+                                origin_timestamp,
+                            },
+                        );
+                        if let (Some(gw), Some(text)) = (gateway, &text) {
+                            gw.privmsg(&nick, &peer, &channel, text);
+                        }
+                        if let Some(hook) = webhook {
+                            hook.message(&peer, &nick, &channel, origin_timestamp, &message);
+                        }
+                        if let (Some(bridge), None) = (mqtt, &bridged_from) {
+                            let payload = webhook::Payload::message(
+                                &peer,
+                                &nick,
+                                &channel,
+                                origin_timestamp,
+                                &message,
+                            );
+                            bridge.forward(&payload, &channel);
+                        }
+                    }
+                    api::ChatApi::ChangeNickname { nick } => {
+                        if let Some(renamed) = state.rename(peer, nick.clone(), Instant::now()) {
+                            renamed_peer(renderer, gateway, &peer, &renamed).context("Rendering rename")?;
+                        } else if state.rename_deferred(&peer) {
+                            behaviour.audit_log().record(
+                                audit::Kind::RateLimited,
+                                &peer,
+                                format_args!("Nickname change to {} deferred", nick),
+                            );
+                        }
+                        if let Some(verified) = state.impersonated(&peer, &nick) {
+                            warn!(%peer, %verified, %nick, "Nickname of a verified peer claimed");
+                            renderer.line(format_args!(
+                                "{} Warning: {} claims the nickname {} of verified peer {}!",
+                                display::DisplayTime::now(),
+                                peer,
+                                nick,
+                                verified
+                            )).context("Rendering warning")?;
+                        }
+                    }
+                    api::ChatApi::DirectMessage {
+                        message,
+                        origin_timestamp,
+                        ..
+                    } => {
+                        let message = dm_requests::Held {
+                            origin_timestamp,
+                            message,
+                            sealed: false,
+                        };
+                        gate_direct_message(behaviour, state, dm_requests, alerts, renderer, &topic, peer, message, width)?;
+                    }
+                    api::ChatApi::SealedDirectMessage { sealed, .. } => {
+                        match behaviour.open_direct_message(&peer, &sealed) {
+                            Ok((origin_timestamp, message)) => {
+                                let message = dm_requests::Held {
+                                    origin_timestamp,
+                                    message,
+                                    sealed: true,
+                                };
+                                gate_direct_message(behaviour, state, dm_requests, alerts, renderer, &topic, peer, message, width)?;
+                            }
+                            Err(error) => {
+                                warn!(%peer, %error, "Dropping direct message");
+                                renderer.line(format_args!(
+                                    "{} Warning: dropped a direct message from {}: {}",
+                                    display::DisplayTime::now(),
+                                    state.nick(&peer),
+                                    error
+                                )).context("Rendering warning")?;
+                            }
+                        }
+                    }
+                    api::ChatApi::DmChallenge { nonce, .. } => {
+                        // Answered only for peers we messaged, so it's no signing oracle.
+                        if !dm_requests.is_known(&peer) {
+                            debug!(%peer, "Ignoring challenge of a peer never messaged");
+                        } else {
+                            match behaviour.answer_dm_challenge(peer, nonce) {
+                                Ok(msg) => {
+                                    publish(behaviour, topic.clone(), &encode::to_cbor(&msg)?);
+                                }
+                                Err(error) => warn!(%peer, %error, "Answering challenge failed"),
+                            }
+                        }
+                    }
+                    api::ChatApi::DmChallengeResponse {
+                        nonce, signature, ..
+                    } => match dm_requests.answer(peer, &nonce, &signature, now) {
+                        Ok(dm_requests::Answered::Accepted(held)) => {
+                            for message in held {
+                                print_direct_message(renderer, state, &peer, &message, width).context("Rendering direct message")?;
+                                if let Some(alerts) = alerts.as_deref_mut() {
+                                    alerts.direct(&peer, &state.nick(&peer), &channel, &message);
+                                }
+                            }
+                        }
+                        Ok(dm_requests::Answered::Unapproved) => {
+                            print_dm_request(renderer, state, &peer).context("Rendering request")?
+                        }
+                        Err(error) => {
+                            warn!(%peer, %error, "Rejecting answer to challenge");
+                            if error == dm_requests::ChallengeError::BadSignature {
+                                behaviour.audit_log().record(
+                                    audit::Kind::InvalidSignature,
+                                    &peer,
+                                    "Answered a direct message challenge with an invalid signature",
+                                );
+                            }
+                        }
+                    },
+                    api::ChatApi::Capabilities { supported } => {
+                        debug!(%peer, ?supported, "Capabilities");
+                        state.set_capabilities(peer, supported);
+                    }
+                    api::ChatApi::HistoryRequest { since, max } => {
+                        if let Some(entries) =
+                            history.respond(&topic, peer, since, max, Instant::now())
+                        {
+                            if !entries.is_empty() {
+                                debug!(%peer, entries = entries.len(), "Serving history");
+                                let msg = api::ChatApi::HistoryResponse { to: peer, entries };
+                                publish(behaviour, topic.clone(), &encode::to_cbor(&msg)?);
+                            }
+                        }
+                    }
+                    api::ChatApi::HistoryResponse { entries, .. } => {
+                        match history.backfill(&topic, peer, entries, Instant::now()) {
+                            Some(entries) => {
+                                // The entries are the responder's word, unsigned by their senders.
+                                let responder = state.display_nick(&peer);
+                                for entry in entries
+                                    .into_iter()
+                                    .filter(|e| !state.is_hidden(&topic, &e.from, now))
+                                {
+                                    let prefix = format!("{} ", display::DisplayTime::from(entry.origin_timestamp));
+                                    let indent = prefix.chars().count();
+                                    let head = format!("{}[backfill via {}] {}: ", prefix, responder, entry.nick);
+                                    renderer.line(format_args!("{}", display::wrap(&head, &entry.message, indent, width))).context("Rendering backfill")?;
+                                }
+                            }
+                            None => debug!(%peer, "Dropping history response to no open request"),
+                        }
+                    }
+                    api::ChatApi::Moderate { action, target } => {
+                        let result = state.moderate(&topic, &peer, target, hidden_until(action, now), now);
+                        behaviour.audit_log().record(
+                            audit::Kind::Moderation,
+                            &peer,
+                            format_args!(
+                                "{:?} of {} in {}, {}",
+                                action,
+                                target,
+                                channel,
+                                match result {
+                                    Ok(()) => "applied".into(),
+                                    Err(rejected) => format!("rejected ({:?})", rejected),
+                                }
+                            ),
+                        );
+                        match result {
+                            Err(rejected) => {
+                                warn!(%peer, %target, ?action, ?rejected, "Ignoring moderation");
+                            }
+                            Ok(()) if target == *behaviour.local_peer_id() => {
+                                let left = action == api::ModerationAction::Kick
+                                    && state.auto_part.contains(&topic);
+                                if left {
+                                    // Only public channels' topics are their names.
+                                    let private = topic_hash(&channel, false) != topic;
+                                    leave_channel(&mut behaviour.gossipsub, &channel, private)?;
+                                }
+                                renderer.line(format_args!(
+                                    "{} You were {} in {} by its owner {}.",
+                                    display::DisplayTime::now(),
+                                    moderation_done(action),
+                                    channel,
+                                    state.nick(&peer)
+                                )).context("Rendering moderation")?;
+                                if left {
+                                    renderer.line(format_args!("{} Left {}.", display::DisplayTime::now(), channel)).context("Rendering moderation")?;
+                                }
+                            }
+                            Ok(()) => renderer.line(format_args!(
+                                "{} {} was {} in {}.",
+                                display::DisplayTime::now(),
+                                state.nick(&target),
+                                moderation_done(action),
+                                channel
+                            )).context("Rendering moderation")?,
+                        }
+                    }
+                    api::ChatApi::GroupKeyExchange {
+                        initiator_pubkey,
+                        round,
+                        participants,
+                    } if state.e2e_encrypt => {
+                        let was_complete = state.key_exchange.as_ref().map_or(false, |e| e.round == round && e.is_complete());
+                        match state::KeyExchangeState::receive(
+                            &mut state.key_exchange,
+                            *behaviour.local_peer_id(),
+                            &state.connected_peers,
+                            peer,
+                            &initiator_pubkey,
+                            round,
+                            &participants,
+                        ) {
+                            Ok(offer) => {
+                                if let Some(offer) = offer {
+                                    debug!(%peer, round, "Joining group key exchange");
+                                    publish(behaviour, topic.clone(), &encode::to_cbor(&key_exchange_message(offer))?);
+                                }
+                                if !was_complete && state.key_exchange.as_ref().map_or(false, |e| e.is_complete()) {
+                                    info!(round, %channel, "Group key exchange complete");
+                                }
+                            }
+                            Err(error) => debug!(%peer, %error, "Ignoring group key exchange"),
+                        }
+                    }
+                    api::ChatApi::GroupKeyExchange { .. } => {
+                        debug!(%peer, "Ignoring group key exchange without --e2e-encrypt");
+                    }
+                    api::ChatApi::MetaEvent {
+                        event_type,
+                        payload,
+                    } => {
+                        let nick = state.nick(&peer);
+                        let line = meta_event_line(show_meta, &nick, &event_type, &payload);
+                        let payload =
+                            webhook::Payload::meta(&peer, &nick, &channel, &event_type, &payload);
+                        if let Some(bridge) = mqtt {
+                            bridge.forward(&payload, &channel);
+                        }
+                        if let Some(hook) = webhook {
+                            hook.meta(payload);
+                        }
+                        if let Some(line) = line {
+                            renderer.line(format_args!("{}", line)).context("Rendering meta event")?;
+                        }
+                    }
+                }
+            }
+            BehaviourEvent::Undecryptable { topic } => renderer.line(format_args!(
+                "{} cannot decrypt messages on {} (wrong --channel-key?)",
+                display::DisplayTime::now(),
+                state.channel(&topic)
+            )).context("Rendering notice")?,
+            BehaviourEvent::Rekeyed { topic } => renderer.line(format_args!(
+                "{} channel {} was rekeyed; obtain the new passphrase and enter /rekey <passphrase>",
+                display::DisplayTime::now(),
+                state.channel(&topic)
+            )).context("Rendering notice")?,
+            BehaviourEvent::Membership {
+                peer,
+                topic,
+                joined: true,
+            } => {
+                debug!(%peer, channel = %state.channel(&topic), "Peer subscribed");
+                state.subscribed(peer, topic);
+            }
+            BehaviourEvent::Membership {
+                peer,
+                topic,
+                joined: false,
+            } => {
+                debug!(%peer, channel = %state.channel(&topic), "Peer unsubscribed");
+                let ours = behaviour.gossipsub.topics().collect::<Vec<_>>();
+                if state.unsubscribed(peer, &topic, &ours) && ours.contains(&&topic) {
+                    renderer.line(format_args!(
+                        "{} {} left {}.",
+                        display::DisplayTime::now(),
+                        state.nick(&peer),
+                        state.channel(&topic)
+                    )).context("Rendering notice")?;
+                }
+            }
+        },
+        SwarmEvent::NewListenAddr { address, .. } => {
+            info!("Listening on {:?}", address);
+        }
+        SwarmEvent::ConnectionEstablished {
+            peer_id, endpoint, ..
+        } => {
+            // Addresses of inbound connections are usually ephemeral ports, not worth redialing.
+            if endpoint.is_dialer() {
+                state.add_address(peer_id, endpoint.get_remote_address().clone());
+            }
+            if state.connection_established(peer_id, endpoint, Instant::now()) {
+                // TODO: handle channel joins, not only connections.
+                let nick = state.nick(&peer_id);
+                if let Some(gw) = gateway {
+                    gw.joined(&nick, &peer_id);
+                }
+                if let Some(hook) = webhook {
+                    hook.membership(webhook::Kind::Join, &peer_id, &nick);
+                }
+                renderer.line(format_args!("{} {} connected.", display::DisplayTime::now(), nick)).context("Rendering notice")?;
+            }
+        }
+        SwarmEvent::ConnectionClosed {
+            peer_id, endpoint, ..
+        } => {
+            if !state.connection_closed(peer_id, &endpoint) {
+                return Ok(());
+            }
+            let nick = state.nick(&peer_id);
+            let announce = state.disconnected(peer_id, Instant::now());
+            if let Some(gw) = gateway {
+                gw.quit(&nick, &peer_id);
+            }
+            if let Some(hook) = webhook {
+                hook.membership(webhook::Kind::Leave, &peer_id, &nick);
+            }
+            if announce {
+                renderer.line(format_args!("{} {} disconnected.", display::DisplayTime::now(), nick)).context("Rendering notice")?;
+            }
+        }
+        SwarmEvent::OutgoingConnectionError {
+            error: DialError::LocalPeerId,
+            ..
+        } => {
+            state.self_dial_attempts += 1;
+            debug!(attempts = state.self_dial_attempts, "Dialed ourselves");
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stdin_idle_after_line_and_connection() {
+        let timeout = Some(Duration::from_secs(5));
+        let start = tokio::time::Instant::now();
+        let later = start + Duration::from_secs(3);
+        assert_eq!(stdin_idle_deadline(timeout, None, Some(start)), None);
+        assert_eq!(stdin_idle_deadline(timeout, Some(start), None), None);
+        assert_eq!(stdin_idle_deadline(None, Some(start), Some(start)), None);
+        // Whichever is later.
+        assert_eq!(
+            stdin_idle_deadline(timeout, Some(start), Some(later)),
+            Some(later + Duration::from_secs(5))
+        );
+        assert_eq!(
+            stdin_idle_deadline(timeout, Some(later), Some(start)),
+            Some(later + Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn short_mutes_are_told_in_seconds() {
+        let muted = |duration_secs| moderation_done(api::ModerationAction::Mute { duration_secs });
+        assert_eq!(muted(1), "muted for 1 second");
+        assert_eq!(muted(30), "muted for 30 seconds");
+        assert_eq!(muted(60), "muted for 1 minute");
+        assert_eq!(muted(61), "muted for 2 minutes");
+        assert_eq!(muted(300), "muted for 5 minutes");
+    }
+
+    /// A swarm listening on localhost, subscribed to `channel`.
+    async fn swarm(channel: &str) -> Swarm<Behaviour> {
+        swarm_with_config(channel, p2p::default_gossipsub_config()).await
+    }
+
+    async fn swarm_with_config(
+        channel: &str,
+        config: gossipsub::GossipsubConfig,
+    ) -> Swarm<Behaviour> {
+        let mut swarm = Behaviour::bootstrap_with_config(Keypair::generate_ed25519(), None, config)
+            .await
+            .unwrap();
+        swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        join_channel(&mut swarm.behaviour_mut().gossipsub, channel, false).unwrap();
+        swarm
+    }
+
+    /// Handles `event` without any sinks, history or held direct messages.
+    fn handle(
+        behaviour: &mut Behaviour,
+        state: &mut State,
+        event: SwarmEvent<BehaviourEvent, SwarmError>,
+    ) -> anyhow::Result<()> {
+        handle_swarm_event(
+            behaviour,
+            state,
+            Sinks::default(),
+            &mut history::History::new(false),
+            &mut dm_requests::DmRequests::new(PeerId::random(), false),
+            &mut Default::default(),
+            event,
+        )
+    }
+
+    /// Connects `a` to `b`, driving both until `a` knows `b` is subscribed to `topic`.
+    async fn connect(
+        a: &mut Swarm<Behaviour>,
+        b: &mut Swarm<Behaviour>,
+        topic: &gossipsub::TopicHash,
+    ) {
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = b.select_next_some().await {
+                break address;
+            }
+        };
+        a.dial(addr).unwrap();
+        let b_id = *b.local_peer_id();
+        while !a
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .any(|(peer, topics)| *peer == b_id && topics.contains(&topic))
+        {
+            tokio::select! {
+                _ = a.select_next_some() => {}
+                _ = b.select_next_some() => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn nickname_propagates() {
+        let topic = topic_hash("nicknames", false);
+        let (mut alice, mut bob) = (swarm("nicknames").await, swarm("nicknames").await);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            connect(&mut alice, &mut bob, &topic).await;
+            let msg = api::ChatApi::ChangeNickname {
+                nick: "alice".into(),
+            };
+            publish(
+                alice.behaviour_mut(),
+                topic.clone(),
+                &encode::to_cbor(&msg).unwrap(),
+            );
+            let event = loop {
+                tokio::select! {
+                    _ = alice.select_next_some() => {}
+                    event = bob.select_next_some() => {
+                        if matches!(event, SwarmEvent::Behaviour(BehaviourEvent::Chat { .. })) {
+                            break event;
+                        }
+                    }
+                }
+            };
+            let mut state = State::default();
+            handle(bob.behaviour_mut(), &mut state, event).unwrap();
+            assert_eq!(state.nick(alice.local_peer_id()), "alice");
+        })
+        .await
+        .expect("Nickname received in time");
+    }
+
+    #[tokio::test]
+    async fn joining_peers_are_announced_to() {
+        let topic = topic_hash("announce", false);
+        let (mut alice, mut bob) = (swarm("announce").await, swarm("announce").await);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let addr = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } = bob.select_next_some().await {
+                    break address;
+                }
+            };
+            alice.dial(addr).unwrap();
+            // Without a ticker, alice announces herself once bob is seen joining, debounced.
+            let mut announce_at = None;
+            let mut joined_at = None;
+            loop {
+                tokio::select! {
+                    event = alice.select_next_some() => {
+                        if debounce_announcement(&mut announce_at, &alice.behaviour().gossipsub, &event) {
+                            joined_at.get_or_insert_with(tokio::time::Instant::now);
+                        }
+                    }
+                    _ = bob.select_next_some() => {}
+                    _ = tokio::time::sleep_until(announce_at.unwrap_or_else(tokio::time::Instant::now)), if announce_at.is_some() => {
+                        break;
+                    }
+                }
+            }
+            let joined_at = joined_at.expect("Scheduled by bob joining");
+            assert!(joined_at.elapsed() >= ANNOUNCE_DEBOUNCE);
+            let msg = api::ChatApi::ChangeNickname {
+                nick: "alice".into(),
+            };
+            publish_all(alice.behaviour_mut(), &encode::to_cbor(&msg).unwrap());
+            let mut state = State::default();
+            tokio::time::timeout(Duration::from_secs(2), async {
+                while state.nick(alice.local_peer_id()) != "alice" {
+                    let event = tokio::select! {
+                        _ = alice.select_next_some() => continue,
+                        event = bob.select_next_some() => event,
+                    };
+                    handle(bob.behaviour_mut(), &mut state, event).unwrap();
+                }
+            })
+            .await
+            .expect("Name learnt within two seconds of joining");
+            assert!(!peer_joined(
+                &alice.behaviour().gossipsub,
+                &SwarmEvent::Behaviour(BehaviourEvent::Membership {
+                    peer: *bob.local_peer_id(),
+                    topic: topic_hash("elsewhere", false),
+                    joined: true,
+                })
+            ));
+            assert!(!peer_joined(
+                &alice.behaviour().gossipsub,
+                &SwarmEvent::Behaviour(BehaviourEvent::Membership {
+                    peer: *bob.local_peer_id(),
+                    topic: topic.clone(),
+                    joined: false,
+                })
+            ));
+            assert!(peer_joined(
+                &alice.behaviour().gossipsub,
+                &SwarmEvent::Behaviour(BehaviourEvent::Membership {
+                    peer: *bob.local_peer_id(),
+                    topic,
+                    joined: true,
+                })
+            ));
+        })
+        .await
+        .expect("Joined in time");
+    }
+
+    #[tokio::test]
+    async fn peers_not_on_the_allowlist_are_disconnected() {
+        let mut alice = Behaviour::bootstrap_with_config(
+            Keypair::generate_ed25519(),
+            Some(Default::default()),
+            p2p::default_gossipsub_config(),
+        )
+        .await
+        .unwrap();
+        alice
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let mut bob = swarm("agora").await;
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = alice.select_next_some().await {
+                break address;
+            }
+        };
+        bob.dial(addr).unwrap();
+        let (alice_id, bob_id) = (*alice.local_peer_id(), *bob.local_peer_id());
+        let (mut ignored, mut closed) = (0, false);
+        while ignored < 2 || !closed {
+            tokio::select! {
+                event = alice.select_next_some() => {
+                    if closed_unless_allowed(&mut alice, &event) {
+                        // Established and closed.
+                        ignored += 1;
+                    }
+                }
+                event = bob.select_next_some() => {
+                    if let SwarmEvent::ConnectionClosed { peer_id, .. } = event {
+                        assert_eq!(peer_id, alice_id);
+                        closed = true;
+                    }
+                }
+            }
+        }
+        assert!(!alice.is_connected(&bob_id));
+        assert!(!bob.is_connected(&alice_id));
+    }
+
+    #[tokio::test]
+    async fn counts_simultaneous_connections() {
+        let (mut alice, mut bob) = (swarm("twice").await, swarm("twice").await);
+        let bob_id = *bob.local_peer_id();
+        let mut state = State::default();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let addr = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } = bob.select_next_some().await {
+                    break address;
+                }
+            };
+            for _ in 0..2 {
+                alice.dial(addr.clone()).unwrap();
+            }
+            let mut connected = 0;
+            let mut closing = false;
+            loop {
+                tokio::select! {
+                    event = alice.select_next_some() => {
+                        if let SwarmEvent::ConnectionEstablished { num_established, .. } = &event {
+                            connected = connected.max(num_established.get());
+                        }
+                        handle(alice.behaviour_mut(), &mut state, event).unwrap();
+                    }
+                    _ = bob.select_next_some() => {}
+                }
+                if !closing && connected == 2 {
+                    assert_eq!(state.connections(&bob_id).len(), 2);
+                    assert!(state.connected_peers.contains(&bob_id));
+                    alice.disconnect_peer_id(bob_id).unwrap();
+                    closing = true;
+                } else if closing && !state.connected_peers.contains(&bob_id) {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("Connected twice and disconnected in time");
+        assert!(state.connections(&bob_id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failing_to_render_skips_only_the_event() {
+        let topic = topic_hash("broken-pipe", false);
+        let (mut alice, mut bob) = (swarm("broken-pipe").await, swarm("broken-pipe").await);
+        let pipe = display::BrokenPipe::default();
+        let mut renderer =
+            display::Renderer::new(Default::default(), None).with_writer(pipe.clone());
+        let mut state = State::default();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            connect(&mut alice, &mut bob, &topic).await;
+            for msg in [
+                api::ChatApi::message("hello".into()),
+                api::ChatApi::ChangeNickname {
+                    nick: "alice".into(),
+                },
+            ] {
+                publish(
+                    alice.behaviour_mut(),
+                    topic.clone(),
+                    &encode::to_cbor(&msg).unwrap(),
+                );
+            }
+            let mut history = history::History::new(false);
+            let mut dm_requests = dm_requests::DmRequests::new(PeerId::random(), false);
+            while state.nick(alice.local_peer_id()) != "alice" {
+                tokio::select! {
+                    _ = alice.select_next_some() => {}
+                    event = bob.select_next_some() => {
+                        let handled = handle_swarm_event(
+                            bob.behaviour_mut(),
+                            &mut state,
+                            Sinks::default(),
+                            &mut history,
+                            &mut dm_requests,
+                            &mut renderer,
+                            event,
+                        );
+                        log_failure(&mut state, handled);
+                    }
+                }
+            }
+        })
+        .await
+        .expect("Nickname received in time");
+        assert_eq!(state.failed_events, 1);
+        assert_eq!(pipe.attempts(), 1);
+    }
+
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn scripts_rewrite_and_hide_messages() {
+        let topic = topic_hash("scripted", false);
+        let (mut alice, mut bob) = (swarm("scripted").await, swarm("scripted").await);
+        let mut scripts =
+            Scripts::load(Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/scripts")).unwrap();
+        let captured = display::Captured::default();
+        let mut renderer =
+            display::Renderer::new(Default::default(), None).with_writer(captured.clone());
+        let mut state = State::default();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            connect(&mut alice, &mut bob, &topic).await;
+            for text in ["Spoiler: it ends well", "so it goes :shrug:", "done"] {
+                publish(
+                    alice.behaviour_mut(),
+                    topic.clone(),
+                    &encode::to_cbor(&api::ChatApi::message(text.into())).unwrap(),
+                );
+            }
+            let mut history = history::History::new(false);
+            let mut dm_requests = dm_requests::DmRequests::new(PeerId::random(), false);
+            while !captured.text().contains("done") {
+                tokio::select! {
+                    _ = alice.select_next_some() => {}
+                    event = bob.select_next_some() => handle_swarm_event(
+                        bob.behaviour_mut(),
+                        &mut state,
+                        Sinks {
+                            scripts: Some(&mut scripts),
+                            ..Default::default()
+                        },
+                        &mut history,
+                        &mut dm_requests,
+                        &mut renderer,
+                        event,
+                    )
+                    .unwrap(),
+                }
+            }
+        })
+        .await
+        .expect("Messages received in time");
+        let shown = captured.text();
+        assert!(!shown.contains("it ends well"), "{}", shown);
+        assert!(shown.contains("so it goes ¯\\_(ツ)_/¯"), "{}", shown);
+    }
+
+    #[tokio::test]
+    async fn peers_leaving_the_channel_are_no_longer_members() {
+        let topic = topic_hash("leaving", false);
+        let (mut alice, mut bob) = (swarm("leaving").await, swarm("leaving").await);
+        let bob_id = *bob.local_peer_id();
+        let mut state = State::default();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let addr = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } = bob.select_next_some().await {
+                    break address;
+                }
+            };
+            alice.dial(addr).unwrap();
+            let mut left = false;
+            loop {
+                tokio::select! {
+                    event = alice.select_next_some() => {
+                        handle(alice.behaviour_mut(), &mut state, event).unwrap()
+                    }
+                    _ = bob.select_next_some() => {}
+                }
+                if !left && state.is_member(&topic, &bob_id) {
+                    assert!(
+                        leave_channel(&mut bob.behaviour_mut().gossipsub, "leaving", false)
+                            .unwrap()
+                    );
+                    left = true;
+                } else if left && !state.is_member(&topic, &bob_id) {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("Left in time");
+        assert!(state.connected_peers.contains(&bob_id));
+        assert!(
+            !state.disconnected(bob_id, Instant::now()),
+            "Disconnecting after leaving isn't mentioned"
+        );
+    }
+
+    #[tokio::test]
+    async fn send_waits_for_enough_mesh_peers() {
+        let topic = topic_hash("deliver", false);
+        let (mut alice, mut bob) = (swarm("deliver").await, swarm("deliver").await);
+        let delivery = Delivery {
+            timeout: Duration::from_secs(5),
+            min_peers: 1,
+            settle: Duration::from_millis(200),
+        };
+        tokio::time::timeout(Duration::from_secs(20), async {
+            connect(&mut alice, &mut bob, &topic).await;
+            let bob_runs = async {
+                loop {
+                    bob.select_next_some().await;
+                }
+            };
+            tokio::pin!(bob_runs);
+            let msgs = [api::ChatApi::message("deployed".into())];
+            tokio::select! {
+                delivered = deliver(&mut alice, &topic, "deliver", msgs, delivery) => delivered.unwrap(),
+                _ = &mut bob_runs => unreachable!(),
+            }
+
+            let msgs = [api::ChatApi::message("deployed".into())];
+            let delivery = Delivery {
+                timeout: Duration::from_secs(2),
+                min_peers: 2,
+                ..delivery
+            };
+            let error = tokio::select! {
+                delivered = deliver(&mut alice, &topic, "deliver", msgs, delivery) => delivered.unwrap_err(),
+                _ = &mut bob_runs => unreachable!(),
+            };
+            assert!(error.to_string().starts_with("Only 1 peers in the mesh of deliver"), "{}", error);
+        })
+        .await
+        .expect("Delivered in time");
+    }
+
+    #[tokio::test]
+    async fn mesh_reports_a_two_node_mesh() {
+        let topic = topic_hash("mesh", false);
+        let (mut alice, mut bob) = (swarm("mesh").await, swarm("mesh").await);
+        let bob_id = *bob.local_peer_id();
+        tokio::time::timeout(Duration::from_secs(20), async {
+            connect(&mut alice, &mut bob, &topic).await;
+            // Grafted on a heartbeat.
+            while !alice
+                .behaviour()
+                .gossipsub
+                .mesh_peers(&topic)
+                .any(|peer| *peer == bob_id)
+            {
+                tokio::select! {
+                    _ = alice.select_next_some() => {}
+                    _ = bob.select_next_some() => {}
+                }
+            }
+        })
+        .await
+        .expect("Meshed in time");
+        let published = publish(alice.behaviour_mut(), topic.clone(), b"hello");
+        assert_eq!(published, Published::Sent { reach: Some(1) });
+        assert!(alice.behaviour_mut().graft(bob_id));
+        assert!(!alice.behaviour_mut().graft(bob_id));
+
+        let mut state = State::default();
+        state.channel_names.insert(topic, "mesh".into());
+        let bob = state.nick(&bob_id);
+        let report = mesh::report(alice.behaviour(), &state, Instant::now());
+        assert!(
+            report[0].starts_with("Heartbeat scheduled every"),
+            "{:?}",
+            report
+        );
+        assert!(report[0].contains(" ago"), "{:?}", report);
+        assert_eq!(report[2], format!("Explicit peers (/mesh graft): {}", bob));
+        assert_eq!(
+            report[3],
+            format!("mesh (subscribed): 1 of 1 peers in the mesh: {}", bob)
+        );
+        assert!(
+            report[4].ends_with(" ago: sent to 1 mesh peers"),
+            "{:?}",
+            report
+        );
+        assert_eq!(report.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_sent_once_a_peer_joins() {
+        let topic = topic_hash("retry", false);
+        let mut alice = swarm("retry").await;
+        let msg = api::ChatApi::message("anybody there?".into());
+        let origin = match &msg {
+            api::ChatApi::Message {
+                origin_timestamp, ..
+            } => *origin_timestamp,
+            _ => unreachable!(),
+        };
+        let mut retries = retry::Retries::default();
+        let published = publish_chat(
+            alice.behaviour_mut(),
+            topic.clone(),
+            msg.clone(),
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+        assert_eq!(published, Published::NoPeers);
+        retries.push(topic.clone(), msg);
+
+        // Bob only starts after the message was typed.
+        let mut bob = swarm("retry").await;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let addr = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } = bob.select_next_some().await {
+                    break address;
+                }
+            };
+            alice.dial(addr).unwrap();
+            let joined = loop {
+                tokio::select! {
+                    event = alice.select_next_some() => {
+                        if peer_joined(&alice.behaviour().gossipsub, &event) {
+                            break event;
+                        }
+                    }
+                    _ = bob.select_next_some() => {}
+                }
+            };
+            let joined = match joined {
+                SwarmEvent::Behaviour(BehaviourEvent::Membership { topic, .. }) => topic,
+                _ => unreachable!(),
+            };
+            let retried = retries
+                .retry(alice.behaviour_mut(), &joined, usize::MAX)
+                .await
+                .unwrap();
+            assert!(matches!(retried[..], [(_, Published::Sent { .. })]));
+            assert_eq!(retries.len(), 0);
+            loop {
+                let event = tokio::select! {
+                    _ = alice.select_next_some() => continue,
+                    event = bob.select_next_some() => event,
+                };
+                if let SwarmEvent::Behaviour(BehaviourEvent::Chat {
+                    message:
+                        api::ChatApi::Message {
+                            message,
+                            origin_timestamp,
+                            ..
+                        },
+                    ..
+                }) = event
+                {
+                    assert_eq!(message, "anybody there?");
+                    assert_eq!(origin_timestamp, origin);
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("Sent once bob joined");
+    }
+
+    #[tokio::test]
+    async fn dials_known_peers_by_id() {
+        let (mut alice, mut bob) = (swarm("address-book").await, swarm("address-book").await);
+        let bob_id = *bob.local_peer_id();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let addr = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } = bob.select_next_some().await {
+                    break address;
+                }
+            };
+            let mut state = State {
+                peer_addresses: alice.behaviour().address_book(),
+                ..Default::default()
+            };
+            state.add_address(bob_id, addr);
+            // No addresses given, so they're looked up in the address book.
+            alice.dial(DialOpts::peer_id(bob_id).build()).unwrap();
+            loop {
+                tokio::select! {
+                    event = alice.select_next_some() => match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id == bob_id => break,
+                        SwarmEvent::OutgoingConnectionError { error, .. } => panic!("{}", error),
+                        _ => {}
+                    },
+                    _ = bob.select_next_some() => {}
+                }
+            }
+        })
+        .await
+        .expect("Connected in time");
+    }
+
+    #[tokio::test]
+    async fn non_members_never_render() {
+        let topic = topic_hash("members", false);
+        let validating = gossipsub::GossipsubConfigBuilder::from(p2p::default_gossipsub_config())
+            .validate_messages()
+            .build()
+            .unwrap();
+        let (mut alice, mut bob) = (
+            swarm("members").await,
+            swarm_with_config("members", validating).await,
+        );
+        let alice_id = *alice.local_peer_id();
+        bob.behaviour_mut()
+            .set_members(topic.clone(), HashSet::from([PeerId::random()]))
+            .unwrap();
+        let send = |alice: &mut Swarm<Behaviour>, text: &str| {
+            let msg = encode::to_cbor(&api::ChatApi::message(text.into())).unwrap();
+            publish(alice.behaviour_mut(), topic.clone(), &msg);
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            connect(&mut alice, &mut bob, &topic).await;
+            send(&mut alice, "hidden");
+            while bob.behaviour().stats().non_members == 0 {
+                tokio::select! {
+                    _ = alice.select_next_some() => {}
+                    event = bob.select_next_some() => {
+                        assert!(!matches!(event, SwarmEvent::Behaviour(BehaviourEvent::Chat { .. })));
+                    }
+                }
+            }
+
+            // Reloaded with Alice on the list.
+            bob.behaviour_mut()
+                .set_members(topic.clone(), HashSet::from([alice_id]))
+                .unwrap();
+            send(&mut alice, "shown");
+            let message = loop {
+                tokio::select! {
+                    _ = alice.select_next_some() => {}
+                    event = bob.select_next_some() => {
+                        if let SwarmEvent::Behaviour(BehaviourEvent::Chat { message, .. }) = event {
+                            break message;
+                        }
+                    }
+                }
+            };
+            assert!(matches!(message, api::ChatApi::Message { message, .. } if message == "shown"));
+        })
+        .await
+        .expect("Messages received in time");
+    }
+
+    #[tokio::test]
+    async fn blocked_peers_are_dropped() {
+        let topic = topic_hash("blocking", false);
+        let (mut alice, mut bob) = (swarm("blocking").await, swarm("blocking").await);
+        let alice_id = *alice.local_peer_id();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            connect(&mut alice, &mut bob, &topic).await;
+            bob.behaviour_mut().graft(alice_id);
+            bob.behaviour_mut().block(alice_id);
+            assert!(!bob.behaviour().explicit_peers().contains(&alice_id));
+            let send = |alice: &mut Swarm<Behaviour>, text: &str| {
+                let msg = encode::to_cbor(&api::ChatApi::message(text.into())).unwrap();
+                publish(alice.behaviour_mut(), topic.clone(), &msg);
+            };
+            send(&mut alice, "blocked");
+            let quiet = tokio::time::sleep(Duration::from_secs(1));
+            tokio::pin!(quiet);
+            loop {
+                tokio::select! {
+                    _ = &mut quiet => break,
+                    _ = alice.select_next_some() => {}
+                    event = bob.select_next_some() => {
+                        assert!(!matches!(event, SwarmEvent::Behaviour(BehaviourEvent::Chat { .. })));
+                    }
+                }
+            }
+
+            bob.behaviour_mut().unblock(alice_id);
+            assert!(bob.behaviour().explicit_peers().contains(&alice_id));
+            send(&mut alice, "unblocked");
+            let message = loop {
+                tokio::select! {
+                    _ = alice.select_next_some() => {}
+                    event = bob.select_next_some() => {
+                        if let SwarmEvent::Behaviour(BehaviourEvent::Chat { peer, message, .. }) = event {
+                            assert_eq!(peer, alice_id);
+                            break message;
+                        }
+                    }
+                }
+            };
+            assert!(matches!(message, api::ChatApi::Message { message, .. } if message == "unblocked"));
+        })
+        .await
+        .expect("Unblocked message received in time");
+    }
+
+    #[tokio::test]
+    async fn forged_moderation_is_ignored() {
+        let topic = topic_hash("moderated", false);
+        let (mut alice, mut bob) = (swarm("moderated").await, swarm("moderated").await);
+        let (owner, carol) = (PeerId::random(), PeerId::random());
+        tokio::time::timeout(Duration::from_secs(10), async {
+            connect(&mut alice, &mut bob, &topic).await;
+            let msg = api::ChatApi::Moderate {
+                action: api::ModerationAction::Kick,
+                target: carol,
+            };
+            for _ in 0..2 {
+                publish(
+                    alice.behaviour_mut(),
+                    topic.clone(),
+                    &encode::to_cbor(&msg).unwrap(),
+                );
+            }
+            let mut received = Vec::new();
+            while received.len() < 2 {
+                tokio::select! {
+                    _ = alice.select_next_some() => {}
+                    event = bob.select_next_some() => {
+                        if matches!(event, SwarmEvent::Behaviour(BehaviourEvent::Chat { .. })) {
+                            received.push(event);
+                        }
+                    }
+                }
+            }
+
+            // Alice doesn't own the channel.
+            let mut state = State::default();
+            state.channel_owners.insert(topic.clone(), owner);
+            handle(bob.behaviour_mut(), &mut state, received.remove(0)).unwrap();
+            assert!(!state.is_hidden(&topic, &carol, Instant::now()));
+
+            let mut state = State::default();
+            state
+                .channel_owners
+                .insert(topic.clone(), *alice.local_peer_id());
+            handle(bob.behaviour_mut(), &mut state, received.remove(0)).unwrap();
+            assert!(state.is_hidden(&topic, &carol, Instant::now()));
+        })
+        .await
+        .expect("Moderation received in time");
+    }
+
+    #[test]
+    fn private_topics_are_hashed() {
+        let public = topic_hash("secret-plans", false);
+        let private = topic_hash("secret-plans", true);
+        assert_eq!(public.as_str(), "secret-plans");
+        assert!(!private.as_str().contains("secret-plans"));
+        assert_eq!(private, topic_hash("secret-plans", true));
+
+        let mut state = State::default();
+        assert_eq!(state.channel(&public), "secret-plans");
+        state
+            .channel_names
+            .insert(private.clone(), "secret-plans".into());
+        assert_eq!(state.channel(&private), "secret-plans");
+
+        // Joined by hash, the name is unknown.
+        let unknown = topic_hash("other-plans", true);
+        assert_eq!(state.channel(&unknown), unknown.as_str());
+        state.hashed_topics = true;
+        assert_eq!(
+            state.channel(&unknown),
+            format!("#{}", &unknown.as_str()[..8])
+        );
+        assert_eq!(state.channel(&private), "secret-plans");
+    }
+
+    #[test]
+    fn checks_channel_names() {
+        let pattern: regex::Regex = DEFAULT_CHANNEL_NAME_REGEX.parse().unwrap();
+        let long = "a".repeat(64);
+        for valid in ["agora", "rust_lang", "team-42", long.as_str()] {
+            assert!(check_channel_name(&pattern, valid).is_ok(), "{}", valid);
+        }
+        let too_long = "a".repeat(65);
+        for invalid in [
+            "",
+            "with space",
+            "ünïcode",
+            "#irc",
+            "a/b",
+            too_long.as_str(),
+        ] {
+            let error = check_channel_name(&pattern, invalid).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Invalid channel name: must match ^[a-zA-Z0-9_-]{1,64}$"
+            );
+        }
+
+        let custom: regex::Regex = "^tenant-[a-z]+$".parse().unwrap();
+        assert!(check_channel_name(&custom, "tenant-acme").is_ok());
+        assert!(check_channel_name(&custom, "agora").is_err());
+        assert!("^tenant-[a-z+$".parse::<regex::Regex>().is_err());
+    }
+
+    #[test]
+    fn meta_events_hidden_by_default() {
+        let payload = serde_json::json!({ "server": "libera", "user": "alice" });
+        assert_eq!(meta_event_line(false, "bot", "irc_join", &payload), None);
+
+        let line = meta_event_line(true, "bot", "irc_join", &payload).unwrap();
+        assert!(
+            line.ends_with(r#" bot [irc_join] {"server":"libera","user":"alice"}"#),
+            "{}",
+            line
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Gossip health of the channels, shown by `/mesh` to tell why messages might not arrive: per
+//! topic whether we're subscribed, the mesh peers among all known to be subscribed and the last
+//! result of publishing, collected by [`Behaviour::mesh_health`].
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+
+use crate::{
+    p2p::{Behaviour, MeshHealth},
+    publish::Published,
+    state::State,
+};
+
+/// The lines of `/mesh`, e.g.
+///
+/// ```text
+/// Heartbeat scheduled every 1s, last due 0.4s ago
+/// agora (subscribed): 1 of 2 peers in the mesh: alice; not in it: bob
+///   last published 12s ago: sent to 1 mesh peers
+/// ```
+pub(crate) fn report(behaviour: &Behaviour, state: &State, now: Instant) -> Vec<String> {
+    let mut lines = vec![format!(
+        "Heartbeat scheduled every {:?}, {}",
+        behaviour.heartbeat_interval(),
+        match behaviour.scheduled_heartbeat(now) {
+            Some(at) => format!("last due {} ago", seconds(now - at)),
+            None => "none due yet".into(),
+        }
+    )];
+    lines.push(
+        "Gossip (IHAVE/IWANT) exchanges aren't shown, gossipsub only counts them into a metrics \
+         registry"
+            .into(),
+    );
+    let explicit = behaviour.explicit_peers();
+    if !explicit.is_empty() {
+        lines.push(format!(
+            "Explicit peers (/mesh graft): {}",
+            nicks(state, explicit)
+        ));
+    }
+    for health in behaviour.mesh_health() {
+        let MeshHealth {
+            topic,
+            subscribed,
+            mesh,
+            peers,
+            last_published,
+        } = health;
+        let mut line = format!(
+            "{} ({}): ",
+            state.channel(&topic),
+            if subscribed {
+                "subscribed"
+            } else {
+                "not subscribed"
+            }
+        );
+        if peers.is_empty() {
+            line.push_str("no peers known to be subscribed");
+        } else {
+            line.push_str(&format!(
+                "{} of {} peers in the mesh: {}",
+                mesh.len(),
+                peers.len(),
+                nicks(state, &mesh)
+            ));
+            let outside = peers.iter().filter(|peer| !mesh.contains(peer));
+            if peers.len() > mesh.len() {
+                line.push_str(&format!("; not in it: {}", nicks(state, outside)));
+            }
+        }
+        lines.push(line);
+        lines.push(match last_published {
+            Some((at, published)) => format!(
+                "  last published {} ago: {}",
+                seconds(now.saturating_duration_since(at)),
+                describe(published)
+            ),
+            None => "  nothing published yet".into(),
+        });
+    }
+    lines
+}
+
+fn nicks<'a>(state: &State, peers: impl IntoIterator<Item = &'a PeerId>) -> String {
+    peers
+        .into_iter()
+        .map(|peer| state.nick(peer))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+fn describe(published: Published) -> String {
+    match published {
+        Published::Sent { reach: Some(n) } => format!("sent to {} mesh peers", n),
+        Published::Sent { reach: None } => "sent to fanout peers, the mesh was empty".into(),
+        _ => published.notice().expect("Only sent messages have none"),
+    }
+}
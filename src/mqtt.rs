@@ -0,0 +1,196 @@
+//! Bridges a channel to an MQTT broker: channel messages are published to
+//! `<prefix>/<channel>/in`, messages received on `<prefix>/<channel>/out` are posted to the
+//! channel.
+use std::time::Duration;
+
+use anyhow::Context;
+use hyper::Uri;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::*;
+
+use crate::{api::HookPost, config::Mqtt, shutdown::Tasks, webhook::Payload};
+
+/// Marks messages posted by the bridge, which are never bridged back.
+pub(crate) const BRIDGE: &str = "mqtt";
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct OutMessage {
+    text: String,
+}
+
+pub(crate) struct Bridge {
+    client: AsyncClient,
+    prefix: String,
+    posts: mpsc::UnboundedReceiver<HookPost>,
+}
+
+impl Bridge {
+    pub(crate) fn spawn(config: Mqtt, channel: String, tasks: &mut Tasks) -> anyhow::Result<Self> {
+        let broker: Uri = config.broker.parse().context("Parsing MQTT broker URL")?;
+        let host = broker.host().context("MQTT broker URL without host")?;
+        let mut options = MqttOptions::new(
+            format!("agora-{}", names::Generator::default().next().unwrap()),
+            host,
+            broker.port_u16().unwrap_or(1883),
+        );
+        options
+            .set_clean_session(true)
+            .set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = config.username {
+            options.set_credentials(username, config.password.unwrap_or_default());
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+        let out_topic = format!("{}/{}/out", config.prefix, channel);
+        let (tx, posts) = mpsc::unbounded_channel();
+        let subscriber = client.clone();
+        tasks.spawn(|shutdown| async move {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let event = tokio::select! {
+                    _ = shutdown.wait() => break,
+                    event = eventloop.poll() => event,
+                };
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        backoff = INITIAL_BACKOFF;
+                        // Clean sessions don't keep subscriptions across reconnects.
+                        if let Err(error) = subscriber.try_subscribe(&out_topic, QoS::AtLeastOnce) {
+                            warn!(%error, "MQTT subscribe failed");
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == out_topic => {
+                        let text = serde_json::from_slice::<OutMessage>(&publish.payload)
+                            .map(|m| m.text)
+                            .or_else(|_| String::from_utf8(publish.payload.to_vec()));
+                        match text {
+                            Ok(text) => {
+                                let post = HookPost {
+                                    channel: channel.clone(),
+                                    nick: config.nick.clone(),
+                                    text,
+                                };
+                                if tx.send(post).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(_) => {
+                                debug!(topic = %publish.topic, "Ignoring non UTF-8 MQTT message")
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        warn!(%error, ?backoff, "MQTT connection failed, reconnecting");
+                        tokio::select! {
+                            _ = shutdown.wait() => break,
+                            _ = tokio::time::sleep(backoff) => {}
+                        }
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            prefix: config.prefix,
+            posts,
+        })
+    }
+
+    /// Publishes a channel message to `<prefix>/<channel>/in`.
+    pub(crate) fn forward(&self, payload: &Payload, channel: &str) {
+        let topic = format!("{}/{}/in", self.prefix, channel);
+        let payload = serde_json::to_vec(payload).expect("Serialization works");
+        if let Err(error) = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, false, payload)
+        {
+            warn!(%error, "Dropping MQTT message");
+        }
+    }
+
+    pub(crate) async fn next_post(bridge: &mut Option<Self>) -> Option<HookPost> {
+        match bridge {
+            Some(bridge) => bridge.posts.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Requires a broker, e.g. `docker run -p 1883:1883 eclipse-mosquitto:1.6`, at
+/// `AGORA_TEST_MQTT_BROKER` (default `mqtt://127.0.0.1:1883`).
+#[cfg(all(test, feature = "mqtt-broker-tests"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn roundtrip_through_broker() {
+        let broker = std::env::var("AGORA_TEST_MQTT_BROKER")
+            .unwrap_or_else(|_| "mqtt://127.0.0.1:1883".into());
+        let config = Mqtt {
+            broker,
+            username: None,
+            password: None,
+            prefix: format!("agora-test-{}", std::process::id()),
+            nick: "bot".into(),
+        };
+        let mut tasks = Tasks::default();
+        let mut bridge = Some(Bridge::spawn(config.clone(), "agora".into(), &mut tasks).unwrap());
+
+        // Act as the home automation side: listen on `in`, post to `out`.
+        let url: Uri = config.broker.parse().unwrap();
+        let (client, mut eventloop) = AsyncClient::new(
+            MqttOptions::new(
+                "agora-test",
+                url.host().unwrap(),
+                url.port_u16().unwrap_or(1883),
+            ),
+            16,
+        );
+        let in_topic = format!("{}/agora/in", config.prefix);
+        client.subscribe(&in_topic, QoS::AtLeastOnce).await.unwrap();
+        let (tx, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(event) = eventloop.poll().await {
+                if let Event::Incoming(Packet::Publish(p)) = event {
+                    tx.send(p.payload).unwrap();
+                }
+            }
+        });
+        // Give both clients time to connect and subscribe.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let payload = Payload::message(
+            &libp2p::PeerId::random(),
+            "alice",
+            "agora",
+            chrono::Utc::now(),
+            "hello",
+        );
+        bridge.as_ref().unwrap().forward(&payload, "agora");
+        let json: serde_json::Value =
+            serde_json::from_slice(&received.recv().await.unwrap()).unwrap();
+        assert_eq!(json["text"], "hello");
+
+        client
+            .publish(
+                format!("{}/agora/out", config.prefix),
+                QoS::AtLeastOnce,
+                false,
+                r#"{"text": "lights on"}"#,
+            )
+            .await
+            .unwrap();
+        let post = Bridge::next_post(&mut bridge).await.unwrap();
+        assert_eq!(post.text, "lights on");
+        assert_eq!(post.nick, "bot");
+        assert_eq!(post.channel, "agora");
+    }
+}
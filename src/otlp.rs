@@ -0,0 +1,114 @@
+//! OpenTelemetry export of the `publish` and `receive` spans.
+use opentelemetry::{
+    sdk::{
+        trace::{self, Sampler},
+        Resource,
+    },
+    KeyValue,
+};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+#[derive(clap::Args, Debug, Default)]
+pub(crate) struct OtlpArgs {
+    /// Export traces to this OTLP/gRPC endpoint, e.g. http://localhost:4317
+    #[clap(long, global = true)]
+    otlp_endpoint: Option<String>,
+
+    /// Fraction of traces to export
+    #[clap(long, global = true, default_value_t = 1.0)]
+    otlp_sample_ratio: f64,
+}
+
+impl OtlpArgs {
+    /// Starts the batch exporter in the background, if an endpoint is configured.
+    pub(crate) fn layer<S>(&self) -> anyhow::Result<Option<OpenTelemetryLayer<S, trace::Tracer>>>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let endpoint = match &self.otlp_endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(None),
+        };
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(
+                trace::config()
+                    .with_sampler(Sampler::TraceIdRatioBased(self.otlp_sample_ratio))
+                    .with_resource(Resource::new([KeyValue::new("service.name", "agora")])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)?;
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+}
+
+/// Flushes pending spans.
+pub(crate) fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use libp2p::gossipsub::{error::PublishError, IdentTopic, MessageId, TopicHash};
+    use opentelemetry::{
+        sdk::export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::TracerProvider,
+        Key,
+    };
+    use tracing_subscriber::prelude::*;
+
+    use crate::{api::ChatApi, publish};
+
+    #[derive(Debug, Clone, Default)]
+    struct InMemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    #[async_trait::async_trait]
+    impl SpanExporter for InMemoryExporter {
+        async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+            self.0.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    struct Sink;
+
+    impl publish::Publisher for Sink {
+        fn publish(&mut self, _: TopicHash, data: &[u8]) -> Result<MessageId, PublishError> {
+            Ok(MessageId::new(data))
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_produces_spans() {
+        let exporter = InMemoryExporter::default();
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("agora")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        publish::publish_chat(
+            &mut Sink,
+            IdentTopic::new("agora"),
+            ChatApi::message("hello".into()),
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+        provider.force_flush();
+
+        let spans = exporter.0.lock().unwrap();
+        let publish = spans.iter().find(|s| s.name == "publish").unwrap();
+        assert!(publish.attributes.get(&Key::new("size")).is_some());
+        assert!(spans.iter().any(|s| s.name == "encode"));
+    }
+}
@@ -0,0 +1,111 @@
+//! Messages typed but not delivered, with why, shown by `/outbox`: those waiting for peers (see
+//! [`Retries`](crate::retry::Retries)) and those which failed for good, e.g. being too large to
+//! publish, kept until `/outbox clear`. Like the retries, they're only kept in memory, and listed
+//! on exit.
+use crate::{display::DisplayTime, publish::Published, retry};
+
+/// A message which failed for good.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Failed {
+    pub(crate) at: DisplayTime,
+    pub(crate) channel: String,
+    pub(crate) text: String,
+    pub(crate) reason: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Outbox {
+    failed: Vec<Failed>,
+}
+
+impl Outbox {
+    /// Records the message if `published` is a failure retrying won't help with, returning
+    /// whether it did. Messages without peers are left to the retries, and duplicates were sent
+    /// just before.
+    pub(crate) fn record(&mut self, channel: &str, text: &str, published: Published) -> bool {
+        let permanent = matches!(
+            published,
+            Published::TooLarge { .. } | Published::SigningFailed | Published::TransformFailed
+        );
+        let reason = match published.notice() {
+            Some(notice) if permanent => notice,
+            _ => return false,
+        };
+        self.fail(channel, text, reason);
+        true
+    }
+
+    /// Records a queued message dropped for newer ones.
+    pub(crate) fn dropped(&mut self, channel: &str, text: &str) {
+        self.fail(
+            channel,
+            text,
+            format!(
+                "Dropped, more than {} were waiting for peers",
+                retry::MAX_QUEUED
+            ),
+        );
+    }
+
+    fn fail(&mut self, channel: &str, text: &str, reason: String) {
+        self.failed.push(Failed {
+            at: DisplayTime::now(),
+            channel: channel.into(),
+            text: text.into(),
+            reason,
+        });
+    }
+
+    pub(crate) fn failed(&self) -> &[Failed] {
+        &self.failed
+    }
+
+    /// Forgets the failed messages, returning how many there were.
+    pub(crate) fn clear(&mut self) -> usize {
+        std::mem::take(&mut self.failed).len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_permanent_failures() {
+        let mut outbox = Outbox::default();
+        for transient in [
+            Published::Sent { reach: Some(3) },
+            Published::NoPeers,
+            Published::Duplicate,
+        ] {
+            assert!(!outbox.record("agora", "hello", transient));
+        }
+        assert!(outbox.failed().is_empty());
+
+        let too_large = Published::TooLarge {
+            size: 70_000,
+            max: Some(65_536),
+        };
+        assert!(outbox.record("agora", "long", too_large));
+        outbox.dropped("agora", "old");
+        assert_eq!(
+            outbox
+                .failed()
+                .iter()
+                .map(|f| (f.text.as_str(), f.reason.clone()))
+                .collect::<Vec<_>>(),
+            [
+                ("long", too_large.notice().unwrap()),
+                (
+                    "old",
+                    format!(
+                        "Dropped, more than {} were waiting for peers",
+                        retry::MAX_QUEUED
+                    )
+                )
+            ]
+        );
+        assert_eq!(outbox.clear(), 2);
+        assert!(outbox.failed().is_empty());
+    }
+}
@@ -1,49 +1,397 @@
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::task::Poll;
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
+use bytes::Bytes;
 use libp2p::{
     core::{
+        connection::ConnectionId,
         either::EitherError,
         muxing::StreamMuxerBox,
         transport::{upgrade, Boxed},
+        ConnectedPoint,
     },
-    gossipsub::{self, error::GossipsubHandlerError, Gossipsub, GossipsubEvent},
-    identity::{self, Keypair},
-    mdns::{self, Mdns, MdnsEvent},
-    mplex, noise, ping,
+    gossipsub::{
+        self,
+        error::{GossipsubHandlerError, PublishError},
+        GossipsubEvent, GossipsubMessage, MessageAcceptance, MessageId, RawGossipsubMessage,
+        TopicHash,
+    },
+    identity::{self, Keypair, PublicKey},
+    mplex,
+    multiaddr::Protocol,
+    noise,
     swarm::{
-        dial_opts::{DialOpts, PeerCondition},
-        NetworkBehaviour, NetworkBehaviourEventProcess, Swarm, SwarmBuilder,
+        ConnectionHandler, IntoConnectionHandler, NetworkBehaviour, NetworkBehaviourEventProcess,
+        PollParameters, Swarm, SwarmBuilder,
     },
     tcp::TokioTcpConfig,
-    NetworkBehaviour, PeerId, Transport,
+    Multiaddr, NetworkBehaviour, PeerId, Transport,
+};
+use tracing::{debug, debug_span, info_span, Span};
+
+use crate::{
+    address_book::AddressBook,
+    api::{ChatApi, Limits},
+    audit::{self, AuditLog},
+    cidr::Cidr,
+    crypt::{ChannelKey, Derived, Keyring, OpenError},
+    discovery::{Discovered, Discoveries, Discovery, PeerAddr},
+    dm, encode,
+    keep_alive::{self, KeepAlivePeers},
+    publish::{Published, Publisher},
+    replay::{self, ReplayGuard, Sequencer},
+    state::{PinnedKeys, SharedAddresses},
+    tcp::TcpKeepalive,
 };
-use tracing::debug;
 
-use crate::api::ChatApi;
+#[cfg(feature = "ping")]
+use libp2p::ping;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+#[cfg(feature = "relay")]
+use libp2p::{core::transport::OrTransport, relay::v2::client as relay};
+
+// Disabled sub-behaviours are replaced by a behaviour doing nothing, keeping `Behaviour`'s shape.
+#[cfg(feature = "ping")]
+type PingBehaviour = ping::Ping;
+#[cfg(not(feature = "ping"))]
+type PingBehaviour = libp2p::swarm::DummyBehaviour;
+#[cfg(feature = "ping")]
+type PingFailure = ping::Failure;
+#[cfg(not(feature = "ping"))]
+type PingFailure = void::Void;
+#[cfg(feature = "relay")]
+type RelayBehaviour = relay::Client;
+#[cfg(not(feature = "relay"))]
+type RelayBehaviour = libp2p::swarm::DummyBehaviour;
+// Named through the client, as its handler types aren't exported.
+#[cfg(feature = "relay")]
+type RelayFailure = <<<relay::Client as NetworkBehaviour>::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::Error;
+#[cfg(not(feature = "relay"))]
+type RelayFailure = void::Void;
+
+/// Multihash code of peer ids embedding their public key.
+const IDENTITY_HASH: u64 = 0x00;
+/// Nicknames pinned on first use, beyond which further ones aren't.
+const MAX_PINNED: usize = 4096;
+
+/// Reads a keypair written by [`generate_identity`].
+pub(crate) fn load_identity(path: &Path) -> anyhow::Result<Keypair> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Reading identity {}", path.display()))?;
+    Ok(Keypair::from_protobuf_encoding(&bytes)?)
+}
+
+/// Reads peer ids, one per line. Empty lines and lines starting with `#` are ignored.
+pub(crate) fn load_allowlist(path: &Path) -> anyhow::Result<Vec<PeerId>> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("Reading allowlist {}", path.display()))?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            l.parse()
+                .with_context(|| format!("Invalid peer id {} in {}", l, path.display()))
+        })
+        .collect()
+}
 
-fn mk_transport() -> (Keypair, Boxed<(PeerId, StreamMuxerBox)>) {
+/// Reads a JSON map of nicknames to the base64 encoded (protobuf) public keys of the peers
+/// allowed to use them.
+pub(crate) fn load_pinned_keys(path: &Path) -> anyhow::Result<BTreeMap<String, PublicKey>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading pinned keys {}", path.display()))?;
+    let encoded: BTreeMap<String, String> = serde_json::from_str(&content)
+        .with_context(|| format!("Parsing pinned keys {}", path.display()))?;
+    encoded
+        .into_iter()
+        .map(|(nick, key)| {
+            let key = base64::decode(&key)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(PublicKey::from_protobuf_encoding(&bytes)?))
+                .with_context(|| format!("Invalid pinned key of {} in {}", nick, path.display()))?;
+            Ok((nick, key))
+        })
+        .collect()
+}
+
+/// Generates a new keypair and writes it to `path`, which must not exist yet.
+pub(crate) fn generate_identity(path: &Path) -> anyhow::Result<Keypair> {
     let keypair = identity::Keypair::generate_ed25519();
+    let bytes = keypair.to_protobuf_encoding()?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(&bytes))
+        .with_context(|| format!("Writing identity {}", path.display()))?;
+    Ok(keypair)
+}
 
-    let transport = TokioTcpConfig::new()
-        .nodelay(true)
+/// Setting up the transport failed.
+#[derive(Debug, thiserror::Error)]
+#[error("Building the transport failed")]
+pub(crate) struct TransportBuildError(#[from] noise::NoiseError);
+
+/// Gossipsub rejected its configuration.
+#[derive(Debug, thiserror::Error)]
+#[error("Building gossipsub failed: {0}")]
+pub(crate) struct GossipsubBuildError(&'static str);
+
+/// Failure of [`Behaviour::bootstrap`].
+///
+/// Callers can match on the failing component, or just bubble it up as `anyhow::Error`:
+///
+/// ```ignore
+/// match Behaviour::bootstrap_with_config(keypair, None, default_gossipsub_config()).await {
+///     Ok(swarm) => run(swarm),
+///     Err(BehaviourBootstrapError::Discovery(backend, error)) => {
+///         warn!(%error, "{} unavailable", backend)
+///     }
+///     Err(error) => return Err(error.into()),
+/// }
+/// ```
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum BehaviourBootstrapError {
+    #[error("Starting {0} failed")]
+    Discovery(&'static str, #[source] std::io::Error),
+    #[error(transparent)]
+    Gossipsub(#[from] GossipsubBuildError),
+    #[error(transparent)]
+    Transport(#[from] TransportBuildError),
+}
+
+/// The transport, along with the relay client dialing and listening through it (if enabled).
+fn mk_transport(
+    keypair: Keypair,
+    tcp_keepalive: TcpKeepalive,
+) -> Result<(Keypair, RelayBehaviour, Boxed<(PeerId, StreamMuxerBox)>), TransportBuildError> {
+    let tcp = TokioTcpConfig::new().nodelay(true).map(move |stream, _| {
+        if let Err(error) = tcp_keepalive.apply(&stream.0) {
+            debug!(%error, "Setting TCP keep-alive failed");
+        }
+        stream
+    });
+    // Addresses ending in `/p2p-circuit` are dialed (or listened on) through the relay they name.
+    #[cfg(feature = "relay")]
+    let (transport, relay) = {
+        let (relayed, relay) = relay::Client::new_transport_and_behaviour(keypair.public().into());
+        (OrTransport::new(relayed, tcp), relay)
+    };
+    #[cfg(not(feature = "relay"))]
+    let (transport, relay) = (
+        tcp,
+        libp2p::swarm::DummyBehaviour::with_keep_alive(libp2p::swarm::KeepAlive::No),
+    );
+    let transport = transport
         .upgrade(upgrade::Version::V1)
         .authenticate(
             noise::NoiseConfig::xx(
-                noise::Keypair::<noise::X25519Spec>::new()
-                    .into_authentic(&keypair)
-                    .unwrap(),
+                noise::Keypair::<noise::X25519Spec>::new().into_authentic(&keypair)?,
             )
             .into_authenticated(),
         )
         .multiplex(mplex::MplexConfig::new())
         .boxed();
 
-    (keypair, transport)
+    Ok((keypair, relay, transport))
+}
+
+/// Rejects messages naming a source without being signed, which permissive validation lets
+/// through (it only verifies signatures present). A received message's `source` is thus either
+/// absent or its validly signed author.
+///
+/// As gossipsub checks that the signing key is the one the source's peer id is derived from,
+/// keys are pinned to nicknames instead, see [`Behaviour::pin_nicknames`].
+#[derive(Debug, Default, Clone)]
+pub(crate) struct RequireSignedSource {
+    audit: AuditLog,
+    /// The peer the messages transformed were received from, set by [`RelayedGossipsub`].
+    relay: Arc<Mutex<Option<PeerId>>>,
+}
+
+/// The public key `peer`'s id embeds, as ed25519 ones do.
+fn embedded_key(peer: &PeerId) -> Option<PublicKey> {
+    let hash = peer.as_ref();
+    if hash.code() != IDENTITY_HASH {
+        return None;
+    }
+    PublicKey::from_protobuf_encoding(hash.digest()).ok()
+}
+
+impl gossipsub::DataTransform for RequireSignedSource {
+    fn inbound_transform(
+        &self,
+        raw: RawGossipsubMessage,
+    ) -> Result<GossipsubMessage, std::io::Error> {
+        if let (Some(source), None) = (&raw.source, &raw.signature) {
+            // The source is just claimed, so it's the relaying peer who's counted.
+            if let Some(relay) = *self.relay.lock().expect("Not poisoned") {
+                self.audit.record(
+                    audit::Kind::InvalidSignature,
+                    &relay,
+                    format_args!("Source {} without signature", source),
+                );
+            }
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Source without signature",
+            ));
+        }
+        Ok(GossipsubMessage {
+            source: raw.source,
+            data: raw.data,
+            sequence_number: raw.sequence_number,
+            topic: raw.topic,
+        })
+    }
+
+    fn outbound_transform(
+        &self,
+        _topic: &TopicHash,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        Ok(data)
+    }
+}
+
+pub(crate) type Gossipsub = gossipsub::Gossipsub<RequireSignedSource>;
+// Named through the behaviour, as its handler types aren't exported.
+type GossipsubHandler =
+    <<Gossipsub as NetworkBehaviour>::ConnectionHandler as IntoConnectionHandler>::Handler;
+
+/// Gossipsub, telling its [`RequireSignedSource`] which peer the messages handed to it were
+/// received from, as [`gossipsub::DataTransform`] isn't told.
+pub(crate) struct RelayedGossipsub {
+    gossipsub: Gossipsub,
+    relay: Arc<Mutex<Option<PeerId>>>,
 }
 
-pub(crate) type SwarmError =
-    EitherError<EitherError<GossipsubHandlerError, void::Void>, ping::Failure>;
+impl Deref for RelayedGossipsub {
+    type Target = Gossipsub;
+
+    fn deref(&self) -> &Gossipsub {
+        &self.gossipsub
+    }
+}
+
+impl DerefMut for RelayedGossipsub {
+    fn deref_mut(&mut self) -> &mut Gossipsub {
+        &mut self.gossipsub
+    }
+}
+
+// Delegates what gossipsub implements, the other methods do nothing there either.
+impl NetworkBehaviour for RelayedGossipsub {
+    type ConnectionHandler = <Gossipsub as NetworkBehaviour>::ConnectionHandler;
+    type OutEvent = GossipsubEvent;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        self.gossipsub.new_handler()
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        failed_addresses: Option<&Vec<Multiaddr>>,
+        other_established: usize,
+    ) {
+        self.gossipsub.inject_connection_established(
+            peer,
+            connection,
+            endpoint,
+            failed_addresses,
+            other_established,
+        )
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        handler: GossipsubHandler,
+        remaining_established: usize,
+    ) {
+        self.gossipsub.inject_connection_closed(
+            peer,
+            connection,
+            endpoint,
+            handler,
+            remaining_established,
+        )
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer: &PeerId,
+        connection: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.gossipsub
+            .inject_address_change(peer, connection, old, new)
+    }
+
+    fn inject_event(
+        &mut self,
+        peer: PeerId,
+        connection: ConnectionId,
+        event: <GossipsubHandler as ConnectionHandler>::OutEvent,
+    ) {
+        *self.relay.lock().expect("Not poisoned") = Some(peer);
+        self.gossipsub.inject_event(peer, connection, event);
+        *self.relay.lock().expect("Not poisoned") = None;
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<libp2p::swarm::NetworkBehaviourAction<GossipsubEvent, Self::ConnectionHandler>> {
+        self.gossipsub.poll(cx, params)
+    }
+}
+
+/// Whether `message` is accepted, `signed` if its author is known, see [`RequireSignedSource`].
+/// Otherwise it's attributed to the peer relaying it, so only plain messages are, to be shown
+/// as unsigned without touching any state.
+pub(crate) fn accept(message: &ChatApi, signed: bool) -> bool {
+    match message {
+        ChatApi::Message { .. } => true,
+        ChatApi::ChangeNickname { .. }
+        | ChatApi::Capabilities { .. }
+        | ChatApi::DirectMessage { .. }
+        | ChatApi::SealedDirectMessage { .. }
+        | ChatApi::DmChallenge { .. }
+        | ChatApi::DmChallengeResponse { .. }
+        | ChatApi::HistoryRequest { .. }
+        | ChatApi::HistoryResponse { .. }
+        | ChatApi::Moderate { .. }
+        | ChatApi::GroupKeyExchange { .. }
+        | ChatApi::MetaEvent { .. } => signed,
+    }
+}
+
+pub(crate) type SwarmError = EitherError<
+    EitherError<
+        EitherError<
+            EitherError<EitherError<GossipsubHandlerError, void::Void>, PingFailure>,
+            RelayFailure,
+        >,
+        void::Void,
+    >,
+    void::Void,
+>;
 #[derive(NetworkBehaviour)]
 #[behaviour(
     event_process = true,
@@ -51,17 +399,251 @@ pub(crate) type SwarmError =
     out_event = "BehaviourEvent"
 )]
 pub(crate) struct Behaviour {
-    pub(crate) gossipsub: Gossipsub,
-    mdns: Mdns,
-    ping: ping::Ping,
+    pub(crate) gossipsub: RelayedGossipsub,
+    discovery: Discoveries,
+    ping: PingBehaviour,
+    relay: RelayBehaviour,
+    /// Connections to peers neither important nor sharing a topic close once idle.
+    keep_alive: KeepAlivePeers,
+    /// Addresses to dial peers at if only their id is given.
+    addresses: AddressBook,
+    /// Keys of the only peers a nickname is accepted from, see [`Behaviour::pin_nicknames`].
+    #[behaviour(ignore)]
+    pinned_keys: PinnedKeys,
+    #[behaviour(ignore)]
+    pin_nicknames: bool,
+    /// Whether signed messages without a sequence are dropped, see [`Behaviour::require_sequence`].
+    #[behaviour(ignore)]
+    require_sequence: bool,
+    /// Shared with the transform, which records invalid signatures.
+    #[behaviour(ignore)]
+    audit: AuditLog,
+    /// Discovered addresses in these are ignored.
+    #[behaviour(ignore)]
+    ignored_prefixes: Vec<Cidr>,
 
     #[behaviour(ignore)]
-    events: VecDeque<NetworkBehaviourAction>,
+    local_peer_id: PeerId,
+    /// To open direct messages sealed to us.
+    #[behaviour(ignore)]
+    keypair: Keypair,
+    /// If set, only these peers are dialed and listened to.
+    #[behaviour(ignore)]
+    allowlist: Option<BTreeSet<PeerId>>,
+    #[behaviour(ignore)]
+    events: EventQueue<NetworkBehaviourAction>,
+    /// If set, payloads are sealed before publishing and opened on receipt.
+    #[behaviour(ignore)]
+    channel_key: Option<Keyring>,
+    /// Keys for peers' salts derived on the blocking thread pool, see [`crate::crypt`].
+    #[behaviour(ignore)]
+    derived: (
+        tokio::sync::mpsc::UnboundedSender<Derived>,
+        tokio::sync::mpsc::UnboundedReceiver<Derived>,
+    ),
+    /// Messages to open once their key is derived: the author, whether signed, topic and data.
+    #[behaviour(ignore)]
+    awaiting_key: VecDeque<(PeerId, bool, TopicHash, Vec<u8>)>,
+    /// Messages dropped because they couldn't be opened with `channel_key`.
+    #[behaviour(ignore)]
+    undecryptable: u64,
+    /// Topics an [`BehaviourEvent::Undecryptable`] has been emitted for.
+    #[behaviour(ignore)]
+    undecryptable_topics: HashSet<TopicHash>,
+    /// Topics messages have been opened on, so a key not held means a rekey rather than a
+    /// wrong passphrase.
+    #[behaviour(ignore)]
+    opened_topics: HashSet<TopicHash>,
+    /// Whether [`BehaviourEvent::Rekeyed`] has been emitted.
+    #[behaviour(ignore)]
+    rekey_noticed: bool,
+    /// Numbers published payloads.
+    #[behaviour(ignore)]
+    sequencer: Sequencer,
+    /// Drops signed messages received before.
+    #[behaviour(ignore)]
+    pub(crate) replay: ReplayGuard,
+    /// Per topic, the only peers whose messages are accepted and forwarded.
+    #[behaviour(ignore)]
+    members: HashMap<TopicHash, HashSet<PeerId>>,
+    #[behaviour(ignore)]
+    non_members: u64,
+    /// Whether gossipsub waits for a validation result before forwarding messages.
+    #[behaviour(ignore)]
+    validate_messages: bool,
+    /// Of the gossipsub config, to tell why a message couldn't be published.
+    #[behaviour(ignore)]
+    max_transmit_size: usize,
+    #[behaviour(ignore)]
+    limits: Limits,
+    /// Messages rejected for exceeding `limits`, per peer.
+    #[behaviour(ignore)]
+    over_limits: HashMap<PeerId, u64>,
+    #[behaviour(ignore)]
+    over_limits_total: u64,
+    /// Whether payloads which don't decode are taken as plain text, see [`plaintext`].
+    #[behaviour(ignore)]
+    compat_plaintext: bool,
+    /// When gossipsub's heartbeats are due, see [`Behaviour::scheduled_heartbeat`].
+    #[behaviour(ignore)]
+    heartbeats: Heartbeats,
+    /// The last result of publishing to each topic, and when.
+    #[behaviour(ignore)]
+    last_published: HashMap<TopicHash, (Instant, Published)>,
+    /// Added by [`Behaviour::graft`].
+    #[behaviour(ignore)]
+    explicit_peers: BTreeSet<PeerId>,
+    /// Explicit peers while blocked, made explicit again by [`Behaviour::unblock`].
+    #[behaviour(ignore)]
+    blocked_explicit_peers: BTreeSet<PeerId>,
+    /// Our subscriptions as last seen by [`Behaviour::my_poll`], to tell the peers sharing them.
+    #[behaviour(ignore)]
+    subscribed: Vec<TopicHash>,
+}
+
+/// Gossipsub's heartbeat schedule, as it doesn't tell when they actually run: the first due
+/// `initial_delay` after `started`, then every `interval`.
+#[derive(Debug, Clone, Copy)]
+struct Heartbeats {
+    started: Instant,
+    initial_delay: Duration,
+    interval: Duration,
+}
+
+impl Heartbeats {
+    /// When the last heartbeat before `now` was due, if any was.
+    fn last(&self, now: Instant) -> Option<Instant> {
+        let first = self.started + self.initial_delay;
+        let since_first = now.checked_duration_since(first)?;
+        let beats = since_first.as_nanos() / self.interval.as_nanos().max(1);
+        Some(first + self.interval * beats as u32)
+    }
+}
+
+/// Gossip health of a topic, shown by `/mesh`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MeshHealth {
+    pub(crate) topic: TopicHash,
+    /// False for topics only published to, e.g. after leaving their channel.
+    pub(crate) subscribed: bool,
+    /// Peers messages are forwarded to in full.
+    pub(crate) mesh: Vec<PeerId>,
+    /// All peers known to be subscribed, in the mesh or not.
+    pub(crate) peers: Vec<PeerId>,
+    pub(crate) last_published: Option<(Instant, Published)>,
+}
+
+/// Counters shown by `/stats`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Stats {
+    /// Messages which couldn't be opened with the channel key.
+    pub(crate) undecryptable: u64,
+    /// Messages dropped as replayed.
+    pub(crate) replayed: u64,
+    /// Messages rejected for not being signed by a member, see [`Behaviour::set_members`].
+    pub(crate) non_members: u64,
+    /// Messages rejected for exceeding the [`Limits`], and the number of peers sending them.
+    pub(crate) over_limits: u64,
+    pub(crate) over_limits_peers: usize,
+}
+
+/// Peers whose messages exceeding the limits are counted individually.
+const MAX_OVER_LIMITS_PEERS: usize = 1024;
+/// Messages waiting for their key to be derived, the oldest are dropped beyond.
+const MAX_AWAITING_KEY: usize = 256;
+
+/// Consecutive events after which a pending action is let through.
+const MAX_BURST: usize = 16;
+
+/// Queue handing out user-visible events before internal actions (e.g. dials after a mass mDNS
+/// discovery), without starving the latter: one action is let through every [`MAX_BURST`]
+/// events.
+#[derive(Debug)]
+struct EventQueue<T> {
+    events: VecDeque<T>,
+    actions: VecDeque<T>,
+    burst: usize,
+}
+
+impl<T> Default for EventQueue<T> {
+    fn default() -> Self {
+        Self {
+            events: VecDeque::new(),
+            actions: VecDeque::new(),
+            burst: 0,
+        }
+    }
+}
+
+impl<T> EventQueue<T> {
+    fn push_event(&mut self, event: T) {
+        self.events.push_back(event);
+    }
+
+    // Only dials for now, which are issued on discoveries.
+    fn push_action(&mut self, action: T) {
+        self.actions.push_back(action);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.burst < MAX_BURST || self.actions.is_empty() {
+            if let Some(event) = self.events.pop_front() {
+                self.burst += 1;
+                return Some(event);
+            }
+        }
+        self.burst = 0;
+        self.actions.pop_front()
+    }
 }
 
 #[derive(Debug)]
 pub(crate) enum BehaviourEvent {
-    Chat { peer: PeerId, message: ChatApi },
+    Chat {
+        peer: PeerId,
+        /// Whether `peer` is the message's signed author, not just relaying it. Only plain
+        /// messages are passed on unsigned, see [`accept`].
+        signed: bool,
+        topic: TopicHash,
+        message: ChatApi,
+        /// The CBOR encoded `message`, as received.
+        message_raw: Bytes,
+        /// `receive` span, to be entered while handling the event.
+        span: Span,
+    },
+    /// The first message on `topic` failing authentication under the channel key, i.e. the
+    /// channel uses another passphrase.
+    Undecryptable { topic: TopicHash },
+    /// The first message on a channel previously opened that is sealed with a key not held,
+    /// i.e. the channel was rekeyed.
+    Rekeyed { topic: TopicHash },
+    /// `peer` subscribed to (`joined`) or unsubscribed from `topic`, which isn't necessarily one
+    /// of ours. Unsubscribing is e.g. leaving the channel while staying connected.
+    Membership {
+        peer: PeerId,
+        topic: TopicHash,
+        joined: bool,
+    },
+}
+
+/// Decodes a gossipsub payload, keeping hold of the raw bytes without copying them.
+pub(crate) fn decode(data: impl Into<Bytes>) -> Option<(Bytes, ChatApi)> {
+    let _span = debug_span!("decode").entered();
+    let raw = data.into();
+    let message = encode::from_cbor(&raw).ok()?;
+    Some((raw, message))
+}
+
+/// A payload of libp2p's gossipsub chat examples, a line of UTF-8 text, as a message sent now.
+/// Our payloads never pass as text, starting with a CBOR map header which isn't valid UTF-8.
+pub(crate) fn plaintext(data: &[u8]) -> Option<ChatApi> {
+    let text = std::str::from_utf8(data)
+        .ok()?
+        .trim_end_matches(['\r', '\n']);
+    if text.trim().is_empty() || text.chars().any(|c| c.is_control() && c != '\t') {
+        return None;
+    }
+    Some(ChatApi::message(text.into()))
 }
 
 impl NetworkBehaviourEventProcess<GossipsubEvent> for Behaviour {
@@ -70,52 +652,217 @@ impl NetworkBehaviourEventProcess<GossipsubEvent> for Behaviour {
         match event {
             GossipsubEvent::Message {
                 propagation_source,
+                message_id,
                 message,
-                ..
             } => {
+                let member = self.is_member(&message.topic, message.source.as_ref());
+                if self.validate_messages {
+                    let acceptance = match member {
+                        true => MessageAcceptance::Accept,
+                        false => MessageAcceptance::Reject,
+                    };
+                    if let Err(error) = self.gossipsub.report_message_validation_result(
+                        &message_id,
+                        &propagation_source,
+                        acceptance,
+                    ) {
+                        debug!(%error, "Reporting validation result failed");
+                    }
+                }
+                let signed = message.source.is_some();
                 let peer = message.source.unwrap_or(propagation_source);
-                if let Ok(message) = serde_cbor::from_slice(&message.data) {
-                    let ev = BehaviourEvent::Chat { peer, message };
-                    self.events
-                        .push_back(libp2p::swarm::NetworkBehaviourAction::GenerateEvent(ev));
+                if !member {
+                    self.non_members += 1;
+                    self.audit
+                        .record(audit::Kind::NonMember, &peer, &message.topic);
+                    debug!(%peer, topic = %message.topic, "Rejecting message from non-member");
+                    return;
+                }
+                if !self.is_allowed(&peer) {
+                    debug!(%peer, "Dropping message from peer not on the allowlist");
+                    return;
                 }
+                self.receive(peer, signed, message.topic, message.data);
+            }
+            GossipsubEvent::Subscribed { peer_id, topic } => {
+                self.update_sharing(&peer_id);
+                let ev = BehaviourEvent::Membership {
+                    peer: peer_id,
+                    topic,
+                    joined: true,
+                };
+                self.events
+                    .push_event(libp2p::swarm::NetworkBehaviourAction::GenerateEvent(ev));
+            }
+            GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                self.update_sharing(&peer_id);
+                let ev = BehaviourEvent::Membership {
+                    peer: peer_id,
+                    topic,
+                    joined: false,
+                };
+                self.events
+                    .push_event(libp2p::swarm::NetworkBehaviourAction::GenerateEvent(ev));
             }
-            GossipsubEvent::Subscribed { .. } => {}
-            GossipsubEvent::Unsubscribed { .. } => {}
             GossipsubEvent::GossipsubNotSupported { .. } => {}
         }
     }
 }
 
+impl Behaviour {
+    /// Opens, decodes and checks the `data` of a message by `peer`, emitting it as
+    /// [`BehaviourEvent::Chat`] if it passes.
+    fn receive(&mut self, peer: PeerId, signed: bool, topic: TopicHash, data: Vec<u8>) {
+        let span = info_span!("receive", %peer, %topic, size = data.len());
+        let data = match self.channel_key.as_mut() {
+            Some(key) => match span.in_scope(|| key.open(&peer, &topic, &data)) {
+                Ok(data) => {
+                    if !self.opened_topics.contains(&topic) {
+                        self.opened_topics.insert(topic.clone());
+                    }
+                    data
+                }
+                Err(OpenError::Deriving) => {
+                    for derivation in key.take_derivations() {
+                        let derived = self.derived.0.clone();
+                        tokio::task::spawn_blocking(move || {
+                            let _ = derived.send(derivation.run());
+                        });
+                    }
+                    if self.awaiting_key.len() >= MAX_AWAITING_KEY {
+                        self.awaiting_key.pop_front();
+                        self.undecryptable += 1;
+                    }
+                    self.awaiting_key.push_back((peer, signed, topic, data));
+                    return;
+                }
+                Err(error) => {
+                    self.undecryptable += 1;
+                    debug!(%peer, %topic, %error, total = self.undecryptable, "Dropping message");
+                    let ev = match error {
+                        OpenError::UnknownKey if self.opened_topics.contains(&topic) => {
+                            (!std::mem::replace(&mut self.rekey_noticed, true))
+                                .then(|| BehaviourEvent::Rekeyed { topic })
+                        }
+                        OpenError::Authentication | OpenError::UnknownKey => self
+                            .undecryptable_topics
+                            .insert(topic.clone())
+                            .then(|| BehaviourEvent::Undecryptable { topic }),
+                        _ => None,
+                    };
+                    if let Some(ev) = ev {
+                        self.events
+                            .push_event(libp2p::swarm::NetworkBehaviourAction::GenerateEvent(ev));
+                    }
+                    return;
+                }
+            },
+            None => data,
+        };
+        let (sequence, data) = replay::split(Bytes::from(data));
+        let mut decoded = span.in_scope(|| decode(data.clone()));
+        // Without a sequence, as ours always have, so there's nothing to check replays by.
+        let mut plain = false;
+        if decoded.is_none() && sequence.is_none() && self.compat_plaintext {
+            decoded = plaintext(&data).map(|message| (data, message));
+            plain = decoded.is_some();
+        }
+        if decoded.is_none() {
+            debug!(%peer, %topic, "Dropping undecodable message");
+            self.audit.record(audit::Kind::DecodeFailed, &peer, &topic);
+        }
+        if let Some((message_raw, message)) = decoded {
+            if let Err(exceeded) = self.limits.check(&message) {
+                self.over_limit(peer);
+                self.audit.record(audit::Kind::OverLimits, &peer, exceeded);
+                debug!(%peer, %exceeded, total = self.over_limits_total, "Rejecting message");
+                return;
+            }
+            if matches!(message, ChatApi::DirectMessage { to, .. }
+                        | ChatApi::SealedDirectMessage { to, .. }
+                        | ChatApi::DmChallenge { to, .. }
+                        | ChatApi::DmChallengeResponse { to, .. }
+                        | ChatApi::HistoryResponse { to, .. } if to != self.local_peer_id)
+            {
+                return;
+            }
+            if !accept(&message, signed) {
+                debug!(%peer, "Dropping unsigned message");
+                self.audit
+                    .record(audit::Kind::Unsigned, &peer, message.kind());
+                return;
+            }
+            if signed && !plain {
+                match sequence {
+                    Some(sequence) if self.replay.check(peer, message.kind(), sequence) => {}
+                    Some(sequence) => {
+                        debug!(%peer, ?sequence, total = self.replay.dropped, "Dropping replayed message");
+                        self.audit.record(
+                            audit::Kind::Replayed,
+                            &peer,
+                            format_args!("{} {:?}", message.kind(), sequence),
+                        );
+                        return;
+                    }
+                    None if self.require_sequence => {
+                        debug!(%peer, "Dropping message without sequence");
+                        self.audit
+                            .record(audit::Kind::Unsequenced, &peer, message.kind());
+                        return;
+                    }
+                    None => debug!(%peer, "Accepting message without sequence, unchecked"),
+                }
+            }
+            if let ChatApi::ChangeNickname { nick } = &message {
+                if self.pin_nicknames && !self.may_use(&peer, nick) {
+                    debug!(%peer, %nick, "Dropping nickname pinned to another key");
+                    self.audit.record(
+                        audit::Kind::InvalidSignature,
+                        &peer,
+                        format_args!("Nickname {} pinned to another key", nick),
+                    );
+                    return;
+                }
+            }
+            let ev = BehaviourEvent::Chat {
+                peer,
+                signed,
+                topic,
+                message,
+                message_raw,
+                span,
+            };
+            self.events
+                .push_event(libp2p::swarm::NetworkBehaviourAction::GenerateEvent(ev));
+        }
+    }
+}
+
+/// Events of disabled sub-behaviours.
+impl NetworkBehaviourEventProcess<void::Void> for Behaviour {
+    fn inject_event(&mut self, event: void::Void) {
+        void::unreachable(event)
+    }
+}
+
+#[cfg(feature = "ping")]
 impl NetworkBehaviourEventProcess<ping::PingEvent> for Behaviour {
     fn inject_event(&mut self, event: ping::PingEvent) {
         debug!(?event, "PingEvent");
     }
 }
 
-impl NetworkBehaviourEventProcess<MdnsEvent> for Behaviour {
-    fn inject_event(&mut self, event: MdnsEvent) {
-        debug!(?event, "MdnsEvent");
-        match event {
-            MdnsEvent::Discovered(addrs) => {
-                let mut addrs_per_peer = BTreeMap::<_, _>::default();
-                for (p, a) in addrs {
-                    addrs_per_peer.entry(p).or_insert_with(Vec::new).push(a);
-                }
-                for (p, addrs) in addrs_per_peer {
-                    let opts = DialOpts::peer_id(p)
-                        .condition(PeerCondition::Disconnected)
-                        .addresses(addrs)
-                        .build();
-                    let ev = libp2p::swarm::NetworkBehaviourAction::Dial {
-                        opts,
-                        handler: self.new_handler(),
-                    };
-                    self.events.push_back(ev);
-                }
-            }
-            MdnsEvent::Expired(_) => {}
-        }
+#[cfg(feature = "relay")]
+impl NetworkBehaviourEventProcess<relay::Event> for Behaviour {
+    fn inject_event(&mut self, event: relay::Event) {
+        debug!(?event, "RelayEvent");
+    }
+}
+
+impl NetworkBehaviourEventProcess<Discovered> for Behaviour {
+    fn inject_event(&mut self, event: Discovered) {
+        debug!(backend = event.backend, peers = ?event.peers, "Discovered");
+        self.dial_discovered(event.peers);
     }
 }
 type NetworkBehaviourAction = libp2p::swarm::NetworkBehaviourAction<
@@ -123,22 +870,164 @@ type NetworkBehaviourAction = libp2p::swarm::NetworkBehaviourAction<
     <Behaviour as NetworkBehaviour>::ConnectionHandler,
 >;
 
+/// The gossipsub configuration used by [`Behaviour::bootstrap`], to start customizing from:
+///
+/// ```ignore
+/// let mut config = gossipsub::GossipsubConfigBuilder::from(default_gossipsub_config());
+/// config.heartbeat_interval(Duration::from_millis(500));
+/// let swarm = Behaviour::bootstrap_with_config(keypair, None, config.build()?).await?;
+/// ```
+pub(crate) fn default_gossipsub_config() -> gossipsub::GossipsubConfig {
+    gossipsub::GossipsubConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Permissive)
+        .build()
+        .expect("Valid config")
+}
+
+/// Scoring of `topic`'s peers: a rejected message makes a peer's score negative, pruning it from
+/// the mesh, a few more graylist it. Quiet peers aren't penalized.
+fn members_peer_score(
+    topic: &TopicHash,
+) -> (gossipsub::PeerScoreParams, gossipsub::PeerScoreThresholds) {
+    let mut params = gossipsub::PeerScoreParams::default();
+    params.topics.insert(
+        topic.clone(),
+        gossipsub::TopicScoreParams {
+            topic_weight: 1.0,
+            mesh_message_deliveries_weight: 0.0,
+            mesh_failure_penalty_weight: 0.0,
+            invalid_message_deliveries_weight: -10.0,
+            invalid_message_deliveries_decay: 0.9,
+            ..Default::default()
+        },
+    );
+    (params, Default::default())
+}
+
 impl Behaviour {
-    pub async fn bootstrap() -> anyhow::Result<Swarm<Self>> {
-        let (keypair, transport) = mk_transport();
+    #[deprecated(note = "Use `bootstrap_with_config(.., default_gossipsub_config())`")]
+    #[allow(dead_code)]
+    pub async fn bootstrap(
+        keypair: Keypair,
+        allowlist: Option<BTreeSet<PeerId>>,
+    ) -> Result<Swarm<Self>, BehaviourBootstrapError> {
+        Self::bootstrap_with_config(keypair, allowlist, default_gossipsub_config()).await
+    }
+
+    pub async fn bootstrap_with_config(
+        keypair: Keypair,
+        allowlist: Option<BTreeSet<PeerId>>,
+        gossipsub_config: gossipsub::GossipsubConfig,
+    ) -> Result<Swarm<Self>, BehaviourBootstrapError> {
+        Self::bootstrap_with_tcp_keepalive(
+            keypair,
+            allowlist,
+            gossipsub_config,
+            TcpKeepalive::default(),
+        )
+        .await
+    }
+
+    /// Like [`Behaviour::bootstrap_with_config`], with the TCP keep-alive of connections.
+    pub async fn bootstrap_with_tcp_keepalive(
+        keypair: Keypair,
+        allowlist: Option<BTreeSet<PeerId>>,
+        gossipsub_config: gossipsub::GossipsubConfig,
+        tcp_keepalive: TcpKeepalive,
+    ) -> Result<Swarm<Self>, BehaviourBootstrapError> {
+        let mut backends = Vec::<Box<dyn Discovery>>::new();
+        #[cfg(feature = "mdns")]
+        backends.push(Box::new(
+            crate::discovery::Mdns::new()
+                .await
+                .map_err(|error| BehaviourBootstrapError::Discovery("mDNS", error))?,
+        ));
+        Self::bootstrap_with_discovery(
+            keypair,
+            allowlist,
+            gossipsub_config,
+            tcp_keepalive,
+            backends,
+        )
+        .await
+    }
+
+    /// Like [`Behaviour::bootstrap_with_tcp_keepalive`], dialing the peers found by the
+    /// `discovery` backends instead of mDNS'. None only dials peers given explicitly.
+    pub async fn bootstrap_with_discovery(
+        keypair: Keypair,
+        allowlist: Option<BTreeSet<PeerId>>,
+        gossipsub_config: gossipsub::GossipsubConfig,
+        tcp_keepalive: TcpKeepalive,
+        discovery: Vec<Box<dyn Discovery>>,
+    ) -> Result<Swarm<Self>, BehaviourBootstrapError> {
+        let (keypair, relay, transport) = mk_transport(keypair, tcp_keepalive)?;
         let peer_id = PeerId::from(keypair.public());
-        let mut gossipsub_config = gossipsub::GossipsubConfigBuilder::default();
-        gossipsub_config.validation_mode(gossipsub::ValidationMode::Permissive);
+        let validate_messages = gossipsub_config.validate_messages();
+        let max_transmit_size = gossipsub_config.max_transmit_size();
+        let heartbeats = Heartbeats {
+            started: Instant::now(),
+            initial_delay: gossipsub_config.heartbeat_initial_delay(),
+            interval: gossipsub_config.heartbeat_interval(),
+        };
+        let pinned_keys = PinnedKeys::default();
+        let audit = AuditLog::default();
+
+        let relay = Arc::new(Mutex::new(None));
 
         let slf = Self {
-            gossipsub: Gossipsub::new(
-                gossipsub::MessageAuthenticity::Signed(keypair),
-                gossipsub_config.build().unwrap(),
-            )
-            .unwrap(),
-            mdns: Mdns::new(mdns::MdnsConfig::default()).await?,
-            ping: ping::Ping::new(ping::Config::new().with_keep_alive(true)),
+            gossipsub: RelayedGossipsub {
+                gossipsub: Gossipsub::new_with_transform(
+                    gossipsub::MessageAuthenticity::Signed(keypair.clone()),
+                    gossipsub_config,
+                    None,
+                    RequireSignedSource {
+                        audit: audit.clone(),
+                        relay: relay.clone(),
+                    },
+                )
+                .map_err(GossipsubBuildError)?,
+                relay,
+            },
+            discovery: Discoveries::new(discovery),
+            #[cfg(feature = "ping")]
+            ping: ping::Ping::new(ping::Config::new()),
+            #[cfg(not(feature = "ping"))]
+            ping: libp2p::swarm::DummyBehaviour::with_keep_alive(libp2p::swarm::KeepAlive::No),
+            relay,
+            keep_alive: Default::default(),
+            addresses: Default::default(),
+            pinned_keys,
+            pin_nicknames: false,
+            require_sequence: false,
+            audit,
+            ignored_prefixes: Vec::new(),
+            local_peer_id: peer_id,
+            keypair,
+            allowlist,
             events: Default::default(),
+            channel_key: None,
+            derived: tokio::sync::mpsc::unbounded_channel(),
+            awaiting_key: Default::default(),
+            undecryptable: 0,
+            undecryptable_topics: Default::default(),
+            opened_topics: Default::default(),
+            rekey_noticed: false,
+            sequencer: Sequencer::now(),
+            replay: Default::default(),
+            members: Default::default(),
+            non_members: 0,
+            validate_messages,
+            max_transmit_size,
+            limits: Default::default(),
+            over_limits: Default::default(),
+            over_limits_total: 0,
+            compat_plaintext: false,
+            heartbeats,
+            last_published: Default::default(),
+            explicit_peers: Default::default(),
+            blocked_explicit_peers: Default::default(),
+            subscribed: Vec::new(),
         };
         let swarm = SwarmBuilder::new(transport, slf, peer_id)
             .executor(Box::new(|fut| {
@@ -148,15 +1037,1048 @@ impl Behaviour {
         Ok(swarm)
     }
 
+    /// The addresses dialed if only a peer's id is given, to be kept up to date by the caller.
+    pub(crate) fn address_book(&self) -> SharedAddresses {
+        self.addresses.addresses.clone()
+    }
+
+    /// The keys nicknames are pinned to, to be filled by the caller.
+    pub(crate) fn pinned_keys(&self) -> PinnedKeys {
+        self.pinned_keys.clone()
+    }
+
+    /// Accepts a nickname only from the peer it's pinned to, pinning it to the key of the first
+    /// peer using it unless pinned by the caller (`--validation-mode author`).
+    pub(crate) fn pin_nicknames(&mut self) {
+        self.pin_nicknames = true;
+    }
+
+    /// Drops signed messages without a sequence, as sent by builds before replay protection,
+    /// rather than accepting them unchecked (`--require-sequence`).
+    pub(crate) fn require_sequence(&mut self) {
+        self.require_sequence = true;
+    }
+
+    /// Whether `peer` may use `nick`, pinning it if it's the first.
+    fn may_use(&self, peer: &PeerId, nick: &str) -> bool {
+        let mut pinned = self.pinned_keys.write().expect("Not poisoned");
+        match pinned.get(nick) {
+            Some(key) => key.to_peer_id() == *peer,
+            None => {
+                if pinned.len() < MAX_PINNED {
+                    if let Some(key) = embedded_key(peer) {
+                        pinned.insert(nick.to_string(), key);
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Where suspicious messages are recorded, to be opened by the caller.
+    pub(crate) fn audit_log(&self) -> AuditLog {
+        self.audit.clone()
+    }
+
+    pub(crate) fn local_peer_id(&self) -> &PeerId {
+        &self.local_peer_id
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        Stats {
+            undecryptable: self.undecryptable,
+            replayed: self.replay.dropped,
+            non_members: self.non_members,
+            over_limits: self.over_limits_total,
+            over_limits_peers: self.over_limits.len(),
+        }
+    }
+
+    /// Replaces the bounds of received messages, [`Limits::default`] unless set.
+    pub(crate) fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Takes payloads which aren't agora messages as plain text chat lines (`--compat-plaintext`).
+    pub(crate) fn set_compat_plaintext(&mut self, enabled: bool) {
+        self.compat_plaintext = enabled;
+    }
+
+    /// The gossip health of the subscribed topics and those published to, ordered by topic.
+    pub(crate) fn mesh_health(&self) -> Vec<MeshHealth> {
+        let subscribed = self.gossipsub.topics().collect::<BTreeSet<_>>();
+        let topics = subscribed
+            .iter()
+            .copied()
+            .chain(self.last_published.keys())
+            .collect::<BTreeSet<_>>();
+        topics
+            .into_iter()
+            .map(|topic| {
+                let mut mesh = self
+                    .gossipsub
+                    .mesh_peers(topic)
+                    .copied()
+                    .collect::<Vec<_>>();
+                let mut peers = self
+                    .gossipsub
+                    .all_peers()
+                    .filter(|(_, topics)| topics.contains(&topic))
+                    .map(|(peer, _)| *peer)
+                    .collect::<Vec<_>>();
+                mesh.sort();
+                peers.sort();
+                MeshHealth {
+                    topic: topic.clone(),
+                    subscribed: subscribed.contains(topic),
+                    mesh,
+                    peers,
+                    last_published: self.last_published.get(topic).copied(),
+                }
+            })
+            .collect()
+    }
+
+    /// When gossipsub's last heartbeat, maintaining the meshes and gossiping, was due by its
+    /// schedule. Derived from the config, not observed: it runs late if the event loop is busy.
+    pub(crate) fn scheduled_heartbeat(&self, now: Instant) -> Option<Instant> {
+        self.heartbeats.last(now)
+    }
+
+    pub(crate) fn heartbeat_interval(&self) -> Duration {
+        self.heartbeats.interval
+    }
+
+    /// Makes `peer` an explicit peer of gossipsub, which is sent all messages of the topics it's
+    /// subscribed to regardless of the mesh, and kept connected to. This applies to all
+    /// topics, gossipsub has no explicit peers per topic.
+    pub(crate) fn graft(&mut self, peer: PeerId) -> bool {
+        self.gossipsub.add_explicit_peer(&peer);
+        self.keep_alive(peer);
+        self.explicit_peers.insert(peer)
+    }
+
+    /// Peers added by [`Behaviour::graft`].
+    pub(crate) fn explicit_peers(&self) -> &BTreeSet<PeerId> {
+        &self.explicit_peers
+    }
+
+    fn over_limit(&mut self, peer: PeerId) {
+        self.over_limits_total += 1;
+        // Counted per peer for a bounded number of them, in total for any.
+        if self.over_limits.len() < MAX_OVER_LIMITS_PEERS || self.over_limits.contains_key(&peer) {
+            *self.over_limits.entry(peer).or_default() += 1;
+        }
+    }
+
+    /// Stops accepting and relaying `peer`'s messages: gossipsub drops them on receipt, so
+    /// they're neither shown nor forwarded to others. An explicit peer stops being one until
+    /// unblocked.
+    pub(crate) fn block(&mut self, peer: PeerId) {
+        self.gossipsub.remove_explicit_peer(&peer);
+        if self.explicit_peers.remove(&peer) {
+            self.blocked_explicit_peers.insert(peer);
+        }
+        self.gossipsub.blacklist_peer(&peer);
+    }
+
+    /// Accepts `peer`'s messages again. If it was an explicit peer when blocked, it's made one
+    /// again and redialed.
+    pub(crate) fn unblock(&mut self, peer: PeerId) {
+        self.gossipsub.remove_blacklisted_peer(&peer);
+        if self.blocked_explicit_peers.remove(&peer) {
+            self.graft(peer);
+            let opts = DialOpts::peer_id(peer)
+                .condition(PeerCondition::Disconnected)
+                .build();
+            let ev = libp2p::swarm::NetworkBehaviourAction::Dial {
+                opts,
+                handler: self.new_handler(),
+            };
+            self.events.push_action(ev);
+        }
+    }
+
+    /// Keeps connections to `peer` alive, even when idle. Applies to connections established
+    /// from now on.
+    pub(crate) fn keep_alive(&mut self, peer: PeerId) {
+        self.keep_alive.insert_peer(peer);
+    }
+
+    /// Keeps the connections to `peer` alive while it's subscribed to one of our topics.
+    fn update_sharing(&mut self, peer: &PeerId) {
+        let subscribed = self.gossipsub.topics().collect::<Vec<_>>();
+        let shares = self
+            .gossipsub
+            .all_peers()
+            .find(|(p, _)| *p == peer)
+            .map_or(false, |(_, topics)| {
+                topics.iter().any(|t| subscribed.contains(t))
+            });
+        self.keep_alive.set_sharing(*peer, shares);
+    }
+
+    /// Dials the `peers` of the loaded profile, keeping the connections to them and to its
+    /// `bootstrap` node alive. Replaces the previous profile's, whose connections may then close.
+    pub(crate) fn set_profile(&mut self, peers: Vec<PeerAddr>, bootstrap: Option<&Multiaddr>) {
+        let mut targets = keep_alive::Targets {
+            peers: peers.iter().map(|p| p.peer).collect(),
+            ..Default::default()
+        };
+        if let Some(addr) = bootstrap {
+            // By its peer id if the address ends in one.
+            let peer = match addr.iter().last() {
+                Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+                _ => None,
+            };
+            match peer {
+                Some(peer) => targets.peers.insert(peer),
+                None => targets.addresses.insert(addr.clone()),
+            };
+        }
+        self.keep_alive.set_profile(targets);
+        self.discovery.set_profile(peers);
+    }
+
+    /// Restarts the discovery backends, forgetting the peers they found, to look for them again.
+    pub(crate) async fn restart_discovery(&mut self) -> Result<(), BehaviourBootstrapError> {
+        self.discovery
+            .restart()
+            .await
+            .map_err(|(backend, error)| BehaviourBootstrapError::Discovery(backend, error))
+    }
+
+    /// Also dials the peers found by `backend`.
+    pub(crate) fn add_discovery(&mut self, backend: Box<dyn Discovery>) {
+        self.discovery.add(backend);
+    }
+
+    /// Ignores discovered addresses in `prefixes`.
+    pub(crate) fn ignore_addr_prefixes(&mut self, prefixes: impl IntoIterator<Item = Cidr>) {
+        self.ignored_prefixes.extend(prefixes);
+    }
+
+    /// Dials peers discovered at `addrs`, other than ourselves, at addresses not ignored.
+    fn dial_discovered(&mut self, addrs: impl IntoIterator<Item = (PeerId, Multiaddr)>) {
+        let mut addrs_per_peer = BTreeMap::<_, _>::default();
+        for (p, a) in addrs {
+            if p == self.local_peer_id {
+                // Other instances on this host discover us, too.
+                debug!(addr = %a, "Not dialing ourselves");
+                continue;
+            }
+            if self.ignored_prefixes.iter().any(|c| c.contains_addr(&a)) {
+                debug!(peer = %p, addr = %a, "Ignoring discovered address");
+                continue;
+            }
+            addrs_per_peer.entry(p).or_insert_with(Vec::new).push(a);
+        }
+        for (p, addrs) in addrs_per_peer {
+            if !self.is_allowed(&p) {
+                continue;
+            }
+            let opts = DialOpts::peer_id(p)
+                .condition(PeerCondition::Disconnected)
+                .addresses(addrs)
+                .build();
+            let ev = libp2p::swarm::NetworkBehaviourAction::Dial {
+                opts,
+                handler: self.new_handler(),
+            };
+            self.events.push_action(ev);
+        }
+    }
+
+    /// Only accepts and forwards messages on `topic` signed by `members`, replacing the ones
+    /// set before. Others' messages are rejected, which lowers their peer score, so they're
+    /// pruned from the mesh. Needs a gossipsub config with `validate_messages()`.
+    pub(crate) fn set_members(
+        &mut self,
+        topic: TopicHash,
+        members: HashSet<PeerId>,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.validate_messages,
+            "Restricting members needs gossipsub to validate messages"
+        );
+        if self.members.is_empty() {
+            let (params, thresholds) = members_peer_score(&topic);
+            self.gossipsub
+                .with_peer_score(params, thresholds)
+                .map_err(|e| anyhow::anyhow!("Enabling peer scoring failed: {}", e))?;
+        }
+        self.members.insert(topic, members);
+        Ok(())
+    }
+
+    fn is_member(&self, topic: &TopicHash, source: Option<&PeerId>) -> bool {
+        self.members.get(topic).map_or(true, |members| {
+            source.map_or(false, |source| members.contains(source))
+        })
+    }
+
+    pub(crate) fn is_allowed(&self, peer: &PeerId) -> bool {
+        self.allowlist
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(peer))
+    }
+
+    /// Seals a direct message to `to`.
+    pub(crate) fn seal_direct_message(
+        &self,
+        to: PeerId,
+        message: &str,
+    ) -> Result<ChatApi, dm::DmError> {
+        let sealed = dm::seal(&self.keypair, &to, chrono::Utc::now(), message)?;
+        Ok(ChatApi::SealedDirectMessage { to, sealed })
+    }
+
+    /// Opens a direct message `from` sealed to us, returning its origin timestamp and text.
+    pub(crate) fn open_direct_message(
+        &self,
+        from: &PeerId,
+        sealed: &[u8],
+    ) -> Result<(chrono::DateTime<chrono::Utc>, String), dm::DmError> {
+        dm::open(&self.keypair, from, sealed)
+    }
+
+    /// Answers a [`ChatApi::DmChallenge`] of `to`.
+    pub(crate) fn answer_dm_challenge(
+        &self,
+        to: PeerId,
+        nonce: Vec<u8>,
+    ) -> Result<ChatApi, dm::DmError> {
+        let signature = dm::sign_challenge(&self.keypair, &to, &nonce)?;
+        Ok(ChatApi::DmChallengeResponse {
+            to,
+            nonce,
+            signature,
+        })
+    }
+
+    /// Encrypts published and decrypts received payloads with `key` from now on.
+    pub(crate) fn set_channel_key(&mut self, key: ChannelKey) {
+        self.channel_key = Some(Keyring::new(key));
+    }
+
+    /// Seals with `key` from now on, still opening messages sealed with the previous keys for
+    /// [`crate::crypt::REKEY_GRACE`].
+    pub(crate) fn rekey(&mut self, key: ChannelKey) -> anyhow::Result<()> {
+        let keyring = self
+            .channel_key
+            .as_mut()
+            .context("Not encrypted, start with --channel-key")?;
+        keyring.rekey(key, std::time::Instant::now());
+        Ok(())
+    }
+
+    /// Stops opening messages sealed with replaced keys, returning how many were dropped.
+    pub(crate) fn retire_keys(&mut self) -> usize {
+        self.channel_key.as_mut().map_or(0, Keyring::retire)
+    }
+
     fn my_poll(
         &mut self,
-        _cx: &mut std::task::Context<'_>,
+        cx: &mut std::task::Context<'_>,
         _params: &mut impl libp2p::swarm::PollParameters,
     ) -> Poll<NetworkBehaviourAction> {
-        if let Some(event) = self.events.pop_front() {
+        while let Poll::Ready(Some(derived)) = self.derived.1.poll_recv(cx) {
+            if let Some(keyring) = &mut self.channel_key {
+                keyring.derived(derived);
+            }
+            // Those whose key is still being derived are put back.
+            for (peer, signed, topic, data) in std::mem::take(&mut self.awaiting_key) {
+                self.receive(peer, signed, topic, data);
+            }
+        }
+        // Subscribed or unsubscribed through `gossipsub` since.
+        if !self.gossipsub.topics().eq(self.subscribed.iter()) {
+            self.subscribed = self.gossipsub.topics().cloned().collect();
+            let peers = self
+                .gossipsub
+                .all_peers()
+                .map(|(p, _)| *p)
+                .collect::<Vec<_>>();
+            for peer in peers {
+                self.update_sharing(&peer);
+            }
+        }
+        if let Some(event) = self.events.pop() {
             return Poll::Ready(event);
         }
 
         Poll::Pending
     }
 }
+
+impl Publisher for Behaviour {
+    fn publish(&mut self, topic: TopicHash, data: &[u8]) -> Result<MessageId, PublishError> {
+        let data = self.sequencer.wrap(data);
+        match &self.channel_key {
+            Some(key) => {
+                let sealed = key.seal(&topic, &data);
+                self.gossipsub.publish(topic, sealed)
+            }
+            None => self.gossipsub.publish(topic, data),
+        }
+    }
+
+    fn mesh_peers(&self, topic: &TopicHash) -> Option<usize> {
+        Publisher::mesh_peers(&self.gossipsub, topic)
+    }
+
+    fn max_transmit_size(&self) -> Option<usize> {
+        Some(self.max_transmit_size)
+    }
+
+    fn published(&mut self, topic: &TopicHash, published: Published) {
+        self.last_published
+            .insert(topic.clone(), (Instant::now(), published));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use proptest::{collection, option, prelude::*, sample};
+
+    use super::*;
+    use crate::api::ModerationAction;
+
+    #[test]
+    fn decode_forwards_raw_bytes() {
+        let data = encode::to_cbor(&ChatApi::message("hello".into())).unwrap();
+        let ptr = data.as_ptr();
+        let (raw, message) = decode(data.clone()).unwrap();
+        assert_eq!(raw, data);
+        assert!(matches!(message, ChatApi::Message { message, .. } if message == "hello"));
+
+        // No copy is made.
+        let (raw, _) = decode(data).unwrap();
+        assert_eq!(raw.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert!(decode(vec![0xff, 0x00]).is_none());
+    }
+
+    fn timestamp() -> impl Strategy<Value = chrono::DateTime<chrono::Utc>> {
+        // Up to 2100, at the wire format's millisecond precision.
+        (0i64..4_102_444_800_000).prop_map(|ms| chrono::Utc.timestamp_millis(ms))
+    }
+
+    fn chat_api() -> impl Strategy<Value = ChatApi> {
+        prop_oneof![
+            (
+                ".*",
+                timestamp(),
+                option::of(".*"),
+                option::of(".*"),
+                option::of(timestamp()),
+                any::<bool>()
+            )
+                .prop_map(
+                    |(
+                        message,
+                        origin_timestamp,
+                        hook_nick,
+                        bridged_from,
+                        wall_timestamp,
+                        auto_response,
+                    )| {
+                        ChatApi::Message {
+                            message,
+                            origin_timestamp,
+                            hook_nick,
+                            bridged_from,
+                            wall_timestamp,
+                            auto_response,
+                        }
+                    }
+                ),
+            ".*".prop_map(|nick| ChatApi::ChangeNickname { nick }),
+            collection::vec(".*", 0..8).prop_map(|supported| ChatApi::Capabilities { supported }),
+            (".*", ".*").prop_map(|(event_type, payload)| ChatApi::MetaEvent {
+                event_type,
+                payload: serde_json::Value::String(payload),
+            }),
+            (".*", timestamp()).prop_map(|(message, origin_timestamp)| {
+                ChatApi::DirectMessage {
+                    to: PeerId::random(),
+                    message,
+                    origin_timestamp,
+                }
+            }),
+            (timestamp(), any::<usize>())
+                .prop_map(|(since, max)| ChatApi::HistoryRequest { since, max }),
+            collection::vec((".*", ".*", timestamp()), 0..8).prop_map(|entries| {
+                ChatApi::HistoryResponse {
+                    to: PeerId::random(),
+                    entries: entries
+                        .into_iter()
+                        .map(
+                            |(nick, message, origin_timestamp)| crate::api::HistoryEntry {
+                                from: PeerId::random(),
+                                nick,
+                                message,
+                                origin_timestamp,
+                            },
+                        )
+                        .collect(),
+                }
+            }),
+            option::of(any::<u64>()).prop_map(|duration_secs| ChatApi::Moderate {
+                action: match duration_secs {
+                    Some(duration_secs) => ModerationAction::Mute { duration_secs },
+                    None => ModerationAction::Kick,
+                },
+                target: PeerId::random(),
+            }),
+            collection::vec(any::<u8>(), 0..256).prop_map(|sealed| {
+                ChatApi::SealedDirectMessage {
+                    to: PeerId::random(),
+                    sealed,
+                }
+            }),
+            (collection::vec(any::<u8>(), 0..64), any::<u32>(), 0..8usize).prop_map(
+                |(initiator_pubkey, round, participants)| ChatApi::GroupKeyExchange {
+                    initiator_pubkey,
+                    round,
+                    participants: (0..participants)
+                        .map(|_| PeerId::random().to_string())
+                        .collect(),
+                }
+            ),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn decode_arbitrary_bytes(data in collection::vec(any::<u8>(), 0..1024)) {
+            let _ = decode(data);
+        }
+
+        #[test]
+        fn decode_roundtrips(msg in chat_api()) {
+            let data = encode::to_cbor(&msg).unwrap();
+            let (_, decoded) = decode(data.clone()).expect("Valid encodings decode");
+            prop_assert_eq!(encode::to_cbor(&decoded).unwrap(), data);
+        }
+
+        #[test]
+        fn decode_mutated(
+            msg in chat_api(),
+            at in any::<sample::Index>(),
+            byte in any::<u8>(),
+            truncate in any::<sample::Index>(),
+        ) {
+            let mut data = encode::to_cbor(&msg).unwrap();
+            let i = at.index(data.len());
+            data[i] = byte;
+            data.truncate(truncate.index(data.len() + 1));
+            let _ = decode(data);
+        }
+    }
+
+    #[test]
+    fn unsigned_only_plain_messages() {
+        let peer = PeerId::random();
+        let timestamp = chrono::Utc::now();
+        let variants = [
+            (ChatApi::message("hi".into()), true),
+            (ChatApi::ChangeNickname { nick: "x".into() }, false),
+            (
+                ChatApi::Capabilities {
+                    supported: vec!["reactions".into()],
+                },
+                false,
+            ),
+            (
+                ChatApi::DirectMessage {
+                    to: peer,
+                    message: "hi".into(),
+                    origin_timestamp: timestamp,
+                },
+                false,
+            ),
+            (
+                ChatApi::SealedDirectMessage {
+                    to: peer,
+                    sealed: vec![0; 8],
+                },
+                false,
+            ),
+            (
+                ChatApi::DmChallenge {
+                    to: peer,
+                    nonce: vec![0; 32],
+                },
+                false,
+            ),
+            (
+                ChatApi::DmChallengeResponse {
+                    to: peer,
+                    nonce: vec![0; 32],
+                    signature: vec![0; 64],
+                },
+                false,
+            ),
+            (
+                ChatApi::HistoryRequest {
+                    since: timestamp,
+                    max: 1,
+                },
+                false,
+            ),
+            (
+                ChatApi::HistoryResponse {
+                    to: peer,
+                    entries: Vec::new(),
+                },
+                false,
+            ),
+            (
+                ChatApi::Moderate {
+                    action: ModerationAction::Kick,
+                    target: peer,
+                },
+                false,
+            ),
+            (
+                ChatApi::MetaEvent {
+                    event_type: "irc_join".into(),
+                    payload: serde_json::Value::Null,
+                },
+                false,
+            ),
+        ];
+        for (message, unsigned) in variants {
+            assert!(accept(&message, true), "{:?}", message);
+            assert_eq!(accept(&message, false), unsigned, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn rejects_source_without_signature() {
+        use gossipsub::DataTransform;
+        let raw = |source: Option<PeerId>, signature: Option<Vec<u8>>| RawGossipsubMessage {
+            source,
+            data: b"data".to_vec(),
+            sequence_number: Some(1),
+            topic: TopicHash::from_raw("agora"),
+            signature,
+            key: None,
+            validated: false,
+        };
+        let peer = Some(PeerId::random());
+        assert!(RequireSignedSource::default()
+            .inbound_transform(raw(peer, None))
+            .is_err());
+        let message = RequireSignedSource::default()
+            .inbound_transform(raw(peer, Some(vec![1; 64])))
+            .unwrap();
+        assert_eq!(message.source, peer);
+        assert_eq!(message.data, b"data");
+        let message = RequireSignedSource::default()
+            .inbound_transform(raw(None, None))
+            .unwrap();
+        assert_eq!(message.source, None);
+    }
+
+    #[tokio::test]
+    async fn pins_nicknames_to_keys() {
+        let mut swarm = Behaviour::bootstrap_with_config(
+            Keypair::generate_ed25519(),
+            None,
+            default_gossipsub_config(),
+        )
+        .await
+        .unwrap();
+        let behaviour = swarm.behaviour_mut();
+        let [alice, mallory, bob] = [(); 3].map(|_| Keypair::generate_ed25519().public());
+        behaviour
+            .pinned_keys()
+            .write()
+            .unwrap()
+            .insert("bob".into(), bob.clone());
+        let mut sequencer = Sequencer::new(1);
+        let mut receive = |behaviour: &mut Behaviour, peer: &PublicKey, nick: &str| {
+            let data = encode::to_cbor(&ChatApi::ChangeNickname { nick: nick.into() }).unwrap();
+            let peer = peer.to_peer_id();
+            let message = GossipsubMessage {
+                source: Some(peer),
+                data: sequencer.wrap(&data),
+                sequence_number: Some(1),
+                topic: TopicHash::from_raw("agora"),
+            };
+            NetworkBehaviourEventProcess::<GossipsubEvent>::inject_event(
+                behaviour,
+                GossipsubEvent::Message {
+                    propagation_source: peer,
+                    message_id: MessageId::new(nick.as_bytes()),
+                    message,
+                },
+            );
+            behaviour.events.pop().is_some()
+        };
+
+        // Unchecked unless asked to.
+        assert!(receive(behaviour, &mallory, "bob"));
+        behaviour.pin_nicknames();
+        // Pinned from the file.
+        assert!(!receive(behaviour, &mallory, "bob"));
+        assert!(receive(behaviour, &bob, "bob"));
+        // On first use.
+        assert!(receive(behaviour, &alice, "alice"));
+        assert!(!receive(behaviour, &mallory, "alice"));
+        assert!(receive(behaviour, &alice, "alice"));
+        assert_eq!(behaviour.pinned_keys().read().unwrap()["alice"], alice);
+    }
+
+    #[tokio::test]
+    async fn accepts_unsequenced_messages_unless_required() {
+        let mut swarm = Behaviour::bootstrap_with_config(
+            Keypair::generate_ed25519(),
+            None,
+            default_gossipsub_config(),
+        )
+        .await
+        .unwrap();
+        let behaviour = swarm.behaviour_mut();
+        let peer = PeerId::random();
+        let data = encode::to_cbor(&ChatApi::ChangeNickname { nick: "old".into() }).unwrap();
+        let mut receive = |behaviour: &mut Behaviour| {
+            NetworkBehaviourEventProcess::<GossipsubEvent>::inject_event(
+                behaviour,
+                GossipsubEvent::Message {
+                    propagation_source: peer,
+                    message_id: MessageId::new(&data),
+                    message: GossipsubMessage {
+                        source: Some(peer),
+                        data: data.clone(),
+                        sequence_number: Some(1),
+                        topic: TopicHash::from_raw("agora"),
+                    },
+                },
+            );
+            behaviour.events.pop().is_some()
+        };
+
+        assert!(receive(behaviour));
+        behaviour.require_sequence();
+        assert!(!receive(behaviour));
+        assert_eq!(
+            behaviour.audit_log().rejection_notices(Instant::now())[0].dropped,
+            [(audit::Kind::Unsequenced, 1)]
+        );
+    }
+
+    #[test]
+    fn loads_pinned_keys() {
+        let [alice, bob] = [(); 2].map(|_| Keypair::generate_ed25519());
+        let entry = |nick: &str, key: &Keypair| {
+            (
+                nick.to_string(),
+                base64::encode(key.public().to_protobuf_encoding()),
+            )
+        };
+        let path = std::env::temp_dir().join(format!(
+            "agora-pinned-{}-{}",
+            std::process::id(),
+            PeerId::random()
+        ));
+        let write = |entries: &[(String, String)]| {
+            let map = entries.iter().cloned().collect::<BTreeMap<_, _>>();
+            std::fs::write(&path, serde_json::to_string(&map).unwrap()).unwrap();
+        };
+
+        write(&[entry("alice", &alice), entry("bob", &bob)]);
+        let keys = load_pinned_keys(&path).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys["alice"], alice.public());
+
+        write(&[("alice".into(), "not base64!".into())]);
+        assert!(load_pinned_keys(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn decode_rejects_oversized() {
+        let data = encode::to_cbor(&ChatApi::message("x".repeat(encode::MAX_DECODE_LEN))).unwrap();
+        assert!(decode(data).is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_messages_over_limits() {
+        let mut swarm = Behaviour::bootstrap_with_config(
+            Keypair::generate_ed25519(),
+            None,
+            default_gossipsub_config(),
+        )
+        .await
+        .unwrap();
+        let behaviour = swarm.behaviour_mut();
+        behaviour.set_limits(Limits {
+            max_nick_len: 4,
+            ..Default::default()
+        });
+        let [spammer, other] = [(); 2].map(|_| PeerId::random());
+        // Signed by the peer, as nickname changes have to be.
+        let mut sequencer = Sequencer::new(1);
+        let mut receive = |peer: PeerId, nick: &str| {
+            let data = encode::to_cbor(&ChatApi::ChangeNickname { nick: nick.into() }).unwrap();
+            let message = GossipsubMessage {
+                source: Some(peer),
+                data: sequencer.wrap(&data),
+                sequence_number: Some(1),
+                topic: TopicHash::from_raw("agora"),
+            };
+            NetworkBehaviourEventProcess::<GossipsubEvent>::inject_event(
+                behaviour,
+                GossipsubEvent::Message {
+                    propagation_source: peer,
+                    message_id: MessageId::new(nick.as_bytes()),
+                    message,
+                },
+            );
+            behaviour.events.pop().is_some()
+        };
+        assert!(receive(spammer, "nick"));
+        assert!(!receive(spammer, "nick5"));
+        assert!(!receive(spammer, "nick56"));
+        assert!(!receive(other, "nick567"));
+        assert!(receive(other, "ok"));
+
+        let behaviour = swarm.behaviour();
+        assert_eq!(behaviour.over_limits[&spammer], 2);
+        assert_eq!(behaviour.over_limits[&other], 1);
+        let stats = behaviour.stats();
+        assert_eq!((stats.over_limits, stats.over_limits_peers), (3, 2));
+    }
+
+    #[test]
+    fn plaintext_is_a_line_of_text() {
+        let text = |msg: Option<ChatApi>| match msg {
+            Some(ChatApi::Message { message, .. }) => Some(message),
+            _ => None,
+        };
+        assert_eq!(
+            text(plaintext(b"hello from libp2p\r\n")).as_deref(),
+            Some("hello from libp2p")
+        );
+        for not_text in [
+            &b""[..],
+            b" \n",
+            b"\xff\xfe",
+            b"bell\x07",
+            &encode::to_cbor(&ChatApi::message("hello".into())).unwrap(),
+        ] {
+            assert_eq!(text(plaintext(not_text)), None, "{:?}", not_text);
+        }
+    }
+
+    #[tokio::test]
+    async fn plaintext_only_in_compat_mode() {
+        let mut swarm = Behaviour::bootstrap_with_config(
+            Keypair::generate_ed25519(),
+            None,
+            default_gossipsub_config(),
+        )
+        .await
+        .unwrap();
+        let behaviour = swarm.behaviour_mut();
+        let author = PeerId::random();
+        let receive = |behaviour: &mut Behaviour| {
+            let message = GossipsubMessage {
+                source: Some(author),
+                data: b"hello from libp2p".to_vec(),
+                sequence_number: Some(1),
+                topic: TopicHash::from_raw("test-net"),
+            };
+            NetworkBehaviourEventProcess::<GossipsubEvent>::inject_event(
+                behaviour,
+                GossipsubEvent::Message {
+                    propagation_source: author,
+                    message_id: MessageId::new(b"1"),
+                    message,
+                },
+            );
+            std::iter::from_fn(|| behaviour.events.pop()).find_map(|action| match action {
+                libp2p::swarm::NetworkBehaviourAction::GenerateEvent(BehaviourEvent::Chat {
+                    peer,
+                    message: ChatApi::Message { message, .. },
+                    ..
+                }) => Some((peer, message)),
+                _ => None,
+            })
+        };
+        assert_eq!(receive(behaviour), None);
+        behaviour.set_compat_plaintext(true);
+        assert_eq!(
+            receive(behaviour),
+            Some((author, "hello from libp2p".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn audits_rejected_messages() {
+        use gossipsub::DataTransform;
+        let mut swarm = Behaviour::bootstrap_with_config(
+            Keypair::generate_ed25519(),
+            None,
+            default_gossipsub_config(),
+        )
+        .await
+        .unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "agora-audit-{}-{}",
+            std::process::id(),
+            swarm.local_peer_id()
+        ));
+        let audit = swarm.behaviour().audit_log();
+        audit.open(&path).unwrap();
+        let behaviour = swarm.behaviour_mut();
+        behaviour.set_limits(Limits {
+            max_nick_len: 4,
+            ..Default::default()
+        });
+        behaviour
+            .members
+            .insert(TopicHash::from_raw("members-only"), HashSet::new());
+        behaviour.require_sequence();
+        let peer = PeerId::random();
+        let mut receive = |source: Option<PeerId>, topic: &str, data: Vec<u8>| {
+            NetworkBehaviourEventProcess::<GossipsubEvent>::inject_event(
+                behaviour,
+                GossipsubEvent::Message {
+                    propagation_source: peer,
+                    message_id: MessageId::new(&data),
+                    message: GossipsubMessage {
+                        source,
+                        data,
+                        sequence_number: None,
+                        topic: TopicHash::from_raw(topic),
+                    },
+                },
+            );
+            assert!(behaviour.events.pop().is_none());
+        };
+        let nick =
+            |nick: &str| encode::to_cbor(&ChatApi::ChangeNickname { nick: nick.into() }).unwrap();
+        receive(None, "members-only", nick("nick"));
+        receive(None, "agora", vec![0xff, 0x00]);
+        receive(None, "agora", nick("nick5"));
+        receive(None, "agora", nick("nick"));
+        receive(Some(peer), "agora", nick("nick"));
+        // Counted against the relaying peer, not the one claimed.
+        let raw = RawGossipsubMessage {
+            source: Some(PeerId::random()),
+            data: nick("nick"),
+            sequence_number: Some(1),
+            topic: TopicHash::from_raw("agora"),
+            signature: None,
+            key: None,
+            validated: false,
+        };
+        let transform = RequireSignedSource {
+            audit: audit.clone(),
+            relay: Arc::new(Mutex::new(Some(peer))),
+        };
+        assert!(transform.inbound_transform(raw).is_err());
+
+        let kinds = audit
+            .tail(10)
+            .unwrap()
+            .into_iter()
+            .inspect(|record| assert_eq!(record.peer, peer))
+            .map(|record| record.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                audit::Kind::NonMember,
+                audit::Kind::DecodeFailed,
+                audit::Kind::OverLimits,
+                audit::Kind::Unsigned,
+                audit::Kind::Unsequenced,
+                audit::Kind::InvalidSignature,
+            ]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn default_gossipsub_config_values() {
+        let config = default_gossipsub_config();
+        assert!(matches!(
+            config.validation_mode(),
+            gossipsub::ValidationMode::Permissive
+        ));
+        assert_eq!(
+            config.heartbeat_interval(),
+            std::time::Duration::from_secs(1)
+        );
+        assert_eq!(config.mesh_n(), 6);
+    }
+
+    #[test]
+    fn event_queue_prefers_events() {
+        let mut queue = EventQueue::default();
+        queue.push_action("dial");
+        queue.push_event("chat 1");
+        queue.push_event("chat 2");
+        assert_eq!(queue.pop(), Some("chat 1"));
+        assert_eq!(queue.pop(), Some("chat 2"));
+        assert_eq!(queue.pop(), Some("dial"));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn event_queue_does_not_starve_actions() {
+        let mut queue = EventQueue::default();
+        for i in 0..2 * MAX_BURST + 1 {
+            queue.push_event(i);
+        }
+        queue.push_action(1000);
+        queue.push_action(1001);
+        let popped = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        let mut expected = (0..MAX_BURST).collect::<Vec<_>>();
+        expected.push(1000);
+        expected.extend(MAX_BURST..2 * MAX_BURST);
+        expected.push(1001);
+        expected.push(2 * MAX_BURST);
+        assert_eq!(popped, expected);
+    }
+
+    #[tokio::test]
+    async fn never_dials_itself() {
+        let mut swarm = Behaviour::bootstrap_with_config(
+            Keypair::generate_ed25519(),
+            None,
+            default_gossipsub_config(),
+        )
+        .await
+        .unwrap();
+        let local = *swarm.local_peer_id();
+        let [remote, on_loopback] = [(); 2].map(|_| PeerId::random());
+        let addr = |s: &str| s.parse::<Multiaddr>().unwrap();
+        let behaviour = swarm.behaviour_mut();
+        behaviour.ignore_addr_prefixes(Cidr::loopback());
+        behaviour.dial_discovered([
+            (local, addr("/ip4/192.168.1.2/tcp/4001")),
+            (local, addr("/ip4/127.0.0.1/tcp/4001")),
+            (remote, addr("/ip4/127.0.0.1/tcp/4002")),
+            (remote, addr("/ip4/192.168.1.3/tcp/4002")),
+            (on_loopback, addr("/ip6/::1/tcp/4003")),
+        ]);
+
+        let mut dialed = Vec::new();
+        while let Some(action) = behaviour.events.pop() {
+            if let libp2p::swarm::NetworkBehaviourAction::Dial { opts, .. } = action {
+                dialed.push(opts.get_peer_id());
+            }
+        }
+        assert_eq!(dialed, [Some(remote)]);
+    }
+}
@@ -0,0 +1,272 @@
+//! What peers tell about themselves in their messages: their nicknames, with changes rate limited,
+//! and their capabilities. Free of the swarm and tokio, so browsers apply messages the same way,
+//! see `wasm/`. Times are generic for that, an `Instant` natively.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    ops::Add,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+/// Capabilities remembered per peer, and their maximum length, so peers can't make us hoard.
+pub(crate) const MAX_CAPABILITIES: usize = 32;
+pub(crate) const MAX_CAPABILITY_LEN: usize = 64;
+/// Nickname changes honored per peer within [`RENAME_WINDOW`]. Further ones are coalesced into
+/// the latest, applied once the window reopens.
+const MAX_RENAMES: usize = 3;
+pub(crate) const RENAME_WINDOW: Duration = Duration::from_secs(60);
+/// Changing back to the previous nickname within this long isn't announced.
+const FLIP_WINDOW: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub(crate) struct Peers<I = Instant> {
+    pub(crate) known_nicknames: HashMap<PeerId, String>,
+    /// As announced by the peers.
+    pub(crate) capabilities: BTreeMap<PeerId, BTreeSet<String>>,
+    /// Recent nickname changes per peer, to rate-limit them.
+    renames: HashMap<PeerId, Renames<I>>,
+}
+
+impl<I> Default for Peers<I> {
+    fn default() -> Self {
+        Self {
+            known_nicknames: HashMap::new(),
+            capabilities: BTreeMap::new(),
+            renames: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Renames<I> {
+    /// When the changes within [`RENAME_WINDOW`] were honored, oldest first.
+    honored: VecDeque<I>,
+    /// The latest change beyond [`MAX_RENAMES`], not applied yet.
+    pending: Option<String>,
+    /// The nickname before the current one, and when it was left.
+    previous: Option<(String, I)>,
+}
+
+impl<I> Default for Renames<I> {
+    fn default() -> Self {
+        Self {
+            honored: VecDeque::new(),
+            pending: None,
+            previous: None,
+        }
+    }
+}
+
+impl<I: Copy + Ord + Add<Duration, Output = I>> Renames<I> {
+    fn is_limited(&mut self, now: I) -> bool {
+        while matches!(self.honored.front(), Some(at) if *at + RENAME_WINDOW <= now) {
+            self.honored.pop_front();
+        }
+        self.honored.len() >= MAX_RENAMES
+    }
+}
+
+/// A nickname change applied by [`Peers::rename`] or [`Peers::apply_pending_renames`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Renamed {
+    pub(crate) old: String,
+    pub(crate) new: String,
+    /// False when changing right back to the previous nickname.
+    pub(crate) announce: bool,
+}
+
+impl<I: Copy + Ord + Add<Duration, Output = I>> Peers<I> {
+    /// Changes the nickname of `peer` to `nick`, unless it's unchanged or `peer` renamed itself
+    /// too often lately. The change is then deferred until [`Peers::apply_pending_renames`],
+    /// replacing any deferred before.
+    pub(crate) fn rename(&mut self, peer: PeerId, nick: String, now: I) -> Option<Renamed> {
+        let renames = self.renames.entry(peer).or_default();
+        if self.known_nicknames.get(&peer) == Some(&nick) {
+            // Peers repeat their nickname periodically, which also cancels a deferred change.
+            renames.pending = None;
+            return None;
+        }
+        if renames.is_limited(now) {
+            renames.pending = Some(nick);
+            return None;
+        }
+        renames.pending = None;
+        Some(self.apply_rename(peer, nick, now))
+    }
+
+    /// Whether a nickname change of `peer` is deferred, see [`Peers::rename`].
+    pub(crate) fn rename_deferred(&self, peer: &PeerId) -> bool {
+        matches!(self.renames.get(peer), Some(renames) if renames.pending.is_some())
+    }
+
+    /// The deferred nickname changes.
+    pub(crate) fn pending_renames(&self) -> impl Iterator<Item = (&PeerId, &str)> {
+        self.renames
+            .iter()
+            .filter_map(|(peer, renames)| Some((peer, renames.pending.as_deref()?)))
+    }
+
+    /// Applies the deferred nickname changes of peers which may rename themselves again.
+    pub(crate) fn apply_pending_renames(&mut self, now: I) -> Vec<(PeerId, Renamed)> {
+        let mut due = self
+            .renames
+            .iter_mut()
+            .filter(|(_, renames)| renames.pending.is_some())
+            .filter_map(|(peer, renames)| match renames.is_limited(now) {
+                true => None,
+                false => Some((*peer, renames.pending.take().expect("checked above"))),
+            })
+            .collect::<Vec<_>>();
+        due.sort_unstable_by_key(|(peer, _)| *peer);
+        due.into_iter()
+            .filter(|(peer, nick)| self.known_nicknames.get(peer) != Some(nick))
+            .map(|(peer, nick)| (peer, self.apply_rename(peer, nick, now)))
+            .collect()
+    }
+
+    fn apply_rename(&mut self, peer: PeerId, nick: String, now: I) -> Renamed {
+        let renames = self.renames.entry(peer).or_default();
+        renames.honored.push_back(now);
+        let flipped_back = matches!(
+            &renames.previous,
+            Some((previous, at)) if *previous == nick && now < *at + FLIP_WINDOW
+        );
+        let old = self
+            .known_nicknames
+            .insert(peer, nick.clone())
+            .unwrap_or_else(|| peer.to_string());
+        renames.previous = Some((old.clone(), now));
+        Renamed {
+            old,
+            new: nick,
+            announce: !flipped_back,
+        }
+    }
+
+    /// Replaces the capabilities of `peer` by the ones announced.
+    pub(crate) fn set_capabilities(&mut self, peer: PeerId, supported: Vec<String>) {
+        let supported = supported
+            .into_iter()
+            .filter(|c| c.len() <= MAX_CAPABILITY_LEN)
+            .take(MAX_CAPABILITIES)
+            .collect();
+        self.capabilities.insert(peer, supported);
+    }
+
+    /// Whether `peer` announced `capability`. Peers not having announced any support none.
+    pub(crate) fn supports(&self, peer: &PeerId, capability: &str) -> bool {
+        self.capabilities
+            .get(peer)
+            .map_or(false, |supported| supported.contains(capability))
+    }
+
+    /// Forgets all about `peer`.
+    pub(crate) fn remove(&mut self, peer: &PeerId) {
+        self.known_nicknames.remove(peer);
+        self.renames.remove(peer);
+        self.capabilities.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capability_negotiation() {
+        let mut peers = Peers::<Instant>::default();
+        let [alice, bob] = [(); 2].map(|_| PeerId::random());
+        assert!(!peers.supports(&alice, "read_receipts"));
+
+        peers.set_capabilities(alice, vec!["read_receipts".into(), "reactions".into()]);
+        peers.set_capabilities(bob, vec!["reactions".into()]);
+        assert!(peers.supports(&alice, "read_receipts"));
+        assert!(!peers.supports(&bob, "read_receipts"));
+        assert!(peers.supports(&bob, "reactions"));
+
+        // Replaced, not merged.
+        peers.set_capabilities(alice, vec!["compression".into()]);
+        assert!(!peers.supports(&alice, "read_receipts"));
+        assert!(peers.supports(&alice, "compression"));
+
+        // Bounded.
+        let many = (0..100).map(|i| format!("c{}", i)).collect::<Vec<_>>();
+        peers.set_capabilities(bob, many);
+        assert_eq!(peers.capabilities[&bob].len(), MAX_CAPABILITIES);
+        peers.set_capabilities(bob, vec!["x".repeat(MAX_CAPABILITY_LEN + 1)]);
+        assert!(peers.capabilities[&bob].is_empty());
+    }
+
+    #[test]
+    fn rate_limits_renames() {
+        let start = Instant::now();
+        let mut peers = Peers::default();
+        let peer = PeerId::random();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut rename = |nick: &str, secs| {
+            peers
+                .rename(peer, nick.into(), at(secs))
+                .map(|renamed| renamed.new)
+        };
+        assert_eq!(rename("a", 0).as_deref(), Some("a"));
+        // Repeating the nickname isn't a change.
+        assert_eq!(rename("a", 0), None);
+        assert_eq!(rename("b", 1).as_deref(), Some("b"));
+        assert_eq!(rename("c", 2).as_deref(), Some("c"));
+        // Rapid fire beyond the limit, coalesced into the latest.
+        for i in 0..100 {
+            assert_eq!(rename(&format!("spam{}", i), 3), None);
+        }
+        assert_eq!(peers.known_nicknames[&peer], "c");
+        assert!(peers.rename_deferred(&peer));
+
+        // Not before the first change leaves the window.
+        assert!(peers.apply_pending_renames(at(59)).is_empty());
+        let applied = peers.apply_pending_renames(at(60));
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].1.old, "c");
+        assert_eq!(applied[0].1.new, "spam99");
+        assert!(peers.apply_pending_renames(at(61)).is_empty());
+        assert_eq!(peers.known_nicknames[&peer], "spam99");
+    }
+
+    #[test]
+    fn deferred_renames_are_cancelled_by_repeating_the_nickname() {
+        let start = Instant::now();
+        let mut peers = Peers::default();
+        let peer = PeerId::random();
+        for (i, nick) in ["a", "b", "c"].into_iter().enumerate() {
+            assert!(peers
+                .rename(peer, nick.into(), start + Duration::from_secs(i as u64))
+                .is_some());
+        }
+        assert_eq!(peers.rename(peer, "d".into(), start), None);
+        assert_eq!(peers.rename(peer, "c".into(), start), None);
+        assert!(peers
+            .apply_pending_renames(start + RENAME_WINDOW * 2)
+            .is_empty());
+        assert_eq!(peers.known_nicknames[&peer], "c");
+    }
+
+    #[test]
+    fn flipping_back_is_quiet() {
+        // Any time will do, e.g. since the page was loaded in browsers.
+        let mut peers = Peers::<Duration>::default();
+        let peer = PeerId::random();
+        let mut rename = |nick: &str, secs| {
+            peers
+                .rename(peer, nick.into(), Duration::from_secs(secs))
+                .unwrap()
+        };
+        assert!(rename("alice", 0).announce);
+        assert!(rename("bob", 1).announce);
+        let back = rename("alice", 2);
+        assert_eq!((back.old.as_str(), back.new.as_str()), ("bob", "alice"));
+        assert!(!back.announce);
+        // A while later it's a change like any other.
+        assert!(rename("bob", 200).announce);
+        assert!(rename("carol", 201).announce);
+        assert!(!rename("bob", 202).announce);
+    }
+}
@@ -0,0 +1,498 @@
+//! Link previews (`--link-previews`): titles of pages linked in messages, fetched off the main
+//! loop and displayed under the message once available.
+//!
+//! Only `http://` links are fetched, as the HTTP client is built without TLS support. Links are
+//! fetched only from hosts named by domain, and only at their public addresses: not at IP
+//! literals, nor private, loopback, link-local or cloud metadata addresses, so a message can't
+//! make us probe the local network.
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::{
+    body::HttpBody,
+    client::{
+        connect::{dns::Name, Connect},
+        HttpConnector,
+    },
+    header,
+    service::Service,
+    Body, Client, Request, StatusCode, Uri,
+};
+use tokio::sync::mpsc;
+use tracing::*;
+
+use crate::shutdown::{Flag, Tasks};
+
+/// Links waiting to be fetched, beyond which new ones are skipped.
+const QUEUE_SIZE: usize = 64;
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Bytes of a page read to find its title.
+const MAX_BYTES: usize = 64 * 1024;
+/// Characters of a title displayed.
+const MAX_TITLE_CHARS: usize = 120;
+
+/// Domains previews are fetched for. A domain matches its subdomains, too.
+#[derive(Debug, Default)]
+pub(crate) struct DomainFilter {
+    /// If not empty, only these.
+    pub(crate) allow: Vec<String>,
+    /// Never these, even if allowed.
+    pub(crate) deny: Vec<String>,
+}
+
+fn matches(host: &str, domain: &str) -> bool {
+    let (host, domain) = (host.to_ascii_lowercase(), domain.to_ascii_lowercase());
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+impl DomainFilter {
+    pub(crate) fn permits(&self, host: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|d| matches(host, d)))
+            && !self.deny.iter().any(|d| matches(host, d))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Preview {
+    pub(crate) host: String,
+    pub(crate) title: String,
+}
+
+impl Preview {
+    /// The line displayed under the message, dimmed if `dim`.
+    pub(crate) fn line(&self, dim: bool) -> String {
+        let line = format!("  ↳ {} ({})", self.title, self.host);
+        match dim {
+            true => format!("\x1b[2m{}\x1b[0m", line),
+            false => line,
+        }
+    }
+}
+
+pub(crate) struct LinkPreviews {
+    filter: DomainFilter,
+    links: mpsc::Sender<Uri>,
+    previews: mpsc::Receiver<Preview>,
+}
+
+impl LinkPreviews {
+    /// Spawns the task fetching the links.
+    pub(crate) fn spawn(filter: DomainFilter, tasks: &mut Tasks) -> Self {
+        let connector = HttpConnector::new_with_resolver(PublicResolver);
+        Self::spawn_with(filter, Client::builder().build(connector), tasks)
+    }
+
+    fn spawn_with<C>(filter: DomainFilter, client: Client<C>, tasks: &mut Tasks) -> Self
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        let (links, rx) = mpsc::channel(QUEUE_SIZE);
+        let (tx, previews) = mpsc::channel(QUEUE_SIZE);
+        tasks.spawn(|shutdown| fetch_all(client, rx, tx, shutdown));
+        Self {
+            filter,
+            links,
+            previews,
+        }
+    }
+
+    /// Queues the first link in `message` to be fetched, if its domain is permitted.
+    pub(crate) fn request(&self, message: &str) {
+        let link = match first_link(message) {
+            Some(link) => link,
+            None => return,
+        };
+        if link.host().map_or(true, is_ip_literal) {
+            debug!(%link, "Not previewing link to an IP address");
+            return;
+        }
+        if !link.host().map_or(false, |host| self.filter.permits(host)) {
+            debug!(%link, "Not previewing link of filtered domain");
+            return;
+        }
+        if self.links.try_send(link).is_err() {
+            debug!("Link preview queue full, skipping");
+        }
+    }
+
+    pub(crate) async fn next_preview(previews: &mut Option<Self>) -> Option<Preview> {
+        match previews {
+            Some(previews) => previews.previews.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// `host` of a URI, IPv6 addresses in brackets.
+fn is_ip_literal(host: &str) -> bool {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_ok()
+}
+
+/// Whether `ip` is reachable on the internet, rather than private, loopback, link-local (which
+/// includes the cloud metadata service at 169.254.169.254) or otherwise special.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let embedded = || {
+                let octets = ip.octets();
+                Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])
+            };
+            match segments {
+                // IPv4-mapped.
+                [0, 0, 0, 0, 0, 0xffff, ..] => is_public_v4(embedded()),
+                // NAT64.
+                [0x64, 0xff9b, 0, 0, 0, 0, ..] => is_public_v4(embedded()),
+                _ => {
+                    !(ip.is_loopback()
+                        || ip.is_unspecified()
+                        || ip.is_multicast()
+                        // Unique local, e.g. fd00:ec2::254 (AWS metadata).
+                        || segments[0] & 0xfe00 == 0xfc00
+                        // Link-local.
+                        || segments[0] & 0xffc0 == 0xfe80
+                        // Documentation.
+                        || segments[..2] == [0x2001, 0xdb8])
+                }
+            }
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "This network".
+        || a == 0
+        // Shared (carrier-grade NAT), e.g. 100.100.100.200 (Alibaba Cloud metadata).
+        || (a == 100 && b & 0xc0 == 64)
+        // Reserved.
+        || a >= 240)
+}
+
+/// Resolves hosts to their public addresses only, failing if there are none. Used by the
+/// connector, so the addresses checked are the ones connected to.
+#[derive(Debug, Clone)]
+struct PublicResolver;
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} has no public address", name),
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// The first `http://` link in `message`.
+fn first_link(message: &str) -> Option<Uri> {
+    message
+        .split_whitespace()
+        .filter_map(|word| word.find("http://").map(|at| &word[at..]))
+        .map(|word| word.trim_end_matches(|c: char| ",.;:!?)]>'\"".contains(c)))
+        .find_map(|word| word.parse::<Uri>().ok().filter(|uri| uri.host().is_some()))
+}
+
+async fn fetch_all<C>(
+    client: Client<C>,
+    mut links: mpsc::Receiver<Uri>,
+    previews: mpsc::Sender<Preview>,
+    shutdown: Flag,
+) where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    loop {
+        let link = tokio::select! {
+            _ = shutdown.wait() => break,
+            link = links.recv() => match link {
+                Some(link) => link,
+                None => break,
+            },
+        };
+        let fetched = tokio::select! {
+            _ = shutdown.wait() => break,
+            fetched = tokio::time::timeout(TIMEOUT, fetch_title(&client, &link)) => fetched,
+        };
+        let title = match fetched {
+            Ok(Ok(Some(title))) => title,
+            Ok(Ok(None)) => {
+                debug!(%link, "No title to preview");
+                continue;
+            }
+            Ok(Err(error)) => {
+                debug!(%link, %error, "Fetching link preview failed");
+                continue;
+            }
+            Err(_) => {
+                debug!(%link, "Fetching link preview timed out");
+                continue;
+            }
+        };
+        let host = link.host().unwrap_or_default().to_string();
+        if previews.send(Preview { host, title }).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn fetch_title<C>(client: &Client<C>, link: &Uri) -> anyhow::Result<Option<String>>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let request = Request::get(link.clone())
+        .header(header::ACCEPT, "text/html")
+        .body(Body::empty())?;
+    let mut response = client.request(request).await?;
+    anyhow::ensure!(
+        response.status() == StatusCode::OK,
+        "Status {}",
+        response.status()
+    );
+    let mut page = Vec::new();
+    while let Some(chunk) = response.body_mut().data().await {
+        page.extend_from_slice(&chunk?);
+        if page.len() >= MAX_BYTES {
+            page.truncate(MAX_BYTES);
+            break;
+        }
+    }
+    Ok(title(&String::from_utf8_lossy(&page)))
+}
+
+/// Contents of the page's `<title>`, whitespace collapsed, control characters (which could
+/// drive the terminal) removed and shortened.
+fn title(page: &str) -> Option<String> {
+    // ASCII lowercasing keeps byte offsets.
+    let lower = page.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    let text = page[start..end]
+        .replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'");
+    let text = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>();
+    let mut title = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.is_empty() {
+        return None;
+    }
+    if let Some((at, _)) = title.char_indices().nth(MAX_TITLE_CHARS) {
+        title.truncate(at);
+        title.push('…');
+    }
+    Some(title)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server,
+    };
+
+    use super::*;
+
+    #[test]
+    fn finds_links() {
+        let link = |m: &str| first_link(m).map(|uri| uri.to_string());
+        assert_eq!(
+            link("see http://example.com/a?b=c, it's great").as_deref(),
+            Some("http://example.com/a?b=c")
+        );
+        assert_eq!(
+            link("(http://example.com/)").as_deref(),
+            Some("http://example.com/")
+        );
+        assert_eq!(link("https://example.com only over TLS"), None);
+        assert_eq!(link("no links"), None);
+    }
+
+    #[test]
+    fn filters_domains() {
+        let filter = DomainFilter {
+            allow: vec![],
+            deny: vec!["tracker.example".into()],
+        };
+        assert!(filter.permits("example.com"));
+        assert!(!filter.permits("tracker.example"));
+        assert!(!filter.permits("cdn.tracker.example"));
+        assert!(filter.permits("nottracker.example"));
+
+        let filter = DomainFilter {
+            allow: vec!["example.com".into()],
+            deny: vec!["ads.example.com".into()],
+        };
+        assert!(filter.permits("example.com"));
+        assert!(filter.permits("www.example.com"));
+        assert!(!filter.permits("ads.example.com"));
+        assert!(!filter.permits("example.org"));
+    }
+
+    #[test]
+    fn extracts_titles() {
+        assert_eq!(
+            title("<html><head><TITLE lang=en>\n  Fish &amp; Chips\n</TITLE>").as_deref(),
+            Some("Fish & Chips")
+        );
+        assert_eq!(title("<title></title>"), None);
+        assert_eq!(title("<title>unterminated"), None);
+        let long = title(&format!("<title>{}</title>", "ä".repeat(500))).unwrap();
+        assert_eq!(long.chars().count(), MAX_TITLE_CHARS + 1);
+        assert_eq!(
+            title("<title>\x1b]0;pwned\x07Hi\x1b[2J</title>").as_deref(),
+            Some("]0;pwned Hi [2J")
+        );
+    }
+
+    #[test]
+    fn fetches_only_public_addresses() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00:ec2::254",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1::1",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_ip_literal("127.0.0.1"));
+        assert!(is_ip_literal("[::1]"));
+        assert!(!is_ip_literal("example.com"));
+    }
+
+    #[tokio::test]
+    async fn refuses_local_targets() {
+        let resolved = PublicResolver.call("localhost".parse().unwrap()).await;
+        assert_eq!(
+            resolved.unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+
+        let mut tasks = Tasks::default();
+        let mut previews = Some(LinkPreviews::spawn(Default::default(), &mut tasks));
+        let previewer = previews.as_ref().unwrap();
+        previewer.request("http://127.0.0.1/");
+        previewer.request("http://[::1]/");
+        previewer.request("http://localhost/");
+        let preview = tokio::time::timeout(
+            Duration::from_millis(500),
+            LinkPreviews::next_preview(&mut previews),
+        )
+        .await;
+        assert!(preview.is_err(), "{:?}", preview);
+        tasks.shutdown().await;
+    }
+
+    /// Resolves any name to its address.
+    #[derive(Debug, Clone)]
+    struct To(SocketAddr);
+
+    impl Service<Name> for To {
+        type Response = std::vec::IntoIter<SocketAddr>;
+        type Error = io::Error;
+        type Future = std::future::Ready<io::Result<Self::Response>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Name) -> Self::Future {
+            std::future::ready(Ok(vec![self.0].into_iter()))
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_titles_in_the_background() {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                let page = format!("<title>Hello</title>{}", "x".repeat(10 * MAX_BYTES));
+                Ok::<_, Infallible>(Response::new(Body::from(page)))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        // Resolving any name to the local server.
+        let client = Client::builder().build(HttpConnector::new_with_resolver(To(addr)));
+        let mut tasks = Tasks::default();
+        let mut previews = Some(LinkPreviews::spawn_with(
+            Default::default(),
+            client,
+            &mut tasks,
+        ));
+        let previewer = previews.as_ref().unwrap();
+        previewer.request("nothing to fetch");
+        previewer.request(&format!("look: http://example.com:{}/page", addr.port()));
+        let preview = tokio::time::timeout(TIMEOUT, LinkPreviews::next_preview(&mut previews))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            preview,
+            Preview {
+                host: "example.com".into(),
+                title: "Hello".into()
+            }
+        );
+        assert_eq!(preview.line(false), "  ↳ Hello (example.com)");
+        tasks.shutdown().await;
+    }
+}
@@ -0,0 +1,196 @@
+//! Named bundles of the settings to join a network with: identity, bootstrap and static peers,
+//! channels and transport settings. Saved by `/profile save <name>` into the data dir (by default
+//! `~/.local/share/agora/profiles/<name>.toml`), and used by `--profile <name>` or loaded into a
+//! running session by `/profile load <name>`, which joins its channels, leaves the others and
+//! dials its peers. Its identity and transport settings only take effect on restart.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context};
+use libp2p::Multiaddr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::discovery::PeerAddr;
+
+/// Where profiles are kept: `agora/profiles` in `$XDG_DATA_HOME`, or in `~/.local/share`.
+pub(crate) fn default_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|data| data.join("agora").join("profiles"))
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Profile {
+    /// Keypair file, a new identity is generated each time if omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) identity: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) bootstrap: Option<Multiaddr>,
+    /// Like `--peer`s
+    #[serde(default, serialize_with = "peers_out", deserialize_with = "peers_in")]
+    pub(crate) peers: Vec<PeerAddr>,
+    /// The first is the one typed messages go to.
+    pub(crate) channels: Vec<String>,
+    #[serde(default)]
+    pub(crate) private_topic: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) listen: Option<Multiaddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tcp_keepalive_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tcp_keepalive_interval_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tcp_keepalive_probes: Option<u32>,
+}
+
+fn peers_out<S: Serializer>(peers: &[PeerAddr], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(peers.iter().map(PeerAddr::to_string))
+}
+
+fn peers_in<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PeerAddr>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|raw| raw.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Names are file names, so letters, digits, `-` and `_` only.
+fn check_name(name: &str) -> anyhow::Result<()> {
+    ensure!(
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "Invalid profile name {:?}: use letters, digits, - and _",
+        name
+    );
+    Ok(())
+}
+
+fn path(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    check_name(name)?;
+    Ok(dir.join(format!("{}.toml", name)))
+}
+
+impl Profile {
+    pub(crate) fn load(dir: &Path, name: &str) -> anyhow::Result<Self> {
+        let path = path(dir, name)?;
+        let raw = fs::read_to_string(&path)
+            .with_context(|| format!("Reading profile {}", path.display()))?;
+        let profile: Self =
+            toml::from_str(&raw).with_context(|| format!("Parsing profile {}", path.display()))?;
+        ensure!(
+            !profile.channels.is_empty(),
+            "Profile {} has no channels",
+            path.display()
+        );
+        Ok(profile)
+    }
+
+    /// Writes the profile, replacing one of the same name, returning where.
+    pub(crate) fn save(&self, dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+        let path = path(dir, name)?;
+        fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+        let raw = toml::to_string(self).context("Serializing profile")?;
+        fs::write(&path, raw).with_context(|| format!("Writing profile {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// The settings of `self` differing from `current`'s which can't change without a restart.
+    pub(crate) fn restart_needed(&self, current: &Profile) -> Vec<&'static str> {
+        let mut differing = Vec::new();
+        if self.identity != current.identity {
+            differing.push("identity");
+        }
+        if self.channels.first() != current.channels.first() {
+            differing.push("channel typed in");
+        }
+        if self.private_topic != current.private_topic {
+            differing.push("private topics");
+        }
+        if self.listen.is_some() && self.listen != current.listen {
+            differing.push("listen address");
+        }
+        let keepalive = |p: &Profile| {
+            (
+                p.tcp_keepalive_secs,
+                p.tcp_keepalive_interval_secs,
+                p.tcp_keepalive_probes,
+            )
+        };
+        if keepalive(self) != (None, None, None) && keepalive(self) != keepalive(current) {
+            differing.push("TCP keep-alive");
+        }
+        differing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::*;
+
+    fn profile() -> Profile {
+        Profile {
+            identity: Some("/home/alice/work.key".into()),
+            bootstrap: Some("/ip4/10.0.0.1/tcp/4001".parse().unwrap()),
+            peers: vec![format!("/ip4/10.0.0.2/tcp/4001/p2p/{}", PeerId::random())
+                .parse()
+                .unwrap()],
+            channels: vec!["ops".into(), "random".into()],
+            tcp_keepalive_secs: Some(30),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = std::env::temp_dir().join(format!(
+            "agora-profiles-{}-{}",
+            std::process::id(),
+            PeerId::random()
+        ));
+        let profile = profile();
+        profile.save(&dir, "work").unwrap();
+        let loaded = Profile::load(&dir, "work");
+        let missing = Profile::load(&dir, "home");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.unwrap(), profile);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn names_are_file_names() {
+        let dir = Path::new("/nonexistent");
+        for invalid in ["", "../work", "work/home", "work.toml"] {
+            assert!(Profile::load(dir, invalid)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Invalid profile name"));
+        }
+        assert!(path(dir, "work-2_b").is_ok());
+    }
+
+    #[test]
+    fn restart_needed_for_identity_and_transport() {
+        let current = profile();
+        let mut other = current.clone();
+        other.peers.clear();
+        other.channels.push("more".into());
+        assert!(other.restart_needed(&current).is_empty());
+
+        other.identity = None;
+        other.channels.reverse();
+        other.listen = Some("/ip4/0.0.0.0/tcp/4001".parse().unwrap());
+        other.tcp_keepalive_secs = None;
+        assert_eq!(
+            other.restart_needed(&current),
+            ["identity", "channel typed in", "listen address"]
+        );
+    }
+}
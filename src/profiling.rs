@@ -0,0 +1,56 @@
+//! CPU profiling (`--profile-cpu`): samples the process while a command runs and writes a
+//! flamegraph of it. See `cargo xtask flamegraph` for profiling under a test workload.
+use std::{future::Future, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+
+use crate::shutdown::ShutdownReason;
+
+/// Samples per second, off the beat of periodic timers.
+const FREQUENCY: i32 = 99;
+
+#[derive(clap::Args, Debug, Default)]
+pub(crate) struct ProfileArgs {
+    /// Run for this many seconds while sampling the CPU, then write a flamegraph to
+    /// agora-flamegraph-<timestamp>.svg
+    #[clap(long, global = true, value_name = "SECS")]
+    profile_cpu: Option<u64>,
+}
+
+impl ProfileArgs {
+    /// Runs `command`, for at most `--profile-cpu` seconds while sampling if set.
+    pub(crate) async fn run(
+        &self,
+        command: impl Future<Output = anyhow::Result<ShutdownReason>>,
+    ) -> anyhow::Result<ShutdownReason> {
+        let secs = match self.profile_cpu {
+            Some(secs) => secs,
+            None => return command.await,
+        };
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .context("Starting the CPU profiler")?;
+        let reason = tokio::time::timeout(Duration::from_secs(secs), command)
+            .await
+            .unwrap_or(Ok(ShutdownReason::Profiled));
+        let path = write_flamegraph(&guard)?;
+        eprintln!("Wrote flamegraph to {}", path.display());
+        reason
+    }
+}
+
+fn write_flamegraph(guard: &pprof::ProfilerGuard) -> anyhow::Result<PathBuf> {
+    let report = guard.report().build().context("Building the CPU profile")?;
+    let path = PathBuf::from(format!(
+        "agora-flamegraph-{}.svg",
+        chrono::Local::now().format("%Y%m%dT%H%M%S")
+    ));
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("Creating {}", path.display()))?;
+    report
+        .flamegraph(file)
+        .with_context(|| format!("Writing {}", path.display()))?;
+    Ok(path)
+}
@@ -0,0 +1,244 @@
+use libp2p::gossipsub::{error::PublishError, MessageId, TopicHash};
+use tracing::{debug, field, info_span, warn, Instrument, Span};
+
+use crate::{
+    api::ChatApi,
+    encode,
+    p2p::{Behaviour, Gossipsub},
+};
+
+/// Anything messages can be published to, i.e. [`Gossipsub`] or [`Behaviour`], which encrypts
+/// them if a channel key is set.
+pub(crate) trait Publisher {
+    fn publish(&mut self, topic: TopicHash, data: &[u8]) -> Result<MessageId, PublishError>;
+
+    /// Number of mesh peers of `topic`, if known.
+    fn mesh_peers(&self, _topic: &TopicHash) -> Option<usize> {
+        None
+    }
+
+    /// Bytes of the largest message gossipsub transmits, if known.
+    fn max_transmit_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Called with the result of each [`publish`].
+    fn published(&mut self, _topic: &TopicHash, _published: Published) {}
+}
+
+impl Publisher for Gossipsub {
+    fn publish(&mut self, topic: TopicHash, data: &[u8]) -> Result<MessageId, PublishError> {
+        Gossipsub::publish(self, topic, data)
+    }
+
+    fn mesh_peers(&self, topic: &TopicHash) -> Option<usize> {
+        Some(Gossipsub::mesh_peers(self, topic).count())
+    }
+}
+
+/// What became of a published message. Failing to publish one is never fatal, callers tell the
+/// user with [`Published::notice`] if they sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Published {
+    /// Delivered directly to about this many peers, see [`publish`].
+    Sent {
+        reach: Option<usize>,
+    },
+    NoPeers,
+    /// Identical to a message published shortly before, which gossipsub drops as a duplicate.
+    Duplicate,
+    TooLarge {
+        size: usize,
+        max: Option<usize>,
+    },
+    SigningFailed,
+    /// Encrypting it with the channel key failed.
+    TransformFailed,
+}
+
+impl Published {
+    fn from_error(error: &PublishError, size: usize, max: Option<usize>) -> Self {
+        match error {
+            PublishError::InsufficientPeers => Self::NoPeers,
+            PublishError::Duplicate => Self::Duplicate,
+            PublishError::MessageTooLarge => Self::TooLarge { size, max },
+            PublishError::SigningError(_) => Self::SigningFailed,
+            PublishError::TransformFailed(_) => Self::TransformFailed,
+        }
+    }
+
+    /// What to tell the user about a message they sent, if it wasn't.
+    pub(crate) fn notice(&self) -> Option<String> {
+        let notice = match self {
+            Self::Sent { .. } => return None,
+            Self::NoPeers => "No peers available".into(),
+            Self::Duplicate => {
+                "Not sent: identical to a message sent just before, which peers would drop".into()
+            }
+            Self::TooLarge {
+                size,
+                max: Some(max),
+            } => format!(
+                "Not sent: the message is {} bytes, more than the limit of {}",
+                size, max
+            ),
+            Self::TooLarge { size, max: None } => {
+                format!("Not sent: the message of {} bytes is too large", size)
+            }
+            Self::SigningFailed => {
+                "Not sent: signing failed (see the log), check the --identity keypair".into()
+            }
+            Self::TransformFailed => {
+                "Not sent: encrypting failed (see the log), check the --channel-key".into()
+            }
+        };
+        Some(notice)
+    }
+}
+
+/// Publishes `message`, returning an estimate of how many peers it was delivered to directly.
+///
+/// The estimate is the topic's mesh size at publish time. It's unknown if the mesh is empty, in
+/// which case gossipsub falls back to fanout peers.
+pub(crate) fn publish(
+    publisher: &mut impl Publisher,
+    topic: impl Into<TopicHash>,
+    message: &[u8],
+) -> Published {
+    let topic = topic.into();
+    let reach = publisher.mesh_peers(&topic).filter(|n| *n > 0);
+    let error = match publisher.publish(topic.clone(), message) {
+        Ok(_) => {
+            publisher.published(&topic, Published::Sent { reach });
+            return Published::Sent { reach };
+        }
+        Err(error) => error,
+    };
+    let published = Published::from_error(&error, message.len(), publisher.max_transmit_size());
+    publisher.published(&topic, published);
+    match published {
+        Published::NoPeers | Published::Duplicate => {
+            debug!(%topic, %error, "Not published");
+        }
+        _ => warn!(%topic, %error, size = message.len(), "Publishing failed"),
+    }
+    published
+}
+
+/// Encodes and publishes a chat message, traced as a `publish` span. Errors are only returned
+/// for messages which can't be encoded.
+pub(crate) async fn publish_chat(
+    publisher: &mut impl Publisher,
+    topic: impl Into<TopicHash>,
+    msg: ChatApi,
+    encode_threshold: usize,
+) -> anyhow::Result<Published> {
+    let topic = topic.into();
+    let span = info_span!("publish", %topic, size = field::Empty);
+    async move {
+        let len_hint = msg.len_hint();
+        let send = |publisher: &mut _, bytes: &[u8]| {
+            Span::current().record("size", &bytes.len());
+            publish(publisher, topic, bytes)
+        };
+        if len_hint < encode_threshold {
+            // Encoded into a reused buffer, see `encode::with_cbor`.
+            return encode::with_cbor(&msg, |bytes| send(publisher, bytes));
+        }
+        let bytes = encode::to_vec(msg, len_hint, encode_threshold)
+            .instrument(info_span!("encode"))
+            .await?;
+        Ok(send(publisher, &bytes))
+    }
+    .instrument(span)
+    .await
+}
+
+/// Publishes `message` to every subscribed topic.
+pub(crate) fn publish_all(behaviour: &mut Behaviour, message: &[u8]) {
+    let topics = behaviour.gossipsub.topics().cloned().collect::<Vec<_>>();
+    for topic in topics {
+        publish(behaviour, topic, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::gossipsub::IdentTopic;
+
+    use super::*;
+
+    struct Mock(Option<PublishError>, Option<usize>);
+
+    impl Publisher for Mock {
+        fn publish(&mut self, _: TopicHash, data: &[u8]) -> Result<MessageId, PublishError> {
+            match self.0.take() {
+                Some(e) => Err(e),
+                None => Ok(MessageId::new(data)),
+            }
+        }
+
+        fn mesh_peers(&self, _: &TopicHash) -> Option<usize> {
+            self.1
+        }
+    }
+
+    fn run(result: Option<PublishError>) -> Published {
+        publish(
+            &mut Mock(result, Some(5)),
+            IdentTopic::new("agora"),
+            b"hello",
+        )
+    }
+
+    #[test]
+    fn reach_is_the_mesh_size() {
+        assert_eq!(run(None), Published::Sent { reach: Some(5) });
+
+        let topic = IdentTopic::new("agora");
+        for mesh in [None, Some(0)] {
+            let published = publish(&mut Mock(None, mesh), topic.clone(), b"hello");
+            assert_eq!(published, Published::Sent { reach: None });
+            assert_eq!(published.notice(), None);
+        }
+    }
+
+    #[test]
+    fn every_error_is_an_outcome() {
+        // `SigningError` can't be constructed outside of libp2p, see `from_error`.
+        let transform = PublishError::TransformFailed(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "transform",
+        ));
+        let cases = [
+            (PublishError::InsufficientPeers, Published::NoPeers),
+            (PublishError::Duplicate, Published::Duplicate),
+            (
+                PublishError::MessageTooLarge,
+                Published::TooLarge { size: 5, max: None },
+            ),
+            (transform, Published::TransformFailed),
+        ];
+        for (error, published) in cases {
+            assert_eq!(run(Some(error)), published);
+            assert!(published.notice().is_some());
+        }
+    }
+
+    #[test]
+    fn notices_tell_the_limit() {
+        let published = Published::from_error(&PublishError::MessageTooLarge, 70_000, Some(65_536));
+        assert_eq!(
+            published.notice().unwrap(),
+            "Not sent: the message is 70000 bytes, more than the limit of 65536"
+        );
+        assert!(Published::SigningFailed
+            .notice()
+            .unwrap()
+            .contains("--identity"));
+        assert!(Published::TransformFailed
+            .notice()
+            .unwrap()
+            .contains("--channel-key"));
+    }
+}
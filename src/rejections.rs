@@ -0,0 +1,245 @@
+//! Notices of messages dropped as invalid, to diagnose signature or format issues between peers,
+//! e.g. "Dropped 3 invalid messages from alice: 2 invalid signature, 1 decode failed". Counted
+//! per peer from what's recorded in the [`AuditLog`](crate::audit::AuditLog), and reported at
+//! most every [`NOTICE_INTERVAL`] per peer. At most [`MAX_NOTICES`] peers are named at a time,
+//! the rest summarized in one line, see [`lines`].
+//!
+//! Messages gossipsub drops itself, e.g. with a signature not verifying or lacking a sequence
+//! number under `--strict-validation`, aren't seen: gossipsub only penalizes their peers' scores.
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+use crate::audit::Kind;
+
+/// Minimum time between notices about the same peer.
+pub(crate) const NOTICE_INTERVAL: Duration = Duration::from_secs(60);
+/// Peers counted, those beyond are ignored until others are forgotten.
+const MAX_PEERS: usize = 1024;
+/// Notices shown at a time, see [`lines`].
+const MAX_NOTICES: usize = 5;
+
+/// Whether messages recorded as `kind` were dropped as invalid.
+fn invalid(kind: Kind) -> bool {
+    matches!(
+        kind,
+        Kind::InvalidSignature
+            | Kind::Unsigned
+            | Kind::Unsequenced
+            | Kind::DecodeFailed
+            | Kind::OverLimits
+    )
+}
+
+#[derive(Debug, Default)]
+struct Tally {
+    /// Since the last notice, in the order first seen.
+    pending: Vec<(Kind, u64)>,
+    last_notice: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Rejections {
+    peers: HashMap<PeerId, Tally>,
+}
+
+/// Invalid messages of a peer since the last notice about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Notice {
+    pub(crate) peer: PeerId,
+    pub(crate) dropped: Vec<(Kind, u64)>,
+}
+
+impl Notice {
+    /// The notice, naming the peer `nick`.
+    pub(crate) fn line(&self, nick: &str) -> String {
+        let total = self.total();
+        format!(
+            "Dropped {} invalid message{} from {}: {}",
+            total,
+            if total == 1 { "" } else { "s" },
+            nick,
+            Kinds(&self.dropped)
+        )
+    }
+
+    fn total(&self) -> u64 {
+        self.dropped.iter().map(|(_, n)| n).sum()
+    }
+}
+
+/// The lines of `notices`, naming their peers by `nick`: those of [`MAX_NOTICES`] peers, and
+/// one summarizing the others', e.g. "Dropped 40 invalid messages from 12 other peers".
+pub(crate) fn lines(notices: &[Notice], nick: impl Fn(&PeerId) -> String) -> Vec<String> {
+    let shown = notices.len().min(MAX_NOTICES);
+    let mut lines = notices[..shown]
+        .iter()
+        .map(|notice| notice.line(&nick(&notice.peer)))
+        .collect::<Vec<_>>();
+    let others = &notices[shown..];
+    if !others.is_empty() {
+        let total = others.iter().map(Notice::total).sum::<u64>();
+        lines.push(format!(
+            "Dropped {} invalid message{} from {} other peer{}",
+            total,
+            if total == 1 { "" } else { "s" },
+            others.len(),
+            if others.len() == 1 { "" } else { "s" }
+        ));
+    }
+    lines
+}
+
+struct Kinds<'a>(&'a [(Kind, u64)]);
+
+impl fmt::Display for Kinds<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (kind, n)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {}", n, kind)?;
+        }
+        Ok(())
+    }
+}
+
+impl Rejections {
+    /// Counts a message of `peer` recorded as `kind`, if that's dropping it as invalid.
+    pub(crate) fn record(&mut self, kind: Kind, peer: &PeerId) {
+        if !invalid(kind) || (self.peers.len() >= MAX_PEERS && !self.peers.contains_key(peer)) {
+            return;
+        }
+        let pending = &mut self.peers.entry(*peer).or_default().pending;
+        match pending.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, n)) => *n += 1,
+            None => pending.push((kind, 1)),
+        }
+    }
+
+    /// The notices due at `now`, of peers with messages dropped since the last notice about
+    /// them, if that's at least [`NOTICE_INTERVAL`] ago. Peers not noticed about within it are
+    /// forgotten.
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<Notice> {
+        let elapsed = |tally: &Tally| {
+            tally.last_notice.map_or(true, |at| {
+                now.saturating_duration_since(at) >= NOTICE_INTERVAL
+            })
+        };
+        self.peers
+            .retain(|_, tally| !tally.pending.is_empty() || !elapsed(tally));
+        let mut notices = self
+            .peers
+            .iter_mut()
+            .filter(|(_, tally)| !tally.pending.is_empty() && elapsed(tally))
+            .map(|(peer, tally)| {
+                tally.last_notice = Some(now);
+                Notice {
+                    peer: *peer,
+                    dropped: std::mem::take(&mut tally.pending),
+                }
+            })
+            .collect::<Vec<_>>();
+        notices.sort_by_key(|notice| notice.peer);
+        notices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notices_are_rate_limited_per_peer() {
+        let mut rejections = Rejections::default();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        for kind in [
+            Kind::InvalidSignature,
+            Kind::DecodeFailed,
+            Kind::InvalidSignature,
+        ] {
+            rejections.record(kind, &alice);
+        }
+        // Not invalid, but otherwise suspicious.
+        rejections.record(Kind::Replayed, &bob);
+        rejections.record(Kind::NonMember, &bob);
+
+        let notices = rejections.due(start);
+        assert_eq!(
+            notices,
+            [Notice {
+                peer: alice,
+                dropped: vec![(Kind::InvalidSignature, 2), (Kind::DecodeFailed, 1)]
+            }]
+        );
+        assert_eq!(
+            notices[0].line("alice"),
+            "Dropped 3 invalid messages from alice: 2 invalid signature, 1 decode failed"
+        );
+
+        rejections.record(Kind::Unsigned, &alice);
+        rejections.record(Kind::OverLimits, &bob);
+        let later = start + Duration::from_secs(10);
+        let notices = rejections.due(later);
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0].peer, bob);
+        assert_eq!(
+            notices[0].line("bob"),
+            "Dropped 1 invalid message from bob: 1 over limits"
+        );
+
+        assert!(rejections.due(start + Duration::from_secs(59)).is_empty());
+        let notices = rejections.due(start + NOTICE_INTERVAL);
+        assert_eq!(
+            notices,
+            [Notice {
+                peer: alice,
+                dropped: vec![(Kind::Unsigned, 1)]
+            }]
+        );
+        // Both forgotten once quiet for a while.
+        assert!(rejections.due(later + 2 * NOTICE_INTERVAL).is_empty());
+        assert!(rejections.peers.is_empty());
+    }
+
+    #[test]
+    fn notices_beyond_the_limit_are_summarized() {
+        let mut rejections = Rejections::default();
+        let peers = (0..MAX_NOTICES + 2)
+            .map(|_| PeerId::random())
+            .collect::<Vec<_>>();
+        for peer in &peers {
+            rejections.record(Kind::DecodeFailed, peer);
+            rejections.record(Kind::OverLimits, peer);
+        }
+        let notices = rejections.due(Instant::now());
+        let shown = lines(&notices, |_| "peer".into());
+        assert_eq!(shown.len(), MAX_NOTICES + 1);
+        assert_eq!(
+            shown[0],
+            "Dropped 2 invalid messages from peer: 1 decode failed, 1 over limits"
+        );
+        assert_eq!(
+            shown[MAX_NOTICES],
+            "Dropped 4 invalid messages from 2 other peers"
+        );
+
+        let notices = &notices[..MAX_NOTICES];
+        assert_eq!(lines(notices, |_| "peer".into()).len(), MAX_NOTICES);
+    }
+
+    #[test]
+    fn peers_counted_are_bounded() {
+        let mut rejections = Rejections::default();
+        for _ in 0..MAX_PEERS + 10 {
+            rejections.record(Kind::DecodeFailed, &PeerId::random());
+        }
+        assert_eq!(rejections.peers.len(), MAX_PEERS);
+        assert_eq!(rejections.due(Instant::now()).len(), MAX_PEERS);
+    }
+}
@@ -0,0 +1,370 @@
+//! Replay protection. Published payloads are prefixed with the sender's epoch and a counter,
+//! authenticated along with the payload by the gossipsub signature (and the channel key, if
+//! set):
+//!
+//! ```text
+//! "agr1" | epoch (u64 BE) | counter (u64 BE) | payload
+//! ```
+//!
+//! The epoch is the sender's startup time in milliseconds, so it increases whenever a sender
+//! restarts and its counter starts over. Receivers keep the highest counter accepted per peer and
+//! kind of message, along with which of the [`WINDOW`] counters below it were accepted, and drop
+//! anything accepted before, older than that or of an earlier epoch.
+//!
+//! The header changed the wire format: builds before it send signed payloads without one, which
+//! can't be checked for replays. They're accepted for the transition, unless `--require-sequence`
+//! is set, which drops them, recorded as unsequenced in the audit log.
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use libp2p::PeerId;
+
+const MAGIC: &[u8; 4] = b"agr1";
+const HEADER_LEN: usize = MAGIC.len() + 2 * 8;
+/// Counters this far below the highest accepted are still accepted once, as messages may arrive
+/// out of order.
+pub(crate) const WINDOW: u64 = 64;
+/// Peers and kinds tracked. Beyond, the least recently used ones are forgotten.
+const MAX_MARKS: usize = 16 * 1024;
+
+/// Where the marks are kept without `--replay-state`: `agora/replay-state` in `$XDG_DATA_HOME`,
+/// or in `~/.local/share`.
+pub(crate) fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|data| data.join("agora").join("replay-state"))
+}
+
+type Key = (PeerId, String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sequence {
+    pub(crate) epoch: u64,
+    pub(crate) counter: u64,
+}
+
+/// Numbers the own messages.
+#[derive(Debug)]
+pub(crate) struct Sequencer {
+    epoch: u64,
+    counter: u64,
+}
+
+impl Sequencer {
+    pub(crate) fn new(epoch: u64) -> Self {
+        Self { epoch, counter: 0 }
+    }
+
+    /// Starting an epoch now.
+    pub(crate) fn now() -> Self {
+        Self::new(chrono::Utc::now().timestamp_millis().max(0) as u64)
+    }
+
+    /// Prefixes `payload` with the next sequence.
+    pub(crate) fn wrap(&mut self, payload: &[u8]) -> Vec<u8> {
+        self.counter += 1;
+        [
+            &MAGIC[..],
+            &self.epoch.to_be_bytes(),
+            &self.counter.to_be_bytes(),
+            payload,
+        ]
+        .concat()
+    }
+}
+
+/// Splits off the sequence, without copying the payload. `None` if there's none.
+pub(crate) fn split(data: Bytes) -> (Option<Sequence>, Bytes) {
+    if data.len() < HEADER_LEN || !data.starts_with(MAGIC) {
+        return (None, data);
+    }
+    let u64_at = |i: usize| u64::from_be_bytes(data[i..i + 8].try_into().expect("8 bytes"));
+    let sequence = Sequence {
+        epoch: u64_at(4),
+        counter: u64_at(12),
+    };
+    (Some(sequence), data.slice(HEADER_LEN..))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mark {
+    epoch: u64,
+    highest: u64,
+    /// Bit `i` is set if `highest - i` was accepted.
+    accepted: u64,
+    /// When a message was last accepted, the key into [`ReplayGuard::by_use`].
+    used: u64,
+}
+
+/// High-water marks of the peers' sequences.
+#[derive(Debug, Default)]
+pub(crate) struct ReplayGuard {
+    marks: HashMap<Key, Mark>,
+    /// The keys of `marks` by last use, least recent first.
+    by_use: BTreeMap<u64, Key>,
+    uses: u64,
+    /// Messages dropped as replayed.
+    pub(crate) dropped: u64,
+    /// Whether `marks` changed since the last [`ReplayGuard::save`].
+    dirty: bool,
+}
+
+impl ReplayGuard {
+    /// Whether the message of `kind` from `peer` at `sequence` wasn't accepted before, recording
+    /// it if so.
+    pub(crate) fn check(&mut self, peer: PeerId, kind: &str, sequence: Sequence) -> bool {
+        let key = (peer, kind.to_string());
+        let fresh = Mark {
+            epoch: sequence.epoch,
+            highest: sequence.counter,
+            accepted: 1,
+            used: 0,
+        };
+        let new = match self.marks.get_mut(&key) {
+            None => {
+                if self.marks.len() >= MAX_MARKS {
+                    self.evict_least_recently_used();
+                }
+                self.marks.insert(key.clone(), fresh);
+                true
+            }
+            // Restarted.
+            Some(mark) if sequence.epoch > mark.epoch => {
+                *mark = Mark {
+                    used: mark.used,
+                    ..fresh
+                };
+                true
+            }
+            Some(mark) if sequence.epoch < mark.epoch => false,
+            Some(mark) if sequence.counter > mark.highest => {
+                let shift = sequence.counter - mark.highest;
+                mark.accepted = mark.accepted.checked_shl(shift as u32).unwrap_or(0) | 1;
+                mark.highest = sequence.counter;
+                true
+            }
+            Some(mark) => {
+                let age = mark.highest - sequence.counter;
+                let bit = 1u64.checked_shl(age as u32).filter(|_| age < WINDOW);
+                match bit {
+                    Some(bit) if mark.accepted & bit == 0 => {
+                        mark.accepted |= bit;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        };
+        if new {
+            self.touch(key);
+            self.dirty = true;
+        } else {
+            self.dropped += 1;
+        }
+        new
+    }
+
+    /// Marks the mark of `key` as used last.
+    fn touch(&mut self, key: Key) {
+        self.uses += 1;
+        let mark = self.marks.get_mut(&key).expect("Checked");
+        self.by_use.remove(&mark.used);
+        mark.used = self.uses;
+        self.by_use.insert(self.uses, key);
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.by_use.keys().next().copied();
+        if let Some(key) = oldest.and_then(|used| self.by_use.remove(&used)) {
+            self.marks.remove(&key);
+        }
+    }
+
+    /// Reads marks saved by [`ReplayGuard::save`], least recently used first, none if `path`
+    /// doesn't exist yet.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        let mut guard = Self::default();
+        for l in content.lines().filter(|l| !l.trim().is_empty()) {
+            let parse = || -> Option<_> {
+                let mut fields = l.split_whitespace();
+                let peer: PeerId = fields.next()?.parse().ok()?;
+                let kind = fields.next()?.to_string();
+                let mut number = || fields.next()?.parse().ok();
+                let mark = Mark {
+                    epoch: number()?,
+                    highest: number()?,
+                    accepted: number()?,
+                    used: 0,
+                };
+                Some(((peer, kind), mark))
+            };
+            let (key, mark) =
+                parse().with_context(|| format!("Invalid line {:?} in {}", l, path.display()))?;
+            guard.marks.insert(key.clone(), mark);
+            guard.touch(key);
+        }
+        Ok(guard)
+    }
+
+    /// Writes the marks to `path` if they changed, least recently used first, one `<peer> <kind>
+    /// <epoch> <highest> <accepted>` per line.
+    pub(crate) fn save(&mut self, path: &Path) -> anyhow::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        // Unique, as several instances may share the default path.
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        path.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::File::create(&tmp))
+            .and_then(|mut file| {
+                for (peer, kind) in self.by_use.values() {
+                    let mark = &self.marks[&(*peer, kind.clone())];
+                    writeln!(
+                        file,
+                        "{} {} {} {} {}",
+                        peer, kind, mark.epoch, mark.highest, mark.accepted
+                    )?;
+                }
+                file.sync_all()
+            })
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .with_context(|| format!("Writing {}", path.display()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq(epoch: u64, counter: u64) -> Sequence {
+        Sequence { epoch, counter }
+    }
+
+    #[test]
+    fn wraps_and_splits() {
+        let mut sequencer = Sequencer::new(7);
+        let first = Bytes::from(sequencer.wrap(b"hello"));
+        let second = Bytes::from(sequencer.wrap(b"again"));
+        assert_eq!(
+            split(first),
+            (Some(seq(7, 1)), Bytes::from_static(b"hello"))
+        );
+        assert_eq!(
+            split(second),
+            (Some(seq(7, 2)), Bytes::from_static(b"again"))
+        );
+        let plain = Bytes::from_static(b"plain cbor");
+        assert_eq!(split(plain.clone()), (None, plain));
+    }
+
+    #[test]
+    fn drops_replays() {
+        let mut guard = ReplayGuard::default();
+        let peer = PeerId::random();
+        assert!(guard.check(peer, "message", seq(1, 1)));
+        assert!(guard.check(peer, "message", seq(1, 2)));
+        assert!(!guard.check(peer, "message", seq(1, 1)));
+        assert!(!guard.check(peer, "message", seq(1, 2)));
+        // Tracked per peer and kind.
+        assert!(guard.check(peer, "change_nickname", seq(1, 1)));
+        assert!(guard.check(PeerId::random(), "message", seq(1, 1)));
+        assert_eq!(guard.dropped, 2);
+    }
+
+    #[test]
+    fn accepts_reordering_within_the_window() {
+        let mut guard = ReplayGuard::default();
+        let peer = PeerId::random();
+        for counter in [3, 1, 5, 2, 4] {
+            assert!(guard.check(peer, "message", seq(1, counter)), "{}", counter);
+        }
+        for counter in 1..=5 {
+            assert!(
+                !guard.check(peer, "message", seq(1, counter)),
+                "{}",
+                counter
+            );
+        }
+        assert!(guard.check(peer, "message", seq(1, 100)));
+        // Too late.
+        assert!(!guard.check(peer, "message", seq(1, 100 - WINDOW)));
+        assert!(guard.check(peer, "message", seq(1, 100 - WINDOW + 1)));
+        // Far ahead, forgetting the window.
+        assert!(guard.check(peer, "message", seq(1, 1000)));
+        assert!(!guard.check(peer, "message", seq(1, 1000)));
+        assert!(guard.check(peer, "message", seq(1, 999)));
+    }
+
+    #[test]
+    fn epoch_bumps_reset_counters() {
+        let mut guard = ReplayGuard::default();
+        let peer = PeerId::random();
+        assert!(guard.check(peer, "message", seq(1, 50)));
+        // Restarted, counting from 1 again.
+        assert!(guard.check(peer, "message", seq(2, 1)));
+        assert!(guard.check(peer, "message", seq(2, 2)));
+        // The old epoch's messages are replays now.
+        assert!(!guard.check(peer, "message", seq(1, 51)));
+        assert!(!guard.check(peer, "message", seq(2, 1)));
+    }
+
+    #[test]
+    fn persists_marks() {
+        let path = std::env::temp_dir().join(format!(
+            "agora-replay-{}-{}",
+            std::process::id(),
+            PeerId::random()
+        ));
+        let mut guard = ReplayGuard::load(&path).unwrap();
+        let peer = PeerId::random();
+        assert!(guard.check(peer, "message", seq(1, 10)));
+        assert!(guard.check(peer, "message", seq(1, 8)));
+        guard.save(&path).unwrap();
+
+        let mut loaded = ReplayGuard::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.marks.len(), guard.marks.len());
+        assert_eq!(
+            loaded.by_use.values().collect::<Vec<_>>(),
+            guard.by_use.values().collect::<Vec<_>>()
+        );
+        assert!(!loaded.check(peer, "message", seq(1, 8)));
+        assert!(loaded.check(peer, "message", seq(1, 9)));
+    }
+
+    #[test]
+    fn forgets_the_least_recently_used() {
+        let mut guard = ReplayGuard::default();
+        let (old, busy) = (PeerId::random(), PeerId::random());
+        assert!(guard.check(busy, "message", seq(1, 1)));
+        assert!(guard.check(old, "message", seq(2, 1)));
+        for counter in 2..MAX_MARKS as u64 {
+            assert!(guard.check(PeerId::random(), "message", seq(3, 1)));
+            if counter % 1000 == 0 {
+                assert!(guard.check(busy, "message", seq(1, counter)));
+            }
+        }
+        assert_eq!(guard.marks.len(), MAX_MARKS);
+        assert!(guard.check(PeerId::random(), "message", seq(3, 1)));
+        assert_eq!(guard.marks.len(), MAX_MARKS);
+        assert_eq!(guard.by_use.len(), MAX_MARKS);
+        // Of an older epoch, but in use.
+        assert!(!guard.check(busy, "message", seq(1, 1000)));
+        // Forgotten, so accepted again.
+        assert!(guard.check(old, "message", seq(2, 1)));
+    }
+}
@@ -0,0 +1,150 @@
+//! Messages typed while nobody was subscribed to their channel, held in memory and published once
+//! a peer joins it, with their original timestamps. They're lost on exit.
+use std::collections::VecDeque;
+
+use libp2p::gossipsub::TopicHash;
+
+use crate::{
+    api::ChatApi,
+    publish::{publish_chat, Published, Publisher},
+};
+
+/// Messages queued at most, the oldest being dropped beyond.
+pub(crate) const MAX_QUEUED: usize = 32;
+
+#[derive(Debug, Default)]
+pub(crate) struct Retries {
+    queued: VecDeque<(TopicHash, ChatApi)>,
+}
+
+impl Retries {
+    /// Queues `msg`, returning the oldest message, with its topic, if it was dropped for it.
+    pub(crate) fn push(&mut self, topic: TopicHash, msg: ChatApi) -> Option<(TopicHash, ChatApi)> {
+        self.queued.push_back((topic, msg));
+        match self.queued.len() > MAX_QUEUED {
+            true => self.queued.pop_front(),
+            false => None,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    /// The queued messages, oldest first.
+    pub(crate) fn queued(&self) -> impl Iterator<Item = &(TopicHash, ChatApi)> {
+        self.queued.iter()
+    }
+
+    /// Publishes the messages queued for `topic`, in order, returning them with their outcome.
+    /// Once one finds no peers, it and the later ones stay queued.
+    pub(crate) async fn retry(
+        &mut self,
+        publisher: &mut impl Publisher,
+        topic: &TopicHash,
+        encode_threshold: usize,
+    ) -> anyhow::Result<Vec<(ChatApi, Published)>> {
+        let (mut due, rest) = std::mem::take(&mut self.queued)
+            .into_iter()
+            .partition::<VecDeque<_>, _>(|(t, _)| t == topic);
+        self.queued = rest;
+        let mut retried = vec![];
+        while let Some((topic, msg)) = due.pop_front() {
+            match publish_chat(publisher, topic.clone(), msg.clone(), encode_threshold).await? {
+                Published::NoPeers => {
+                    due.push_front((topic, msg));
+                    break;
+                }
+                published => retried.push((msg, published)),
+            }
+        }
+        // Ahead of the other channels' ones, being older than any queued since.
+        due.append(&mut self.queued);
+        self.queued = due;
+        Ok(retried)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::gossipsub::{error::PublishError, IdentTopic, MessageId};
+
+    use super::*;
+
+    /// Fails with `InsufficientPeers` until `peers`.
+    struct Mock {
+        peers: bool,
+        published: Vec<Vec<u8>>,
+    }
+
+    impl Publisher for Mock {
+        fn publish(&mut self, _: TopicHash, data: &[u8]) -> Result<MessageId, PublishError> {
+            if !self.peers {
+                return Err(PublishError::InsufficientPeers);
+            }
+            self.published.push(data.to_vec());
+            Ok(MessageId::new(data))
+        }
+    }
+
+    fn text(msg: &ChatApi) -> &str {
+        match msg {
+            ChatApi::Message { message, .. } => message,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_in_order_once_peers_joined() {
+        let (a, b) = (IdentTopic::new("a").hash(), IdentTopic::new("b").hash());
+        let mut retries = Retries::default();
+        for (topic, message) in [(&a, "1"), (&b, "2"), (&a, "3")] {
+            assert!(retries
+                .push(topic.clone(), ChatApi::message(message.into()))
+                .is_none());
+        }
+        let mut mock = Mock {
+            peers: false,
+            published: vec![],
+        };
+        assert!(retries
+            .retry(&mut mock, &a, usize::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(retries.len(), 3);
+
+        mock.peers = true;
+        let retried = retries.retry(&mut mock, &a, usize::MAX).await.unwrap();
+        assert_eq!(
+            retried
+                .iter()
+                .map(|(msg, published)| (text(msg), *published))
+                .collect::<Vec<_>>(),
+            [
+                ("1", Published::Sent { reach: None }),
+                ("3", Published::Sent { reach: None })
+            ]
+        );
+        assert_eq!(mock.published.len(), 2);
+        assert_eq!(retries.len(), 1);
+    }
+
+    #[test]
+    fn drops_the_oldest_beyond_the_cap() {
+        let topic = IdentTopic::new("a").hash();
+        let mut retries = Retries::default();
+        for i in 0..MAX_QUEUED {
+            assert!(retries
+                .push(topic.clone(), ChatApi::message(i.to_string()))
+                .is_none());
+        }
+        let dropped = retries.push(topic, ChatApi::message("last".into()));
+        assert_eq!(dropped.as_ref().map(|(_, msg)| text(msg)), Some("0"));
+        assert_eq!(retries.len(), MAX_QUEUED);
+    }
+}
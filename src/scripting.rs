@@ -0,0 +1,351 @@
+//! Lua scripts for small automations without recompiling, loaded from `--scripts` (by default
+//! `~/.config/agora/scripts/*.lua`) and reloaded by `/script reload`. Scripts may define
+//!
+//! - `on_message(peer, nick, text)`, returning `nil` to show a message as is, a string to show
+//!   instead, or `false` to not show it at all,
+//! - `on_command(name, args, channel)`, called for the slash commands the script registered with
+//!   `agora.command(name)`,
+//!
+//! and publish with `agora.send(channel, text)`. See `examples/scripts` for some.
+//!
+//! Each script runs in its own interpreter, capped in memory, and every call into it (including
+//! loading it) may run for a limited number of instructions and time, so a buggy script fails
+//! rather than wedging the event loop. A script's first failure is reported, later ones logged.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use libp2p::PeerId;
+use mlua::{FromLuaMulti, Function, HookTriggers, Lua, ToLuaMulti, Value};
+use tracing::*;
+
+use crate::display::DisplayTime;
+
+/// Bytes a script's interpreter may allocate.
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+/// Instructions between checks of the budget.
+const HOOK_EVERY: u32 = 1000;
+/// Budget of a call into a script.
+const MAX_INSTRUCTIONS: u32 = 1_000_000;
+const MAX_TIME: Duration = Duration::from_millis(50);
+
+/// Where scripts are loaded from without `--scripts`: `agora/scripts` in `$XDG_CONFIG_HOME`, or
+/// in `~/.config`.
+pub(crate) fn default_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|config| config.join("agora").join("scripts"))
+}
+
+/// The loaded scripts, in alphabetical order of their file names.
+pub(crate) struct Scripts {
+    dir: PathBuf,
+    scripts: Vec<Script>,
+    /// Channels and texts of `agora.send`, published by the event loop after calling into the
+    /// scripts.
+    outgoing: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl Scripts {
+    /// Loads the `*.lua` files in `dir`, none if it doesn't exist.
+    pub(crate) fn load(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let mut scripts = Self {
+            dir: dir.into(),
+            scripts: Vec::new(),
+            outgoing: Default::default(),
+        };
+        scripts.reload()?;
+        Ok(scripts)
+    }
+
+    /// Loads the scripts anew, returning how many did. Those failing to load are reported and
+    /// skipped.
+    pub(crate) fn reload(&mut self) -> anyhow::Result<usize> {
+        let mut paths = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(error) => Err(error),
+        }
+        .with_context(|| format!("Reading scripts in {}", self.dir.display()))?;
+        paths.retain(|path| path.extension() == Some("lua".as_ref()));
+        paths.sort();
+        self.scripts.clear();
+        for path in paths {
+            let name = path
+                .file_name()
+                .expect("Read from the directory")
+                .to_string_lossy()
+                .into_owned();
+            match fs::read_to_string(&path) {
+                Ok(source) => self.add(name, &source),
+                Err(error) => report(&name, &error),
+            }
+        }
+        Ok(self.scripts.len())
+    }
+
+    fn add(&mut self, name: String, source: &str) {
+        match Script::load(name.clone(), source, self.outgoing.clone()) {
+            Ok(script) => {
+                debug!(script = %name, "Loaded script");
+                self.scripts.push(script);
+            }
+            Err(error) => report(&name, &error),
+        }
+    }
+
+    /// Passes a message through the scripts' `on_message` in turn, returning the text to show, or
+    /// `None` if a script hid it. Failing scripts leave the text as is.
+    pub(crate) fn on_message(&mut self, peer: &PeerId, nick: &str, text: &str) -> Option<String> {
+        let peer = peer.to_base58();
+        let mut text = text.to_string();
+        for script in &mut self.scripts {
+            let verdict = script
+                .call::<Option<Value>>("on_message", (peer.as_str(), nick, text.as_str()))
+                .and_then(|value| match value.flatten() {
+                    None | Some(Value::Boolean(true)) => Ok(None),
+                    Some(Value::Boolean(false)) => Ok(Some(None)),
+                    Some(Value::String(replaced)) => Ok(Some(Some(replaced.to_str()?.to_string()))),
+                    Some(other) => Err(mlua::Error::RuntimeError(format!(
+                        "on_message returned a {}, expected nil, false or a string",
+                        other.type_name()
+                    ))),
+                });
+            match verdict {
+                Ok(None) => {}
+                Ok(Some(None)) => return None,
+                Ok(Some(Some(replaced))) => text = replaced,
+                Err(error) => script.report(&error),
+            }
+        }
+        Some(text)
+    }
+
+    /// Whether a script registered `/name`.
+    pub(crate) fn has_command(&self, name: &str) -> bool {
+        self.scripts.iter().any(|script| script.registered(name))
+    }
+
+    /// Runs `/name args`, entered in `channel`, by the scripts which registered it.
+    pub(crate) fn command(&mut self, name: &str, args: &str, channel: &str) {
+        for script in &mut self.scripts {
+            if !script.registered(name) {
+                continue;
+            }
+            if let Err(error) = script.call::<()>("on_command", (name, args, channel)) {
+                script.report(&error);
+            }
+        }
+    }
+
+    /// The channels and texts sent by scripts since the last call.
+    pub(crate) fn take_outgoing(&mut self) -> Vec<(String, String)> {
+        std::mem::take(&mut *self.outgoing.lock().unwrap())
+    }
+}
+
+fn report(name: &str, error: &dyn std::fmt::Display) {
+    warn!(script = %name, %error, "Script failed");
+    println!("{} Script {} failed: {}", DisplayTime::now(), name, error);
+}
+
+/// Instructions run and time taken by the current call into a script.
+#[derive(Debug)]
+struct Budget {
+    started: Instant,
+    instructions: u32,
+}
+
+impl Budget {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            instructions: 0,
+        }
+    }
+
+    /// Counts another [`HOOK_EVERY`] instructions, failing once beyond the budget.
+    fn spend(&mut self) -> mlua::Result<()> {
+        self.instructions += HOOK_EVERY;
+        if self.instructions > MAX_INSTRUCTIONS || self.started.elapsed() > MAX_TIME {
+            return Err(mlua::Error::RuntimeError(format!(
+                "exceeded the budget of {} instructions or {:?}",
+                MAX_INSTRUCTIONS, MAX_TIME
+            )));
+        }
+        Ok(())
+    }
+}
+
+struct Script {
+    name: String,
+    lua: Lua,
+    budget: Arc<Mutex<Budget>>,
+    /// Registered by `agora.command`.
+    commands: Arc<Mutex<Vec<String>>>,
+    /// Whether a failure was reported already, later ones are only logged.
+    failed: bool,
+}
+
+impl Script {
+    fn load(
+        name: String,
+        source: &str,
+        outgoing: Arc<Mutex<Vec<(String, String)>>>,
+    ) -> mlua::Result<Self> {
+        let lua = Lua::new();
+        lua.set_memory_limit(MEMORY_LIMIT)?;
+        let budget = Arc::new(Mutex::new(Budget::new()));
+        let spent = budget.clone();
+        lua.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(HOOK_EVERY),
+                ..Default::default()
+            },
+            move |_, _| spent.lock().unwrap().spend(),
+        )?;
+
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let registered = commands.clone();
+        let agora = lua.create_table()?;
+        agora.set(
+            "send",
+            lua.create_function(move |_, (channel, text): (String, String)| {
+                outgoing.lock().unwrap().push((channel, text));
+                Ok(())
+            })?,
+        )?;
+        agora.set(
+            "command",
+            lua.create_function(move |_, name: String| {
+                registered.lock().unwrap().push(name);
+                Ok(())
+            })?,
+        )?;
+        lua.globals().set("agora", agora)?;
+        lua.load(source).set_name(&name)?.exec()?;
+
+        Ok(Self {
+            name,
+            lua,
+            budget,
+            commands,
+            failed: false,
+        })
+    }
+
+    fn registered(&self, command: &str) -> bool {
+        self.commands.lock().unwrap().iter().any(|c| c == command)
+    }
+
+    /// Calls the global function `name` within a fresh budget, `None` if it isn't defined.
+    fn call<'lua, R: FromLuaMulti<'lua>>(
+        &'lua self,
+        name: &str,
+        args: impl ToLuaMulti<'lua>,
+    ) -> mlua::Result<Option<R>> {
+        let function = match self.lua.globals().get::<_, Option<Function>>(name)? {
+            Some(function) => function,
+            None => return Ok(None),
+        };
+        *self.budget.lock().unwrap() = Budget::new();
+        function.call(args).map(Some)
+    }
+
+    /// Reports the first failure, logging later ones.
+    fn report(&mut self, error: &mlua::Error) {
+        if std::mem::replace(&mut self.failed, true) {
+            debug!(script = %self.name, %error, "Script failed again");
+        } else {
+            report(&self.name, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scripts(sources: &[(&str, &str)]) -> Scripts {
+        let mut scripts = Scripts::load("/nonexistent").unwrap();
+        for (name, source) in sources {
+            scripts.add(name.to_string(), source);
+        }
+        scripts
+    }
+
+    #[test]
+    fn messages_pass_through_in_turn() {
+        let peer = PeerId::random();
+        let mut scripts = scripts(&[
+            ("a.lua", "function on_message(peer, nick, text) return text .. '!' end"),
+            ("b.lua", "function on_message(peer, nick, text) return nick .. ': ' .. text end"),
+            ("c.lua", "function on_message(peer, nick, text) if text:find('hide') then return false end end"),
+        ]);
+        assert_eq!(
+            scripts.on_message(&peer, "alice", "hi").as_deref(),
+            Some("alice: hi!")
+        );
+        assert_eq!(scripts.on_message(&peer, "alice", "hide me"), None);
+    }
+
+    #[test]
+    fn registers_commands_and_sends() {
+        let mut scripts = scripts(&[(
+            "echo.lua",
+            r#"
+            agora.command("echo")
+            function on_command(name, args, channel)
+                agora.send(channel, name .. " " .. args)
+            end
+            "#,
+        )]);
+        assert!(scripts.has_command("echo"));
+        assert!(!scripts.has_command("quit"));
+        scripts.command("echo", "hello", "agora");
+        assert_eq!(
+            scripts.take_outgoing(),
+            [("agora".to_string(), "echo hello".to_string())]
+        );
+        assert!(scripts.take_outgoing().is_empty());
+    }
+
+    #[test]
+    fn runaway_scripts_fail_within_the_budget() {
+        let peer = PeerId::random();
+        let mut scripts = scripts(&[
+            ("loop.lua", "function on_message() while true do end end"),
+            (
+                "hog.lua",
+                "function on_message() local t = {} for i = 1, 1e9 do t[i] = i end end",
+            ),
+            ("broken.lua", "function on_message( end"),
+        ]);
+        assert_eq!(scripts.scripts.len(), 2, "Broken script not loaded");
+        let start = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(
+                scripts.on_message(&peer, "alice", "hi").as_deref(),
+                Some("hi")
+            );
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(scripts.scripts.iter().all(|script| script.failed));
+    }
+
+    #[test]
+    fn examples_load() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/scripts");
+        let scripts = Scripts::load(dir).unwrap();
+        assert_eq!(scripts.scripts.len(), 2);
+        assert!(scripts.scripts.iter().all(|script| !script.failed));
+        assert!(scripts.has_command("roll"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::Notify, task::JoinSet};
+
+/// Time given to tasks to observe the shutdown flag before they are awaited.
+const GRACE: Duration = Duration::from_millis(500);
+/// Tasks still running after this are aborted.
+const DEADLINE: Duration = Duration::from_secs(2);
+
+/// Set once the node shuts down. Spawned tasks select on [`Flag::wait`] alongside what they're
+/// waiting for, and return once it's set.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Flag(Arc<FlagState>);
+
+#[derive(Debug, Default)]
+struct FlagState {
+    set: AtomicBool,
+    notify: Notify,
+}
+
+impl Flag {
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.set.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the flag is set.
+    pub(crate) async fn wait(&self) {
+        loop {
+            // Created before checking, so a concurrent `set` isn't missed.
+            let notified = self.0.notify.notified();
+            if self.is_set() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn set(&self) {
+        self.0.set.store(true, Ordering::Release);
+        self.0.notify.notify_waiters();
+    }
+}
+
+/// Tasks spawned by the node's components, stopped by [`Tasks::shutdown`].
+pub(crate) struct Tasks {
+    flag: Flag,
+    set: JoinSet<()>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Self {
+            flag: Default::default(),
+            set: JoinSet::new(),
+        }
+    }
+}
+
+impl Tasks {
+    pub(crate) fn spawn<F>(&mut self, task: impl FnOnce(Flag) -> F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.set.spawn(task(self.flag.clone()));
+    }
+
+    /// Sets the flag and waits for the tasks to finish, aborting the ones exceeding [`DEADLINE`].
+    pub(crate) async fn shutdown(mut self) {
+        self.flag.set();
+        tokio::time::sleep(GRACE).await;
+        let all = async { while self.set.join_next().await.is_some() {} };
+        if tokio::time::timeout(DEADLINE, all).await.is_err() {
+            tracing::warn!(
+                tasks = self.set.len(),
+                "Aborting tasks not shutting down in time"
+            );
+        }
+    }
+}
+
+/// Why the main loop stopped.
+#[derive(Debug)]
+pub(crate) enum ShutdownReason {
+    /// Ctrl-C
+    Interrupted,
+    /// SIGTERM
+    Terminated,
+    /// `/quit`
+    Quit,
+    /// End of input
+    StdinClosed,
+    /// No input within `--stdin-timeout`
+    StdinIdle,
+    /// A one-shot command completed
+    Done,
+    /// `--profile-cpu` elapsed
+    #[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+    Profiled,
+    Fatal(anyhow::Error),
+}
+
+impl ShutdownReason {
+    /// 0 for user-requested exits, non-zero for fatal ones.
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            Self::Interrupted
+            | Self::Terminated
+            | Self::Quit
+            | Self::StdinClosed
+            | Self::StdinIdle
+            | Self::Done
+            | Self::Profiled => 0,
+            Self::Fatal(_) => 1,
+        }
+    }
+}
+
+/// Resolves on SIGTERM, never on platforms without it.
+pub(crate) async fn terminate() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+                return;
+            }
+            Err(error) => tracing::warn!(%error, "Can't listen for SIGTERM"),
+        }
+    }
+    std::future::pending().await
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Interrupted => write!(f, "interrupted"),
+            Self::Terminated => write!(f, "terminated"),
+            Self::Quit => write!(f, "quit"),
+            Self::StdinClosed => write!(f, "stdin closed"),
+            Self::StdinIdle => write!(f, "stdin idle"),
+            Self::Done => write!(f, "done"),
+            Self::Profiled => write!(f, "profiled"),
+            Self::Fatal(e) => write!(f, "fatal error: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn tasks_observe_the_flag() {
+        let mut tasks = Tasks::default();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tasks.spawn(|shutdown| async move {
+            while !shutdown.is_set() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tx.send("cleaned up").unwrap();
+        });
+        tasks.shutdown().await;
+        assert_eq!(rx.await.unwrap(), "cleaned up");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_tasks_wake_up() {
+        let mut tasks = Tasks::default();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tasks.spawn(|shutdown| async move {
+            tokio::select! {
+                _ = shutdown.wait() => tx.send("woken").unwrap(),
+                _ = std::future::pending::<()>() => {}
+            }
+        });
+        let start = tokio::time::Instant::now();
+        tasks.shutdown().await;
+        assert_eq!(rx.await.unwrap(), "woken");
+        // Not aborted, but woken up within the grace period.
+        assert!(start.elapsed() < GRACE + DEADLINE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stuck_tasks_are_aborted() {
+        let mut tasks = Tasks::default();
+        let resource = Arc::new(());
+        let held = resource.clone();
+        tasks.spawn(|_| async move {
+            let _held = held;
+            std::future::pending::<()>().await
+        });
+        let start = tokio::time::Instant::now();
+        tasks.shutdown().await;
+        assert!(start.elapsed() <= GRACE + DEADLINE);
+        // The aborted task released what it held.
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(Arc::strong_count(&resource), 1);
+    }
+}
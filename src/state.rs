@@ -0,0 +1,1120 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use libp2p::{core::ConnectedPoint, gossipsub::TopicHash, identity::PublicKey, Multiaddr, PeerId};
+use serde::Serialize;
+
+use crate::{
+    api::GROUP_KEY_LEN,
+    peers::{Peers, Renamed},
+};
+
+/// Upper bound of entries examined per [`State::gc`] call.
+const GC_BUDGET: usize = 1024;
+/// Addresses remembered per peer, the most recent ones.
+const MAX_ADDRESSES: usize = 4;
+/// Minimum time between `/reconnect`s, and between redials of `--peer`s.
+pub(crate) const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+/// Characters of a hashed topic labeling a channel of unknown name.
+const HASH_LABEL_LEN: usize = 8;
+
+/// Addresses per peer, shared with the swarm to dial peers by id.
+pub(crate) type SharedAddresses = Arc<RwLock<HashMap<PeerId, Vec<Multiaddr>>>>;
+/// Keys nicknames are pinned to, shared with the swarm checking them.
+pub(crate) type PinnedKeys = Arc<RwLock<BTreeMap<String, PublicKey>>>;
+
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    pub(crate) connected_peers: HashSet<PeerId>,
+    /// Endpoints of the connections to the connected peers, several with simultaneous dials or
+    /// multiple transports.
+    connections: HashMap<PeerId, Vec<ConnectedPoint>>,
+    /// Nicknames and capabilities of peers.
+    pub(crate) peers: Peers,
+    /// When peers were last connected or heard from.
+    last_seen: HashMap<PeerId, Instant>,
+    /// Base58 renderings of the peers in `last_seen`, their nickname until they announce one.
+    peer_ids: HashMap<PeerId, String>,
+    /// Every peer in `last_seen` once, roughly ordered by when it expires, so [`State::gc`] only
+    /// looks at candidates for eviction.
+    expiry: VecDeque<(Instant, PeerId)>,
+    /// Addresses peers were successfully dialed at, to redial them on `/reconnect`.
+    pub(crate) peer_addresses: SharedAddresses,
+    pub(crate) last_reconnect_attempt: Option<Instant>,
+    /// Names of joined channels by topic, needed for private (hashed) topics.
+    pub(crate) channel_names: HashMap<TopicHash, String>,
+    /// Whether topics are hashes of the channel names (`--private-topic`), rather than the names.
+    pub(crate) hashed_topics: bool,
+    /// Peers verified with `/verify`, with their nickname at the time.
+    pub(crate) verified: HashMap<PeerId, String>,
+    /// Nicknames only accepted from the peer with the given key, from `--pinned-keys-file` or
+    /// pinned on first use (`--validation-mode author`).
+    pub(crate) pinned_keys: PinnedKeys,
+    /// Peers blocked with `/block`, whose messages are neither shown nor relayed.
+    pub(crate) blocked_peers: HashSet<PeerId>,
+    /// Dials which failed for reaching ourselves.
+    pub(crate) self_dial_attempts: u32,
+    /// Events whose handling failed, which were logged and skipped.
+    pub(crate) failed_events: u64,
+    /// Messages too old to be rendered live, per signing peer, see `display::Staleness`.
+    pub(crate) stale_dropped: HashMap<PeerId, u64>,
+    /// Including the unsigned ones.
+    pub(crate) stale_dropped_total: u64,
+    /// Owners of moderated channels, as configured. Only they may kick and mute.
+    pub(crate) channel_owners: HashMap<TopicHash, PeerId>,
+    /// Moderated channels to leave when kicked.
+    pub(crate) auto_part: HashSet<TopicHash>,
+    /// Peers whose messages are hidden per channel, until then or for good if kicked.
+    hidden: HashMap<(TopicHash, PeerId), Option<Instant>>,
+    /// Whether to take part in group key exchanges (`--e2e-encrypt`).
+    pub(crate) e2e_encrypt: bool,
+    /// The latest group key exchange, if any was started or joined.
+    pub(crate) key_exchange: Option<KeyExchangeState>,
+    /// Connected peers per topic they're subscribed to, ours or not.
+    members: HashMap<TopicHash, HashSet<PeerId>>,
+    /// Connected peers which left all channels shared with us, whose disconnection then goes
+    /// unmentioned.
+    parted: HashSet<PeerId>,
+}
+
+/// What [`State`] knows, for diagnostics. Times are in seconds before the snapshot was taken.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Snapshot {
+    pub(crate) connected_peers: BTreeSet<String>,
+    pub(crate) known_nicknames: BTreeMap<String, String>,
+    pub(crate) last_seen_secs_ago: BTreeMap<String, u64>,
+    pub(crate) peer_addresses: BTreeMap<String, Vec<String>>,
+    pub(crate) last_reconnect_attempt_secs_ago: Option<u64>,
+    pub(crate) channel_names: BTreeMap<String, String>,
+    pub(crate) verified: BTreeMap<String, String>,
+    pub(crate) pinned_keys: BTreeSet<String>,
+    pub(crate) blocked_peers: BTreeSet<String>,
+    pub(crate) peer_capabilities: BTreeMap<String, BTreeSet<String>>,
+    pub(crate) self_dial_attempts: u32,
+    pub(crate) failed_events: u64,
+    pub(crate) stale_dropped: BTreeMap<String, u64>,
+    pub(crate) channel_owners: BTreeMap<String, String>,
+    pub(crate) pending_renames: BTreeMap<String, String>,
+}
+
+/// Our part of a group key exchange round (`--e2e-encrypt`): every participant publishes an
+/// X25519 public key for the round, and it's complete once all of them did. The next round,
+/// started when peers join, supersedes it. Rounds wrap around after `u32::MAX`.
+pub(crate) struct KeyExchangeState {
+    pub(crate) round: u32,
+    pub(crate) participants: BTreeSet<PeerId>,
+    /// The public keys received for the round, ours included.
+    pub(crate) keys: BTreeMap<PeerId, [u8; GROUP_KEY_LEN]>,
+    // TODO: Derive the group key from it and the participants' keys once complete, and seal the
+    // channel's messages with that.
+    #[allow(dead_code)]
+    secret: x25519_dalek::StaticSecret,
+}
+
+impl std::fmt::Debug for KeyExchangeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyExchangeState")
+            .field("round", &self.round)
+            .field("participants", &self.participants)
+            .field("keys", &self.keys.keys())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Our public key for a round, to be published as `ChatApi::GroupKeyExchange`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KeyExchangeOffer {
+    pub(crate) round: u32,
+    pub(crate) public_key: [u8; GROUP_KEY_LEN],
+    pub(crate) participants: Vec<PeerId>,
+}
+
+/// Why a group key exchange message is ignored.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum KeyExchangeError {
+    #[error("Participant {0} isn't a peer id")]
+    InvalidParticipant(String),
+    #[error("Participant {0} isn't connected")]
+    NotConnected(PeerId),
+    #[error("The sender isn't a participant")]
+    SenderNotParticipant,
+    #[error("We aren't a participant")]
+    NotIncluded,
+    #[error("Public key of {0} bytes")]
+    InvalidKey(usize),
+    #[error("Round {round} doesn't follow round {current}")]
+    UnexpectedRound { round: u32, current: u32 },
+    #[error("The round was started with other participants, which take precedence")]
+    ParticipantsMismatch,
+    #[error("Another key for the round was published already")]
+    ConflictingKey,
+}
+
+impl KeyExchangeState {
+    fn new(round: u32, participants: BTreeSet<PeerId>, local: PeerId) -> Self {
+        let mut secret = [0; GROUP_KEY_LEN];
+        OsRng.fill_bytes(&mut secret);
+        let secret = x25519_dalek::StaticSecret::from(secret);
+        let keys = BTreeMap::from([(local, x25519_dalek::PublicKey::from(&secret).to_bytes())]);
+        Self {
+            round,
+            participants,
+            keys,
+            secret,
+        }
+    }
+
+    fn offer(&self, local: &PeerId) -> KeyExchangeOffer {
+        KeyExchangeOffer {
+            round: self.round,
+            public_key: self.keys[local],
+            participants: self.participants.iter().copied().collect(),
+        }
+    }
+
+    /// Whether all participants' keys were received.
+    pub(crate) fn is_complete(&self) -> bool {
+        self.keys.len() == self.participants.len()
+    }
+
+    /// Starts the round after the current one (if any) with `peers`, superseding it.
+    pub(crate) fn start(
+        exchange: &mut Option<Self>,
+        local: PeerId,
+        peers: impl IntoIterator<Item = PeerId>,
+    ) -> KeyExchangeOffer {
+        let round = exchange.as_ref().map_or(0, |e| e.round.wrapping_add(1));
+        let participants = peers.into_iter().chain([local]).collect();
+        exchange
+            .insert(Self::new(round, participants, local))
+            .offer(&local)
+    }
+
+    /// Takes in `from`'s key for `round`, joining the round if it's the next one: our offer is then
+    /// returned, to be published. All `participants` must be connected.
+    ///
+    /// Peers starting the same round concurrently may do so with different participants. Of
+    /// those, the lowest set (compared by peer id) wins on every peer, and the others' keys are
+    /// ignored.
+    pub(crate) fn receive(
+        exchange: &mut Option<Self>,
+        local: PeerId,
+        connected: &HashSet<PeerId>,
+        from: PeerId,
+        public_key: &[u8],
+        round: u32,
+        participants: &[String],
+    ) -> Result<Option<KeyExchangeOffer>, KeyExchangeError> {
+        let public_key: [u8; GROUP_KEY_LEN] = public_key
+            .try_into()
+            .map_err(|_| KeyExchangeError::InvalidKey(public_key.len()))?;
+        let participants = participants
+            .iter()
+            .map(|p| {
+                let peer = p
+                    .parse::<PeerId>()
+                    .map_err(|_| KeyExchangeError::InvalidParticipant(p.clone()))?;
+                match peer == local || connected.contains(&peer) {
+                    true => Ok(peer),
+                    false => Err(KeyExchangeError::NotConnected(peer)),
+                }
+            })
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        if !participants.contains(&from) {
+            return Err(KeyExchangeError::SenderNotParticipant);
+        }
+        let join = match exchange {
+            None => true,
+            Some(current) if round == current.round => {
+                match participants.cmp(&current.participants) {
+                    std::cmp::Ordering::Equal => false,
+                    std::cmp::Ordering::Less => true,
+                    std::cmp::Ordering::Greater => {
+                        return Err(KeyExchangeError::ParticipantsMismatch)
+                    }
+                }
+            }
+            Some(current) if round == current.round.wrapping_add(1) => true,
+            Some(current) => {
+                return Err(KeyExchangeError::UnexpectedRound {
+                    round,
+                    current: current.round,
+                })
+            }
+        };
+        let mut offer = None;
+        if join {
+            if !participants.contains(&local) {
+                return Err(KeyExchangeError::NotIncluded);
+            }
+            let joined = exchange.insert(Self::new(round, participants, local));
+            offer = Some(joined.offer(&local));
+        }
+        let current = exchange.as_mut().expect("Set above");
+        match current.keys.get(&from) {
+            Some(key) if *key != public_key => return Err(KeyExchangeError::ConflictingKey),
+            _ => current.keys.insert(from, public_key),
+        };
+        Ok(offer)
+    }
+}
+
+/// Why a moderation message is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ModerationRejected {
+    /// The channel has no owner configured.
+    NotModerated,
+    /// Sent by someone other than the channel's owner.
+    NotOwner,
+}
+
+impl State {
+    pub(crate) fn nick(&self, peer: &PeerId) -> String {
+        self.peers
+            .known_nicknames
+            .get(peer)
+            .or_else(|| self.peer_ids.get(peer))
+            .cloned()
+            .unwrap_or_else(|| peer.to_string())
+    }
+
+    /// Like [`State::nick`], marked if the peer is verified.
+    pub(crate) fn display_nick(&self, peer: &PeerId) -> String {
+        match self.verified.contains_key(peer) {
+            true => format!("{} ✔", self.nick(peer)),
+            false => self.nick(peer),
+        }
+    }
+
+    /// A verified peer other than `peer` known by `nick`.
+    pub(crate) fn impersonated(&self, peer: &PeerId, nick: &str) -> Option<PeerId> {
+        self.verified
+            .iter()
+            .find(|(verified, verified_nick)| *verified != peer && *verified_nick == nick)
+            .map(|(verified, _)| *verified)
+    }
+
+    /// See [`Peers::rename`].
+    pub(crate) fn rename(&mut self, peer: PeerId, nick: String, now: Instant) -> Option<Renamed> {
+        self.peers.rename(peer, nick, now)
+    }
+
+    /// See [`Peers::rename_deferred`].
+    pub(crate) fn rename_deferred(&self, peer: &PeerId) -> bool {
+        self.peers.rename_deferred(peer)
+    }
+
+    /// See [`Peers::apply_pending_renames`].
+    pub(crate) fn apply_pending_renames(&mut self, now: Instant) -> Vec<(PeerId, Renamed)> {
+        self.peers.apply_pending_renames(now)
+    }
+
+    pub(crate) fn snapshot(&self, now: Instant) -> Snapshot {
+        let ago = |at: &Instant| now.saturating_duration_since(*at).as_secs();
+        Snapshot {
+            connected_peers: self.connected_peers.iter().map(|p| p.to_string()).collect(),
+            known_nicknames: self
+                .peers
+                .known_nicknames
+                .iter()
+                .map(|(p, nick)| (p.to_string(), nick.clone()))
+                .collect(),
+            last_seen_secs_ago: self
+                .last_seen
+                .iter()
+                .map(|(p, at)| (p.to_string(), ago(at)))
+                .collect(),
+            peer_addresses: self
+                .peer_addresses
+                .read()
+                .expect("Not poisoned")
+                .iter()
+                .map(|(p, addrs)| (p.to_string(), addrs.iter().map(|a| a.to_string()).collect()))
+                .collect(),
+            last_reconnect_attempt_secs_ago: self.last_reconnect_attempt.as_ref().map(ago),
+            channel_names: self
+                .channel_names
+                .iter()
+                .map(|(topic, name)| (topic.to_string(), name.clone()))
+                .collect(),
+            verified: self
+                .verified
+                .iter()
+                .map(|(p, nick)| (p.to_string(), nick.clone()))
+                .collect(),
+            pinned_keys: self
+                .pinned_keys
+                .read()
+                .expect("Not poisoned")
+                .keys()
+                .cloned()
+                .collect(),
+            blocked_peers: self.blocked_peers.iter().map(|p| p.to_string()).collect(),
+            peer_capabilities: self
+                .peers
+                .capabilities
+                .iter()
+                .map(|(p, capabilities)| (p.to_string(), capabilities.clone()))
+                .collect(),
+            self_dial_attempts: self.self_dial_attempts,
+            failed_events: self.failed_events,
+            stale_dropped: self
+                .stale_dropped
+                .iter()
+                .map(|(p, n)| (p.to_string(), *n))
+                .collect(),
+            channel_owners: self
+                .channel_owners
+                .iter()
+                .map(|(topic, owner)| (topic.to_string(), owner.to_string()))
+                .collect(),
+            pending_renames: self
+                .peers
+                .pending_renames()
+                .map(|(p, nick)| (p.to_string(), nick.to_owned()))
+                .collect(),
+        }
+    }
+
+    /// See [`Peers::set_capabilities`].
+    pub(crate) fn set_capabilities(&mut self, peer: PeerId, supported: Vec<String>) {
+        self.peers.set_capabilities(peer, supported)
+    }
+
+    /// See [`Peers::supports`].
+    pub(crate) fn supports(&self, peer: &PeerId, capability: &str) -> bool {
+        self.peers.supports(peer, capability)
+    }
+
+    /// Hides the messages of `target` in the channel of `topic` until `until`, or for good, if
+    /// `from` owns the channel.
+    pub(crate) fn moderate(
+        &mut self,
+        topic: &TopicHash,
+        from: &PeerId,
+        target: PeerId,
+        until: Option<Instant>,
+        now: Instant,
+    ) -> Result<(), ModerationRejected> {
+        match self.channel_owners.get(topic) {
+            None => Err(ModerationRejected::NotModerated),
+            Some(owner) if owner != from => Err(ModerationRejected::NotOwner),
+            Some(_) => {
+                self.hidden
+                    .retain(|_, until| until.map_or(true, |until| now < until));
+                self.hidden.insert((topic.clone(), target), until);
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether `peer` is blocked, or kicked or muted in the channel of `topic`.
+    pub(crate) fn is_hidden(&self, topic: &TopicHash, peer: &PeerId, now: Instant) -> bool {
+        self.blocked_peers.contains(peer)
+            || self
+                .hidden
+                .get(&(topic.clone(), *peer))
+                .map_or(false, |until| until.map_or(true, |until| now < until))
+    }
+
+    /// Name of the channel of `topic`. Public channels' topics are their names. Hashed topics of
+    /// unknown names, e.g. subscribed to by hash, are labeled `#<hash prefix>` rather than being
+    /// taken for a name.
+    pub(crate) fn channel<'a>(&'a self, topic: &'a TopicHash) -> Cow<'a, str> {
+        match self.channel_names.get(topic) {
+            Some(name) => Cow::Borrowed(name),
+            None if self.hashed_topics => Cow::Owned(format!(
+                "#{}",
+                topic
+                    .as_str()
+                    .chars()
+                    .take(HASH_LABEL_LEN)
+                    .collect::<String>()
+            )),
+            None => Cow::Borrowed(topic.as_str()),
+        }
+    }
+
+    /// Resolves `@<peer id prefix>` against connected peers, anything else against nicknames.
+    pub(crate) fn resolve(&self, to: &str) -> Vec<PeerId> {
+        let mut peers = match to.strip_prefix('@') {
+            Some(prefix) => self
+                .connected_peers
+                .iter()
+                .filter(|p| p.to_base58().starts_with(prefix))
+                .copied()
+                .collect::<Vec<_>>(),
+            None => self
+                .peers
+                .known_nicknames
+                .iter()
+                .filter(|(_, nick)| *nick == to)
+                .map(|(peer, _)| *peer)
+                .collect(),
+        };
+        peers.sort_unstable();
+        peers
+    }
+
+    /// Records activity of `peer`, which defers its eviction by [`State::gc`].
+    pub(crate) fn seen(&mut self, peer: PeerId, now: Instant) {
+        if self.last_seen.insert(peer, now).is_none() {
+            self.expiry.push_back((now, peer));
+            self.peer_ids.insert(peer, peer.to_base58());
+        }
+    }
+
+    /// Records a connection to `peer`, returning whether it's the first one.
+    pub(crate) fn connection_established(
+        &mut self,
+        peer: PeerId,
+        endpoint: ConnectedPoint,
+        now: Instant,
+    ) -> bool {
+        self.seen(peer, now);
+        self.connections.entry(peer).or_default().push(endpoint);
+        self.connected_peers.insert(peer)
+    }
+
+    /// Records a connection to `peer` closing, returning whether it was the last one, after which
+    /// [`State::disconnected`] is due.
+    pub(crate) fn connection_closed(&mut self, peer: PeerId, endpoint: &ConnectedPoint) -> bool {
+        let connections = match self.connections.get_mut(&peer) {
+            Some(connections) => connections,
+            None => return false,
+        };
+        if let Some(i) = connections.iter().position(|e| e == endpoint) {
+            connections.swap_remove(i);
+        }
+        if !connections.is_empty() {
+            return false;
+        }
+        self.connections.remove(&peer);
+        true
+    }
+
+    /// Endpoints of the connections to `peer`.
+    pub(crate) fn connections(&self, peer: &PeerId) -> &[ConnectedPoint] {
+        self.connections.get(peer).map_or(&[], Vec::as_slice)
+    }
+
+    /// Forgets `peer`'s channels, returning whether to mention the disconnection, i.e. it hadn't
+    /// left all channels shared with us already.
+    pub(crate) fn disconnected(&mut self, peer: PeerId, now: Instant) -> bool {
+        if self.connected_peers.remove(&peer) {
+            self.seen(peer, now);
+        }
+        self.connections.remove(&peer);
+        self.members.retain(|_, members| {
+            members.remove(&peer);
+            !members.is_empty()
+        });
+        !self.parted.remove(&peer)
+    }
+
+    /// Records `peer` joining `topic`.
+    pub(crate) fn subscribed(&mut self, peer: PeerId, topic: TopicHash) {
+        self.parted.remove(&peer);
+        self.members.entry(topic).or_default().insert(peer);
+    }
+
+    /// Records `peer` leaving `topic`, returning whether it was a member. Once it left the last of
+    /// `ours` it was in, its disconnection goes unmentioned.
+    pub(crate) fn unsubscribed(
+        &mut self,
+        peer: PeerId,
+        topic: &TopicHash,
+        ours: &[&TopicHash],
+    ) -> bool {
+        let left = match self.members.get_mut(topic) {
+            Some(members) => {
+                let left = members.remove(&peer);
+                if members.is_empty() {
+                    self.members.remove(topic);
+                }
+                left
+            }
+            None => false,
+        };
+        if left && ours.contains(&topic) && !ours.iter().any(|t| self.is_member(t, &peer)) {
+            self.parted.insert(peer);
+        }
+        left
+    }
+
+    /// Whether `peer` is subscribed to `topic`.
+    pub(crate) fn is_member(&self, topic: &TopicHash, peer: &PeerId) -> bool {
+        self.members
+            .get(topic)
+            .map_or(false, |members| members.contains(peer))
+    }
+
+    /// Remembers that `peer` was reachable at `address`.
+    pub(crate) fn add_address(&mut self, peer: PeerId, address: Multiaddr) {
+        let mut peer_addresses = self.peer_addresses.write().expect("Not poisoned");
+        let addresses = peer_addresses.entry(peer).or_default();
+        addresses.retain(|a| *a != address);
+        if addresses.len() >= MAX_ADDRESSES {
+            addresses.remove(0);
+        }
+        addresses.push(address);
+    }
+
+    /// Known peers to redial, i.e. `only` or all of them, leaving out connected ones. Fails with
+    /// the time left to wait if called again within [`RECONNECT_INTERVAL`].
+    pub(crate) fn reconnect_targets(
+        &mut self,
+        only: Option<PeerId>,
+        now: Instant,
+    ) -> Result<Vec<(PeerId, Vec<Multiaddr>)>, Duration> {
+        self.attempt_reconnect(now)?;
+        Ok(self.known_addresses(|peer| {
+            only.map_or(true, |only| only == *peer) && !self.connected_peers.contains(peer)
+        }))
+    }
+
+    /// All known peers, connected or not, to redial after resetting the connections. Rate
+    /// limited along with [`State::reconnect_targets`].
+    pub(crate) fn reset_targets(
+        &mut self,
+        now: Instant,
+    ) -> Result<Vec<(PeerId, Vec<Multiaddr>)>, Duration> {
+        self.attempt_reconnect(now)?;
+        Ok(self.known_addresses(|_| true))
+    }
+
+    fn attempt_reconnect(&mut self, now: Instant) -> Result<(), Duration> {
+        if let Some(last) = self.last_reconnect_attempt {
+            let elapsed = now.duration_since(last);
+            if elapsed < RECONNECT_INTERVAL {
+                return Err(RECONNECT_INTERVAL - elapsed);
+            }
+        }
+        self.last_reconnect_attempt = Some(now);
+        Ok(())
+    }
+
+    /// Addresses of the known peers matching `filter`, ordered by peer.
+    fn known_addresses(&self, filter: impl Fn(&PeerId) -> bool) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut targets = self
+            .peer_addresses
+            .read()
+            .expect("Not poisoned")
+            .iter()
+            .filter(|(peer, _)| filter(peer))
+            .map(|(peer, addresses)| (*peer, addresses.clone()))
+            .collect::<Vec<_>>();
+        targets.sort_unstable_by_key(|(peer, _)| *peer);
+        targets
+    }
+
+    /// Counts a message not rendered for being stale, by `peer` if signed.
+    pub(crate) fn dropped_stale(&mut self, peer: Option<PeerId>) {
+        self.stale_dropped_total += 1;
+        if let Some(peer) = peer {
+            *self.stale_dropped.entry(peer).or_default() += 1;
+        }
+    }
+
+    /// Forgets peers which are not connected and haven't been seen within `max_age`, examining at
+    /// most [`GC_BUDGET`] entries. Returns the number of evicted peers.
+    pub(crate) fn gc(&mut self, now: Instant, max_age: Duration) -> usize {
+        let mut evicted = 0;
+        for _ in 0..GC_BUDGET {
+            match self.expiry.front() {
+                Some((at, _)) if now.duration_since(*at) >= max_age => {}
+                _ => break,
+            }
+            let (at, peer) = self.expiry.pop_front().expect("checked above");
+            let last_seen = self.last_seen.get(&peer).copied().unwrap_or(at);
+            if self.connected_peers.contains(&peer) {
+                self.expiry.push_back((now, peer));
+            } else if now.duration_since(last_seen) < max_age {
+                self.expiry.push_back((last_seen, peer));
+            } else {
+                self.last_seen.remove(&peer);
+                self.peer_ids.remove(&peer);
+                self.peers.remove(&peer);
+                self.peer_addresses
+                    .write()
+                    .expect("Not poisoned")
+                    .remove(&peer);
+                self.stale_dropped.remove(&peer);
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn gc_evicts_exactly_the_stale_peers() {
+        let start = Instant::now();
+        let mut state = State::default();
+        let [connected, recent, stale, relayed] = [(); 4].map(|_| PeerId::random());
+        for peer in [connected, recent, stale, relayed] {
+            state.peers.known_nicknames.insert(peer, "nick".into());
+            state.seen(peer, start);
+        }
+        for peer in [connected, recent, stale] {
+            state.connected_peers.insert(peer);
+        }
+        state.disconnected(stale, start);
+        state.disconnected(recent, start + MAX_AGE);
+
+        // Nothing is old enough yet.
+        assert_eq!(state.gc(start + MAX_AGE / 2, MAX_AGE), 0);
+
+        assert_eq!(state.gc(start + MAX_AGE, MAX_AGE), 2);
+        assert!(state.peers.known_nicknames.contains_key(&connected));
+        assert!(state.peers.known_nicknames.contains_key(&recent));
+        assert!(!state.peers.known_nicknames.contains_key(&stale));
+        assert!(!state.peers.known_nicknames.contains_key(&relayed));
+
+        // Eventually `recent` goes as well, the connected peer never.
+        assert_eq!(state.gc(start + 3 * MAX_AGE, MAX_AGE), 1);
+        assert_eq!(
+            state.peers.known_nicknames.keys().collect::<Vec<_>>(),
+            vec![&connected]
+        );
+    }
+
+    #[test]
+    fn verified_peers() {
+        let mut state = State::default();
+        let [alice, mallory] = [(); 2].map(|_| PeerId::random());
+        for peer in [alice, mallory] {
+            state.peers.known_nicknames.insert(peer, "alice".into());
+        }
+        assert_eq!(state.display_nick(&alice), "alice");
+        assert_eq!(state.impersonated(&mallory, "alice"), None);
+
+        state.verified.insert(alice, "alice".into());
+        assert_eq!(state.display_nick(&alice), "alice ✔");
+        assert_eq!(state.display_nick(&mallory), "alice");
+        assert_eq!(state.impersonated(&mallory, "alice"), Some(alice));
+        assert_eq!(state.impersonated(&alice, "alice"), None);
+        assert_eq!(state.impersonated(&mallory, "bob"), None);
+    }
+
+    #[test]
+    fn snapshots_serialize() {
+        let start = Instant::now();
+        let mut state = State::default();
+        let [alice, bob] = [(); 2].map(|_| PeerId::random());
+        state.connected_peers.insert(alice);
+        state.peers.known_nicknames.insert(alice, "alice".into());
+        state.seen(bob, start);
+        state.disconnected(bob, start);
+        state.add_address(bob, addr(1));
+
+        let json = serde_json::to_value(state.snapshot(start + Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            json["connected_peers"],
+            serde_json::json!([alice.to_string()])
+        );
+        assert_eq!(json["known_nicknames"][alice.to_string()], "alice");
+        assert_eq!(json["last_seen_secs_ago"][bob.to_string()], 5);
+        assert_eq!(
+            json["peer_addresses"][bob.to_string()],
+            serde_json::json!(["/ip4/127.0.0.1/tcp/1"])
+        );
+    }
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn remembers_recent_addresses() {
+        let mut state = State::default();
+        let peer = PeerId::random();
+        for port in [1, 2, 1, 3, 4, 5] {
+            state.add_address(peer, addr(port));
+        }
+        assert_eq!(
+            state.peer_addresses.read().unwrap()[&peer],
+            vec![addr(2), addr(3), addr(4), addr(5)]
+        );
+    }
+
+    #[test]
+    fn counts_connections_per_peer() {
+        let now = Instant::now();
+        let mut state = State::default();
+        let peer = PeerId::random();
+        let dialed = ConnectedPoint::Dialer {
+            address: "/ip4/10.0.0.1/tcp/4001".parse().unwrap(),
+            role_override: libp2p::core::Endpoint::Dialer,
+        };
+        let accepted = ConnectedPoint::Listener {
+            local_addr: "/ip4/10.0.0.2/tcp/4001".parse().unwrap(),
+            send_back_addr: "/ip4/10.0.0.1/tcp/50000".parse().unwrap(),
+        };
+        assert!(state.connection_established(peer, dialed.clone(), now));
+        assert!(!state.connection_established(peer, accepted.clone(), now));
+        assert_eq!(state.connections(&peer), [dialed.clone(), accepted.clone()]);
+
+        assert!(!state.connection_closed(peer, &accepted));
+        assert_eq!(state.connections(&peer), [dialed.clone()]);
+        assert!(state.connected_peers.contains(&peer));
+        assert!(state.connection_closed(peer, &dialed));
+        assert!(state.disconnected(peer, now));
+        assert!(state.connections(&peer).is_empty());
+        assert!(!state.connected_peers.contains(&peer));
+        // Closing connections not seen established is no disconnection.
+        assert!(!state.connection_closed(peer, &dialed));
+    }
+
+    #[test]
+    fn reconnects_all_disconnected_peers() {
+        let start = Instant::now();
+        let mut state = State::default();
+        let mut peers = [(); 3].map(|_| PeerId::random());
+        peers.sort_unstable();
+        for (port, peer) in peers.iter().enumerate() {
+            state.add_address(*peer, addr(port as u16));
+        }
+        state.connected_peers.insert(peers[1]);
+
+        assert_eq!(
+            state.reconnect_targets(None, start).unwrap(),
+            vec![(peers[0], vec![addr(0)]), (peers[2], vec![addr(2)])]
+        );
+        // Rate limited, whether reconnecting all or one.
+        let later = start + RECONNECT_INTERVAL / 3;
+        assert_eq!(
+            state.reconnect_targets(None, later),
+            Err(RECONNECT_INTERVAL * 2 / 3)
+        );
+        assert!(state.reconnect_targets(Some(peers[0]), later).is_err());
+        assert!(state
+            .reconnect_targets(None, start + RECONNECT_INTERVAL)
+            .is_ok());
+    }
+
+    #[test]
+    fn reconnects_one_peer() {
+        let start = Instant::now();
+        let mut state = State::default();
+        let [peer, other, connected] = [(); 3].map(|_| PeerId::random());
+        for peer in [peer, other, connected] {
+            state.add_address(peer, addr(1));
+        }
+        state.connected_peers.insert(connected);
+
+        assert_eq!(
+            state.reconnect_targets(Some(peer), start).unwrap(),
+            vec![(peer, vec![addr(1)])]
+        );
+        let later = start + RECONNECT_INTERVAL;
+        assert_eq!(state.reconnect_targets(Some(connected), later), Ok(vec![]));
+        let unknown = PeerId::random();
+        let later = later + RECONNECT_INTERVAL;
+        assert_eq!(state.reconnect_targets(Some(unknown), later), Ok(vec![]));
+    }
+
+    #[test]
+    fn resets_include_connected_peers() {
+        let start = Instant::now();
+        let mut state = State::default();
+        let mut peers = [(); 2].map(|_| PeerId::random());
+        peers.sort_unstable();
+        for (port, peer) in peers.iter().enumerate() {
+            state.add_address(*peer, addr(port as u16));
+        }
+        state.connected_peers.insert(peers[1]);
+
+        assert_eq!(
+            state.reset_targets(start).unwrap(),
+            vec![(peers[0], vec![addr(0)]), (peers[1], vec![addr(1)])]
+        );
+        // Sharing the rate limit with reconnects.
+        assert!(state.reconnect_targets(None, start).is_err());
+        assert!(state.reset_targets(start).is_err());
+    }
+
+    #[test]
+    fn only_the_owner_moderates() {
+        let now = Instant::now();
+        let mut state = State::default();
+        let [owner, mallory, target] = [(); 3].map(|_| PeerId::random());
+        let (moderated, open) = (
+            TopicHash::from_raw("moderated"),
+            TopicHash::from_raw("open"),
+        );
+        state.channel_owners.insert(moderated.clone(), owner);
+
+        // Forged by a member, or for a channel nobody owns.
+        assert_eq!(
+            state.moderate(&moderated, &mallory, target, None, now),
+            Err(ModerationRejected::NotOwner)
+        );
+        assert_eq!(
+            state.moderate(&open, &owner, target, None, now),
+            Err(ModerationRejected::NotModerated)
+        );
+        assert!(!state.is_hidden(&moderated, &target, now));
+        assert!(!state.is_hidden(&open, &target, now));
+
+        let muted_for = Duration::from_secs(60);
+        assert_eq!(
+            state.moderate(&moderated, &owner, target, Some(now + muted_for), now),
+            Ok(())
+        );
+        assert!(state.is_hidden(&moderated, &target, now));
+        assert!(!state.is_hidden(&open, &target, now));
+        assert!(!state.is_hidden(&moderated, &target, now + muted_for));
+
+        // Kicked for good.
+        assert_eq!(
+            state.moderate(&moderated, &owner, mallory, None, now),
+            Ok(())
+        );
+        assert!(state.is_hidden(&moderated, &mallory, now + muted_for * 1000));
+
+        // Blocked ones in every channel.
+        state.blocked_peers.insert(target);
+        assert!(state.is_hidden(&open, &target, now + muted_for));
+    }
+
+    #[test]
+    fn key_exchange_rounds() {
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let connected = |peers: &[PeerId]| peers.iter().copied().collect::<HashSet<_>>();
+        let strings = |peers: &[PeerId]| peers.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        // Alice starts round 0 with bob.
+        let mut at_alice = None;
+        let offer = KeyExchangeState::start(&mut at_alice, alice, [bob]);
+        assert_eq!(offer.round, 0);
+        assert!(!at_alice.as_ref().unwrap().is_complete());
+
+        // Bob joins it, publishing his own key, and has all keys.
+        let mut at_bob = None;
+        let participants = strings(&offer.participants);
+        let answer = KeyExchangeState::receive(
+            &mut at_bob,
+            bob,
+            &connected(&[alice]),
+            alice,
+            &offer.public_key,
+            offer.round,
+            &participants,
+        )
+        .unwrap()
+        .expect("Joined");
+        assert_eq!(answer.round, 0);
+        assert_ne!(answer.public_key, offer.public_key);
+        assert!(at_bob.as_ref().unwrap().is_complete());
+        // Repeated, it's nothing new.
+        let again = KeyExchangeState::receive(
+            &mut at_bob,
+            bob,
+            &connected(&[alice]),
+            alice,
+            &offer.public_key,
+            offer.round,
+            &participants,
+        );
+        assert_eq!(again, Ok(None));
+
+        // Alice completes the round with bob's key.
+        let done = KeyExchangeState::receive(
+            &mut at_alice,
+            alice,
+            &connected(&[bob]),
+            bob,
+            &answer.public_key,
+            answer.round,
+            &participants,
+        );
+        assert_eq!(done, Ok(None));
+        assert!(at_alice.as_ref().unwrap().is_complete());
+
+        // Carol joining starts round 1, superseding round 0.
+        let rekey = KeyExchangeState::start(&mut at_alice, alice, [bob, carol]);
+        assert_eq!(rekey.round, 1);
+        assert!(!at_alice.as_ref().unwrap().is_complete());
+        let stale = KeyExchangeState::receive(
+            &mut at_alice,
+            alice,
+            &connected(&[bob, carol]),
+            bob,
+            &answer.public_key,
+            0,
+            &participants,
+        );
+        assert_eq!(
+            stale,
+            Err(KeyExchangeError::UnexpectedRound {
+                round: 0,
+                current: 1
+            })
+        );
+        // Skipping a round isn't accepted either.
+        let skipped = KeyExchangeState::receive(
+            &mut at_alice,
+            alice,
+            &connected(&[bob, carol]),
+            bob,
+            &answer.public_key,
+            3,
+            &participants,
+        );
+        assert_eq!(
+            skipped,
+            Err(KeyExchangeError::UnexpectedRound {
+                round: 3,
+                current: 1
+            })
+        );
+    }
+
+    #[test]
+    fn key_exchange_rounds_wrap_around() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut at_alice = Some(KeyExchangeState::new(
+            u32::MAX,
+            BTreeSet::from([alice, bob]),
+            alice,
+        ));
+        let mut at_bob = Some(KeyExchangeState::new(
+            u32::MAX,
+            BTreeSet::from([alice, bob]),
+            bob,
+        ));
+
+        let offer = KeyExchangeState::start(&mut at_alice, alice, [bob]);
+        assert_eq!(offer.round, 0);
+        let participants = offer
+            .participants
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        let answer = KeyExchangeState::receive(
+            &mut at_bob,
+            bob,
+            &HashSet::from([alice]),
+            alice,
+            &offer.public_key,
+            offer.round,
+            &participants,
+        )
+        .unwrap()
+        .expect("Joined");
+        assert_eq!(answer.round, 0);
+        assert!(at_bob.unwrap().is_complete());
+    }
+
+    #[test]
+    fn concurrent_key_exchanges_settle_on_the_lowest_participants() {
+        let mut peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+        peers.sort();
+        let [low, high, third] = peers;
+        let connected = HashSet::from(peers);
+        let strings = |peers: &[PeerId]| peers.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        // `high` starts round 0 with everyone, while `low` starts it with only `high`.
+        let mut at_high = None;
+        let everyone = KeyExchangeState::start(&mut at_high, high, [low, third]);
+        let mut at_low = None;
+        let pair = KeyExchangeState::start(&mut at_low, low, [high]);
+        assert!(pair.participants < everyone.participants);
+
+        // `low` keeps its round.
+        let kept = KeyExchangeState::receive(
+            &mut at_low,
+            low,
+            &connected,
+            high,
+            &everyone.public_key,
+            0,
+            &strings(&everyone.participants),
+        );
+        assert_eq!(kept, Err(KeyExchangeError::ParticipantsMismatch));
+        // `high` switches to it, publishing a key for it.
+        let switched = KeyExchangeState::receive(
+            &mut at_high,
+            high,
+            &connected,
+            low,
+            &pair.public_key,
+            0,
+            &strings(&pair.participants),
+        )
+        .unwrap()
+        .expect("Joined");
+        assert_eq!(switched.participants, pair.participants);
+        assert!(at_high.as_ref().unwrap().is_complete());
+
+        let done = KeyExchangeState::receive(
+            &mut at_low,
+            low,
+            &connected,
+            high,
+            &switched.public_key,
+            0,
+            &strings(&switched.participants),
+        );
+        assert_eq!(done, Ok(None));
+        assert!(at_low.unwrap().is_complete());
+    }
+
+    #[test]
+    fn key_exchange_validation() {
+        let (local, from, stranger) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let connected = HashSet::from([from]);
+        let receive = |participants: &[String], key: &[u8]| {
+            KeyExchangeState::receive(&mut None, local, &connected, from, key, 0, participants)
+        };
+        let key = [7; GROUP_KEY_LEN];
+        let (local_s, from_s) = (local.to_string(), from.to_string());
+
+        assert_eq!(
+            receive(
+                &[local_s.clone(), from_s.clone(), stranger.to_string()],
+                &key
+            ),
+            Err(KeyExchangeError::NotConnected(stranger))
+        );
+        assert_eq!(
+            receive(&[local_s.clone(), "nobody".into()], &key),
+            Err(KeyExchangeError::InvalidParticipant("nobody".into()))
+        );
+        assert_eq!(
+            receive(&[local_s.clone()], &key),
+            Err(KeyExchangeError::SenderNotParticipant)
+        );
+        assert_eq!(
+            receive(&[from_s.clone()], &key),
+            Err(KeyExchangeError::NotIncluded)
+        );
+        assert_eq!(
+            receive(&[local_s.clone(), from_s.clone()], &key[1..]),
+            Err(KeyExchangeError::InvalidKey(GROUP_KEY_LEN - 1))
+        );
+
+        let mut exchange = None;
+        let participants = [local_s, from_s];
+        let mut receive = |participants: &[String], key: &[u8]| {
+            KeyExchangeState::receive(&mut exchange, local, &connected, from, key, 0, participants)
+        };
+        assert!(receive(&participants, &key).unwrap().is_some());
+        assert_eq!(
+            receive(&participants, &[8; GROUP_KEY_LEN]),
+            Err(KeyExchangeError::ConflictingKey)
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! OS-level TCP keep-alive of connections (`--tcp-keepalive-secs`), so firewalls and NATs
+//! dropping idle connections see traffic. It's independent of the `ping` feature's keep-alive,
+//! which exchanges libp2p pings over the connection's streams: TCP keep-alive probes are sent by
+//! the kernel, without waking the node, and also cover connections without the ping protocol.
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TcpKeepalive {
+    /// Idle time before the first probe, `None` leaving keep-alive off.
+    pub(crate) time: Option<Duration>,
+    /// Time between unanswered probes (`TCP_KEEPINTVL`), the OS default if `None`.
+    pub(crate) interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped (`TCP_KEEPCNT`), the OS default if
+    /// `None`.
+    pub(crate) probes: Option<u32>,
+}
+
+impl Default for TcpKeepalive {
+    fn default() -> Self {
+        Self {
+            time: Some(Duration::from_secs(60)),
+            interval: None,
+            probes: None,
+        }
+    }
+}
+
+impl TcpKeepalive {
+    /// From the flags' seconds, 0 disabling keep-alive.
+    pub(crate) fn from_secs(time: u64, interval: Option<u64>, probes: Option<u32>) -> Self {
+        Self {
+            time: Some(Duration::from_secs(time)).filter(|t| !t.is_zero()),
+            interval: interval.map(Duration::from_secs),
+            probes,
+        }
+    }
+
+    /// Enables keep-alive on `stream`, unless disabled. The interval and probes are ignored on
+    /// platforms which can't set them.
+    pub(crate) fn apply(&self, stream: &tokio::net::TcpStream) -> std::io::Result<()> {
+        let time = match self.time {
+            Some(time) => time,
+            None => return Ok(()),
+        };
+        #[allow(unused_mut)]
+        let mut params = socket2::TcpKeepalive::new().with_time(time);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "macos",
+            target_os = "ios",
+            windows
+        ))]
+        if let Some(interval) = self.interval {
+            params = params.with_interval(interval);
+        }
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "macos",
+            target_os = "ios"
+        ))]
+        if let Some(probes) = self.probes {
+            params = params.with_retries(probes);
+        }
+        socket2::SockRef::from(stream).set_tcp_keepalive(&params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn connected() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dialed = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        (dialed, accepted)
+    }
+
+    #[test]
+    fn zero_disables() {
+        assert_eq!(TcpKeepalive::from_secs(0, Some(5), Some(3)).time, None);
+        assert_eq!(
+            TcpKeepalive::from_secs(60, None, None),
+            TcpKeepalive::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_the_socket_options() {
+        let (dialed, accepted) = connected().await;
+        TcpKeepalive::from_secs(0, None, None)
+            .apply(&dialed)
+            .unwrap();
+        assert!(!socket2::SockRef::from(&dialed).keepalive().unwrap());
+
+        let keepalive = TcpKeepalive::from_secs(42, Some(7), Some(4));
+        keepalive.apply(&accepted).unwrap();
+        let socket = socket2::SockRef::from(&accepted);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(42));
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(7));
+            assert_eq!(socket.keepalive_retries().unwrap(), 4);
+        }
+    }
+}
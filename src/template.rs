@@ -0,0 +1,260 @@
+//! Canned responses: named templates with `{nick}`, `{channel}` and `{time}` placeholders,
+//! published on `/template <name>` or when a message contains a configured keyword. Keywords are
+//! responded to at most once per `cooldown_secs` per peer and channel, and never in responses, so
+//! two responders can't keep answering each other.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use libp2p::PeerId;
+use serde::Deserialize;
+
+/// Peers and channels responded to remembered, those beyond forget the expired ones.
+const MAX_RESPONDED: usize = 1024;
+
+/// Values substituted for the placeholders.
+#[derive(Debug)]
+pub(crate) struct Context<'a> {
+    pub(crate) nick: &'a str,
+    pub(crate) channel: &'a str,
+    pub(crate) time: String,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn now(nick: &'a str, channel: &'a str) -> Self {
+        Self {
+            nick,
+            channel,
+            time: chrono::Local::now().format("%H:%M").to_string(),
+        }
+    }
+}
+
+/// Substitutes the placeholders in `template`, `{{` and `}}` are literal braces.
+pub(crate) fn render(template: &str, ctx: &Context) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(|c| c == '{' || c == '}') {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if let Some(tail) = tail.strip_prefix("{{") {
+            out.push('{');
+            rest = tail;
+        } else if let Some(tail) = tail.strip_prefix("}}") {
+            out.push('}');
+            rest = tail;
+        } else if tail.starts_with('}') {
+            bail!("Unmatched '}}' in {:?}", template);
+        } else {
+            let end = tail
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed '{{' in {:?}", template))?;
+            match &tail[1..end] {
+                "nick" => out.push_str(ctx.nick),
+                "channel" => out.push_str(ctx.channel),
+                "time" => out.push_str(&ctx.time),
+                other => bail!("Unknown placeholder {{{}}} in {:?}", other, template),
+            }
+            rest = &tail[end + 1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The `[responses]` section of the config file.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Responses {
+    /// Templates by name
+    #[serde(default)]
+    templates: BTreeMap<String, String>,
+    /// Name of the template to respond with, by keyword
+    #[serde(default)]
+    keywords: BTreeMap<String, String>,
+    /// Minimum time between responses to the same peer in the same channel
+    #[serde(default = "default_cooldown_secs")]
+    cooldown_secs: u64,
+    /// When last responded to a peer in a channel.
+    #[serde(skip)]
+    responded: Mutex<HashMap<(PeerId, String), Instant>>,
+}
+
+fn default_cooldown_secs() -> u64 {
+    60
+}
+
+impl Default for Responses {
+    fn default() -> Self {
+        Self {
+            templates: Default::default(),
+            keywords: Default::default(),
+            cooldown_secs: default_cooldown_secs(),
+            responded: Default::default(),
+        }
+    }
+}
+
+impl Responses {
+    /// Checks that all templates render and all keywords refer to a template.
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let ctx = Context::now("nick", "channel");
+        for template in self.templates.values() {
+            render(template, &ctx)?;
+        }
+        for (keyword, name) in &self.keywords {
+            if !self.templates.contains_key(name) {
+                bail!(
+                    "Keyword {:?} refers to unknown template {:?}",
+                    keyword,
+                    name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn render(&self, name: &str, ctx: &Context) -> anyhow::Result<String> {
+        let template = self
+            .templates
+            .get(name)
+            .ok_or_else(|| anyhow!("No template {:?}", name))?;
+        render(template, ctx)
+    }
+
+    /// The response to `text` of `peer` in `channel` if it contains a keyword (case
+    /// insensitive), unless responded to within the cooldown. Responses containing the keyword
+    /// themselves are dropped, so two responders can't ping-pong.
+    pub(crate) fn respond(
+        &self,
+        peer: &PeerId,
+        channel: &str,
+        text: &str,
+        ctx: &Context,
+        now: Instant,
+    ) -> Option<String> {
+        let cooldown = Duration::from_secs(self.cooldown_secs);
+        let mut responded = self.responded.lock().expect("Not poisoned");
+        let key = (*peer, channel.to_string());
+        if matches!(responded.get(&key), Some(at) if now.saturating_duration_since(*at) < cooldown)
+        {
+            return None;
+        }
+        let response = self.response(text, ctx)?;
+        if responded.len() >= MAX_RESPONDED {
+            responded.retain(|_, at| now.saturating_duration_since(*at) < cooldown);
+        }
+        if responded.len() < MAX_RESPONDED {
+            responded.insert(key, now);
+        }
+        Some(response)
+    }
+
+    fn response(&self, text: &str, ctx: &Context) -> Option<String> {
+        let (keyword, name) = text
+            .split(|c: char| !c.is_alphanumeric())
+            .find_map(|word| {
+                self.keywords
+                    .iter()
+                    .find(|(keyword, _)| keyword.eq_ignore_ascii_case(word))
+            })?;
+        let response = self.render(name, ctx).ok()?;
+        let keyword = keyword.to_lowercase();
+        (!response.to_lowercase().contains(&keyword)).then(|| response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> Context<'static> {
+        Context {
+            nick: "alice",
+            channel: "agora",
+            time: "12:34".into(),
+        }
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        assert_eq!(
+            render("Hi {nick}, welcome to {channel}! It's {time}.", &ctx()).unwrap(),
+            "Hi alice, welcome to agora! It's 12:34."
+        );
+        assert_eq!(render("{{nick}} }}", &ctx()).unwrap(), "{nick} }");
+        assert_eq!(render("", &ctx()).unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for template in ["{nick", "nick}", "{unknown}", "{}"] {
+            assert!(render(template, &ctx()).is_err(), "{}", template);
+        }
+    }
+
+    fn responses(config: &str) -> Responses {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn validate() {
+        let valid = responses(
+            r#"
+            templates = { greet = "Hi {nick}" }
+            keywords = { hello = "greet" }
+            "#,
+        );
+        assert!(valid.validate().is_ok());
+        assert!(responses(r#"keywords = { hello = "greet" }"#)
+            .validate()
+            .is_err());
+        assert!(responses(r#"templates = { greet = "Hi {name}" }"#)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn responds_to_keywords() {
+        let responses = responses(
+            r#"
+            templates = { greet = "Hi {nick}", echo = "ping yourself" }
+            keywords = { hello = "greet", ping = "echo" }
+            "#,
+        );
+        let (peer, now) = (PeerId::random(), Instant::now());
+        let respond = |text| responses.respond(&peer, "agora", text, &ctx(), now);
+        assert_eq!(respond("othello"), None);
+        // Would trigger itself.
+        assert_eq!(respond("ping"), None);
+        assert_eq!(respond("well, HELLO there").as_deref(), Some("Hi alice"));
+    }
+
+    #[test]
+    fn responds_once_per_cooldown() {
+        let responses = responses(
+            r#"
+            cooldown_secs = 10
+            templates = { greet = "Hi {nick}" }
+            keywords = { hello = "greet" }
+            "#,
+        );
+        let (alice, bob, start) = (PeerId::random(), PeerId::random(), Instant::now());
+        let respond = |peer, channel, secs| {
+            responses.respond(
+                peer,
+                channel,
+                "hello",
+                &ctx(),
+                start + Duration::from_secs(secs),
+            )
+        };
+        assert!(respond(&alice, "agora", 0).is_some());
+        assert!(respond(&alice, "agora", 9).is_none());
+        assert!(respond(&alice, "ops", 9).is_some());
+        assert!(respond(&bob, "agora", 9).is_some());
+        assert!(respond(&alice, "agora", 10).is_some());
+    }
+}
@@ -0,0 +1,161 @@
+//! The gossipsub mesh of the joined channels, shown by `/topology` and served at
+//! `GET /topology`.
+use serde::Serialize;
+
+use crate::{p2p::Gossipsub, state::State};
+
+/// Peers listed per kind before truncating, in the human readable format.
+const MAX_LISTED: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub(crate) struct Peer {
+    pub(crate) nick: String,
+    pub(crate) id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ChannelTopology {
+    pub(crate) channel: String,
+    /// Peers messages are forwarded to in full.
+    pub(crate) mesh: Vec<Peer>,
+    /// Peers published to without being subscribed. Always empty for joined channels, as
+    /// gossipsub only keeps fanout for topics it isn't subscribed to.
+    pub(crate) fanout: Vec<Peer>,
+    /// All peers known to be subscribed.
+    pub(crate) all: Vec<Peer>,
+}
+
+/// Topology of each joined channel, ordered by channel name, peers by nickname.
+pub(crate) fn collect(gossipsub: &Gossipsub, state: &State) -> Vec<ChannelTopology> {
+    let peer = |id: &libp2p::PeerId| Peer {
+        nick: state.nick(id),
+        id: id.to_base58(),
+    };
+    let mut channels = gossipsub
+        .topics()
+        .map(|topic| {
+            let mut mesh = gossipsub.mesh_peers(topic).map(peer).collect::<Vec<_>>();
+            let mut all = gossipsub
+                .all_peers()
+                .filter(|(_, topics)| topics.contains(&topic))
+                .map(|(id, _)| peer(id))
+                .collect::<Vec<_>>();
+            mesh.sort();
+            all.sort();
+            ChannelTopology {
+                channel: state.channel(topic).to_string(),
+                mesh,
+                fanout: Vec::new(),
+                all,
+            }
+        })
+        .collect::<Vec<_>>();
+    channels.sort_by(|a, b| a.channel.cmp(&b.channel));
+    channels
+}
+
+fn nicks(peers: &[Peer]) -> String {
+    let mut listed = peers
+        .iter()
+        .take(MAX_LISTED)
+        .map(|p| p.nick.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    if peers.len() > MAX_LISTED {
+        listed.push_str(&format!(",...and {} more", peers.len() - MAX_LISTED));
+    }
+    listed
+}
+
+fn ids(peers: &[Peer]) -> String {
+    peers
+        .iter()
+        .map(|p| p.id.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl ChannelTopology {
+    /// `#channel: mesh=[alice,bob] fanout=[] all=[alice,bob,carol]`, or with `machine_readable`
+    /// `channel|mesh|fanout|all` with comma separated peer ids, never truncated.
+    pub(crate) fn format(&self, machine_readable: bool) -> String {
+        if machine_readable {
+            format!(
+                "{}|{}|{}|{}",
+                self.channel,
+                ids(&self.mesh),
+                ids(&self.fanout),
+                ids(&self.all)
+            )
+        } else {
+            format!(
+                "#{}: mesh=[{}] fanout=[{}] all=[{}]",
+                self.channel,
+                nicks(&self.mesh),
+                nicks(&self.fanout),
+                nicks(&self.all)
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(nicks: &[&str]) -> Vec<Peer> {
+        nicks
+            .iter()
+            .map(|nick| Peer {
+                nick: nick.to_string(),
+                id: format!("id-{}", nick),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn formats_topology() {
+        let topology = ChannelTopology {
+            channel: "agora".into(),
+            mesh: peers(&["alice", "bob", "carol"]),
+            fanout: peers(&["dave"]),
+            all: peers(&["alice", "bob", "carol", "dave", "eve"]),
+        };
+        assert_eq!(
+            topology.format(false),
+            "#agora: mesh=[alice,bob,carol] fanout=[dave] all=[alice,bob,carol,dave,eve]"
+        );
+        assert_eq!(
+            topology.format(true),
+            "agora|id-alice,id-bob,id-carol|id-dave|id-alice,id-bob,id-carol,id-dave,id-eve"
+        );
+    }
+
+    #[test]
+    fn truncates_many_peers() {
+        let many = (0..13).map(|i| format!("p{}", i)).collect::<Vec<_>>();
+        let many = peers(&many.iter().map(String::as_str).collect::<Vec<_>>());
+        let topology = ChannelTopology {
+            channel: "agora".into(),
+            mesh: many[..MAX_LISTED].to_vec(),
+            fanout: Vec::new(),
+            all: many,
+        };
+        assert_eq!(
+            topology.format(false),
+            "#agora: mesh=[p0,p1,p2,p3,p4,p5,p6,p7,p8,p9] fanout=[] \
+             all=[p0,p1,p2,p3,p4,p5,p6,p7,p8,p9,...and 3 more]"
+        );
+        // Everything for machines.
+        assert_eq!(
+            topology
+                .format(true)
+                .split('|')
+                .nth(3)
+                .unwrap()
+                .split(',')
+                .count(),
+            13
+        );
+    }
+}
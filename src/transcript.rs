@@ -0,0 +1,163 @@
+//! Transcripts of a channel's kept history (`/export`), as Markdown or HTML by the file's
+//! extension, e.g. to archive meetings.
+use std::{fmt::Write, path::Path};
+
+use anyhow::Context;
+
+use crate::api::HistoryEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Markdown,
+    Html,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown transcript format of {0}: use .md or .html")]
+pub(crate) struct UnknownFormat(String);
+
+impl Format {
+    pub(crate) fn from_path(path: &Path) -> Result<Self, UnknownFormat> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        match extension.to_ascii_lowercase().as_str() {
+            "md" | "markdown" => Ok(Self::Markdown),
+            "html" | "htm" => Ok(Self::Html),
+            _ => Err(UnknownFormat(path.display().to_string())),
+        }
+    }
+}
+
+pub(crate) fn render(format: Format, channel: &str, entries: &[HistoryEntry]) -> String {
+    let mut out = String::new();
+    match format {
+        Format::Markdown => {
+            let _ = writeln!(out, "# {}\n", escape_markdown(channel));
+            for entry in entries {
+                let _ = writeln!(
+                    out,
+                    "- `{}` **{}**: {}",
+                    entry.origin_timestamp,
+                    escape_markdown(&entry.nick),
+                    // Continuation lines stay within the list item.
+                    escape_markdown(&entry.message).replace('\n', "\n  ")
+                );
+            }
+        }
+        Format::Html => {
+            let channel = escape_html(channel);
+            let _ = writeln!(
+                out,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">"
+            );
+            let _ = writeln!(out, "<title>{}</title>\n</head>\n<body>", channel);
+            let _ = writeln!(out, "<h1>{}</h1>\n<ul>", channel);
+            for entry in entries {
+                let _ = writeln!(
+                    out,
+                    "<li><time datetime=\"{}\">{}</time> <b>{}</b>: {}</li>",
+                    entry.origin_timestamp.to_rfc3339(),
+                    entry.origin_timestamp,
+                    escape_html(&entry.nick),
+                    escape_html(&entry.message).replace('\n', "<br>")
+                );
+            }
+            let _ = writeln!(out, "</ul>\n</body>\n</html>");
+        }
+    }
+    out
+}
+
+/// Writes the transcript of `entries` to `path`, replacing it.
+pub(crate) fn export(path: &Path, channel: &str, entries: &[HistoryEntry]) -> anyhow::Result<()> {
+    let format = Format::from_path(path)?;
+    std::fs::write(path, render(format, channel, entries))
+        .with_context(|| format!("Writing {}", path.display()))
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_{}[]<>()#+-.!|~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use libp2p::PeerId;
+
+    use super::*;
+
+    fn entries() -> Vec<HistoryEntry> {
+        vec![
+            HistoryEntry {
+                from: PeerId::random(),
+                nick: "alice".into(),
+                message: "Agenda: *one*\nand <two>".into(),
+                origin_timestamp: Utc.timestamp(1_600_000_000, 0),
+            },
+            HistoryEntry {
+                from: PeerId::random(),
+                nick: "bob_".into(),
+                message: "Done & dusted".into(),
+                origin_timestamp: Utc.timestamp(1_600_000_060, 0),
+            },
+        ]
+    }
+
+    #[test]
+    fn format_by_extension() {
+        assert_eq!(
+            Format::from_path(Path::new("meeting.md")).unwrap(),
+            Format::Markdown
+        );
+        assert_eq!(
+            Format::from_path(Path::new("a/meeting.HTML")).unwrap(),
+            Format::Html
+        );
+        assert!(Format::from_path(Path::new("meeting.txt")).is_err());
+        assert!(Format::from_path(Path::new("meeting")).is_err());
+    }
+
+    #[test]
+    fn renders_markdown() {
+        let md = render(Format::Markdown, "standup", &entries());
+        assert_eq!(
+            md,
+            "# standup\n\n\
+             - `2020-09-13 12:26:40 UTC` **alice**: Agenda: \\*one\\*\n  and \\<two\\>\n\
+             - `2020-09-13 12:27:40 UTC` **bob\\_**: Done & dusted\n"
+        );
+    }
+
+    #[test]
+    fn renders_html() {
+        let html = render(Format::Html, "<standup>", &entries());
+        assert!(html.contains("<title>&lt;standup&gt;</title>"));
+        assert!(html.contains(
+            "<li><time datetime=\"2020-09-13T12:26:40+00:00\">2020-09-13 12:26:40 UTC</time> \
+             <b>alice</b>: Agenda: *one*<br>and &lt;two&gt;</li>"
+        ));
+        assert!(html.contains("<b>bob_</b>: Done &amp; dusted</li>"));
+        assert!(html.ends_with("</ul>\n</body>\n</html>\n"));
+    }
+}
@@ -0,0 +1,63 @@
+//! Peers verified with `/verify`, stored one per line as `<peer id> <nickname>`.
+use std::{collections::HashMap, io::Write, path::Path};
+
+use anyhow::Context;
+use libp2p::PeerId;
+
+/// Reads the verified peers, none if `path` doesn't exist yet.
+pub(crate) fn load(path: &Path) -> anyhow::Result<HashMap<PeerId, String>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Reading verified peers {}", path.display()))
+        }
+    };
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let (peer, nick) = l.split_once(' ').unwrap_or((l, ""));
+            let peer = peer
+                .parse()
+                .with_context(|| format!("Invalid peer id {} in {}", peer, path.display()))?;
+            Ok((peer, nick.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Adds `peer` to the verified peers at `path`.
+pub(crate) fn append(path: &Path, peer: &PeerId, nick: &str) -> anyhow::Result<()> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{} {}", peer, nick))
+        .with_context(|| format!("Writing verified peers {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let path = std::env::temp_dir().join(format!(
+            "agora-verified-{}-{}",
+            std::process::id(),
+            PeerId::random()
+        ));
+        assert!(load(&path).unwrap().is_empty());
+
+        let [alice, bob] = [(); 2].map(|_| PeerId::random());
+        append(&path, &alice, "alice").unwrap();
+        append(&path, &bob, "bob").unwrap();
+        let verified = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            verified,
+            HashMap::from([(alice, "alice".into()), (bob, "bob".into())])
+        );
+    }
+}
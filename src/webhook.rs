@@ -0,0 +1,302 @@
+//! Outgoing webhook delivery of channel events.
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use hyper::{header, Body, Client, Request, Uri};
+use libp2p::PeerId;
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use tracing::*;
+
+use crate::shutdown::{Flag, Tasks};
+
+/// Header carrying the hex encoded HMAC-SHA256 of the body, if a secret is configured.
+pub(crate) const SIGNATURE_HEADER: &str = "x-agora-signature";
+const QUEUE_SIZE: usize = 1024;
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Kind {
+    Message,
+    Join,
+    Leave,
+    Meta,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Payload {
+    event: Kind,
+    peer: String,
+    nick: String,
+    channel: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    /// Type and data of a `meta` event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<serde_json::Value>,
+}
+
+impl Payload {
+    pub(crate) fn message(
+        peer: &PeerId,
+        nick: &str,
+        channel: &str,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        text: &str,
+    ) -> Self {
+        Self {
+            event: Kind::Message,
+            peer: peer.to_string(),
+            nick: nick.into(),
+            channel: channel.into(),
+            timestamp,
+            text: Some(text.into()),
+            event_type: None,
+            payload: None,
+        }
+    }
+
+    pub(crate) fn meta(
+        peer: &PeerId,
+        nick: &str,
+        channel: &str,
+        event_type: &str,
+        payload: &serde_json::Value,
+    ) -> Self {
+        Self {
+            event: Kind::Meta,
+            peer: peer.to_string(),
+            nick: nick.into(),
+            channel: channel.into(),
+            timestamp: chrono::Utc::now(),
+            text: None,
+            event_type: Some(event_type.into()),
+            payload: Some(payload.clone()),
+        }
+    }
+}
+
+pub(crate) struct Webhook {
+    channel: String,
+    events: bool,
+    queue: mpsc::Sender<Payload>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Webhook {
+    /// Spawns the delivery task. Joins and leaves are only delivered if `events` is set.
+    pub(crate) fn spawn(
+        url: Uri,
+        secret: Option<String>,
+        events: bool,
+        channel: String,
+        tasks: &mut Tasks,
+    ) -> Self {
+        let (queue, rx) = mpsc::channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::default());
+        let counter = dropped.clone();
+        tasks.spawn(|shutdown| deliver(url, secret, rx, counter, shutdown));
+        Self {
+            channel,
+            events,
+            queue,
+            dropped,
+        }
+    }
+
+    fn enqueue(&self, payload: Payload) {
+        if self.queue.try_send(payload).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(dropped, "Webhook queue full, dropping event");
+        }
+    }
+
+    pub(crate) fn message(
+        &self,
+        peer: &PeerId,
+        nick: &str,
+        channel: &str,
+        timestamp: chrono::DateTime<chrono::Utc>,
+        text: &str,
+    ) {
+        self.enqueue(Payload::message(peer, nick, channel, timestamp, text));
+    }
+
+    pub(crate) fn meta(&self, payload: Payload) {
+        self.enqueue(payload);
+    }
+
+    pub(crate) fn membership(&self, kind: Kind, peer: &PeerId, nick: &str) {
+        if self.events {
+            self.enqueue(Payload {
+                event: kind,
+                peer: peer.to_string(),
+                nick: nick.into(),
+                channel: self.channel.clone(),
+                timestamp: chrono::Utc::now(),
+                text: None,
+                event_type: None,
+                payload: None,
+            });
+        }
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn deliver(
+    url: Uri,
+    secret: Option<String>,
+    mut queue: mpsc::Receiver<Payload>,
+    dropped: Arc<AtomicU64>,
+    shutdown: Flag,
+) {
+    let client = Client::new();
+    loop {
+        let payload = tokio::select! {
+            _ = shutdown.wait() => break,
+            payload = queue.recv() => match payload {
+                Some(payload) => payload,
+                None => break,
+            },
+        };
+        let body = serde_json::to_vec(&payload).expect("Serialization works");
+        let signature = secret.as_deref().map(|s| sign(s, &body));
+        let mut backoff = INITIAL_BACKOFF;
+        let mut delivered = false;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut request =
+                Request::post(url.clone()).header(header::CONTENT_TYPE, "application/json");
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            let request = request
+                .body(Body::from(body.clone()))
+                .expect("Valid request");
+            let response = tokio::select! {
+                _ = shutdown.wait() => return,
+                response = tokio::time::timeout(TIMEOUT, client.request(request)) => response,
+            };
+            match response {
+                Ok(Ok(response)) if response.status().is_success() => {
+                    delivered = true;
+                    break;
+                }
+                Ok(Ok(response)) => {
+                    debug!(attempt, status = %response.status(), "Webhook delivery failed")
+                }
+                Ok(Err(error)) => debug!(attempt, %error, "Webhook delivery failed"),
+                Err(_) => debug!(attempt, "Webhook delivery timed out"),
+            }
+            if attempt < MAX_ATTEMPTS {
+                tokio::select! {
+                    _ = shutdown.wait() => return,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff *= 2;
+            }
+        }
+        if !delivered {
+            let dropped = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                dropped,
+                "Webhook delivery failed after {} attempts, dropping event", MAX_ATTEMPTS
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server, StatusCode,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn delivers_signed_payloads_with_retries() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let calls = Arc::new(AtomicUsize::default());
+        let make_svc = make_service_fn(move |_| {
+            let (tx, calls) = (tx.clone(), calls.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let (tx, calls) = (tx.clone(), calls.clone());
+                    async move {
+                        let signature = req.headers()[SIGNATURE_HEADER]
+                            .to_str()
+                            .unwrap()
+                            .to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        tx.send((signature, body)).unwrap();
+                        // Fail the first attempt.
+                        let status = if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        } else {
+                            StatusCode::OK
+                        };
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/hook", server.local_addr())
+            .parse()
+            .unwrap();
+        tokio::spawn(server);
+
+        let mut tasks = Tasks::default();
+        let webhook = Webhook::spawn(
+            url,
+            Some("secret".into()),
+            false,
+            "agora".into(),
+            &mut tasks,
+        );
+        let peer = PeerId::random();
+        webhook.membership(Kind::Join, &peer, "alice");
+        webhook.message(&peer, "alice", "agora", chrono::Utc::now(), "hello");
+
+        for _ in 0..2 {
+            let (signature, body) = rx.recv().await.unwrap();
+            assert_eq!(signature, sign("secret", &body));
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["event"], "message");
+            assert_eq!(json["peer"], peer.to_string());
+            assert_eq!(json["nick"], "alice");
+            assert_eq!(json["channel"], "agora");
+            assert_eq!(json["text"], "hello");
+        }
+        assert_eq!(webhook.dropped.load(Ordering::Relaxed), 0);
+    }
+}
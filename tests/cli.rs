@@ -0,0 +1,375 @@
+use std::{
+    io::{BufRead, BufReader},
+    net::TcpListener,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::mpsc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+fn agora() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_agora"))
+}
+
+fn unique(name: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("agora-test-{}-{}-{}", name, std::process::id(), nanos)
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(unique(name))
+}
+
+#[test]
+fn generate_identity() {
+    let path = temp_path("identity");
+    let output = agora()
+        .args(["generate-identity", "--output"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.lines().any(|l| l.starts_with("12D3KooW")),
+        "{}",
+        stdout
+    );
+    assert!(!std::fs::read(&path).unwrap().is_empty());
+
+    // Never overwrites an existing identity.
+    let output = agora()
+        .args(["generate-identity", "--output"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn send_without_peers_fails() {
+    let status = agora()
+        .args(["send", "--message", "hello", "--timeout", "1", "--channel"])
+        .arg(unique("channel"))
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn validates_channel_names() {
+    let send = |flags: &[&str]| {
+        let output = agora()
+            .args(["send", "--message", "hello", "--timeout", "1"])
+            .args(flags)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let stderr = send(&["--channel-name-regex", "^[a-z+$", "--channel", "agora"]);
+    assert!(stderr.contains("--channel-name-regex"), "{}", stderr);
+
+    let stderr = send(&["--channel", "no spaces"]);
+    assert!(
+        stderr.contains("Invalid channel name: must match ^[a-zA-Z0-9_-]{1,64}$"),
+        "{}",
+        stderr
+    );
+    let stderr = send(&["--channel-name-regex", "^team-", "--channel", "agora"]);
+    assert!(
+        stderr.contains("Invalid channel name: must match ^team-"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn warns_about_permissive_validation() {
+    const WARNING: &str = "Running in permissive validation mode";
+    let send = |flags: &[&str]| {
+        let output = agora()
+            .args(["send", "--message", "hello", "--timeout", "1", "--channel"])
+            .arg(unique("channel"))
+            .args(flags)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let stderr = send(&[]);
+    assert_eq!(stderr.matches(WARNING).count(), 1, "{}", stderr);
+    for flag in [
+        "--strict-validation",
+        "--i-know-permissive",
+        "--validation-mode=permissive",
+        "--validation-mode=author",
+    ] {
+        let stderr = send(&[flag]);
+        assert!(!stderr.contains(WARNING), "{}: {}", flag, stderr);
+    }
+}
+
+#[cfg(feature = "mdns")]
+#[test]
+#[ignore = "needs mDNS to discover the listening peer"]
+fn send_reaches_listener() {
+    let channel = unique("channel");
+    let mut listener = agora()
+        .args(["listen", "--channel", &channel])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let status = agora()
+        .args(["send", "--message", "hello listener", "--channel", &channel])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let stdout = BufReader::new(listener.stdout.take().unwrap());
+    let received = stdout
+        .lines()
+        .map(Result::unwrap)
+        .any(|l| l.ends_with(": hello listener"));
+    listener.kill().unwrap();
+    assert!(received);
+}
+
+#[test]
+#[ignore = "needs TCP connections between local processes"]
+fn clients_meet_through_node() {
+    let channel = unique("channel");
+    let identity = temp_path("node-identity");
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let node_addr = format!("/ip4/127.0.0.1/tcp/{}", port);
+    let mut node = agora()
+        .args([
+            "node",
+            "--channel",
+            &channel,
+            "--listen",
+            &node_addr,
+            "--identity",
+        ])
+        .arg(&identity)
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let mut listener = agora()
+        .args(["listen", "--channel", &channel, "--bootstrap", &node_addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(listener.stdout.take().unwrap());
+    let (tx, lines) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in stdout.lines().map(Result::unwrap) {
+            let _ = tx.send(line);
+        }
+    });
+    std::thread::sleep(Duration::from_secs(2));
+
+    let status = agora()
+        .args(["send", "--message", "hello via node", "--channel", &channel])
+        .args(["--bootstrap", &node_addr])
+        .status()
+        .unwrap();
+
+    let received = std::iter::from_fn(|| lines.recv_timeout(Duration::from_secs(5)).ok())
+        .any(|l| l.ends_with(": hello via node"));
+    listener.kill().unwrap();
+    node.kill().unwrap();
+    // The node persisted its identity.
+    assert!(!std::fs::read(&identity).unwrap().is_empty());
+    std::fs::remove_file(identity).unwrap();
+    assert!(status.success());
+    assert!(received);
+}
+
+#[test]
+#[ignore = "needs TCP connections between local processes"]
+fn private_topics_exchange_messages() {
+    let channel = unique("channel");
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let listen_addr = format!("/ip4/127.0.0.1/tcp/{}", port);
+    let mut listener = agora()
+        .args(["listen", "--private-topic", "--channel", &channel])
+        .args(["--listen", &listen_addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(listener.stdout.take().unwrap());
+    let (tx, lines) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in stdout.lines().map(Result::unwrap) {
+            let _ = tx.send(line);
+        }
+    });
+    std::thread::sleep(Duration::from_secs(1));
+
+    let status = agora()
+        .args(["send", "--private-topic", "--message", "hello in private"])
+        .args(["--channel", &channel, "--bootstrap", &listen_addr])
+        .status()
+        .unwrap();
+
+    let received = std::iter::from_fn(|| lines.recv_timeout(Duration::from_secs(5)).ok())
+        .any(|l| l.ends_with(": hello in private"));
+    listener.kill().unwrap();
+    assert!(status.success());
+    assert!(received);
+}
+
+#[test]
+#[ignore = "needs TCP connections between local processes"]
+fn exits_when_stdin_is_idle() {
+    let channel = unique("channel");
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let listen_addr = format!("/ip4/127.0.0.1/tcp/{}", port);
+    let mut listener = agora()
+        .args(["listen", "--channel", &channel, "--listen", &listen_addr])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let mut chat = agora()
+        .args(["chat", "--stdin-timeout", "2"])
+        .args(["--channel", &channel, "--bootstrap", &listen_addr])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Kept open, so only the timeout ends the chat.
+    let mut stdin = chat.stdin.take().unwrap();
+    std::io::Write::write_all(&mut stdin, b"hello\n").unwrap();
+    let stdout = BufReader::new(chat.stdout.take().unwrap());
+    let (tx, lines) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in stdout.lines().map(Result::unwrap) {
+            let _ = tx.send(line);
+        }
+    });
+
+    let timed_out = std::iter::from_fn(|| lines.recv_timeout(Duration::from_secs(10)).ok())
+        .any(|l| l == "Stdin idle timeout, exiting");
+    let status = chat.wait().unwrap();
+    drop(stdin);
+    listener.kill().unwrap();
+    assert!(timed_out);
+    assert!(status.success());
+}
+
+#[test]
+#[ignore = "needs TCP connections between local processes"]
+fn publishes_piped_scripts() {
+    let channel = unique("channel");
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let listen_addr = format!("/ip4/127.0.0.1/tcp/{}", port);
+    let mut listener = agora()
+        .args(["listen", "--channel", &channel, "--listen", &listen_addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(listener.stdout.take().unwrap());
+    let (tx, lines) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in stdout.lines().map(Result::unwrap) {
+            let _ = tx.send(line);
+        }
+    });
+    std::thread::sleep(Duration::from_secs(1));
+
+    let mut chat = agora()
+        .args(["chat", "--name", "bob", "--channel", &channel])
+        .args(["--bootstrap", &listen_addr])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut chat_stdout = BufReader::new(chat.stdout.take().unwrap()).lines();
+    let connected = chat_stdout
+        .by_ref()
+        .map(Result::unwrap)
+        .any(|l| l.ends_with(" connected."));
+    assert!(connected);
+    // Time to join the channel's mesh.
+    std::thread::sleep(Duration::from_secs(2));
+    let mut stdin = chat.stdin.take().unwrap();
+    std::io::Write::write_all(&mut stdin, b"hello\nhello\n/nick alice\nbye\n/quit\n").unwrap();
+    drop(stdin);
+    std::thread::spawn(move || chat_stdout.for_each(drop));
+    let status = chat.wait().unwrap();
+
+    let received = std::iter::from_fn(|| lines.recv_timeout(Duration::from_secs(5)).ok())
+        .filter_map(|l| {
+            ["bob: hello", "alice: bye"]
+                .into_iter()
+                .find(|message| l.ends_with(message))
+        })
+        .take(3)
+        .collect::<Vec<_>>();
+    listener.kill().unwrap();
+    assert!(status.success());
+    // Identical lines aren't dropped as duplicates, and commands apply in order.
+    assert_eq!(received, ["bob: hello", "bob: hello", "alice: bye"]);
+}
+
+#[test]
+#[cfg(unix)]
+fn dumps_state_on_sigusr1() {
+    let channel = unique("channel");
+    let dump = temp_path("dump");
+    let mut listener = agora()
+        .args(["listen", "--channel", &channel, "--no-local-discovery"])
+        .arg("--sigusr1-dump-file")
+        .arg(&dump)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let status = Command::new("kill")
+        .args(["-USR1", &listener.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let json = (0..100)
+        .find_map(|_| {
+            std::thread::sleep(Duration::from_millis(100));
+            std::fs::read_to_string(&dump).ok()
+        })
+        .expect("Dumped");
+    // Still running after dumping.
+    assert!(listener.try_wait().unwrap().is_none());
+    listener.kill().unwrap();
+    std::fs::remove_file(&dump).unwrap();
+
+    let dump: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(dump["state"]["channel_names"][&channel], channel);
+    assert_eq!(dump["topology"][0]["channel"], channel);
+    assert_eq!(dump["topology"][0]["mesh"], serde_json::json!([]));
+}
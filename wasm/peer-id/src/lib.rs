@@ -0,0 +1,102 @@
+//! Stands in for `libp2p::PeerId` where libp2p doesn't build, e.g. on wasm32: the base58 string
+//! of the wire format, kept as opaque bytes. Like libp2p's, only identity and SHA2-256 multihashes
+//! are accepted.
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// Multihash codes, of the public key itself for small keys, else of its SHA2-256 hash.
+const IDENTITY: u8 = 0x00;
+const SHA2_256: u8 = 0x12;
+/// The largest identity digest libp2p accepts, and the bytes of the multihash with it.
+const MAX_INLINE_KEY_LEN: usize = 42;
+const MAX_LEN: usize = 2 + MAX_INLINE_KEY_LEN;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId {
+    len: u8,
+    bytes: [u8; MAX_LEN],
+}
+
+impl PeerId {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        if bytes.len() < 2 || bytes.len() > MAX_LEN {
+            return Err(ParseError::Length(bytes.len()));
+        }
+        // Both digest lengths fit the single byte varint.
+        let (code, len) = (bytes[0], bytes[1] as usize);
+        match code {
+            IDENTITY if len <= MAX_INLINE_KEY_LEN => {}
+            SHA2_256 if len == 32 => {}
+            _ => return Err(ParseError::Multihash),
+        }
+        if bytes.len() != 2 + len {
+            return Err(ParseError::Multihash);
+        }
+        let mut id = Self {
+            len: bytes.len() as u8,
+            bytes: [0; MAX_LEN],
+        };
+        id.bytes[..bytes.len()].copy_from_slice(bytes);
+        Ok(id)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.bytes[..self.len as usize].to_vec()
+    }
+
+    pub fn to_base58(&self) -> String {
+        bs58::encode(&self.bytes[..self.len as usize]).into_string()
+    }
+}
+
+/// By the bytes, like libp2p's.
+impl Ord for PeerId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bytes[..self.len as usize].cmp(&other.bytes[..other.len as usize])
+    }
+}
+
+impl PartialOrd for PeerId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(s).into_vec().map_err(|_| ParseError::Base58)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_base58().fmt(f)
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PeerId").field(&self.to_base58()).finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    Base58,
+    Length(usize),
+    Multihash,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Base58 => f.write_str("invalid base58"),
+            Self::Length(len) => write!(f, "{} bytes, expected 2 to {}", len, MAX_LEN),
+            Self::Multihash => f.write_str("not an identity or SHA2-256 multihash of a key"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
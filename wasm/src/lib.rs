@@ -0,0 +1,127 @@
+//! The wire format of `src/api.rs` for browsers: decoding CBOR payloads to JSON and back, with the
+//! same limits as the node, and applying them to what's known of the peers like the node does, see
+//! `src/peers.rs`. Builds for wasm32-unknown-unknown, `libp2p::PeerId` being replaced by the opaque
+//! one of `peer-id/`.
+use std::time::Duration;
+
+use wasm_bindgen::prelude::*;
+
+#[allow(dead_code)]
+#[path = "../../src/api.rs"]
+mod api;
+#[allow(dead_code)]
+#[path = "../../src/encode.rs"]
+mod encode;
+#[allow(dead_code)]
+#[path = "../../src/peers.rs"]
+mod peers;
+
+/// Decodes a CBOR payload, as published into a channel, to JSON.
+pub fn decode_to_json(bytes: &[u8]) -> anyhow::Result<String> {
+    let msg = encode::from_cbor::<api::ChatApi>(bytes)?;
+    api::Limits::default().check(&msg)?;
+    Ok(serde_json::to_string(&msg)?)
+}
+
+/// Encodes a message given as JSON to CBOR, to be published into a channel.
+pub fn encode_from_json(json: &str) -> anyhow::Result<Vec<u8>> {
+    let msg = serde_json::from_str::<api::ChatApi>(json)?;
+    api::Limits::default().check(&msg)?;
+    Ok(encode::to_cbor(&msg)?)
+}
+
+#[wasm_bindgen]
+pub fn decode(bytes: &[u8]) -> Result<String, JsValue> {
+    decode_to_json(bytes).map_err(|e| JsValue::from_str(&format!("{:#}", e)))
+}
+
+#[wasm_bindgen]
+pub fn encode(json: &str) -> Result<Vec<u8>, JsValue> {
+    encode_from_json(json).map_err(|e| JsValue::from_str(&format!("{:#}", e)))
+}
+
+/// Nicknames and capabilities of a channel's peers, from the messages received in it. Times are
+/// milliseconds of any monotonic clock, e.g. `performance.now()`.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Channel {
+    peers: peers::Peers<Duration>,
+}
+
+impl Channel {
+    /// Decodes a payload received from `from` like [`decode_to_json`] and applies it, returning
+    /// `{"message": .., "renamed": {"old": .., "new": ..}}`, the latter if a rename is to be
+    /// announced.
+    pub fn apply(&mut self, from: &str, bytes: &[u8], now: Duration) -> anyhow::Result<String> {
+        let from = from.parse::<libp2p::PeerId>()?;
+        let msg = encode::from_cbor::<api::ChatApi>(bytes)?;
+        api::Limits::default().check(&msg)?;
+        let renamed = match &msg {
+            api::ChatApi::ChangeNickname { nick } => self.peers.rename(from, nick.clone(), now),
+            api::ChatApi::Capabilities { supported } => {
+                self.peers.set_capabilities(from, supported.clone());
+                None
+            }
+            _ => None,
+        };
+        Ok(serde_json::json!({
+            "message": msg,
+            "renamed": renamed
+                .filter(|renamed| renamed.announce)
+                .map(|renamed| serde_json::json!({ "old": renamed.old, "new": renamed.new })),
+        })
+        .to_string())
+    }
+
+    /// Applies the nickname changes deferred by the rate limit, to be called periodically.
+    /// Returns `[{"peer": .., "old": .., "new": ..}]` of the ones to announce.
+    pub fn apply_pending_renames(&mut self, now: Duration) -> String {
+        let renamed = self
+            .peers
+            .apply_pending_renames(now)
+            .into_iter()
+            .filter(|(_, renamed)| renamed.announce)
+            .map(|(peer, renamed)| {
+                serde_json::json!({
+                    "peer": peer.to_string(),
+                    "old": renamed.old,
+                    "new": renamed.new,
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::Value::from(renamed).to_string()
+    }
+}
+
+#[wasm_bindgen]
+impl Channel {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn receive(&mut self, from: &str, bytes: &[u8], now_ms: f64) -> Result<String, JsValue> {
+        self.apply(from, bytes, millis(now_ms))
+            .map_err(|e| JsValue::from_str(&format!("{:#}", e)))
+    }
+
+    #[wasm_bindgen(js_name = applyPendingRenames)]
+    pub fn apply_pending_renames_js(&mut self, now_ms: f64) -> String {
+        self.apply_pending_renames(millis(now_ms))
+    }
+
+    /// The nickname of `peer`, if it announced one.
+    pub fn nick(&self, peer: &str) -> Option<String> {
+        let peer = peer.parse::<libp2p::PeerId>().ok()?;
+        self.peers.known_nicknames.get(&peer).cloned()
+    }
+
+    /// Whether `peer` announced `capability`.
+    pub fn supports(&self, peer: &str, capability: &str) -> bool {
+        matches!(peer.parse::<libp2p::PeerId>(), Ok(peer) if self.peers.supports(&peer, capability))
+    }
+}
+
+fn millis(ms: f64) -> Duration {
+    Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+}
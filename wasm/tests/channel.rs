@@ -0,0 +1,80 @@
+//! Messages are applied like the node does, see `src/peers.rs`, natively and under wasm.
+use std::time::Duration;
+
+use agora_wasm::Channel;
+use serde_json::Value;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::wasm_bindgen_test;
+
+const PEER: &str = "12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA";
+
+fn change_nickname(nick: &str) -> Vec<u8> {
+    agora_wasm::encode_from_json(&format!(r#"{{"ChangeNickname":{{"nick":"{}"}}}}"#, nick)).unwrap()
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn applies_nicknames_and_capabilities() {
+    let mut channel = Channel::new();
+    assert_eq!(channel.nick(PEER), None);
+
+    let applied = channel
+        .apply(PEER, &change_nickname("alice"), Duration::ZERO)
+        .unwrap();
+    let applied = serde_json::from_str::<Value>(&applied).unwrap();
+    assert_eq!(applied["message"]["ChangeNickname"]["nick"], "alice");
+    assert_eq!(applied["renamed"]["old"], PEER);
+    assert_eq!(applied["renamed"]["new"], "alice");
+    assert_eq!(channel.nick(PEER).as_deref(), Some("alice"));
+
+    let capabilities =
+        agora_wasm::encode_from_json(r#"{"Capabilities":{"supported":["dm_challenges"]}}"#)
+            .unwrap();
+    channel.apply(PEER, &capabilities, Duration::ZERO).unwrap();
+    assert!(channel.supports(PEER, "dm_challenges"));
+    assert!(!channel.supports(PEER, "serve_history"));
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn rate_limits_renames() {
+    let mut channel = Channel::new();
+    let at = Duration::from_secs;
+    for (i, nick) in ["a", "b", "c", "d"].into_iter().enumerate() {
+        channel
+            .apply(PEER, &change_nickname(nick), at(i as u64))
+            .unwrap();
+    }
+    assert_eq!(channel.nick(PEER).as_deref(), Some("c"));
+
+    let renamed = channel.apply_pending_renames(at(60));
+    let renamed = serde_json::from_str::<Value>(&renamed).unwrap();
+    assert_eq!(renamed[0]["peer"], PEER);
+    assert_eq!(renamed[0]["new"], "d");
+    assert_eq!(channel.nick(PEER).as_deref(), Some("d"));
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn rejects_malformed_peer_ids() {
+    let valid = PEER.parse::<libp2p::PeerId>().unwrap().to_bytes();
+    assert_eq!(
+        libp2p::PeerId::from_bytes(&valid).unwrap().to_string(),
+        PEER
+    );
+
+    let mut unknown_hash = valid.clone();
+    unknown_hash[0] = 0x13;
+    let mut truncated = valid.clone();
+    truncated.pop();
+    let mut sha2_256 = vec![0x12, 32];
+    sha2_256.extend([0; 31]);
+    for bytes in [unknown_hash, truncated, sha2_256] {
+        assert!(libp2p::PeerId::from_bytes(&bytes).is_err(), "{:?}", bytes);
+    }
+
+    let mut channel = Channel::new();
+    assert!(channel
+        .apply("not a peer", &change_nickname("alice"), Duration::ZERO)
+        .is_err());
+}
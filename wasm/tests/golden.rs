@@ -0,0 +1,50 @@
+//! The golden vectors decode the same here, natively and under wasm (`wasm-pack test --node`), as
+//! in the node's own tests of `src/api.rs`.
+use serde_json::Value;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::wasm_bindgen_test;
+
+fn vectors() -> Vec<(String, Vec<u8>, Value)> {
+    let vectors: Vec<Value> = serde_json::from_str(include_str!("golden.json")).unwrap();
+    vectors
+        .into_iter()
+        .map(|v| {
+            (
+                v["name"].as_str().unwrap().to_string(),
+                hex::decode(v["cbor"].as_str().unwrap()).unwrap(),
+                v["json"].clone(),
+            )
+        })
+        .collect()
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn decodes_golden_vectors() {
+    for (name, cbor, json) in vectors() {
+        let decoded = agora_wasm::decode_to_json(&cbor).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&decoded).unwrap(),
+            json,
+            "{}",
+            name
+        );
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn encodes_golden_vectors() {
+    for (name, cbor, json) in vectors() {
+        let encoded = agora_wasm::encode_from_json(&json.to_string()).unwrap();
+        assert_eq!(encoded, cbor, "{}", name);
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+fn rejects_what_the_node_rejects() {
+    assert!(agora_wasm::decode_to_json(&[0xff]).is_err());
+    let long_nick = format!(r#"{{"ChangeNickname":{{"nick":"{}"}}}}"#, "x".repeat(65));
+    assert!(agora_wasm::encode_from_json(&long_nick).is_err());
+}
@@ -0,0 +1,148 @@
+//! Development tasks, run as `cargo xtask <task>`:
+//!
+//! - `features`: lints every combination of features in [`FEATURE_MATRIX`].
+//! - `flamegraph [secs]`: profiles a node relaying a stream of messages for `secs` seconds
+//!   (default 30) and opens the flamegraph.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+    thread,
+    time::Duration,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Where the profiled node listens, for the senders to bootstrap from.
+const NODE_ADDR: &str = "/ip4/127.0.0.1/tcp/4801";
+const DEFAULT_SECS: u64 = 30;
+
+/// Feature combinations which must build: without any optional sub-behaviour, with each one
+/// alone, with all of them, and with each of the other features on top of the defaults.
+const FEATURE_MATRIX: &[&[&str]] = &[
+    &["--no-default-features"],
+    &["--no-default-features", "--features", "mdns"],
+    &["--no-default-features", "--features", "ping"],
+    &["--no-default-features", "--features", "relay"],
+    &["--no-default-features", "--features", "mdns,ping,relay"],
+    &["--no-default-features", "--features", "http-api"],
+    &["--no-default-features", "--features", "scripting"],
+    &["--features", "relay"],
+    &["--features", "otlp"],
+    &["--features", "profiling"],
+    &["--features", "scripting"],
+];
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("features") => features(),
+        Some("flamegraph") => flamegraph(args.next()),
+        _ => {
+            eprintln!("Usage: cargo xtask <features | flamegraph [secs]>");
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("Within the repository")
+        .to_path_buf()
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let status = command.status()?;
+    if !status.success() {
+        return Err(format!("{:?} failed: {}", command, status).into());
+    }
+    Ok(())
+}
+
+fn cargo() -> Command {
+    let mut cargo = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".into()));
+    cargo.current_dir(root());
+    cargo
+}
+
+fn features() -> Result<()> {
+    for features in FEATURE_MATRIX {
+        println!("Checking {}", features.join(" "));
+        run(cargo()
+            .args(["clippy", "--all-targets"])
+            .args(*features)
+            .args(["--", "-D", "warnings"]))?;
+    }
+    Ok(())
+}
+
+fn flamegraph(secs: Option<String>) -> Result<()> {
+    let secs = secs.map(|s| s.parse()).transpose()?.unwrap_or(DEFAULT_SECS);
+    let root = root();
+    run(cargo().args(["build", "--release", "--features", "profiling"]))?;
+    let agora = root.join("target/release/agora");
+    let dir = root.join("target/flamegraph");
+    fs::create_dir_all(&dir)?;
+
+    let mut node = Command::new(&agora)
+        .current_dir(&dir)
+        .args([
+            "node",
+            "--render",
+            "--no-local-discovery",
+            "--listen",
+            NODE_ADDR,
+        ])
+        .args(["--profile-cpu", &secs.to_string()])
+        .stdout(Stdio::null())
+        .spawn()?;
+    // The workload: one sender after the other joining and publishing.
+    let mut sent = 0;
+    let status = loop {
+        if let Some(status) = node.try_wait()? {
+            break status;
+        }
+        let _ = Command::new(&agora)
+            .args(["send", "--no-local-discovery", "--bootstrap", NODE_ADDR])
+            .args(["--timeout", "5", "--message"])
+            .arg(format!("Message {} of the flamegraph workload", sent))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        sent += 1;
+        thread::sleep(Duration::from_millis(50));
+    };
+    if !status.success() {
+        return Err(format!("The profiled node failed: {}", status).into());
+    }
+    println!("Sent {} messages", sent);
+
+    let svg = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str());
+            matches!(name, Some(name) if name.starts_with("agora-flamegraph-") && name.ends_with(".svg"))
+        })
+        // Timestamped names sort chronologically.
+        .max()
+        .ok_or("No flamegraph written")?;
+    println!("{}", svg.display());
+    open(&svg)
+}
+
+fn open(path: &Path) -> Result<()> {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    run(Command::new(opener).arg(path))
+}